@@ -0,0 +1,74 @@
+//! Test a bot without a server.
+//!
+//! # Example
+//!
+//! ```
+//! # use tsclientlib::ClientId;
+//! # use tsclientlib_bot::{Bot, Command};
+//! # use tsclientlib_bot::harness::TestHarness;
+//! let bot = Bot::new("!").command(Command::new("ping", |ctx, _| {
+//! 	ctx.reply("pong");
+//! }));
+//! let harness = TestHarness::new(bot);
+//! assert_eq!(harness.send_private(ClientId(1), "!ping"), vec!["pong"]);
+//! ```
+use std::collections::HashMap;
+
+use tsclientlib::{ClientId, ServerGroupId, TextMessageTargetMode, Uid};
+
+use crate::{Bot, Reply, TextMessage};
+
+/// Feeds synthetic messages into a bot.
+///
+/// Handlers get no connection in their [`Context`].
+///
+/// [`Context`]: ../struct.Context.html
+pub struct TestHarness {
+	bot: Bot,
+	server_groups: HashMap<ClientId, Vec<ServerGroupId>>,
+}
+
+impl TestHarness {
+	pub fn new(bot: Bot) -> Self {
+		Self { bot, server_groups: HashMap::new() }
+	}
+
+	/// Set the server groups of a client, by default clients are in no group.
+	pub fn server_groups(mut self, client: ClientId,
+		groups: Vec<ServerGroupId>) -> Self {
+		self.server_groups.insert(client, groups);
+		self
+	}
+
+	/// Send a message and get all replies.
+	pub fn send(&self, client: ClientId, target_mode: TextMessageTargetMode,
+		message: &str) -> Vec<Reply> {
+		let msg = TextMessage {
+			target_mode,
+			invoker: client,
+			invoker_name: format!("Client {}", client.0),
+			invoker_uid: Some(Uid(format!("client-{}", client.0))),
+			message: message.to_string(),
+		};
+		let groups = self.server_groups.get(&client)
+			.map(|g| g.as_slice())
+			.unwrap_or_default();
+		self.bot.handle(&msg, groups, None)
+	}
+
+	/// Send a private message and get the text of all replies.
+	pub fn send_private(&self, client: ClientId, message: &str)
+		-> Vec<String> {
+		self.texts(self.send(client, TextMessageTargetMode::Client, message))
+	}
+
+	/// Send a message to the channel and get the text of all replies.
+	pub fn send_channel(&self, client: ClientId, message: &str)
+		-> Vec<String> {
+		self.texts(self.send(client, TextMessageTargetMode::Channel, message))
+	}
+
+	fn texts(&self, replies: Vec<Reply>) -> Vec<String> {
+		replies.into_iter().map(|r| r.message).collect()
+	}
+}
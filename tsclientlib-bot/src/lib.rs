@@ -0,0 +1,483 @@
+//! A framework for chat bots, which react to commands in text messages.
+//!
+//! A [`Bot`] has a list of [`Command`]s. A command is a message, which starts
+//! with a prefix like `!`, followed by the name of the command and its
+//! arguments, e.g. `!kick Alice "for no reason"`. Arguments with spaces can be
+//! put into quotes.
+//!
+//! The text messages of a connection are received with the packet handler from
+//! [`message_handler`]. The [`TestHarness`] can be used to test a bot without
+//! a server.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use futures::Future;
+//! # use tsclientlib::{ConnectOptions, Connection};
+//! # use tsclientlib_bot::{message_handler, Bot, Command};
+//! # fn f(logger: slog::Logger) {
+//! let bot = Bot::new("!").command(Command::new("echo", |ctx, args| {
+//! 	let text = args.get("text").unwrap_or_default().to_string();
+//! 	ctx.reply(text);
+//! }).rest_arg("text").description("Repeat a text"));
+//!
+//! let (handler, messages) = message_handler(logger.clone());
+//! let options = ConnectOptions::new("localhost").handle_packets(handler);
+//! tokio::spawn(Connection::new(options)
+//! 	.and_then(move |con| bot.run(con, messages, logger))
+//! 	.map_err(|e| println!("Error: {}", e)));
+//! # }
+//! ```
+//!
+//! [`Bot`]: struct.Bot.html
+//! [`Command`]: struct.Command.html
+//! [`message_handler`]: message/fn.message_handler.html
+//! [`TestHarness`]: harness/struct.TestHarness.html
+#[macro_use]
+extern crate failure;
+
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use parking_lot::Mutex;
+use slog::{warn, Logger};
+use tsclientlib::{
+	data, ClientId, Connection, ServerGroupId, TextMessageTargetMode,
+};
+
+pub mod harness;
+pub mod message;
+
+pub use crate::message::{message_handler, MessageStream, Reply, TextMessage};
+
+type Result<T> = std::result::Result<T, failure::Error>;
+pub type Handler = Box<Fn(&mut Context, &Args) + Send + Sync>;
+
+/// A command of a bot.
+pub struct Command {
+	name: String,
+	description: String,
+	args: Vec<Argument>,
+	/// If not empty, the sender has to be in one of these groups.
+	server_groups: Vec<ServerGroupId>,
+	handler: Handler,
+}
+
+struct Argument {
+	name: String,
+	required: bool,
+	/// Takes the rest of the message.
+	rest: bool,
+}
+
+/// The parsed arguments of a command.
+#[derive(Clone, Debug, Default)]
+pub struct Args(HashMap<String, String>);
+
+/// Passed to the handler of a command.
+pub struct Context<'a> {
+	pub message: &'a TextMessage,
+	/// The state of the connection, `None` in tests.
+	pub connection: Option<&'a data::Connection>,
+	replies: Vec<Reply>,
+}
+
+pub struct Bot {
+	prefix: String,
+	commands: Vec<Command>,
+	/// At most this many commands per client in this time.
+	rate_limit: Option<(usize, Duration)>,
+	history: Mutex<HashMap<ClientId, VecDeque<Instant>>>,
+}
+
+impl Command {
+	pub fn new<F: Fn(&mut Context, &Args) + Send + Sync + 'static>(
+		name: &str,
+		handler: F,
+	) -> Self
+	{
+		Self {
+			name: name.to_string(),
+			description: String::new(),
+			args: Vec::new(),
+			server_groups: Vec::new(),
+			handler: Box::new(handler),
+		}
+	}
+
+	/// The description is shown in the help.
+	pub fn description(mut self, description: &str) -> Self {
+		self.description = description.to_string();
+		self
+	}
+
+	/// Add a required argument.
+	pub fn arg(self, name: &str) -> Self { self.add_arg(name, true, false) }
+
+	/// Add an optional argument, it has to be after all required arguments.
+	pub fn optional_arg(self, name: &str) -> Self {
+		self.add_arg(name, false, false)
+	}
+
+	/// Add an optional argument, which takes the rest of the message.
+	pub fn rest_arg(self, name: &str) -> Self {
+		self.add_arg(name, false, true)
+	}
+
+	fn add_arg(mut self, name: &str, required: bool, rest: bool) -> Self {
+		self.args.push(Argument { name: name.to_string(), required, rest });
+		self
+	}
+
+	/// Only allow clients in one of these server groups to use the command.
+	pub fn server_groups(mut self, groups: Vec<ServerGroupId>) -> Self {
+		self.server_groups = groups;
+		self
+	}
+
+	pub fn usage(&self, prefix: &str) -> String {
+		let mut res = format!("{}{}", prefix, self.name);
+		for a in &self.args {
+			let dots = if a.rest { "…" } else { "" };
+			if a.required {
+				res.push_str(&format!(" <{}{}>", a.name, dots));
+			} else {
+				res.push_str(&format!(" [{}{}]", a.name, dots));
+			}
+		}
+		res
+	}
+
+	fn is_allowed(&self, groups: &[ServerGroupId]) -> bool {
+		self.server_groups.is_empty()
+			|| self.server_groups.iter().any(|g| groups.contains(g))
+	}
+
+	fn parse_args(&self, words: &[String]) -> Option<Args> {
+		let mut res = HashMap::new();
+		let mut words = words.iter();
+		for a in &self.args {
+			if a.rest {
+				let rest = words.by_ref().cloned().collect::<Vec<_>>();
+				if !rest.is_empty() {
+					res.insert(a.name.clone(), rest.join(" "));
+				}
+			} else if let Some(w) = words.next() {
+				res.insert(a.name.clone(), w.clone());
+			} else if a.required {
+				return None;
+			}
+		}
+		if words.next().is_some() {
+			// Too many arguments
+			return None;
+		}
+		Some(Args(res))
+	}
+}
+
+impl Args {
+	pub fn get(&self, name: &str) -> Option<&str> {
+		self.0.get(name).map(|s| s.as_str())
+	}
+
+	/// Parse an argument, returns `None` if the argument was not given.
+	pub fn get_parse<T: FromStr>(&self, name: &str) -> Option<Result<T>>
+	where T::Err: std::fmt::Debug {
+		self.get(name).map(|s| s.parse().map_err(|e| {
+			format_err!("Cannot parse {} ({:?})", name, e)
+		}))
+	}
+}
+
+impl<'a> Context<'a> {
+	/// Answer in the chat, in which the command was sent.
+	pub fn reply<S: Into<String>>(&mut self, message: S) {
+		let reply = self.message.reply(message);
+		self.replies.push(reply);
+	}
+
+	/// Answer in a private chat with the sender.
+	pub fn reply_private<S: Into<String>>(&mut self, message: S) {
+		self.replies.push(Reply {
+			target_mode: TextMessageTargetMode::Client,
+			target: Some(self.message.invoker),
+			message: message.into(),
+		});
+	}
+
+	/// Send a message to another chat.
+	pub fn send(&mut self, reply: Reply) { self.replies.push(reply); }
+}
+
+impl Bot {
+	/// Create a bot, which reacts to messages starting with `prefix`.
+	///
+	/// The bot contains a `help` command, which lists all commands.
+	pub fn new(prefix: &str) -> Self {
+		Self {
+			prefix: prefix.to_string(),
+			commands: Vec::new(),
+			rate_limit: None,
+			history: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Add a command.
+	pub fn command(mut self, command: Command) -> Self {
+		self.commands.push(command);
+		self
+	}
+
+	/// Ignore commands of a client, if it sent more than `count` commands
+	/// in the last `duration`.
+	pub fn rate_limit(mut self, count: usize, duration: Duration) -> Self {
+		self.rate_limit = Some((count, duration));
+		self
+	}
+
+	/// Handle the received text messages of a connection until the stream
+	/// ends.
+	pub fn run(
+		self,
+		con: Connection,
+		messages: MessageStream,
+		logger: Logger,
+	) -> impl Future<Item = (), Error = tsclientlib::Error>
+	{
+		messages
+			.map_err(|_| -> tsclientlib::Error {
+				format_err!("Failed to receive message").into()
+			})
+			.for_each(move |msg| {
+				let replies = {
+					let c = con.lock();
+					// Ignore our own messages
+					if msg.invoker == c.own_client {
+						Vec::new()
+					} else {
+						self.handle_message(&*c, &msg)
+					}
+				};
+				let logger = logger.clone();
+				future::join_all(replies.iter().map(|r| r.send(&con))
+					.collect::<Vec<_>>())
+					.then(move |r| {
+						if let Err(e) = r {
+							warn!(logger, "Failed to send reply";
+								"error" => ?e);
+						}
+						Ok::<_, tsclientlib::Error>(())
+					})
+			})
+	}
+
+	/// Handle a message and get the replies.
+	///
+	/// The server groups of the sender are taken from the connection.
+	pub fn handle_message(&self, con: &data::Connection, msg: &TextMessage)
+		-> Vec<Reply> {
+		let groups = con.server.clients.get(&msg.invoker)
+			.map(|c| c.server_groups.iter().cloned().collect::<Vec<_>>())
+			.unwrap_or_default();
+		self.handle(msg, &groups, Some(con))
+	}
+
+	pub(crate) fn handle(
+		&self,
+		msg: &TextMessage,
+		groups: &[ServerGroupId],
+		con: Option<&data::Connection>,
+	) -> Vec<Reply>
+	{
+		if !msg.message.starts_with(&self.prefix) {
+			return Vec::new();
+		}
+		let words = split_words(&msg.message[self.prefix.len()..]);
+		let name = match words.first() {
+			Some(n) => n.as_str(),
+			None => return Vec::new(),
+		};
+		if !self.check_rate_limit(msg.invoker) {
+			return Vec::new();
+		}
+
+		if name == "help" {
+			let text = match words.get(1) {
+				Some(c) => self.command_help(c, groups),
+				None => self.help(groups),
+			};
+			return vec![msg.reply(text)];
+		}
+
+		let command = match self.commands.iter().find(|c| c.name == name) {
+			Some(c) => c,
+			None => {
+				// Do not spam public chats, other bots may know the command
+				if msg.target_mode == TextMessageTargetMode::Client {
+					return vec![msg.reply(format!(
+						"Unknown command, try {}help", self.prefix))];
+				}
+				return Vec::new();
+			}
+		};
+		if !command.is_allowed(groups) {
+			return vec![msg.reply("You are not allowed to use this command")];
+		}
+		let args = match command.parse_args(&words[1..]) {
+			Some(a) => a,
+			None => return vec![msg.reply(format!("Usage: {}",
+				command.usage(&self.prefix)))],
+		};
+
+		let mut ctx = Context {
+			message: msg,
+			connection: con,
+			replies: Vec::new(),
+		};
+		(command.handler)(&mut ctx, &args);
+		ctx.replies
+	}
+
+	/// The list of commands, which can be used with these groups.
+	pub fn help(&self, groups: &[ServerGroupId]) -> String {
+		let mut res = String::from("Commands:");
+		for c in self.commands.iter().filter(|c| c.is_allowed(groups)) {
+			res.push_str(&format!("\n{}", c.usage(&self.prefix)));
+			if !c.description.is_empty() {
+				res.push_str(&format!(" – {}", c.description));
+			}
+		}
+		res.push_str(&format!("\n{}help [command]", self.prefix));
+		res
+	}
+
+	fn command_help(&self, name: &str, groups: &[ServerGroupId]) -> String {
+		match self.commands.iter()
+			.find(|c| c.name == name && c.is_allowed(groups)) {
+			Some(c) if c.description.is_empty() => c.usage(&self.prefix),
+			Some(c) => format!("{}\n{}", c.usage(&self.prefix), c.description),
+			None => format!("Unknown command {}", name),
+		}
+	}
+
+	/// Returns `false` if the client sent too many commands.
+	fn check_rate_limit(&self, client: ClientId) -> bool {
+		let (count, duration) = match self.rate_limit {
+			Some(l) => l,
+			None => return true,
+		};
+		let now = Instant::now();
+		let mut history = self.history.lock();
+		// Forget old commands and clients without recent commands
+		history.retain(|_, times| {
+			while times.front().map(|t| now - *t >= duration).unwrap_or(false)
+			{
+				times.pop_front();
+			}
+			!times.is_empty()
+		});
+		let times = history.entry(client).or_default();
+		if times.len() >= count {
+			return false;
+		}
+		times.push_back(now);
+		true
+	}
+}
+
+/// Split a message at whitespace, text in quotes is kept together.
+fn split_words(s: &str) -> Vec<String> {
+	let mut res = Vec::new();
+	let mut cur = String::new();
+	let mut in_word = false;
+	let mut in_quotes = false;
+	for c in s.chars() {
+		if c == '"' {
+			in_quotes = !in_quotes;
+			in_word = true;
+		} else if c.is_whitespace() && !in_quotes {
+			if in_word {
+				res.push(std::mem::replace(&mut cur, String::new()));
+				in_word = false;
+			}
+		} else {
+			cur.push(c);
+			in_word = true;
+		}
+	}
+	if in_word {
+		res.push(cur);
+	}
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::harness::TestHarness;
+
+	fn bot() -> Bot {
+		Bot::new("!")
+			.command(Command::new("echo", |ctx, args| {
+				let text = args.get("text").unwrap_or_default().to_string();
+				ctx.reply(text);
+			}).rest_arg("text").description("Repeat a text"))
+			.command(Command::new("kick", |ctx, args| {
+				let name = args.get("name").unwrap().to_string();
+				ctx.reply(format!("Kicked {}", name));
+			}).arg("name").optional_arg("reason")
+				.server_groups(vec![ServerGroupId(6)]))
+	}
+
+	#[test]
+	fn words() {
+		assert_eq!(split_words(r#" kick "Alice B" now "#),
+			vec!["kick", "Alice B", "now"]);
+		assert_eq!(split_words(r#"a """#), vec!["a", ""]);
+	}
+
+	#[test]
+	fn commands() {
+		let harness = TestHarness::new(bot())
+			.server_groups(ClientId(2), vec![ServerGroupId(6)]);
+		let replies = harness.send_private(ClientId(1), "!echo a  b");
+		assert_eq!(replies, vec!["a b"]);
+		assert!(harness.send_channel(ClientId(1), "echo a").is_empty());
+		assert!(harness.send_channel(ClientId(1), "!unknown").is_empty());
+
+		assert_eq!(harness.send_private(ClientId(1), "!kick Alice"),
+			vec!["You are not allowed to use this command"]);
+		assert_eq!(harness.send_private(ClientId(2), "!kick"),
+			vec!["Usage: !kick <name> [reason]"]);
+		assert_eq!(harness.send_private(ClientId(2), "!kick \"Alice B\""),
+			vec!["Kicked Alice B"]);
+
+		let help = harness.send_private(ClientId(1), "!help");
+		assert_eq!(help, vec!["Commands:\n!echo [text…] – Repeat a text\n\
+			!help [command]"]);
+	}
+
+	#[test]
+	fn rate_limit() {
+		let harness = TestHarness::new(bot()
+			.rate_limit(2, Duration::from_secs(60)));
+		assert_eq!(harness.send_private(ClientId(1), "!echo 1").len(), 1);
+		assert_eq!(harness.send_private(ClientId(1), "!echo 2").len(), 1);
+		assert!(harness.send_private(ClientId(1), "!echo 3").is_empty());
+		// Other clients are not limited
+		assert_eq!(harness.send_private(ClientId(2), "!echo 4").len(), 1);
+	}
+
+	#[test]
+	fn rate_limit_history() {
+		let bot = bot().rate_limit(1, Duration::from_secs(0));
+		assert!(bot.check_rate_limit(ClientId(1)));
+		assert!(bot.check_rate_limit(ClientId(2)));
+		// The expired command of the first client is removed
+		assert_eq!(bot.history.lock().keys().collect::<Vec<_>>(),
+			vec![&ClientId(2)]);
+	}
+}
@@ -0,0 +1,162 @@
+//! Receive and send text messages.
+use futures::sync::mpsc;
+use futures::{Future, Stream};
+use slog::{warn, Logger};
+use tsclientlib::{
+	ClientId, Connection, PHBox, PacketHandler, TextMessageTargetMode, Uid,
+};
+use tsproto::packets::{Direction, InAudio, InCommand, OutCommand, PacketType};
+
+use crate::Result;
+
+/// A text message, which was received from the server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TextMessage {
+	/// If the message was sent to us, to the channel or to the server.
+	pub target_mode: TextMessageTargetMode,
+	pub invoker: ClientId,
+	pub invoker_name: String,
+	pub invoker_uid: Option<Uid>,
+	pub message: String,
+}
+
+/// An answer to a message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Reply {
+	pub target_mode: TextMessageTargetMode,
+	/// The receiver for private messages.
+	pub target: Option<ClientId>,
+	pub message: String,
+}
+
+/// The received text messages of a connection.
+pub type MessageStream = mpsc::UnboundedReceiver<TextMessage>;
+
+/// Forwards text messages from the command stream of a connection.
+///
+/// Use [`message_handler`] to create it.
+///
+/// [`message_handler`]: fn.message_handler.html
+struct MessageHandler {
+	send: mpsc::UnboundedSender<TextMessage>,
+	logger: Logger,
+}
+
+impl TextMessage {
+	/// Get the text messages from a `notifytextmessage`.
+	pub fn parse(cmd: &InCommand) -> Result<Vec<Self>> {
+		if cmd.name() != "notifytextmessage" {
+			return Ok(Vec::new());
+		}
+		cmd.iter().map(|r| {
+			let get = |key: &str| r.get(key).ok_or_else(||
+				format_err!("Missing argument {}", key));
+			let target_mode = match get("targetmode")? {
+				"1" => TextMessageTargetMode::Client,
+				"2" => TextMessageTargetMode::Channel,
+				"3" => TextMessageTargetMode::Server,
+				_ => TextMessageTargetMode::Unknown,
+			};
+			Ok(Self {
+				target_mode,
+				invoker: ClientId(get("invokerid")?.parse().map_err(|e|
+					format_err!("Cannot parse invokerid ({:?})", e))?),
+				invoker_name: get("invokername")?.to_string(),
+				invoker_uid: r.get("invokeruid").map(|u| Uid(u.to_string())),
+				message: get("msg")?.to_string(),
+			})
+		}).collect()
+	}
+
+	/// Answer in the same chat, in which the message was received.
+	pub fn reply<S: Into<String>>(&self, message: S) -> Reply {
+		let target = if self.target_mode == TextMessageTargetMode::Client {
+			Some(self.invoker)
+		} else {
+			None
+		};
+		Reply { target_mode: self.target_mode, target, message: message.into() }
+	}
+}
+
+impl Reply {
+	/// Send this reply.
+	///
+	/// Messages to the channel go into the current channel of our own client.
+	pub fn send(&self, con: &Connection)
+		-> impl Future<Item = (), Error = tsclientlib::Error> {
+		let (mode, target) = match self.target_mode {
+			TextMessageTargetMode::Client => (1, self.target),
+			TextMessageTargetMode::Channel => (2, None),
+			_ => (3, None),
+		};
+		let mut args = vec![
+			("targetmode", mode.to_string()),
+			("msg", self.message.clone()),
+		];
+		if let Some(target) = target {
+			args.push(("target", target.0.to_string()));
+		}
+		let packet = OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"sendtextmessage",
+			args.into_iter(),
+			std::iter::empty(),
+		);
+		con.send_packet(packet)
+	}
+}
+
+/// Create a packet handler, which returns all received text messages in a
+/// stream.
+///
+/// The packet handler has to be set with [`ConnectOptions::handle_packets`].
+/// Messages which cannot be parsed are logged to `logger`.
+///
+/// [`ConnectOptions::handle_packets`]: ../../tsclientlib/struct.ConnectOptions.html#method.handle_packets
+pub fn message_handler(logger: Logger) -> (PHBox, MessageStream) {
+	let (send, recv) = mpsc::unbounded();
+	(Box::new(MessageHandler { send, logger }), recv)
+}
+
+impl PacketHandler for MessageHandler {
+	fn new_connection(
+		&mut self,
+		command_stream: Box<
+			Stream<Item = InCommand, Error = tsproto::Error> + Send,
+		>,
+		audio_stream: Box<
+			Stream<Item = InAudio, Error = tsproto::Error> + Send,
+		>,
+	)
+	{
+		let send = self.send.clone();
+		let logger = self.logger.clone();
+		let logger2 = self.logger.clone();
+		tokio::spawn(command_stream.for_each(move |cmd| {
+			match TextMessage::parse(&cmd) {
+				Ok(msgs) => for msg in msgs {
+					// The receiver may be gone
+					let _ = send.unbounded_send(msg);
+				},
+				Err(e) => warn!(logger, "Failed to parse text message";
+					"error" => ?e),
+			}
+			Ok(())
+		}).map_err(move |e| {
+			warn!(logger2, "Command stream exited with error"; "error" => ?e)
+		}));
+		let logger = self.logger.clone();
+		tokio::spawn(audio_stream.for_each(|_| Ok(())).map_err(move |e| {
+			warn!(logger, "Audio stream exited with error"; "error" => ?e)
+		}));
+	}
+
+	fn clone(&self) -> PHBox {
+		Box::new(MessageHandler {
+			send: self.send.clone(),
+			logger: self.logger.clone(),
+		})
+	}
+}
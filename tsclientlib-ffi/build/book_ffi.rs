@@ -31,7 +31,7 @@ fn get_ffi_type(s: &str) -> String {
 		return format!("Option<{}>", get_ffi_type(inner));
 	}
 	match s {
-		"str" => "*mut c_char",
+		"str" | "String" => "*mut c_char",
 		"ClientId" => "u16",
 		"Uid" => "*mut c_char",
 		"ClientDbId" => "u64",
@@ -45,7 +45,7 @@ fn get_ffi_type(s: &str) -> String {
 		// Enum
 		"GroupType" | "GroupNamingMode" | "Codec" | "ChannelType" | "ClientType"
 		| "HostMessageMode" | "CodecEncryptionMode" | "HostBannerMode"
-		| "LicenseType" | "TextMessageTargetMode" => "u32",
+		| "LicenseType" | "TextMessageTargetMode" | "TalkStatus" => "u32",
 		_ => s,
 	}.into()
 }
@@ -82,7 +82,8 @@ fn get_id_arg_names(structs: &[Struct], struc: &Struct) -> String {
 /// Convert to ffi type
 fn convert_val(type_s: &str) -> String {
 	match type_s {
-		"str" => "CString::new(val.as_bytes()).unwrap().into_raw()".into(),
+		"str" | "String" =>
+			"CString::new(val.as_bytes()).unwrap().into_raw()".into(),
 		"Uid" => "CString::new(val.0.as_bytes()).unwrap().into_raw()".into(),
 		"ClientId" | "ClientDbId" | "ChannelId" | "ServerGroupId"
 		| "ChannelGroupId" | "IconHash" => "val.0".into(),
@@ -93,7 +94,7 @@ fn convert_val(type_s: &str) -> String {
 		// Enum
 		"GroupType" | "GroupNamingMode" | "Codec" | "ChannelType" | "ClientType"
 		| "HostMessageMode" | "CodecEncryptionMode" | "HostBannerMode"
-		| "LicenseType" | "TextMessageTargetMode" =>
+		| "LicenseType" | "TextMessageTargetMode" | "TalkStatus" =>
 		"val.to_u32().unwrap()".into(),
 		_ => "*val".into(),
 	}
@@ -116,7 +117,7 @@ fn convert_to_rust(name: &str, type_s: &str) -> String {
 		// Enum
 		"GroupType" | "GroupNamingMode" | "Codec" | "ChannelType" | "ClientType"
 		| "HostMessageMode" | "CodecEncryptionMode" | "HostBannerMode"
-		| "LicenseType" | "TextMessageTargetMode" =>
+		| "LicenseType" | "TextMessageTargetMode" | "TalkStatus" =>
 		format!("{}.from_u32({}).unwrap()", type_s, name),
 		_ => name.into(),
 	}
@@ -1,5 +1,3 @@
-#[macro_use]
-extern crate failure;
 
 use std::ffi::{CStr, CString};
 use std::fmt;
@@ -12,8 +10,9 @@ use lazy_static::lazy_static;
 use num::ToPrimitive;
 use parking_lot::{Mutex, RwLock};
 use slog::{error, o, Drain, Logger};
+use tsclientlib::manager::{self, ConnectionManager};
 use tsclientlib::{
-	ChannelId, ClientId, ConnectOptions, Connection, ServerGroupId,
+	ChannelGroupId, ChannelId, ClientId, ConnectOptions, ServerGroupId,
 };
 use tsproto::packets::OutPacket;
 use tsproto_audio::{audio_to_ts, ts_to_audio};
@@ -31,10 +30,9 @@ lazy_static! {
 
 	static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Runtime::new()
 		.unwrap();
-	static ref FIRST_FREE_CON_ID: Mutex<ConnectionId> =
-		Mutex::new(ConnectionId(0));
-	static ref CONNECTIONS: CHashMap<ConnectionId, Connection> =
-		CHashMap::new();
+	/// All connections share their sockets if possible.
+	static ref CONNECTIONS: ConnectionManager =
+		ConnectionManager::new(Some(LOGGER.clone()));
 
 	// TODO In theory, this should be only one for every connection
 	/// The gstreamer pipeline which plays back other peoples voice.
@@ -94,6 +92,14 @@ impl Event {
 	}
 }
 
+impl From<manager::ConnectionId> for ConnectionId {
+	fn from(id: manager::ConnectionId) -> Self { ConnectionId(id.0 as u32) }
+}
+
+impl From<ConnectionId> for manager::ConnectionId {
+	fn from(id: ConnectionId) -> Self { manager::ConnectionId(id.0 as usize) }
+}
+
 impl fmt::Display for ConnectionId {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		write!(f, "{:?}", self)
@@ -153,6 +159,7 @@ trait ConnectionExt {
 		&self,
 	) -> &tsclientlib::data::OptionalServerData;
 	fn get_server_group(&self, id: u64) -> &tsclientlib::data::ServerGroup;
+	fn get_channel_group(&self, id: u64) -> &tsclientlib::data::ChannelGroup;
 
 	fn get_client(&self, id: u16) -> &tsclientlib::data::Client;
 	fn get_connection_client_data(
@@ -200,6 +207,9 @@ impl ConnectionExt for tsclientlib::data::Connection {
 	fn get_server_group(&self, id: u64) -> &tsclientlib::data::ServerGroup {
 		self.server.groups.get(&ServerGroupId(id)).unwrap()
 	}
+	fn get_channel_group(&self, id: u64) -> &tsclientlib::data::ChannelGroup {
+		self.server.channel_groups.get(&ChannelGroupId(id)).unwrap()
+	}
 
 	fn get_client(&self, id: u16) -> &tsclientlib::data::Client {
 		self.server.clients.get(&ClientId(id)).unwrap()
@@ -257,12 +267,20 @@ impl ConnectionExt for tsclientlib::data::Connection {
 	}
 	fn get_file(
 		&self,
-		_id: u64,
-		_path: *const c_char,
-		_name: *const c_char,
+		id: u64,
+		path: *const c_char,
+		name: *const c_char,
 	) -> &tsclientlib::data::File
 	{
-		unimplemented!("TODO Files are not implemented")
+		let path = unsafe { CStr::from_ptr(path) }.to_str().unwrap();
+		let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap();
+		self.server
+			.channels
+			.get(&ChannelId(id))
+			.unwrap()
+			.files
+			.get(&tsclientlib::filetransfer::join_path(path, name))
+			.unwrap()
 	}
 }
 
@@ -282,27 +300,6 @@ impl<'a> ConnectionMutExt<'a> for tsclientlib::data::ConnectionMut<'a> {
 
 // TODO On future errors, send event
 
-impl ConnectionId {
-	fn next_free() -> Self {
-		let mut next_free = FIRST_FREE_CON_ID.lock();
-		let res = *next_free;
-		let mut next = res.0 + 1;
-		while CONNECTIONS.contains_key(&ConnectionId(next)) {
-			next += 1;
-		}
-		*next_free = ConnectionId(next);
-		res
-	}
-
-	/// Should be called when a connection is removed
-	fn mark_free(&self) {
-		let mut next_free = FIRST_FREE_CON_ID.lock();
-		if *self < *next_free {
-			*next_free = *self;
-		}
-	}
-}
-
 fn remove_connection(con_id: ConnectionId) {
 	// Disable sound for this connection
 	let mut cas = CURRENT_AUDIO_SINK.lock();
@@ -314,32 +311,36 @@ fn remove_connection(con_id: ConnectionId) {
 	drop(cas);
 	T2A_PIPES.remove(&con_id);
 
-	CONNECTIONS.remove(&con_id);
-	con_id.mark_free();
 	EVENTS.0.send(Event::ConnectionRemoved(con_id)).unwrap();
 }
 
 #[no_mangle]
 pub extern "C" fn connect(address: *const c_char) -> ConnectionId {
 	let address = unsafe { CStr::from_ptr(address) };
-	let options = ConnectOptions::new(address.to_str().unwrap());
-	let con_id = ConnectionId::next_free();
+	let mut options = ConnectOptions::new(address.to_str().unwrap());
+
+	// Create TeamSpeak to audio pipeline
+	let t2a_pipe = match ts_to_audio::Pipeline::new(LOGGER.clone(),
+		RUNTIME.executor()) {
+		Ok(t2a_pipe) => {
+			let aph = t2a_pipe.create_packet_handler();
+			options = options.audio_packet_handler(aph);
+			Some(t2a_pipe)
+		}
+		Err(e) => {
+			error!(LOGGER, "Failed to create t2a pipeline"; "error" => ?e);
+			None
+		}
+	};
+	let (con_id, con) = CONNECTIONS.start_connect(options);
+	let con_id = con_id.into();
+	if let Some(t2a_pipe) = t2a_pipe {
+		T2A_PIPES.insert(con_id, t2a_pipe);
+	}
 
 	RUNTIME.executor().spawn(
 		future::lazy(move || {
-			let mut options = options;
-			// Create TeamSpeak to audio pipeline
-			match ts_to_audio::Pipeline::new(LOGGER.clone(),
-				RUNTIME.executor()) {
-				Ok(t2a_pipe) => {
-					let aph = t2a_pipe.create_packet_handler();
-					options = options.audio_packet_handler(aph);
-					T2A_PIPES.insert(con_id, t2a_pipe);
-				}
-				Err(e) => error!(LOGGER, "Failed to create t2a pipeline";
-					"error" => ?e),
-			}
-			Connection::new(options).map(move |con| {
+			con.map(move |con| {
 				// Or automatically try to reconnect.
 				con.add_on_disconnect(Box::new(move || {
 					remove_connection(con_id);
@@ -365,7 +366,6 @@ pub extern "C" fn connect(address: *const c_char) -> ConnectionId {
 					}
 				}
 
-				CONNECTIONS.insert(con_id, con);
 				EVENTS.0.send(Event::ConnectionAdded(con_id)).unwrap();
 			})
 			.map_err(move |e| {
@@ -381,13 +381,7 @@ pub extern "C" fn connect(address: *const c_char) -> ConnectionId {
 pub extern "C" fn disconnect(con_id: ConnectionId) {
 	RUNTIME.executor().spawn(
 		future::lazy(move || {
-			if let Some(con) = CONNECTIONS.get(&con_id) {
-				con.clone().disconnect(None)
-			} else {
-				Box::new(future::err(
-					format_err!("Connection not found").into(),
-				))
-			}
+			CONNECTIONS.disconnect(con_id.into(), None)
 		})
 		.map_err(|_| ()),
 	);
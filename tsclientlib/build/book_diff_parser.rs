@@ -0,0 +1,61 @@
+use std::default::Default;
+use std::ops::Deref;
+
+use tsproto_structs::book::*;
+use tsproto_structs::messages_to_book::{self, MessagesToBookDeclarations};
+use tsproto_util::*;
+
+use crate::events::{get_event_properties, get_property_name};
+
+#[derive(Template)]
+#[TemplatePath = "build/BookDiff.tt"]
+#[derive(Debug)]
+pub struct BookDiffDeclarations<'a>(
+	&'a BookDeclarations,
+	&'a MessagesToBookDeclarations<'a>,
+);
+
+impl<'a> Deref for BookDiffDeclarations<'a> {
+	type Target = BookDeclarations;
+	fn deref(&self) -> &Self::Target { &self.0 }
+}
+
+impl Default for BookDiffDeclarations<'static> {
+	fn default() -> Self {
+		BookDiffDeclarations(&DATA, &messages_to_book::DATA)
+	}
+}
+
+impl<'a> BookDiffDeclarations<'a> {
+	/// If there is a `PropertyId` for this property.
+	fn is_event_property(&self, struc: &Struct, p: &Property) -> bool {
+		get_event_properties(&self.structs, self.1, struc)
+			.iter()
+			.any(|e| e.name == p.name)
+	}
+}
+
+/// The arguments for the `PropertyId` of a struct, the ids are taken from the
+/// struct `var`.
+fn get_ids(struc: &Struct, var: &str) -> String {
+	get_ids_with(struc, var, "")
+}
+
+/// Like `get_ids` but `extra` is appended to the ids if it is not empty.
+fn get_ids_with(struc: &Struct, var: &str, extra: &str) -> String {
+	let mut ids: Vec<_> = struc
+		.id
+		.iter()
+		.map(|id| {
+			format!("{}.{}.clone()", var, PropId::from(id).get_attr_name(struc))
+		})
+		.collect();
+	if !extra.is_empty() {
+		ids.push(format!("{}.clone()", extra));
+	}
+	if ids.is_empty() {
+		String::new()
+	} else {
+		format!("({})", ids.join(", "))
+	}
+}
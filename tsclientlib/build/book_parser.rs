@@ -11,3 +11,12 @@ pub struct BookDeclarations<'a>(pub &'a book::BookDeclarations);
 impl Default for BookDeclarations<'static> {
 	fn default() -> Self { BookDeclarations(&tsproto_structs::book::DATA) }
 }
+
+/// Types which do not implement serde traits are serialized by a module.
+fn get_serde_with(rust_type: &str) -> Option<&'static str> {
+	match rust_type {
+		"Duration" => Some("crate::snapshot::serde_duration"),
+		"Option<Duration>" => Some("crate::snapshot::serde_opt_duration"),
+		_ => None,
+	}
+}
@@ -6,12 +6,14 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
+mod book_diff_parser;
 mod book_parser;
 mod book_to_messages_parser;
 mod events;
 mod facade_parser;
 mod messages_to_book_parser;
 
+use crate::book_diff_parser::BookDiffDeclarations;
 use crate::book_parser::BookDeclarations;
 use crate::book_to_messages_parser::BookToMessagesDeclarations;
 use crate::events::EventDeclarations;
@@ -41,4 +43,8 @@ fn main() {
 	// Events
 	let mut structs = File::create(&path.join("events.rs")).unwrap();
 	write!(&mut structs, "{}", EventDeclarations::default()).unwrap();
+
+	// Book diff
+	let mut structs = File::create(&path.join("book_diff.rs")).unwrap();
+	write!(&mut structs, "{}", BookDiffDeclarations::default()).unwrap();
 }
@@ -54,7 +54,7 @@ pub fn get_property_name(p: &Property) -> &str {
 }
 
 /// Add only things which are in messages to book, which actually will be
-/// changed.
+/// changed, and properties which are managed by tsclientlib.
 pub fn get_event_properties<'a>(structs: &'a [Struct],
 	m2b: &'a MessagesToBookDeclarations<'a>, s: &'a Struct)
 	-> Vec<&'a Property> {
@@ -71,6 +71,6 @@ pub fn get_event_properties<'a>(structs: &'a [Struct],
 		if structs.iter().any(|s| s.name == p.type_s) {
 			return false;
 		}
-		set_props.contains(&p.name)
+		p.manual || set_props.contains(&p.name)
 	}).collect()
 }
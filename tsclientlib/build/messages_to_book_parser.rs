@@ -23,6 +23,21 @@ impl Default for MessagesToBookDeclarations<'static> {
 	fn default() -> Self { MessagesToBookDeclarations(&DATA) }
 }
 
+impl<'a> MessagesToBookDeclarations<'a> {
+	/// The rules grouped by their message, so every message is matched once.
+	fn get_message_groups(&self) -> Vec<(&'a Message, Vec<&'a Event<'a>>)> {
+		let mut res: Vec<(&Message, Vec<&Event>)> = Vec::new();
+		for e in &self.0.decls {
+			if let Some(g) = res.iter_mut().find(|g| g.0.name == e.msg.name) {
+				g.1.push(e);
+			} else {
+				res.push((e.msg, vec![e]));
+			}
+		}
+		res
+	}
+}
+
 fn get_id_args(event: &Event) -> String {
 	let mut res = String::new();
 	for f in &event.id {
@@ -75,6 +90,17 @@ fn try_result(s: &str) -> &'static str {
 	}
 }
 
+/// Getters of optional structs return an `Option` and the rules are only
+/// applied if the struct exists.
+fn is_optional_getter(s: &str) -> bool {
+	match s {
+		"get_mut_optional_client_data" | "get_mut_optional_channel_data" => {
+			true
+		}
+		_ => false,
+	}
+}
+
 fn get_property_name(e: &Event, p: &Property) -> String {
 	format!("{}{}", e.book_struct.name, crate::events::get_property_name(p))
 }
@@ -103,3 +129,22 @@ fn get_property_id(e: &Event, p: &Property, from: &Field) -> String {
 fn get_property(e: &Event, p: &Property, name: &str) -> String {
 	format!("Property::{}({})", get_property_name(e, p), name)
 }
+
+/// Properties which are managed by tsclientlib and not set by this event.
+///
+/// They are initialized with their default value when a struct is added.
+fn get_unset_manual_properties<'a>(e: &'a Event) -> Vec<&'a Property> {
+	e.book_struct
+		.properties
+		.iter()
+		.filter(|p| {
+			p.manual
+				&& !e.rules.iter().any(|r| match r {
+					RuleKind::Map { to, .. } => to.name == p.name,
+					RuleKind::Function { to, .. } => {
+						to.iter().any(|t| t.name == p.name)
+					}
+				})
+		})
+		.collect()
+}
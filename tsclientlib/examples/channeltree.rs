@@ -4,11 +4,10 @@ extern crate futures;
 extern crate structopt;
 extern crate tokio;
 extern crate tsclientlib;
-extern crate tsproto;
 
 use std::time::{Duration, Instant};
 
-use futures::{Future, Sink};
+use futures::Future;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use tokio::timer::Delay;
@@ -17,7 +16,6 @@ use tsclientlib::data::{self, Channel, Client};
 use tsclientlib::{
 	ChannelId, ConnectOptions, Connection, DisconnectOptions, Reason,
 };
-use tsproto::packets::{Direction, OutCommand, PacketType};
 
 #[derive(StructOpt, Debug)]
 #[structopt(raw(global_settings = "&[AppSettings::ColoredHelp, \
@@ -86,7 +84,9 @@ fn main() -> Result<(), failure::Error> {
 			let con_config = ConnectOptions::new(args.address)
 				.log_commands(args.verbose >= 1)
 				.log_packets(args.verbose >= 2)
-				.log_udp_packets(args.verbose >= 3);
+				.log_udp_packets(args.verbose >= 3)
+				// Get the clients in all channels
+				.auto_subscribe(true);
 
 			// Optionally set the key of this client, otherwise a new key is generated.
 			let con_config = con_config.private_key_str(
@@ -97,32 +97,6 @@ fn main() -> Result<(), failure::Error> {
 			// Connect
 			Connection::new(con_config)
 		})
-		.and_then(|con| {
-			let packet = OutCommand::new::<
-				String,
-				String,
-				String,
-				String,
-				_,
-				_,
-				std::iter::Empty<_>,
-			>(
-				Direction::C2S,
-				PacketType::Command,
-				"channelsubscribeall",
-				std::iter::empty(),
-				std::iter::empty(),
-			);
-
-			// Send a message and wait until we get an answer for the return code
-			con.get_packet_sink().send(packet).map(|_| con)
-		})
-		.and_then(|con| {
-			// Wait some time
-			Delay::new(Instant::now() + Duration::from_secs(1))
-				.map(move |_| con)
-				.map_err(|e| format_err!("Failed to wait ({:?})", e).into())
-		})
 		.and_then(|con| {
 			// Print channel tree
 			{
@@ -7,12 +7,13 @@ extern crate tsclientlib;
 
 use std::time::{Duration, Instant};
 
-use futures::{stream, Future, Stream};
+use futures::Future;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 use tokio::timer::Delay;
 
-use tsclientlib::{ConnectOptions, Connection};
+use tsclientlib::manager::ConnectionManager;
+use tsclientlib::ConnectOptions;
 
 #[derive(StructOpt, Debug)]
 #[structopt(raw(global_settings = "&[AppSettings::ColoredHelp, \
@@ -36,6 +37,13 @@ struct Args {
 	// 1. Print command string
 	// 2. Print packets
 	// 3. Print udp packets
+	#[structopt(
+		short = "i",
+		long = "interval",
+		default_value = "100",
+		help = "Milliseconds between two new connections"
+	)]
+	interval: u64,
 	#[structopt(help = "How many connections")]
 	count: usize,
 }
@@ -43,28 +51,36 @@ struct Args {
 fn main() -> Result<(), failure::Error> {
 	// Parse command line options
 	let args = Args::from_args();
+	let interval = Duration::from_millis(args.interval);
 
 	tokio::run(
-		futures::lazy(|| {
-			stream::futures_ordered((0..args.count).map(move |_| {
+		futures::lazy(move || {
+			let manager = ConnectionManager::new(None);
+			let options = (0..args.count).map(|i| {
 				let con_config = ConnectOptions::new(args.address.as_str())
+					.name(format!("Bot {}", i))
 					.log_commands(args.verbose >= 1)
 					.log_packets(args.verbose >= 2)
 					.log_udp_packets(args.verbose >= 3);
 
 				// Optionally set the key of this client, otherwise a new key is generated.
-				let con_config = con_config.private_key_ts(
+				con_config.private_key_str(
 					"MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
 					k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITs\
-					C/50CIA8M5nmDBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap();
+					C/50CIA8M5nmDBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap()
+			}).collect();
 
-				// Connect
-				Connection::new(con_config)
-			})).collect()
+			// Connect
+			manager.connect_all(options, interval).map(move |results| {
+				for r in results.iter().filter_map(|r| r.as_ref().err()) {
+					println!("Failed to connect: {}", r);
+				}
+				manager
+			})
 		})
-		.and_then(|cons| {
-			{
-				let con = cons[0].lock();
+		.and_then(|manager| {
+			if let Some(con) = manager.ids().first().and_then(|id| manager.get(*id)) {
+				let con = con.lock();
 				println!(
 					"Server welcome message: {}",
 					sanitize(&con.server.welcome_message)
@@ -73,13 +89,12 @@ fn main() -> Result<(), failure::Error> {
 
 			// Wait some time
 			Delay::new(Instant::now() + Duration::from_secs(10))
-				.map(move |_| cons)
+				.map(move |_| manager)
 				.map_err(|e| format_err!("Failed to wait ({:?})", e).into())
 		})
-		.and_then(|cons| {
+		.and_then(move |manager| {
 			// Disconnect
-			drop(cons);
-			Ok(())
+			manager.disconnect_all(None, interval)
 		})
 		.map_err(|e| panic!("An error occurred {:?}", e)),
 	);
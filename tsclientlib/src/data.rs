@@ -7,14 +7,17 @@ use std::net::SocketAddr;
 use std::ops::Deref;
 use std::u16;
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use futures::Future;
+use serde_derive::{Deserialize, Serialize};
 use slog::{debug, Logger};
+use tsproto::packets::{Direction, OutCommand, PacketType};
 use tsproto_commands::messages::s2c::{self, InMessage, InMessages};
 use tsproto_commands::*;
 
 use crate::{Error, Result};
 use crate::events::{Events, Property, PropertyId};
+use crate::talk::TalkStatus;
 
 include!(concat!(env!("OUT_DIR"), "/b2mdecls.rs"));
 include!(concat!(env!("OUT_DIR"), "/facades.rs"));
@@ -93,6 +96,7 @@ impl Connection {
 				clients: HashMap::new(),
 				channels: HashMap::new(),
 				groups: HashMap::new(),
+				channel_groups: HashMap::new(),
 			),
 		}
 	}
@@ -154,6 +158,26 @@ impl Connection {
 	fn remove_channel(&mut self, channel: ChannelId) -> Option<Channel> {
 		self.server.channels.remove(&channel)
 	}
+	fn get_mut_optional_channel_data(
+		&mut self,
+		channel: ChannelId,
+	) -> Option<&mut OptionalChannelData>
+	{
+		self.server
+			.channels
+			.get_mut(&channel)
+			.and_then(|c| c.optional_data.as_mut())
+	}
+	fn get_mut_optional_client_data(
+		&mut self,
+		client: ClientId,
+	) -> Option<&mut OptionalClientData>
+	{
+		self.server
+			.clients
+			.get_mut(&client)
+			.and_then(|c| c.optional_data.as_mut())
+	}
 
 	// Backing functions for MessageToBook declarations
 
@@ -273,6 +297,43 @@ impl Connection {
 		}
 	}
 
+	/// The answer to `channelgetdescription` creates the optional data.
+	fn optional_channel_data_fun(
+		&mut self,
+		channel_id: ChannelId,
+		cmd: &s2c::ChannelEditedPart,
+		events: &mut Vec<Events>,
+	)
+	{
+		if let Ok(channel) = self.get_mut_channel(channel_id) {
+			if channel.optional_data.is_none() && cmd.description.is_some() {
+				channel.optional_data = Some(OptionalChannelData::empty());
+				events.push(Events::PropertyAdded(
+					PropertyId::OptionalChannelData(channel_id)));
+			}
+		}
+	}
+
+	/// The answer to `clientgetvariables` creates the optional data.
+	///
+	/// Only this answer contains the creation time, other updates only
+	/// change attributes of existing data.
+	fn optional_client_data_fun(
+		&mut self,
+		client_id: ClientId,
+		cmd: &s2c::ClientUpdatedPart,
+		events: &mut Vec<Events>,
+	)
+	{
+		if let Ok(client) = self.get_mut_client(client_id) {
+			if client.optional_data.is_none() && cmd.created.is_some() {
+				client.optional_data = Some(OptionalClientData::empty());
+				events.push(Events::PropertyAdded(
+					PropertyId::OptionalClientData(client_id)));
+			}
+		}
+	}
+
 	fn away_fun(&self, cmd: &s2c::ClientEnterViewPart) -> Option<String> {
 		if cmd.is_away {
 			Some(cmd.away_message.into())
@@ -316,3 +377,83 @@ impl Connection {
 	}
 
 }
+
+impl<'a> ChannelMut<'a> {
+	/// Request the description of this channel.
+	///
+	/// The description is applied to the `optional_data` of the channel when
+	/// the `notifychanneledited` arrives, which emits the usual events.
+	///
+	/// The future resolves when the `notifychanneledited` for this channel was
+	/// received, so the description is already applied.
+	pub fn fetch_description(&self) -> impl Future<Item = (), Error = Error> {
+		let packet = OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"channelgetdescription",
+			vec![("cid", self.inner.id.0.to_string())].into_iter(),
+			std::iter::empty(),
+		);
+		self.connection.send_packet_and_wait(
+			packet,
+			"notifychanneledited",
+			"cid",
+			self.inner.id.0.to_string(),
+		)
+	}
+}
+
+impl<'a> ClientMut<'a> {
+	/// Request the details of this client, like the description, the creation
+	/// date and the number of connections.
+	///
+	/// The details are applied to the `optional_data` of the client when the
+	/// `notifyclientupdated` arrives, which emits the usual events.
+	///
+	/// The future resolves when the `notifyclientupdated` for this client was
+	/// received, so the details are already applied.
+	pub fn fetch_details(&self) -> impl Future<Item = (), Error = Error> {
+		let packet = OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"clientgetvariables",
+			vec![("clid", self.inner.id.0.to_string())].into_iter(),
+			std::iter::empty(),
+		);
+		self.connection.send_packet_and_wait(
+			packet,
+			"notifyclientupdated",
+			"clid",
+			self.inner.id.0.to_string(),
+		)
+	}
+}
+
+impl OptionalChannelData {
+	/// The data before the attributes are received.
+	fn empty() -> Self {
+		Self {
+			description: String::new(),
+		}
+	}
+}
+
+impl OptionalClientData {
+	/// The data before the attributes are received.
+	fn empty() -> Self {
+		Self {
+			version: String::new(),
+			platform: String::new(),
+			login_name: String::new(),
+			created: Utc.timestamp(0, 0),
+			last_connected: Utc.timestamp(0, 0),
+			connection_total: 0,
+			month_bytes_uploaded: 0,
+			month_bytes_downloaded: 0,
+			total_bytes_uploaded: 0,
+			total_bytes_downloaded: 0,
+			description: String::new(),
+			country: String::new(),
+		}
+	}
+}
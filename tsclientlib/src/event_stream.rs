@@ -0,0 +1,299 @@
+//! Receive events as a `Stream` instead of a callback.
+//!
+//! A stream is created with [`Connection::events`]. Every item contains the
+//! events, which were generated by a single message from the server. Only
+//! events, which pass the [`EventFilter`], are included.
+//!
+//! The stream buffers a limited number of batches. If the receiver is too
+//! slow, the oldest batches are dropped and the receiver gets notified with an
+//! [`EventBatch::Lagged`].
+//!
+//! [`Connection::events`]: ../struct.Connection.html#method.events
+//! [`EventFilter`]: struct.EventFilter.html
+//! [`EventBatch::Lagged`]: enum.EventBatch.html#variant.Lagged
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use futures::task::{self, Task};
+use futures::{Async, Poll, Stream};
+use parking_lot::{Mutex, RwLock};
+
+use crate::data;
+use crate::events::{Events, Property, PropertyId};
+use crate::{ChannelId, Error, EventListener};
+
+/// Used to create unique keys for the event listeners of streams.
+static NEXT_STREAM_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// An item of an [`EventStream`].
+///
+/// [`EventStream`]: struct.EventStream.html
+#[derive(Clone, Debug, PartialEq)]
+pub enum EventBatch {
+	/// The events, which were caused by a single message.
+	Events(Vec<Events>),
+	/// The buffer was full and this many batches were dropped.
+	Lagged(usize),
+}
+
+/// Decides which events are passed on to an [`EventStream`].
+///
+/// Filters can be combined with [`and`] and [`or`].
+///
+/// # Example
+///
+/// ```
+/// # use tsclientlib::ChannelId;
+/// # use tsclientlib::event_stream::EventFilter;
+/// // Moved clients and changes of the channel 5 and its subchannels
+/// let filter = EventFilter::client_moves()
+/// 	.or(EventFilter::channel_edits().and(EventFilter::subtree(ChannelId(5))));
+/// ```
+///
+/// [`EventStream`]: struct.EventStream.html
+/// [`and`]: #method.and
+/// [`or`]: #method.or
+pub struct EventFilter(Box<Fn(&data::Connection, &Events) -> bool + Send + Sync>);
+
+/// A stream of event batches, created by [`Connection::events`].
+///
+/// The stream ends when the connection is dropped.
+///
+/// [`Connection::events`]: ../struct.Connection.html#method.events
+pub struct EventStream {
+	key: String,
+	shared: Arc<Mutex<Shared>>,
+	listeners: Weak<RwLock<HashMap<String, EventListener>>>,
+}
+
+struct Shared {
+	capacity: usize,
+	queue: VecDeque<Vec<Events>>,
+	/// The number of dropped batches since the last `Lagged` notification.
+	lagged: usize,
+	/// Set when the event listener was removed.
+	closed: bool,
+	task: Option<Task>,
+}
+
+/// Owned by the event listener, closes the stream when it is dropped.
+struct Sender(Arc<Mutex<Shared>>);
+
+impl EventFilter {
+	pub fn new<F: Fn(&data::Connection, &Events) -> bool + Send + Sync + 'static>(
+		f: F,
+	) -> Self {
+		EventFilter(Box::new(f))
+	}
+
+	/// Pass all events.
+	pub fn all() -> Self { Self::new(|_, _| true) }
+
+	/// Only clients which switch their channel.
+	pub fn client_moves() -> Self {
+		Self::new(|_, e| match e {
+			Events::PropertyChanged(PropertyId::ClientChannel(_), _) => true,
+			_ => false,
+		})
+	}
+
+	/// Only channels which are added, removed or changed.
+	pub fn channel_edits() -> Self {
+		Self::new(|_, e| {
+			e.id().map(|id| id.struct_name() == "Channel").unwrap_or(false)
+		})
+	}
+
+	/// Only events of `root` and its subchannels.
+	///
+	/// Events, which do not belong to a channel, are filtered out.
+	pub fn subtree(root: ChannelId) -> Self {
+		Self::new(move |con, e| {
+			let channel = match e.id().and_then(|id| id.channel_id()) {
+				Some(c) => c,
+				None => return false,
+			};
+			// A removed channel is not in the connection anymore
+			let parent = match e {
+				Events::PropertyRemoved(_, Property::Channel(c)) => Some(c.parent),
+				_ => None,
+			};
+			is_in_subtree(con, channel, parent, root)
+		})
+	}
+
+	/// Pass events which pass both filters.
+	pub fn and(self, other: Self) -> Self {
+		Self::new(move |con, e| self.matches(con, e) && other.matches(con, e))
+	}
+
+	/// Pass events which pass one of the filters.
+	pub fn or(self, other: Self) -> Self {
+		Self::new(move |con, e| self.matches(con, e) || other.matches(con, e))
+	}
+
+	pub fn matches(&self, con: &data::Connection, event: &Events) -> bool {
+		(self.0)(con, event)
+	}
+}
+
+/// Check if `channel` is `root` or a subchannel of `root`.
+///
+/// `parent` can be given for channels which are not in the connection.
+fn is_in_subtree(
+	con: &data::Connection,
+	mut channel: ChannelId,
+	parent: Option<ChannelId>,
+	root: ChannelId,
+) -> bool
+{
+	if channel == root {
+		return true;
+	}
+	if let Some(p) = parent {
+		channel = p;
+	}
+	// Limit the depth in case the channel tree contains a cycle
+	for _ in 0..=con.server.channels.len() {
+		if channel == root {
+			return true;
+		}
+		match con.server.channels.get(&channel) {
+			Some(c) => channel = c.parent,
+			None => return false,
+		}
+	}
+	false
+}
+
+impl EventStream {
+	pub(crate) fn new(
+		listeners: &Arc<RwLock<HashMap<String, EventListener>>>,
+		filter: EventFilter,
+		capacity: usize,
+	) -> Self
+	{
+		let key = format!(
+			"tsclientlib-event-stream-{}",
+			NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed)
+		);
+		let shared = Arc::new(Mutex::new(Shared {
+			capacity: std::cmp::max(capacity, 1),
+			queue: VecDeque::new(),
+			lagged: 0,
+			closed: false,
+			task: None,
+		}));
+
+		let sender = Sender(shared.clone());
+		listeners.write().insert(
+			key.clone(),
+			Box::new(move |con, events| {
+				let events: Vec<_> = events
+					.iter()
+					.filter(|e| filter.matches(con, e))
+					.cloned()
+					.collect();
+				if !events.is_empty() {
+					sender.push(events);
+				}
+			}),
+		);
+
+		Self { key, shared, listeners: Arc::downgrade(listeners) }
+	}
+}
+
+impl Sender {
+	fn push(&self, events: Vec<Events>) {
+		let mut shared = self.0.lock();
+		if shared.queue.len() >= shared.capacity {
+			shared.queue.pop_front();
+			shared.lagged += 1;
+		}
+		shared.queue.push_back(events);
+		if let Some(task) = shared.task.take() {
+			task.notify();
+		}
+	}
+}
+
+impl Drop for Sender {
+	fn drop(&mut self) {
+		let mut shared = self.0.lock();
+		shared.closed = true;
+		if let Some(task) = shared.task.take() {
+			task.notify();
+		}
+	}
+}
+
+impl Stream for EventStream {
+	type Item = EventBatch;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		let mut shared = self.shared.lock();
+		// The dropped batches were older than the ones in the queue
+		if shared.lagged > 0 {
+			let lagged = std::mem::replace(&mut shared.lagged, 0);
+			return Ok(Async::Ready(Some(EventBatch::Lagged(lagged))));
+		}
+		if let Some(events) = shared.queue.pop_front() {
+			return Ok(Async::Ready(Some(EventBatch::Events(events))));
+		}
+		if shared.closed {
+			return Ok(Async::Ready(None));
+		}
+		shared.task = Some(task::current());
+		Ok(Async::NotReady)
+	}
+}
+
+impl Drop for EventStream {
+	fn drop(&mut self) {
+		if let Some(listeners) = self.listeners.upgrade() {
+			listeners.write().remove(&self.key);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::{future, Future};
+	use tokio::runtime::Runtime;
+
+	use crate::packet_handler::call_event_listeners;
+	use crate::tests::offline_connection;
+
+	fn added(id: u64) -> Events {
+		Events::PropertyAdded(PropertyId::ChannelGroup(
+			crate::ChannelGroupId(id),
+		))
+	}
+
+	#[test]
+	fn lagged_stream() {
+		let mut rt = Runtime::new().unwrap();
+		let items = rt
+			.block_on(future::lazy(|| {
+				let con = offline_connection();
+				let stream = con.events(EventFilter::all(), 2);
+				// Events reach the stream through its event listener
+				for i in 0..4 {
+					call_event_listeners(&con, &[added(i)]);
+				}
+				// Removing the listener ends the stream
+				con.inner.event_listeners.write().clear();
+				stream.collect()
+			}))
+			.unwrap();
+		assert_eq!(items, vec![
+			EventBatch::Lagged(2),
+			EventBatch::Events(vec![added(2)]),
+			EventBatch::Events(vec![added(3)]),
+		]);
+	}
+}
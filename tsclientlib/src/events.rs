@@ -5,15 +5,17 @@ use chrono::Duration;
 use crate::*;
 use crate::data::{ServerGroup, Server, OptionalChannelData, File, Channel,
 	OptionalClientData, ConnectionClientData, Client, OptionalServerData,
-	ConnectionServerData, ChatEntry, Connection,
+	ConnectionServerData, ChatEntry, Connection, ChannelGroup,
 };
+use crate::permissions::PermissionOwner;
+use crate::talk::TalkStatus;
 
 include!(concat!(env!("OUT_DIR"), "/events.rs"));
 
 /// An event gets fired when something in the data structure of a connection
 /// changes.
 ///
-/// The three different types are
+/// The three different types of changes in the data structure are
 ///
 /// - [`PropertyAdded`]: When a new item is added, like a client gets assigned
 ///   a new server group or a new client joins the server.
@@ -49,6 +51,20 @@ pub enum Events {
 	/// This happens when a client leaves the server (including our own client)
 	/// or a channel is removed.
 	PropertyRemoved(PropertyId, Property),
+	/// A permission list was received or changed.
+	///
+	/// The new list can be found in the [`Permissions`] of the connection.
+	///
+	/// [`Permissions`]: ../permissions/struct.Permissions.html
+	PermissionsChanged(PermissionOwner),
+	/// A client was added to a server group.
+	ServerGroupClientAdded(ClientId, ServerGroupId),
+	/// A client was removed from a server group.
+	ServerGroupClientRemoved(ClientId, ServerGroupId),
+	/// A client got a new channel group.
+	///
+	/// The second tuple item holds the old channel group.
+	ClientChannelGroupChanged(ClientId, ChannelGroupId),
 }
 
 impl Events {
@@ -56,11 +72,20 @@ impl Events {
 	///
 	/// For a removed object, you can no longer access it in the connection data
 	/// structure but the object is available in the second tuple item.
-	pub fn id(&self) -> &PropertyId {
+	///
+	/// Returns `None` for events which do not affect the connection data
+	/// structure, e.g. [`PermissionsChanged`].
+	///
+	/// [`PermissionsChanged`]: #variant.PermissionsChanged
+	pub fn id(&self) -> Option<&PropertyId> {
 		match self {
 			Events::PropertyAdded(id) |
 			Events::PropertyChanged(id, _) |
-			Events::PropertyRemoved(id, _) => id,
+			Events::PropertyRemoved(id, _) => Some(id),
+			Events::PermissionsChanged(_) |
+			Events::ServerGroupClientAdded(_, _) |
+			Events::ServerGroupClientRemoved(_, _) |
+			Events::ClientChannelGroupChanged(_, _) => None,
 		}
 	}
 }
@@ -0,0 +1,724 @@
+//! Browse the files of channels and transfer files.
+//!
+//! The file lists of channels are requested with [`Connection::list_files`].
+//! The received entries are stored in the [`files`] of the channel, adding and
+//! removing them emits the normal [`Events`].
+//!
+//! Uploads and downloads are initiated over the normal connection, the data is
+//! sent over a separate TCP connection to the file transfer port of the server.
+//! The first thing sent on this connection is the key, which the server sent
+//! in the `notifystartupload` or `notifystartdownload`.
+//!
+//! [`Connection::list_files`]: ../struct.Connection.html#method.list_files
+//! [`files`]: ../data/struct.Channel.html#structfield.files
+//! [`Events`]: ../events/enum.Events.html
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+
+use chrono::{TimeZone, Utc};
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Async, Future, Poll, Stream};
+use num_traits::FromPrimitive;
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tsproto::commands::CanonicalCommand;
+use tsproto::packets::{Direction, InCommand, OutCommand, PacketType};
+use tsproto_commands::*;
+
+use crate::data::{self, File};
+use crate::events::{Events, Property, PropertyId};
+use crate::packet_handler::get_parse;
+use crate::{BoxFuture, Connection, Error, Result, TsError};
+
+/// The file lists and transfers, which wait for an answer of the server.
+#[derive(Debug, Default)]
+pub(crate) struct FileRequests {
+	/// Lists which are not finished yet.
+	pending_lists: HashMap<(ChannelId, String), Vec<File>>,
+	next_transfer_id: u16,
+	waiting: HashMap<u16, oneshot::Sender<Result<TransferStart>>>,
+}
+
+/// The answer of the server to `ftinitupload` and `ftinitdownload`.
+#[derive(Clone, Debug)]
+pub(crate) struct TransferStart {
+	server_id: u16,
+	key: String,
+	ip: Option<std::net::IpAddr>,
+	port: u16,
+	/// Where the transfer starts in the file.
+	seek_position: u64,
+	/// The size of the file, only set for downloads.
+	size: u64,
+}
+
+/// The progress of a transfer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Progress {
+	/// The number of bytes, which are transferred so far. This includes the
+	/// skipped bytes of a resumed transfer.
+	pub transferred: u64,
+	/// The size of the whole file.
+	pub size: u64,
+}
+
+/// A running upload or download.
+///
+/// The transfer is a stream of its progress and ends when the file is
+/// transferred completely.
+pub struct FileTransfer {
+	connection: Connection,
+	server_id: u16,
+	size: u64,
+	progress: mpsc::UnboundedReceiver<Result<Progress>>,
+	cancel: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+/// Counts the read bytes and reports them as progress.
+struct ProgressReader<R> {
+	inner: R,
+	progress: Progress,
+	sender: mpsc::UnboundedSender<Result<Progress>>,
+}
+
+impl FileRequests {
+	/// Register a transfer and get the id, which is sent to the server.
+	pub(crate) fn add_transfer(&mut self)
+		-> (u16, oneshot::Receiver<Result<TransferStart>>) {
+		let (send, recv) = oneshot::channel();
+		let mut id = self.next_transfer_id;
+		while self.waiting.contains_key(&id) {
+			id = id.wrapping_add(1);
+		}
+		self.next_transfer_id = id.wrapping_add(1);
+		self.waiting.insert(id, send);
+		(id, recv)
+	}
+
+	pub(crate) fn remove_transfer(&mut self, id: u16) {
+		self.waiting.remove(&id);
+	}
+}
+
+/// Apply a file notification.
+///
+/// Finished file lists are written into the channels of the book.
+///
+/// Returns `false` if the command is not related to files.
+pub(crate) fn handle_command(
+	con: &mut data::Connection,
+	requests: &mut FileRequests,
+	cmd: &InCommand,
+	events: &mut Vec<Events>,
+) -> Result<bool>
+{
+	match cmd.name() {
+		"notifyfilelist" => {
+			for r in cmd.iter() {
+				let file = parse_entry(&r)?;
+				requests.pending_lists
+					.entry((file.channel_id, file.path.clone()))
+					.or_default()
+					.push(file);
+			}
+		}
+		"notifyfilelistfinished" => {
+			for r in cmd.iter() {
+				let channel = ChannelId(get_parse(&r, "cid")?);
+				let path = normalize_path(r.get("path").unwrap_or("/"))
+					.to_string();
+				let files = requests.pending_lists
+					.remove(&(channel, path.clone()))
+					.unwrap_or_default();
+				set_directory(con, channel, &path, files, events);
+			}
+		}
+		"notifystartupload" | "notifystartdownload" => {
+			for r in cmd.iter() {
+				let id = get_parse(&r, "clientftfid")?;
+				let start = TransferStart {
+					server_id: get_parse(&r, "serverftfid")?,
+					key: r.get("ftkey").unwrap_or_default().to_string(),
+					ip: r.get("ip")
+						.and_then(|ip| ip.parse().ok())
+						.filter(|ip: &std::net::IpAddr| !ip.is_unspecified()),
+					port: get_parse(&r, "port")?,
+					seek_position: if r.has("seekpos") {
+						get_parse(&r, "seekpos")?
+					} else {
+						0
+					},
+					size: if r.has("size") {
+						get_parse(&r, "size")?
+					} else {
+						0
+					},
+				};
+				if let Some(send) = requests.waiting.remove(&id) {
+					let _ = send.send(Ok(start));
+				}
+			}
+		}
+		"notifystatusfiletransfer" => {
+			for r in cmd.iter() {
+				let id = get_parse(&r, "clientftfid")?;
+				let status: u32 = get_parse(&r, "status")?;
+				if status == TsError::FileTransferComplete as u32 {
+					continue;
+				}
+				// The transfer failed before it was started
+				if let Some(send) = requests.waiting.remove(&id) {
+					let e = TsError::from_u32(status).map(Error::from)
+						.unwrap_or_else(|| format_err!(
+							"File transfer failed ({}: {})", status,
+							r.get("msg").unwrap_or_default()).into());
+					let _ = send.send(Err(e));
+				}
+			}
+		}
+		_ => return Ok(false),
+	}
+	Ok(true)
+}
+
+/// The entries of a directory in the files of a channel.
+///
+/// Only directories, which were listed before, are known.
+pub fn get_directory<'a>(channel: &'a data::Channel, path: &str)
+	-> Vec<&'a File> {
+	let path = normalize_path(path);
+	channel.files.values().filter(|f| f.path == path).collect()
+}
+
+/// Insert or replace a file or directory in the book.
+///
+/// A changed entry is removed and added again.
+pub(crate) fn update_file(
+	con: &mut data::Connection,
+	file: File,
+	events: &mut Vec<Events>,
+)
+{
+	let channel =
+		if let Some(c) = con.server.channels.get_mut(&file.channel_id) {
+			c
+		} else {
+			return;
+		};
+	let key = join_path(&file.path, &file.name);
+	if channel.files.get(&key) == Some(&file) {
+		return;
+	}
+	let id = PropertyId::File(file.channel_id, file.path.clone(),
+		file.name.clone());
+	if let Some(old) = channel.files.insert(key, file) {
+		events.push(Events::PropertyRemoved(id.clone(), Property::File(old)));
+	}
+	events.push(Events::PropertyAdded(id));
+}
+
+/// Remove a file or directory with all its content from the book.
+pub(crate) fn remove_file(
+	con: &mut data::Connection,
+	channel: ChannelId,
+	path: &str,
+	events: &mut Vec<Events>,
+)
+{
+	let channel = if let Some(c) = con.server.channels.get_mut(&channel) {
+		c
+	} else {
+		return;
+	};
+	let path = normalize_path(path);
+	let prefix = format!("{}/", path);
+	let removed = channel.files.keys()
+		.filter(|k| *k == path || k.starts_with(&prefix))
+		.cloned()
+		.collect::<Vec<_>>();
+	for k in removed {
+		let old = channel.files.remove(&k).unwrap();
+		events.push(Events::PropertyRemoved(
+			PropertyId::File(old.channel_id, old.path.clone(),
+				old.name.clone()),
+			Property::File(old),
+		));
+	}
+}
+
+/// Replace the content of a directory with a received list.
+pub(crate) fn set_directory(
+	con: &mut data::Connection,
+	channel: ChannelId,
+	path: &str,
+	files: Vec<File>,
+	events: &mut Vec<Events>,
+)
+{
+	let removed = if let Some(c) = con.server.channels.get(&channel) {
+		get_directory(c, path).into_iter()
+			.filter(|f| !files.iter().any(|n| n.name == f.name))
+			.map(|f| join_path(&f.path, &f.name))
+			.collect::<Vec<_>>()
+	} else {
+		return;
+	};
+	for p in removed {
+		remove_file(con, channel, &p, events);
+	}
+	for f in files {
+		update_file(con, f, events);
+	}
+}
+
+fn parse_entry(row: &CanonicalCommand) -> Result<File> {
+	Ok(File {
+		channel_id: ChannelId(get_parse(row, "cid")?),
+		path: normalize_path(row.get("path").unwrap_or("/")).to_string(),
+		name: row.get("name").unwrap_or_default().to_string(),
+		size: get_parse(row, "size")?,
+		last_changed: Utc.timestamp(get_parse(row, "datetime")?, 0),
+		// 0 is a directory, 1 is a file
+		is_file: get_parse::<u8>(row, "type")? != 0,
+	})
+}
+
+/// An entry at `path`, which was just created by this client.
+pub(crate) fn created_file(
+	channel: ChannelId,
+	path: &str,
+	size: u64,
+	is_file: bool,
+) -> File
+{
+	let (dir, name) = split_path(path);
+	File {
+		channel_id: channel,
+		path: dir.to_string(),
+		name: name.to_string(),
+		size,
+		last_changed: Utc::now(),
+		is_file,
+	}
+}
+
+/// The full path of the entry `name` in the directory `path`.
+pub fn join_path(path: &str, name: &str) -> String {
+	let path = normalize_path(path);
+	if path == "/" {
+		format!("/{}", name)
+	} else {
+		format!("{}/{}", path, name)
+	}
+}
+
+/// Remove trailing slashes, the root directory stays `/`.
+fn normalize_path(path: &str) -> &str {
+	let p = path.trim_end_matches('/');
+	if p.is_empty() { "/" } else { p }
+}
+
+/// Split a path into the directory and the name.
+fn split_path(path: &str) -> (&str, &str) {
+	let path = normalize_path(path);
+	match path.rfind('/') {
+		Some(0) => ("/", &path[1..]),
+		Some(i) => (&path[..i], &path[i + 1..]),
+		None => ("/", path),
+	}
+}
+
+/// Create a file command, which contains the channel and its password.
+pub(crate) fn file_command(
+	name: &str,
+	channel: ChannelId,
+	password: &str,
+	args: Vec<(&str, String)>,
+) -> tsproto::packets::OutPacket
+{
+	let mut all_args = vec![("cid", channel.0.to_string()),
+		("cpw", password.to_string())];
+	all_args.extend(args);
+	OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+		Direction::C2S,
+		PacketType::Command,
+		name,
+		all_args.into_iter(),
+		std::iter::empty(),
+	)
+}
+
+/// Send an init command for a transfer and wait for the answer.
+pub(crate) fn init_transfer(
+	con: &Connection,
+	name: &str,
+	channel: ChannelId,
+	password: &str,
+	mut args: Vec<(&str, String)>,
+) -> BoxFuture<TransferStart>
+{
+	let (id, recv) = con.inner.file_requests.write().add_transfer();
+	args.insert(0, ("clientftfid", id.to_string()));
+	let packet = file_command(name, channel, password, args);
+	let requests = con.inner.file_requests.clone();
+	Box::new(con.send_packet(packet)
+		.map_err(move |e| {
+			requests.write().remove_transfer(id);
+			e
+		})
+		.and_then(|_| recv.from_err())
+		.and_then(|r| r))
+}
+
+/// Get the address of the file transfer port of the server.
+fn transfer_address(con: &Connection, start: &TransferStart)
+	-> Result<SocketAddr> {
+	let mut addr = {
+		let c = con.inner.client_connection.upgrade().ok_or_else(||
+			format_err!("Connection does not exist anymore"))?;
+		let c = c.mutex.lock();
+		c.1.address
+	};
+	if let Some(ip) = start.ip {
+		addr.set_ip(ip);
+	}
+	addr.set_port(start.port);
+	Ok(addr)
+}
+
+impl FileTransfer {
+	/// Start the upload of `size` bytes from `reader`.
+	///
+	/// If the upload is resumed, the part which the server has already is
+	/// skipped in `reader`.
+	pub(crate) fn upload<R: AsyncRead + Send + 'static>(
+		con: Connection,
+		start: TransferStart,
+		size: u64,
+		reader: R,
+	) -> Result<Self>
+	{
+		let addr = transfer_address(&con, &start)?;
+		let seek_position = start.seek_position;
+		let key = start.key;
+		Ok(Self::start(con, start.server_id, size, seek_position,
+			move |sender| {
+				let skip = tokio::io::copy(reader.take(seek_position),
+					io::sink());
+				Box::new(skip.from_err().and_then(move |(_, reader, _)| {
+					TcpStream::connect(&addr).from_err()
+						.and_then(move |stream| {
+							tokio::io::write_all(stream, key.into_bytes())
+								.from_err()
+						})
+						.and_then(move |(stream, _)| {
+							let reader = ProgressReader::new(
+								reader.into_inner()
+									.take(size.saturating_sub(seek_position)),
+								seek_position, size, sender);
+							tokio::io::copy(reader, stream).from_err()
+						})
+						.and_then(|(_, reader, stream)| {
+							let transferred = reader.progress.transferred;
+							tokio::io::shutdown(stream).from_err()
+								.map(move |_| transferred)
+						})
+				}))
+			}))
+	}
+
+	/// Start the download into `writer`.
+	pub(crate) fn download<W: AsyncWrite + Send + 'static>(
+		con: Connection,
+		start: TransferStart,
+		writer: W,
+	) -> Result<Self>
+	{
+		let addr = transfer_address(&con, &start)?;
+		let seek_position = start.seek_position;
+		let size = start.size;
+		let key = start.key;
+		Ok(Self::start(con, start.server_id, size, seek_position,
+			move |sender| {
+				Box::new(TcpStream::connect(&addr).from_err()
+					.and_then(move |stream| {
+						tokio::io::write_all(stream, key.into_bytes())
+							.from_err()
+					})
+					.and_then(move |(stream, _)| {
+						let reader = ProgressReader::new(
+							stream.take(size.saturating_sub(seek_position)),
+							seek_position, size, sender);
+						tokio::io::copy(reader, writer).from_err()
+					})
+					.map(|(_, reader, _)| reader.progress.transferred))
+			}))
+	}
+
+	/// Spawn the transfer.
+	///
+	/// `run` gets the sender for the progress and returns the number of
+	/// transferred bytes.
+	fn start<F>(
+		connection: Connection,
+		server_id: u16,
+		size: u64,
+		seek_position: u64,
+		run: F,
+	) -> Self
+	where F: FnOnce(mpsc::UnboundedSender<Result<Progress>>) -> BoxFuture<u64>
+	{
+		let (send, progress) = mpsc::unbounded();
+		let (cancel_send, cancel_recv) = oneshot::channel();
+
+		let _ = send.unbounded_send(Ok(Progress {
+			transferred: seek_position,
+			size,
+		}));
+		let result_send = send.clone();
+		let transfer = run(send)
+			.and_then(move |transferred| if transferred == size {
+				Ok(())
+			} else {
+				Err(format_err!("File transfer incomplete ({} of {} bytes)",
+					transferred, size).into())
+			})
+			// Dropping the transfer does not cancel it
+			.select2(cancel_recv.or_else(|_| future::empty()))
+			.then(move |r| {
+				if let Err(future::Either::A((e, _))) = r {
+					let _ = result_send.unbounded_send(Err(e));
+				}
+				Ok::<_, ()>(())
+			});
+		tokio::spawn(transfer);
+
+		Self {
+			connection,
+			server_id,
+			size,
+			progress,
+			cancel: Mutex::new(Some(cancel_send)),
+		}
+	}
+
+	/// The id of this transfer on the server.
+	pub fn server_id(&self) -> u16 { self.server_id }
+
+	/// The size of the whole file in bytes.
+	pub fn size(&self) -> u64 { self.size }
+
+	/// Stop the transfer.
+	///
+	/// If `delete` is `true`, the server deletes the partially transferred
+	/// file, otherwise the transfer can be resumed later.
+	pub fn cancel(&self, delete: bool) -> BoxFuture<()> {
+		if let Some(cancel) = self.cancel.lock().take() {
+			let _ = cancel.send(());
+		}
+		let packet = OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"ftstop",
+			vec![
+				("serverftfid", self.server_id.to_string()),
+				("delete", (delete as u8).to_string()),
+			].into_iter(),
+			std::iter::empty(),
+		);
+		Box::new(self.connection.send_packet(packet))
+	}
+}
+
+impl Stream for FileTransfer {
+	type Item = Progress;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		match self.progress.poll() {
+			Ok(Async::Ready(Some(Ok(p)))) => Ok(Async::Ready(Some(p))),
+			Ok(Async::Ready(Some(Err(e)))) => Err(e),
+			Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+			Ok(Async::NotReady) => Ok(Async::NotReady),
+			Err(()) => Ok(Async::Ready(None)),
+		}
+	}
+}
+
+impl<R> ProgressReader<R> {
+	fn new(
+		inner: R,
+		transferred: u64,
+		size: u64,
+		sender: mpsc::UnboundedSender<Result<Progress>>,
+	) -> Self
+	{
+		Self { inner, progress: Progress { transferred, size }, sender }
+	}
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = self.inner.read(buf)?;
+		if len > 0 {
+			self.progress.transferred += len as u64;
+			// The receiver may not be interested in the progress anymore
+			let _ = self.sender.unbounded_send(Ok(self.progress));
+		}
+		Ok(len)
+	}
+}
+
+impl<R: AsyncRead> AsyncRead for ProgressReader<R> {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::{connection, parse_cmd};
+
+	const CHANNEL: ChannelId = ChannelId(1);
+
+	fn file_id(path: &str, name: &str) -> PropertyId {
+		PropertyId::File(CHANNEL, path.into(), name.into())
+	}
+
+	#[test]
+	fn paths() {
+		assert_eq!(normalize_path("/"), "/");
+		assert_eq!(normalize_path("/a/b/"), "/a/b");
+		assert_eq!(split_path("/a"), ("/", "a"));
+		assert_eq!(split_path("/a/b/c"), ("/a/b", "c"));
+		assert_eq!(join_path("/", "a"), "/a");
+		assert_eq!(join_path("/a/", "b"), "/a/b");
+	}
+
+	#[test]
+	fn file_list() {
+		let mut con = connection();
+		let mut requests = FileRequests::default();
+		let mut events = Vec::new();
+		update_file(&mut con, created_file(CHANNEL, "/old", 1, true),
+			&mut events);
+		update_file(&mut con, created_file(CHANNEL, "/b.txt", 1, true),
+			&mut events);
+		events.clear();
+
+		let cmd = parse_cmd("notifyfilelist cid=1 path=\\/ name=a size=0 \
+			datetime=1500000000 type=0|cid=1 path=\\/ name=b.txt size=10 \
+			datetime=1500000000 type=1");
+		assert!(handle_command(&mut con, &mut requests, &cmd, &mut events)
+			.unwrap());
+		// Nothing changes before the list is finished
+		assert!(events.is_empty());
+		assert_eq!(con.server.channels[&CHANNEL].files.len(), 2);
+
+		let cmd = parse_cmd("notifyfilelistfinished cid=1 path=\\/");
+		assert!(handle_command(&mut con, &mut requests, &cmd, &mut events)
+			.unwrap());
+
+		let files = &con.server.channels[&CHANNEL].files;
+		assert_eq!(files.len(), 2);
+		assert!(!files["/a"].is_file);
+		assert_eq!(files["/b.txt"].size, 10);
+		assert_eq!(files["/b.txt"].last_changed, Utc.timestamp(1500000000, 0));
+		assert_eq!(get_directory(&con.server.channels[&CHANNEL], "/").len(),
+			2);
+
+		assert_eq!(events.len(), 4);
+		assert!(events.contains(&Events::PropertyAdded(file_id("/", "a"))));
+		// The changed file is replaced
+		assert!(events.contains(&Events::PropertyAdded(file_id("/",
+			"b.txt"))));
+		assert!(events.iter().any(|e| match e {
+			Events::PropertyRemoved(id, Property::File(f)) =>
+				*id == file_id("/", "b.txt") && f.size == 1,
+			_ => false,
+		}));
+		assert!(events.iter().any(|e| match e {
+			Events::PropertyRemoved(id, _) => *id == file_id("/", "old"),
+			_ => false,
+		}));
+	}
+
+	#[test]
+	fn remove_subtree() {
+		let mut con = connection();
+		let mut events = Vec::new();
+		for (p, is_file) in &[("/a", false), ("/a/b", true), ("/ab", false)] {
+			update_file(&mut con, created_file(CHANNEL, p, 0, *is_file),
+				&mut events);
+		}
+		assert_eq!(events.len(), 3);
+		events.clear();
+
+		remove_file(&mut con, CHANNEL, "/a/", &mut events);
+		let files = &con.server.channels[&CHANNEL].files;
+		assert_eq!(files.keys().collect::<Vec<_>>(), vec!["/ab"]);
+		assert_eq!(events.len(), 2);
+	}
+
+	#[test]
+	fn start_transfer() {
+		let mut con = connection();
+		let mut requests = FileRequests::default();
+		let mut events = Vec::new();
+		let (id, mut recv) = requests.add_transfer();
+
+		let cmd = parse_cmd(&format!("notifystartdownload clientftfid={} \
+			serverftfid=7 ftkey=abc port=30033 proto=1 size=10 ip=0.0.0.0",
+			id));
+		assert!(handle_command(&mut con, &mut requests, &cmd, &mut events)
+			.unwrap());
+		let start = match recv.poll() {
+			Ok(Async::Ready(Ok(s))) => s,
+			r => panic!("Transfer was not started: {:?}", r),
+		};
+		assert_eq!(start.server_id, 7);
+		assert_eq!(start.key, "abc");
+		assert_eq!(start.port, 30033);
+		assert_eq!(start.size, 10);
+		assert_eq!(start.seek_position, 0);
+		// The address of the connection is used
+		assert_eq!(start.ip, None);
+		assert!(requests.waiting.is_empty());
+		assert!(events.is_empty());
+	}
+
+	#[test]
+	fn transfer_status() {
+		let mut con = connection();
+		let mut requests = FileRequests::default();
+		let mut events = Vec::new();
+		let (id, mut recv) = requests.add_transfer();
+
+		// A finished transfer is not an error
+		let cmd = parse_cmd(&format!("notifystatusfiletransfer \
+			clientftfid={} status=2065 msg=complete size=0", id));
+		assert!(handle_command(&mut con, &mut requests, &cmd, &mut events)
+			.unwrap());
+		assert!(requests.waiting.contains_key(&id));
+
+		let cmd = parse_cmd(&format!("notifystatusfiletransfer \
+			clientftfid={} status=2052 msg=file\\snot\\sfound", id));
+		assert!(handle_command(&mut con, &mut requests, &cmd, &mut events)
+			.unwrap());
+		match recv.poll() {
+			Ok(Async::Ready(Err(Error::Ts(e)))) =>
+				assert_eq!(e as u32, 2052),
+			r => panic!("Transfer did not fail: {:?}", r),
+		}
+		assert!(requests.waiting.is_empty());
+		assert!(events.is_empty());
+	}
+
+	#[test]
+	fn other_command() {
+		let mut con = connection();
+		let mut requests = FileRequests::default();
+		let cmd = parse_cmd("notifychannelsubscribed cid=1");
+		assert!(!handle_command(&mut con, &mut requests, &cmd,
+			&mut Vec::new()).unwrap());
+	}
+}
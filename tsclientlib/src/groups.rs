@@ -0,0 +1,227 @@
+//! Channel groups and the group membership of clients.
+//!
+//! Server groups and channel groups are part of the [`data`] structures.
+//! Channel groups are only sent as a whole list, the changes to the last list
+//! are reported as normal [`Events`].
+//!
+//! [`data`]: ../data/index.html
+//! [`Events`]: ../events/enum.Events.html
+use std::collections::HashMap;
+use std::mem;
+
+use num_traits::FromPrimitive;
+use tsproto::commands::CanonicalCommand;
+use tsproto::packets::InCommand;
+use tsproto_commands::*;
+
+use crate::data::{self, ChannelGroup};
+use crate::events::{Events, Property, PropertyId};
+use crate::packet_handler::{get_bool, get_parse};
+use crate::snapshot;
+use crate::Result;
+
+fn parse_channel_group(row: &CanonicalCommand) -> Result<ChannelGroup> {
+	Ok(ChannelGroup {
+		id: ChannelGroupId(get_parse(row, "cgid")?),
+		name: row.get("name").unwrap_or_default().to_string(),
+		group_type: get_enum(row, "type")?,
+		icon_id: get_icon(row, "iconid")?,
+		is_permanent: get_bool(row, "savedb")?,
+		sort_id: get_parse(row, "sortid")?,
+		naming_mode: get_enum(row, "namemode")?,
+		needed_modify_power: get_parse(row, "n_modifyp")?,
+		needed_member_add_power: get_parse(row, "n_member_addp")?,
+		needed_member_remove_power: get_parse(row, "n_member_removep")?,
+	})
+}
+
+/// Apply a group notification.
+///
+/// Returns `false` if the command is not related to groups.
+pub(crate) fn handle_command(
+	con: &mut data::Connection,
+	cmd: &InCommand,
+	events: &mut Vec<Events>,
+) -> Result<bool>
+{
+	match cmd.name() {
+		"notifychannelgrouplist" => {
+			handle_channel_group_list(con, cmd, events)?
+		}
+		"notifyservergrouplist" => {
+			// New and changed groups are applied to the book, but deleted
+			// groups have to be removed here.
+			let ids = cmd
+				.iter()
+				.map(|r| Ok(ServerGroupId(get_parse(&r, "sgid")?)))
+				.collect::<Result<Vec<_>>>()?;
+			let removed = con
+				.server
+				.groups
+				.keys()
+				.filter(|id| !ids.contains(id))
+				.cloned()
+				.collect::<Vec<_>>();
+			for id in removed {
+				if let Some(old) = con.server.groups.remove(&id) {
+					events.push(Events::PropertyRemoved(
+						PropertyId::ServerGroup(id),
+						Property::ServerGroup(old),
+					));
+				}
+			}
+		}
+		"notifyservergroupclientadded" => {
+			for r in cmd.iter() {
+				let client = ClientId(get_parse(&r, "clid")?);
+				let group = ServerGroupId(get_parse(&r, "sgid")?);
+				if let Some(c) = con.server.clients.get_mut(&client) {
+					if !c.server_groups.contains(&group) {
+						c.server_groups.push(group);
+						events.push(Events::ServerGroupClientAdded(
+							client, group,
+						));
+					}
+				}
+			}
+		}
+		"notifyservergroupclientdeleted" => {
+			for r in cmd.iter() {
+				let client = ClientId(get_parse(&r, "clid")?);
+				let group = ServerGroupId(get_parse(&r, "sgid")?);
+				if let Some(c) = con.server.clients.get_mut(&client) {
+					if let Some(i) =
+						c.server_groups.iter().position(|g| *g == group)
+					{
+						c.server_groups.remove(i);
+						events.push(Events::ServerGroupClientRemoved(
+							client, group,
+						));
+					}
+				}
+			}
+		}
+		"notifyclientchannelgroupchanged" => {
+			for r in cmd.iter() {
+				let client = ClientId(get_parse(&r, "clid")?);
+				let group = ChannelGroupId(get_parse(&r, "cgid")?);
+				if let Some(c) = con.server.clients.get_mut(&client) {
+					if c.channel_group != group {
+						let old = mem::replace(&mut c.channel_group, group);
+						events.push(Events::ClientChannelGroupChanged(
+							client, old,
+						));
+					}
+				}
+			}
+		}
+		_ => return Ok(false),
+	}
+	Ok(true)
+}
+
+/// The server always sends the whole list.
+fn handle_channel_group_list(
+	con: &mut data::Connection,
+	cmd: &InCommand,
+	events: &mut Vec<Events>,
+) -> Result<()>
+{
+	let mut new_groups = HashMap::new();
+	for r in cmd.iter() {
+		let group = parse_channel_group(&r)?;
+		new_groups.insert(group.id, group);
+	}
+
+	let channel_groups = &mut con.server.channel_groups;
+	let old_groups = mem::replace(channel_groups, new_groups);
+	for (id, group) in &*channel_groups {
+		match old_groups.get(id) {
+			None => events
+				.push(Events::PropertyAdded(PropertyId::ChannelGroup(*id))),
+			Some(old) => snapshot::diff_channel_group(old, group, events),
+		}
+	}
+	for (id, old) in old_groups {
+		if !channel_groups.contains_key(&id) {
+			events.push(Events::PropertyRemoved(
+				PropertyId::ChannelGroup(id),
+				Property::ChannelGroup(old),
+			));
+		}
+	}
+	Ok(())
+}
+
+fn get_enum<T: FromPrimitive>(row: &CanonicalCommand, key: &str) -> Result<T> {
+	let i = get_parse::<u32>(row, key)?;
+	Ok(T::from_u32(i).ok_or_else(|| {
+		format_err!("Invalid value {} for argument {}", i, key)
+	})?)
+}
+
+/// Icon ids are sometimes sent as negative or as 64 bit numbers.
+fn get_icon(row: &CanonicalCommand, key: &str) -> Result<IconHash> {
+	let val = row.get(key).unwrap_or("0");
+	Ok(IconHash(if val.starts_with('-') {
+		val.parse::<i32>().map(|i| i as u32)
+	} else {
+		val.parse::<u64>().map(|i| i as u32)
+	}
+	.map_err(|e| format_err!("Cannot parse argument {} ({:?})", key, e))?))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::{connection, parse_cmd};
+
+	fn group(id: u64, name: &str) -> ChannelGroup {
+		ChannelGroup {
+			id: ChannelGroupId(id),
+			name: name.into(),
+			group_type: GroupType::Regular,
+			icon_id: IconHash(0),
+			is_permanent: true,
+			sort_id: 0,
+			naming_mode: GroupNamingMode::None,
+			needed_modify_power: 75,
+			needed_member_add_power: 50,
+			needed_member_remove_power: 50,
+		}
+	}
+
+	#[test]
+	fn channel_group_list() {
+		let cmd = parse_cmd("notifychannelgrouplist cgid=5 name=Channel\\sAdmin \
+			type=1 iconid=0 savedb=1 sortid=0 namemode=0 n_modifyp=75 \
+			n_member_addp=50 n_member_removep=50|cgid=8 name=Guest type=1 \
+			iconid=-1 savedb=1 sortid=0 namemode=2 n_modifyp=75 \
+			n_member_addp=0 n_member_removep=0");
+		let mut con = connection();
+		let groups = &mut con.server.channel_groups;
+		groups.insert(ChannelGroupId(5), group(5, "Admin"));
+		groups.insert(ChannelGroupId(7), group(7, "Old"));
+		let old = groups[&ChannelGroupId(7)].clone();
+
+		let mut events = Vec::new();
+		assert!(handle_command(&mut con, &cmd, &mut events).unwrap());
+
+		let groups = &con.server.channel_groups;
+		assert_eq!(groups.len(), 2);
+		assert_eq!(groups[&ChannelGroupId(5)].name, "Channel Admin");
+		let guest = &groups[&ChannelGroupId(8)];
+		assert_eq!(guest.name, "Guest");
+		assert_eq!(guest.icon_id, IconHash(u32::max_value()));
+		assert_eq!(guest.naming_mode, GroupNamingMode::After);
+		assert_eq!(events.len(), 3);
+		assert!(events.contains(&Events::PropertyAdded(
+			PropertyId::ChannelGroup(ChannelGroupId(8)))));
+		assert!(events.contains(&Events::PropertyChanged(
+			PropertyId::ChannelGroupName(ChannelGroupId(5)),
+			Property::ChannelGroupName("Admin".into()))));
+		assert!(events.contains(&Events::PropertyRemoved(
+			PropertyId::ChannelGroup(ChannelGroupId(7)),
+			Property::ChannelGroup(old))));
+	}
+}
@@ -0,0 +1,403 @@
+//! Download and cache icons and avatars.
+//!
+//! Icons and avatars are stored as internal files of a server. Icons are
+//! named `/icon_<id>`, avatars `/avatar_<uid>`, where the uid is encoded with
+//! the letters `a` – `p`.
+//!
+//! An [`IconCache`] keeps downloaded images in memory and in a directory on
+//! disk. The files on disk are sorted by the uid of the server because icon
+//! ids are only unique on a single server.
+//!
+//! An icon id is a checksum of the content, so the same id always refers to
+//! the same image. Avatars can change, a cached avatar is removed when the
+//! avatar of the client changes.
+//!
+//! [`IconCache`]: struct.IconCache.html
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+
+use futures::future::Shared;
+use futures::{future, Future, Stream};
+use parking_lot::{Mutex, RwLock};
+use tokio::io::AsyncWrite;
+use tsproto_commands::*;
+
+use crate::events::{Events, PropertyId};
+use crate::{BoxFuture, Connection, Error, EventListener, Result};
+
+/// Used to create unique keys for the event listeners of caches.
+static NEXT_CACHE_ID: AtomicUsize = AtomicUsize::new(0);
+/// Identifies a load, so an invalidated load does not fill the cache.
+static NEXT_LOAD_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Icon ids below this value are built into the client and cannot be
+/// downloaded.
+const FIRST_CUSTOM_ICON: u32 = 1000;
+
+/// Downloads icons and avatars of a connection and stores them.
+///
+/// # Example
+///
+/// ```no_run
+/// # use futures::Future;
+/// # use tsclientlib::{ClientId, Connection};
+/// # use tsclientlib::icons::IconCache;
+/// # fn f(con: &Connection) {
+/// let cache = IconCache::new(con, "cache".into());
+/// let avatar = cache.get_avatar(ClientId(1)).map(|image| {
+/// 	if let Some(image) = image {
+/// 		println!("Avatar has {} bytes", image.len());
+/// 	}
+/// });
+/// tokio::spawn(avatar.map_err(|e| println!("Error: {}", e)));
+/// # }
+/// ```
+pub struct IconCache {
+	connection: Connection,
+	key: String,
+	/// The directory of this server.
+	dir: PathBuf,
+	memory: Arc<Mutex<HashMap<CacheKey, Arc<Vec<u8>>>>>,
+	/// Loads which are in progress, so every file is downloaded only once.
+	pending: Arc<Mutex<HashMap<CacheKey, (usize, SharedLoad)>>>,
+	listeners: Weak<RwLock<HashMap<String, EventListener>>>,
+}
+
+type SharedLoad = Shared<BoxFuture<Arc<Vec<u8>>>>;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum CacheKey {
+	Icon(IconHash),
+	/// The encoded uid of a client.
+	Avatar(String),
+}
+
+/// A writer which can be read after the download finished.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl IconCache {
+	/// Create a cache, which stores files in a subdirectory of `dir`.
+	///
+	/// The cache listens to events of the connection until it is dropped.
+	pub fn new(con: &Connection, dir: PathBuf) -> Self {
+		let server_uid = con.lock().server.uid.clone();
+		let dir = dir.join(encode_uid(&server_uid));
+		let key = format!(
+			"tsclientlib-icon-cache-{}",
+			NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed)
+		);
+		let memory = Arc::new(Mutex::new(HashMap::new()));
+		let pending = Arc::new(Mutex::new(HashMap::new()));
+
+		let listener_memory = memory.clone();
+		let listener_pending = pending.clone();
+		let listener_dir = dir.clone();
+		con.inner.event_listeners.write().insert(
+			key.clone(),
+			Box::new(move |con, events| {
+				for e in events {
+					if let Events::PropertyChanged(
+						PropertyId::ClientAvatarHash(id), _) = e {
+						if let Some(client) = con.server.clients.get(id) {
+							let name = encode_uid(&client.uid);
+							let key = CacheKey::Avatar(name.clone());
+							listener_memory.lock().remove(&key);
+							// A running download fetches the old avatar
+							listener_pending.lock().remove(&key);
+							// Do not block while the connection is locked
+							let dir = listener_dir.clone();
+							let hash = client.avatar_hash.clone()
+								.unwrap_or_default();
+							tokio::spawn(future::poll_fn(move || {
+								tokio_threadpool::blocking(|| {
+									remove_avatar_files(&dir, &name, &hash)
+								})
+							}).map_err(|_| ()));
+						}
+					}
+				}
+			}),
+		);
+
+		Self {
+			connection: con.clone(),
+			key,
+			dir,
+			memory,
+			pending,
+			listeners: Arc::downgrade(&con.inner.event_listeners),
+		}
+	}
+
+	/// Get an icon.
+	///
+	/// Returns `None` if the id is `0`, which means that no icon is set.
+	pub fn get_icon(&self, icon: IconHash)
+		-> BoxFuture<Option<Arc<Vec<u8>>>> {
+		if icon.0 == 0 {
+			return Box::new(future::ok(None));
+		}
+		if icon.0 < FIRST_CUSTOM_ICON {
+			return Box::new(future::err(format_err!(
+				"Icon {} is built into the client", icon.0).into()));
+		}
+		let name = format!("icon_{}", icon.0);
+		Box::new(self.get(CacheKey::Icon(icon), name.clone(), name).map(Some))
+	}
+
+	/// Get the avatar of a client.
+	///
+	/// Returns `None` if the client has no avatar.
+	pub fn get_avatar(&self, client: ClientId)
+		-> BoxFuture<Option<Arc<Vec<u8>>>> {
+		let (uid, hash) = {
+			let con = self.connection.lock();
+			match con.server.clients.get(&client) {
+				Some(c) => (c.uid.clone(), c.avatar_hash.clone()),
+				None => return Box::new(future::err(format_err!(
+					"Client {} does not exist", client.0).into())),
+			}
+		};
+		let hash = match hash {
+			Some(ref h) if !h.is_empty() => h.clone(),
+			_ => return Box::new(future::ok(None)),
+		};
+		let name = format!("avatar_{}", encode_uid(&uid));
+		// Include the hash, so an old file is not used for a new avatar
+		let file_name = format!("{}.{}", name, hash);
+		let key = CacheKey::Avatar(encode_uid(&uid));
+		Box::new(self.get(key, name, file_name).map(Some))
+	}
+
+	/// Remove all cached images of this server from memory and disk.
+	pub fn clear(&self) -> Result<()> {
+		self.memory.lock().clear();
+		self.pending.lock().clear();
+		match fs::remove_dir_all(&self.dir) {
+			Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+			r => Ok(r?),
+		}
+	}
+
+	/// Look in memory, on disk and download the file `name` if it is not
+	/// found.
+	///
+	/// Concurrent requests for the same image share one load.
+	fn get(&self, key: CacheKey, name: String, file_name: String)
+		-> BoxFuture<Arc<Vec<u8>>> {
+		if let Some(data) = self.memory.lock().get(&key) {
+			return Box::new(future::ok(data.clone()));
+		}
+
+		let mut pending = self.pending.lock();
+		let load = if let Some((_, load)) = pending.get(&key) {
+			load.clone()
+		} else {
+			let id = NEXT_LOAD_ID.fetch_add(1, Ordering::Relaxed);
+			let load = self.load(key.clone(), id, name, file_name).shared();
+			pending.insert(key, (id, load.clone()));
+			load
+		};
+		Box::new(load.map(|data| (*data).clone()).map_err(|e| {
+			format_err!("Failed to load image ({})", *e).into()
+		}))
+	}
+
+	/// Read the file from disk or download it and store it in memory.
+	///
+	/// The result is only stored if the load with the number `id` was not
+	/// invalidated in the meantime.
+	fn load(&self, key: CacheKey, id: usize, name: String, file_name: String)
+		-> BoxFuture<Arc<Vec<u8>>> {
+		let path = self.dir.join(&file_name);
+		let dir = self.dir.clone();
+		let con = self.connection.clone();
+		let memory = self.memory.clone();
+		let pending = self.pending.clone();
+		let read = future::poll_fn(move || {
+			tokio_threadpool::blocking(|| match fs::read(&path) {
+				Ok(data) => Ok(Some(data)),
+				Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+				Err(e) => Err(Error::from(e)),
+			})
+		}).from_err::<Error>().and_then(|r| r);
+
+		Box::new(read.and_then(move |data| -> BoxFuture<Vec<u8>> {
+			if let Some(data) = data {
+				return Box::new(future::ok(data));
+			}
+			let buffer = SharedBuffer::default();
+			let download = con.download_file(ChannelId(0), "",
+				&format!("/{}", name), 0, buffer.clone())
+				.and_then(|transfer| transfer.for_each(|_| Ok(())))
+				.map(move |_| std::mem::replace(&mut *buffer.0.lock(),
+					Vec::new()));
+			Box::new(download.and_then(move |mut data| {
+				future::poll_fn(move || {
+					tokio_threadpool::blocking(|| -> Result<_> {
+						fs::create_dir_all(&dir)?;
+						fs::write(dir.join(&file_name), &data)?;
+						Ok(std::mem::replace(&mut data, Vec::new()))
+					})
+				}).from_err::<Error>().and_then(|r| r)
+			}))
+		}).then(move |res| -> Result<_> {
+			let mut pending = pending.lock();
+			let current = pending.get(&key).map(|(i, _)| *i == id)
+				.unwrap_or(false);
+			if current {
+				pending.remove(&key);
+			}
+			let data = Arc::new(res?);
+			if current {
+				memory.lock().insert(key, data.clone());
+			}
+			Ok(data)
+		}))
+	}
+}
+
+impl Drop for IconCache {
+	fn drop(&mut self) {
+		if let Some(listeners) = self.listeners.upgrade() {
+			listeners.write().remove(&self.key);
+		}
+	}
+}
+
+impl Write for SharedBuffer {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.lock().write(buf)
+	}
+
+	fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl AsyncWrite for SharedBuffer {
+	fn shutdown(&mut self) -> futures::Poll<(), io::Error> {
+		Ok(futures::Async::Ready(()))
+	}
+}
+
+/// Encode a uid like the TeamSpeak client does for avatar names.
+///
+/// Every half byte of the decoded uid is written as a letter from `a` to `p`.
+/// If the uid is not valid base64, it is used as it is with `/` replaced.
+pub fn encode_uid(uid: &Uid) -> String {
+	match base64::decode(&uid.0) {
+		Ok(data) => data
+			.iter()
+			.flat_map(|b| vec![b >> 4, b & 0xf])
+			.map(|b| (b'a' + b) as char)
+			.collect(),
+		Err(_) => uid.0.replace('/', "_"),
+	}
+}
+
+/// Remove all cached files of an avatar, except the one with the current
+/// hash.
+fn remove_avatar_files(dir: &Path, name: &str, hash: &str) {
+	let prefix = format!("avatar_{}.", name);
+	let current = format!("{}{}", prefix, hash);
+	if let Ok(entries) = fs::read_dir(dir) {
+		for e in entries.filter_map(|e| e.ok()) {
+			let file_name = e.file_name().to_string_lossy().into_owned();
+			if file_name.starts_with(&prefix) && file_name != current {
+				let _ = fs::remove_file(e.path());
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::runtime::Runtime;
+
+	use crate::events::Property;
+	use crate::packet_handler::call_event_listeners;
+	use crate::tests::offline_connection;
+
+	/// A cache directory, which is not used by other tests.
+	fn cache_dir(name: &str) -> PathBuf {
+		std::env::temp_dir().join(format!("tsclientlib-test-{}-{}", name,
+			std::process::id()))
+	}
+
+	#[test]
+	fn avatar_name() {
+		// The decoded uid starts with the bytes 0x00, 0x1f
+		assert_eq!(&encode_uid(&Uid("AB8=".into())), "aabp");
+	}
+
+	#[test]
+	fn disk_cache() {
+		let mut rt = Runtime::new().unwrap();
+		rt.block_on(future::lazy(|| -> Result<()> {
+			let con = offline_connection();
+			let cache = IconCache::new(&con, cache_dir("disk"));
+			fs::create_dir_all(&cache.dir)?;
+			fs::write(cache.dir.join("icon_1234"), b"icon")?;
+
+			// Both requests share one load
+			let first = cache.get_icon(IconHash(1234));
+			let second = cache.get_icon(IconHash(1234));
+			assert_eq!(cache.pending.lock().len(), 1);
+			let (first, second) = first.join(second).wait()?;
+			let (first, second) = (first.unwrap(), second.unwrap());
+			assert_eq!(&first[..], b"icon");
+			assert!(Arc::ptr_eq(&first, &second));
+			assert!(cache.pending.lock().is_empty());
+			assert!(cache.memory.lock()
+				.contains_key(&CacheKey::Icon(IconHash(1234))));
+
+			cache.clear()?;
+			assert!(!cache.dir.exists());
+			Ok(())
+		})).unwrap();
+	}
+
+	#[test]
+	fn avatar_invalidation() {
+		let mut rt = Runtime::new().unwrap();
+		rt.block_on(future::lazy(|| -> Result<()> {
+			let con = offline_connection();
+			let client = ClientId(2);
+			let name = {
+				let mut data = con.inner.connection.write();
+				let c = data.server.clients.get_mut(&client).unwrap();
+				c.avatar_hash = Some("old".into());
+				encode_uid(&c.uid)
+			};
+			let cache = IconCache::new(&con, cache_dir("avatar"));
+			fs::create_dir_all(&cache.dir)?;
+			let path = |hash: &str| cache.dir.join(format!("avatar_{}.{}", name,
+				hash));
+			fs::write(path("old"), b"old")?;
+			fs::write(path("new"), b"new")?;
+
+			let avatar = cache.get_avatar(client).wait()?.unwrap();
+			assert_eq!(&avatar[..], b"old");
+
+			// The client changes its avatar
+			con.inner.connection.write().server.clients
+				.get_mut(&client).unwrap().avatar_hash = Some("new".into());
+			call_event_listeners(&con, &[Events::PropertyChanged(
+				PropertyId::ClientAvatarHash(client),
+				Property::ClientAvatarHash(Some("old".into())),
+			)]);
+			assert!(!cache.memory.lock()
+				.contains_key(&CacheKey::Avatar(name.clone())));
+
+			let avatar = cache.get_avatar(client).wait()?.unwrap();
+			assert_eq!(&avatar[..], b"new");
+			cache.clear()?;
+			Ok(())
+		})).unwrap();
+	}
+}
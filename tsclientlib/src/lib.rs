@@ -25,13 +25,17 @@ use std::collections::HashMap;
 use std::fmt;
 use std::net::SocketAddr;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use failure::ResultExt;
-use futures::sync::oneshot;
+use futures::sync::{mpsc, oneshot};
 use futures::{future, stream, Future, Sink, Stream};
-use parking_lot::{Once, RwLock, RwLockReadGuard, ONCE_INIT};
+use parking_lot::{Mutex, Once, RwLock, RwLockReadGuard, ONCE_INIT};
 use slog::{debug, error, info, o, Drain, Logger};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
 use tsproto::algorithms as algs;
 use tsproto::{client, crypto, log};
 use tsproto::connectionmanager::ConnectionManager;
@@ -43,7 +47,13 @@ use tsproto::packets::{
 use tsproto_audio::ts_to_audio::AudioPacketHandler;
 use tsproto_commands::messages::s2c::{InMessage, InMessages};
 
-use crate::packet_handler::{ReturnCodeHandler, SimplePacketHandler};
+use crate::filetransfer::{FileRequests, FileTransfer};
+use crate::manager::Sockets;
+use crate::packet_handler::{
+	NotificationWaiters, ReturnCodeHandler, SimplePacketHandler,
+};
+use crate::permissions::{ClientContext, PermissionOwner, Permissions};
+use crate::plugin::{PluginCommand, PluginListeners, PluginTarget};
 
 macro_rules! copy_attrs {
 	($from:ident, $to:ident; $($attr:ident),* $(,)*; $($extra:ident: $ex:expr),* $(,)*) => {
@@ -55,14 +65,26 @@ macro_rules! copy_attrs {
 }
 
 pub mod data;
+pub mod event_stream;
 pub mod events;
+pub mod filetransfer;
+pub mod groups;
+pub mod icons;
+pub mod manager;
 mod packet_handler;
+pub mod permissions;
+pub mod plugin;
 pub mod resolver;
+pub mod snapshot;
+pub mod subscriptions;
+pub mod talk;
 
 #[cfg(test)]
 mod tests;
 
 // Reexports
+pub use tsproto::stats::{ConnectionStats, Counter, PacketCategory};
+pub use tsproto::resend::ResendStats;
 pub use tsproto_commands::errors::Error as TsError;
 pub use tsproto_commands::versions::Version;
 pub use tsproto_commands::{
@@ -72,6 +94,14 @@ pub use tsproto_commands::{
 	LicenseType, HostBannerMode, HostMessageMode, CodecEncryptionMode, UidRef,
 };
 
+/// Wait this long for an answer from a server address before also trying the
+/// next address.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// The maximum length of a nickname in characters.
+const MAX_NICKNAME_LEN: usize = 30;
+/// Requests fail if their notification is not received in this time.
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
 type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
 type Result<T> = std::result::Result<T, Error>;
 pub type EventListener = Box<Fn(&ConnectionLock, &[events::Events]) + Send + Sync>;
@@ -87,6 +117,8 @@ pub enum Error {
 	#[fail(display = "{}", _0)]
 	Io(#[cause] std::io::Error),
 	#[fail(display = "{}", _0)]
+	Json(#[cause] serde_json::Error),
+	#[fail(display = "{}", _0)]
 	ParseMessage(#[cause] tsproto_commands::messages::ParseError),
 	#[fail(display = "{}", _0)]
 	Resolve(#[cause] trust_dns_resolver::error::ResolveError),
@@ -129,6 +161,10 @@ impl From<std::io::Error> for Error {
 	fn from(e: std::io::Error) -> Self { Error::Io(e) }
 }
 
+impl From<serde_json::Error> for Error {
+	fn from(e: serde_json::Error) -> Self { Error::Json(e) }
+}
+
 impl From<tsproto_commands::messages::ParseError> for Error {
 	fn from(e: tsproto_commands::messages::ParseError) -> Self {
 		Error::ParseMessage(e)
@@ -196,13 +232,24 @@ impl<'a> Deref for ConnectionLock<'a> {
 	fn deref(&self) -> &Self::Target { &*self.guard }
 }
 
+/// The state of a connection, split over several locks.
+///
+/// `connection` contains the book, the other locks contain data which is kept
+/// next to it. To prevent deadlocks, locks have to be taken in this order:
+/// `connection`, then `event_listeners`, then at most one of the remaining
+/// locks. Event listeners are called while the `connection` and
+/// `event_listeners` locks are held.
 #[derive(Clone)]
 struct InnerConnection {
 	connection: Arc<RwLock<data::Connection>>,
-	client_data: client::ClientDataM<SimplePacketHandler>,
+	client_data: client::SharedClientDataM<SimplePacketHandler>,
 	client_connection: client::ClientConVal,
 	return_code_handler: Arc<ReturnCodeHandler>,
 	event_listeners: Arc<RwLock<HashMap<String, EventListener>>>,
+	permissions: Arc<RwLock<Permissions>>,
+	file_requests: Arc<RwLock<FileRequests>>,
+	plugin_listeners: Arc<Mutex<PluginListeners>>,
+	notification_waiters: Arc<Mutex<NotificationWaiters>>,
 }
 
 #[derive(Clone)]
@@ -210,7 +257,25 @@ pub struct Connection {
 	inner: InnerConnection,
 }
 
-struct DisconnectListener(Option<Box<FnOnce() + Send>>);
+/// Calls a function when a connection is removed from its socket.
+struct DisconnectListener {
+	con: client::ClientConVal,
+	f: Option<Box<FnOnce() + Send>>,
+}
+
+/// A connection to a single address, which is raced against the other
+/// addresses of the server.
+struct ConnectAttempt {
+	addr: SocketAddr,
+	logger: Logger,
+	client: client::SharedClientDataM<SimplePacketHandler>,
+	con: client::ClientConVal,
+	/// Resolves when the handshake reached the `Connecting` state.
+	connecting: BoxFuture<()>,
+	initserver_recv: oneshot::Receiver<InCommand>,
+	connection_send: oneshot::Sender<Connection>,
+	return_code_handler: Arc<ReturnCodeHandler>,
+}
 
 /// The main type of this crate, which represents a connection to a server.
 ///
@@ -282,9 +347,7 @@ impl Connection {
 		});
 		let logger = logger.new(o!("addr" => options.address.to_string()));
 
-		// Try all addresses
-		let addr: Box<Stream<Item = _, Error = _> + Send> =
-			options.address.resolve(&logger);
+		let addrs = options.address.resolve(&logger).collect();
 		let private_key =
 			match options.private_key.take().map(Ok).unwrap_or_else(|| {
 				// Create new ECDH key
@@ -296,202 +359,126 @@ impl Connection {
 
 		// Make options clonable
 		let options = Arc::new(options);
+		let options2 = options.clone();
+		let auto_subscribe = options.auto_subscribe;
 		let logger2 = logger.clone();
+		let private_key2 = private_key.clone();
+		// Set when the connection is established, so delayed attempts do not
+		// start anymore.
+		let connected = Arc::new(AtomicBool::new(false));
+		let connected2 = connected.clone();
 		Box::new(
-			addr.and_then(
-				move |addr| -> Box<Future<Item = _, Error = _> + Send> {
-					let (initserver_send, initserver_recv) = oneshot::channel();
-					let (connection_send, connection_recv) = oneshot::channel();
-					let ph: Option<PHBox> = options.handle_packets.as_ref().map(|h| (*h).clone());
-					let packet_handler = SimplePacketHandler::new(
-						logger.clone(),
-						ph,
-						initserver_send,
-						connection_recv,
-						#[cfg(feature = "audio")]
-						options.audio_packet_handler.clone(),
-					);
-					let return_code_handler =
-						packet_handler.return_codes.clone();
-					let client = match client::new(
-						options.local_address.unwrap_or_else(|| if addr.is_ipv4() {
-							"0.0.0.0:0".parse().unwrap()
-						} else {
-							"[::]:0".parse().unwrap()
-						}),
-						private_key.clone(),
-						packet_handler,
-						logger.clone(),
-					) {
-						Ok(client) => client,
-						Err(error) => return Box::new(future::err(error.into())),
-					};
-
-					{
-						let mut c = client.lock();
-						let c = &mut *c;
-						// Logging
-						if options.log_commands { log::add_command_logger(c); }
-						if options.log_packets { log::add_packet_logger(c); }
-						if options.log_udp_packets { log::add_udp_packet_logger(c); }
+			addrs
+				.and_then(move |addrs| -> Result<Vec<BoxFuture<_>>> {
+					let addrs = resolver::interleave_families(addrs);
+					if addrs.is_empty() {
+						return Err(
+							format_err!("Found no address for the server")
+								.into(),
+						);
 					}
 
-					if let Some(prepare_client) = &options.prepare_client {
-						prepare_client(&client);
-					}
-
-					let client = client.clone();
-					let client2 = client.clone();
-					let options = options.clone();
-
-					// Create a connection
-					debug!(logger, "Connecting"; "address" => %addr);
-					let connect_fut = client::connect(
-						Arc::downgrade(&client),
-						&mut *client.lock(),
-						addr,
-					).from_err();
-
-					let initserver_poll = initserver_recv
-						.map_err(|e| {
-							format_err!(
-								"Error while waiting for initserver ({:?})",
-								e
-							).into()
-						}).and_then(move |cmd| {
-							let msg = InMessage::new(cmd).map_err(|(_, e)| e)?;
-							if let InMessages::InitServer(_) = msg.msg() {
-								Ok(msg)
-							} else {
-								Err(Error::ConnectionFailed(String::from(
-									"Got no initserver",
-								)))
-							}
-						});
-
-					Box::new(
-						connect_fut
-				.and_then(move |con| -> Box<Future<Item=_, Error=_> + Send> {
-					let logger = {
-						let mutex = match con.upgrade().ok_or_else(||
-							format_err!("Connection does not exist anymore")) {
-							Ok(r) => r.mutex,
-							Err(e) => return Box::new(future::err(e.into())),
-						};
-						let con = mutex.lock();
-						con.1.logger.clone()
-					};
-					// TODO Add possibility to specify offset and level in ConnectOptions
-					// Compute hash cash
-					let mut time_reporter = slog_perf::TimeReporter::new_with_level(
-						"Compute public key hash cash level", logger.clone(),
-						slog::Level::Info);
-					time_reporter.start("Compute public key hash cash level");
-					let pub_k = {
-						let c = client.lock();
-						c.private_key.to_pub()
-					};
-					Box::new(future::poll_fn(move || {
-						tokio_threadpool::blocking(|| {
-							let res = (
-								con.clone(),
-								algs::hash_cash(&pub_k, 8).unwrap(),
-								pub_k.to_ts().unwrap(),
-								logger.clone(),
-							);
-							res
+					// Start the attempts delayed, so that we do not flood the
+					// network with packets if the first server answers fast.
+					let now = Instant::now();
+					Ok(addrs
+						.into_iter()
+						.enumerate()
+						.map(|(i, addr)| -> BoxFuture<_> {
+							let logger = logger.clone();
+							let options = options.clone();
+							let private_key = private_key.clone();
+							let connected = connected.clone();
+							let delay = CONNECTION_ATTEMPT_DELAY * i as u32;
+							Box::new(
+								Delay::new(now + delay)
+									.map_err(|e| {
+										format_err!("Timer failed ({:?})", e)
+											.into()
+									})
+									.and_then(move |_| -> BoxFuture<_> {
+										if connected.load(Ordering::Relaxed) {
+											return Box::new(future::err(
+												format_err!("Already \
+													connected").into()));
+										}
+										ConnectAttempt::start(
+											logger,
+											&options,
+											private_key,
+											addr,
+										)
+									}),
+							)
 						})
-					}).map(|r| { time_reporter.finish(); r })
-					.map_err(|e| format_err!("Failed to start \
-						blocking operation ({:?})", e).into()))
+						.collect())
 				})
-				.and_then(move |(con, offset, omega, logger)| {
-					info!(logger, "Computed hash cash level";
-						"level" => algs::get_hash_cash_level(&omega, offset),
-						"offset" => offset);
-
-					// Create clientinit packet
-					let version_string = options.version.get_version_string();
-					let version_platform = options.version.get_platform();
-					let version_sign = base64::encode(options.version.get_signature());
-					let offset = offset.to_string();
-					let packet = OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
-						Direction::C2S,
-						PacketType::Command,
-						"clientinit",
-						vec![
-							("client_nickname", options.name.as_str()),
-							("client_version", &version_string),
-							("client_platform", &version_platform),
-							("client_input_hardware", "1"),
-							("client_output_hardware", "1"),
-							("client_default_channel", ""),
-							("client_default_channel_password", ""),
-							("client_server_password", ""),
-							("client_meta_data", ""),
-							("client_version_sign", &version_sign),
-							("client_nickname_phonetic", ""),
-							("client_key_offset", &offset),
-							("client_default_token", ""),
-							("hwid", "923f136fb1e22ae6ce95e60255529c00,d13231b1bc33edfecfb9169cc7a63bcc"),
-						].into_iter(),
-						std::iter::empty(),
-					);
-
-					let sink = con.as_packet_sink();
-					sink.send(packet).map(move |_| con)
+				.and_then(move |attempts| {
+					// Continue with the first server that answers. If the rest
+					// of the handshake fails, take the next one.
+					future::loop_fn((attempts, 0), move |(attempts, retries)| {
+						let logger = logger2.clone();
+						let options = options2.clone();
+						let private_key = private_key2.clone();
+						let connected = connected2.clone();
+						future::select_ok(attempts).and_then(
+							move |(attempt, mut rest)| {
+								let addr = attempt.addr;
+								let name = get_nickname(&options.name, retries);
+								let finish = attempt.finish(options.clone(), name);
+								finish.then(move |r| match r {
+									Ok(con) => {
+										connected
+											.store(true, Ordering::Relaxed);
+										ConnectAttempt::abort_all(rest);
+										Ok(future::Loop::Break(con))
+									}
+									Err(Error::Ts(TsError::ClientNicknameInuse))
+										if retries < options.nickname_retries => {
+										debug!(logger, "Nickname is in use, \
+											trying another one";
+											"address" => %addr);
+										// Try the same address again
+										rest.insert(0, ConnectAttempt::start(
+											logger,
+											&options,
+											private_key,
+											addr,
+										));
+										Ok(future::Loop::Continue((rest,
+											retries + 1)))
+									}
+									// The server rejected us, other addresses
+									// belong to the same server.
+									Err(e @ Error::Ts(_)) => {
+										connected
+											.store(true, Ordering::Relaxed);
+										ConnectAttempt::abort_all(rest);
+										Err(e)
+									}
+									Err(e) => {
+										if rest.is_empty() {
+											return Err(e);
+										}
+										debug!(logger, "Connecting failed, \
+											trying next address";
+											"address" => %addr,
+											"error" => ?e);
+										Ok(future::Loop::Continue((rest,
+											retries)))
+									}
+								})
+							},
+						)
+					})
 				})
-				.from_err()
-				// Wait until we sent the clientinit packet and afterwards received
-				// the initserver packet.
-				.and_then(move |con| initserver_poll.map(|r| (con, r)))
-				.and_then(move |(con, initserver)| {
-					// Get uid of server
-					let uid = {
-						let mutex = con.upgrade().ok_or_else(||
-							format_err!("Connection does not exist anymore"))?
-							.mutex;
-						let con = mutex.lock();
-						con.1.params.as_ref().ok_or_else(||
-							format_err!("Connection params do not exist"))?
-							.public_key.get_uid()?
-					};
-
-					// Create connection
-					let data = data::Connection::new(Uid(uid), &initserver);
-					let con = InnerConnection {
-						connection: Arc::new(RwLock::new(data)),
-						client_data: client2,
-						client_connection: con,
-						return_code_handler,
-						event_listeners: Arc::new(RwLock::new(HashMap::new())),
-					};
-
-					// Send connection to packet handler
-					let con = Connection { inner: con };
-					connection_send.send(con.clone()).map_err(|_|
-						format_err!("Failed to send connection to packet \
-							handler"))?;
-
-					Ok(con)
+				.and_then(move |con| -> BoxFuture<_> {
+					if auto_subscribe {
+						Box::new(con.subscribe_all().map(move |_| con))
+					} else {
+						Box::new(future::ok(con))
+					}
 				}),
-					)
-				},
-			).then(move |r| -> Result<_> {
-				if let Err(e) = &r {
-					debug!(logger2, "Connecting failed, trying next address";
-					"error" => ?e);
-				}
-				Ok(r.ok())
-			}).filter_map(|r| r)
-			.into_future()
-			.map_err(|_| {
-				Error::from(format_err!("Failed to connect to server"))
-			}).and_then(|(r, _)| {
-				r.ok_or_else(|| {
-					format_err!("Failed to connect to server").into()
-				})
-			}),
 		)
 	}
 
@@ -564,6 +551,142 @@ impl Connection {
 		ConnectionLock::new(self.clone(), self.inner.connection.read())
 	}
 
+	/// Get a copy of the current state of this connection.
+	///
+	/// The copy can be serialized and compared to other states with the
+	/// functions in the [`snapshot`] module.
+	///
+	/// [`snapshot`]: snapshot/index.html
+	pub fn snapshot(&self) -> data::Connection {
+		self.inner.connection.read().clone()
+	}
+
+	/// Change the book and send the resulting events to the event listeners.
+	fn update_book<F>(&self, f: F)
+		where F: FnOnce(&mut data::Connection, &mut Vec<events::Events>) {
+		let mut events = Vec::new();
+		f(&mut *self.inner.connection.write(), &mut events);
+		packet_handler::call_event_listeners(self, &events);
+	}
+
+	/// Get a snapshot of the packet counters and the round trip time of this
+	/// connection.
+	pub fn get_stats(&self) -> Result<ConnectionStats> {
+		let con = self.inner.client_connection.upgrade().ok_or_else(||
+			format_err!("Connection does not exist anymore"))?;
+		let stats = con.mutex.lock().1.get_stats();
+		Ok(stats)
+	}
+
+	/// Send a packet and wait until the server answers with the notification
+	/// `name`, which contains `arg=value`.
+	///
+	/// Fails if the request is rejected or if the notification does not arrive
+	/// in time.
+	pub(crate) fn send_packet_and_wait(
+		&self,
+		packet: OutPacket,
+		name: &'static str,
+		arg: &'static str,
+		value: String,
+	) -> BoxFuture<()>
+	{
+		// Register before sending, the notification can arrive before the
+		// return code.
+		let recv =
+			self.inner.notification_waiters.lock().add(name, arg, value);
+		Box::new(self.send_packet(packet).and_then(move |_| {
+			packet_handler::wait_for_notification(recv, name,
+				NOTIFICATION_TIMEOUT)
+		}))
+	}
+
+	/// Request the connection info of a client from the server.
+	///
+	/// The future resolves with the info when the `notifyconnectioninfo` for
+	/// the client was received. The info is also stored in the
+	/// `connection_data` of the client.
+	pub fn fetch_connection_info(&self, client: ClientId)
+		-> BoxFuture<data::ConnectionClientData> {
+		let packet = OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"getconnectioninfo",
+			vec![("clid", client.0.to_string())].into_iter(),
+			std::iter::empty(),
+		);
+		let con = self.clone();
+		Box::new(self.send_packet_and_wait(packet, "notifyconnectioninfo",
+			"clid", client.0.to_string())
+			.and_then(move |_| {
+				con.lock().server.clients.get(&client)
+					.and_then(|c| c.connection_data.clone())
+					.ok_or_else(|| format_err!(
+						"Client {} has no connection info", client).into())
+			}))
+	}
+
+	/// Answer a `notifyconnectioninforequest` of the server with our own
+	/// statistics.
+	pub(crate) fn send_connection_info(&self) -> BoxFuture<()> {
+		let stats = match self.get_stats() {
+			Ok(s) => s,
+			Err(e) => return Box::new(future::err(e)),
+		};
+		let categories = [
+			("speech", PacketCategory::Speech),
+			("keepalive", PacketCategory::Keepalive),
+			("control", PacketCategory::Control),
+		];
+		let as_ms = |d: Duration| {
+			d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1e6
+		};
+
+		let mut args = vec![
+			("connection_ping".to_string(),
+				format!("{:.4}", stats.rtt.map(as_ms).unwrap_or_default())),
+			("connection_ping_deviation".to_string(),
+				format!("{:.4}", as_ms(stats.jitter))),
+		];
+		for (name, cat) in &categories {
+			let sent = stats.get_sent(*cat);
+			let received = stats.get_received(*cat);
+			let second = stats.get_bandwidth(*cat, Duration::from_secs(1));
+			let minute = stats.get_bandwidth(*cat, Duration::from_secs(60));
+			args.extend(vec![
+				(format!("connection_packets_sent_{}", name),
+					sent.packets.to_string()),
+				(format!("connection_bytes_sent_{}", name),
+					sent.bytes.to_string()),
+				(format!("connection_packets_received_{}", name),
+					received.packets.to_string()),
+				(format!("connection_bytes_received_{}", name),
+					received.bytes.to_string()),
+				(format!("connection_server2client_packetloss_{}", name),
+					format!("{:.4}", stats.get_loss(*cat))),
+				(format!("connection_bandwidth_sent_last_second_{}", name),
+					second.0.to_string()),
+				(format!("connection_bandwidth_sent_last_minute_{}", name),
+					minute.0.to_string()),
+				(format!("connection_bandwidth_received_last_second_{}", name),
+					second.1.to_string()),
+				(format!("connection_bandwidth_received_last_minute_{}", name),
+					minute.1.to_string()),
+			]);
+		}
+		args.push(("connection_server2client_packetloss_total".to_string(),
+			format!("{:.4}", stats.get_total_loss())));
+
+		let packet = OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"setconnectioninfo",
+			args.into_iter(),
+			std::iter::empty(),
+		);
+		Box::new(self.get_packet_sink().send(packet).map(|_| ()))
+	}
+
 	/// Disconnect from the server.
 	///
 	/// # Arguments
@@ -637,12 +760,16 @@ impl Connection {
 				std::iter::empty(),
 			);
 
-		let addr = if let Some(con) = self.inner.client_connection.upgrade() {
-			con.mutex.lock().1.address
-		} else {
-			return Box::new(future::ok(()));
+		let wait = {
+			let mut client = self.inner.client_data.lock();
+			if let Some(key) =
+				client.get_connection_key(&self.inner.client_connection)
+			{
+				client.wait_for_disconnect(key)
+			} else {
+				return Box::new(future::ok(()));
+			}
 		};
-		let wait = self.inner.client_data.lock().wait_for_disconnect(addr);
 		let inner = self.inner.clone();
 		Box::new(
 			self.inner
@@ -681,7 +808,10 @@ impl Connection {
 	/// ```
 	pub fn add_on_disconnect(&self, f: Box<FnOnce() + Send>) {
 		self.inner.client_data.lock().connection_listeners.push(Box::new(
-			DisconnectListener(Some(f))
+			DisconnectListener {
+				con: self.inner.client_connection.clone(),
+				f: Some(f),
+			}
 		));
 	}
 
@@ -705,6 +835,720 @@ impl Connection {
 	pub fn remove_on_event(&self, key: &str) -> Option<EventListener> {
 		self.inner.event_listeners.write().remove(key)
 	}
+
+	/// Get the events of this connection as a stream.
+	///
+	/// Only events which pass the `filter` are returned. At most `capacity`
+	/// batches are buffered, if the stream is not polled fast enough, the
+	/// oldest batches are dropped and an [`EventBatch::Lagged`] is returned.
+	///
+	/// Internally, this registers an event listener, which is removed when
+	/// the stream is dropped.
+	///
+	/// # Example
+	///
+	/// ```no_run
+	/// # use futures::{Future, Stream};
+	/// # use tsclientlib::Connection;
+	/// # use tsclientlib::event_stream::{EventBatch, EventFilter};
+	/// # fn f(con: &Connection) {
+	/// let moves = con.events(EventFilter::client_moves(), 64)
+	/// 	.for_each(|batch| {
+	/// 		if let EventBatch::Events(events) = batch {
+	/// 			println!("Clients moved: {:?}", events);
+	/// 		}
+	/// 		Ok(())
+	/// 	});
+	/// tokio::spawn(moves.map_err(|e| println!("Error: {}", e)));
+	/// # }
+	/// ```
+	///
+	/// [`EventBatch::Lagged`]: event_stream/enum.EventBatch.html#variant.Lagged
+	pub fn events(
+		&self,
+		filter: event_stream::EventFilter,
+		capacity: usize,
+	) -> event_stream::EventStream
+	{
+		event_stream::EventStream::new(
+			&self.inner.event_listeners,
+			filter,
+			capacity,
+		)
+	}
+
+	/// Send a plugin command to other clients.
+	///
+	/// The `name` identifies the plugin, which should handle the command.
+	pub fn send_plugin_command(
+		&self,
+		name: &str,
+		data: &str,
+		target: &PluginTarget,
+	) -> BoxFuture<()>
+	{
+		let packet = plugin::create_command(name, data, target);
+		Box::new(self.send_packet(packet))
+	}
+
+	/// Get the plugin commands, which are sent to us by other clients.
+	///
+	/// The stream ends when the connection is dropped.
+	pub fn plugin_commands(&self) -> mpsc::UnboundedReceiver<PluginCommand> {
+		let (send, recv) = mpsc::unbounded();
+		self.inner.plugin_listeners.lock().push(send);
+		recv
+	}
+
+	/// Request the list of all permissions and the permission lists which
+	/// apply to our own client.
+	///
+	/// This is done automatically after connecting. Missing permission lists
+	/// are also requested when our own client changes its channel or groups.
+	pub fn fetch_permissions(&self) -> BoxFuture<()> {
+		let packet = OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			"permissionlist",
+			std::iter::empty::<(&str, &str)>(),
+			std::iter::empty(),
+		);
+		let mut futures: Vec<BoxFuture<()>> =
+			vec![Box::new(self.send_packet(packet))];
+		futures.extend(self.own_permission_lists().into_iter()
+			.map(|o| self.fetch_permission_list(o)));
+		Box::new(future::join_all(futures).map(|_| ()))
+	}
+
+	/// Request the permission lists of our own client, which are not known
+	/// yet.
+	pub(crate) fn fetch_missing_permissions(&self) -> BoxFuture<()> {
+		let owners = self.own_permission_lists();
+		self.fetch_missing_lists(owners)
+	}
+
+	fn own_permission_lists(&self) -> Vec<PermissionOwner> {
+		let own_client = self.inner.connection.read().own_client;
+		self.client_permission_lists(own_client)
+	}
+
+	/// The permission lists, which apply to a client.
+	fn client_permission_lists(&self, client: ClientId)
+		-> Vec<PermissionOwner> {
+		let con = self.inner.connection.read();
+		let ctx = if let Some(ctx) = ClientContext::new(&con, client) {
+			ctx
+		} else {
+			return Vec::new();
+		};
+		let mut owners = ctx.server_groups.iter()
+			.map(|g| PermissionOwner::ServerGroup(*g))
+			.collect::<Vec<_>>();
+		owners.push(PermissionOwner::Client(ctx.database_id));
+		owners.push(PermissionOwner::Channel(ctx.channel));
+		owners.push(PermissionOwner::ChannelGroup(ctx.channel_group));
+		owners.push(PermissionOwner::ChannelClient(ctx.channel,
+			ctx.database_id));
+		owners
+	}
+
+	/// Request the permission lists of a client, which are not known yet.
+	///
+	/// These are the lists of its server groups, channel group and channel and
+	/// its own client and channel-client lists. They are needed to compute the
+	/// needed powers of the client, e.g. for kicking or moving it.
+	pub fn fetch_client_permissions(&self, client: ClientId) -> BoxFuture<()> {
+		let owners = self.client_permission_lists(client);
+		self.fetch_missing_lists(owners)
+	}
+
+	fn fetch_missing_lists(&self, mut owners: Vec<PermissionOwner>)
+		-> BoxFuture<()> {
+		{
+			let perms = self.inner.permissions.read();
+			owners.retain(|o| perms.get_list(*o).is_none());
+		}
+		Box::new(future::join_all(owners.into_iter()
+			.map(|o| self.fetch_permission_list(o))).map(|_| ()))
+	}
+
+	/// Check if our own client is allowed to do an action.
+	///
+	/// In contrast to [`ConnectionLock::can`], this requests all missing
+	/// permission lists of the target client or channel from the server
+	/// before checking.
+	///
+	/// [`ConnectionLock::can`]: struct.ConnectionLock.html#method.can
+	pub fn can(&self, action: permissions::Action) -> BoxFuture<bool> {
+		use crate::permissions::Action;
+
+		let owners = match action {
+			Action::MoveClient(client, channel) => {
+				let mut owners = self.client_permission_lists(client);
+				owners.push(PermissionOwner::Channel(channel));
+				owners
+			}
+			Action::KickFromChannel(client) | Action::KickFromServer(client) =>
+				self.client_permission_lists(client),
+			Action::JoinChannel(channel) | Action::SubscribeChannel(channel) =>
+				vec![PermissionOwner::Channel(channel)],
+			Action::Talk(_) => Vec::new(),
+		};
+		let con = self.clone();
+		Box::new(self.fetch_missing_lists(owners).and_then(move |()| {
+			con.lock().can(action).ok_or_else(|| {
+				format_err!("Permissions for {:?} are unknown", action).into()
+			})
+		}))
+	}
+
+	/// Request a permission list from the server.
+	///
+	/// The future resolves when the list was received. Afterwards, it can be
+	/// found in the [`Permissions`] of this connection.
+	///
+	/// The permissions of our own client are sent by the server without a
+	/// request.
+	///
+	/// [`Permissions`]: permissions/struct.Permissions.html
+	pub fn fetch_permission_list(&self, owner: PermissionOwner)
+		-> BoxFuture<()> {
+		let (name, args) = match owner {
+			PermissionOwner::ServerGroup(g) => ("servergrouppermlist",
+				vec![("sgid", g.0)]),
+			PermissionOwner::ChannelGroup(g) => ("channelgrouppermlist",
+				vec![("cgid", g.0)]),
+			PermissionOwner::Client(c) => ("clientpermlist",
+				vec![("cldbid", c.0)]),
+			PermissionOwner::Channel(c) => ("channelpermlist",
+				vec![("cid", c.0)]),
+			PermissionOwner::ChannelClient(c, cl) => ("channelclientpermlist",
+				vec![("cid", c.0), ("cldbid", cl.0)]),
+			PermissionOwner::OwnClient => return Box::new(future::ok(())),
+		};
+		let packet = OutCommand::new::<_, _, &str, &str, _, _, std::iter::Empty<_>>(
+			Direction::C2S,
+			PacketType::Command,
+			name,
+			args.into_iter().map(|(k, v)| (k, v.to_string())),
+			std::iter::empty(),
+		);
+
+		let permissions = self.inner.permissions.clone();
+		Box::new(self.send_packet(packet).or_else(move |e| match e {
+			Error::Ts(TsError::DatabaseEmptyResult) => {
+				permissions.write().set_empty(owner);
+				Ok(())
+			}
+			e => Err(e),
+		}))
+	}
+
+	/// Forget permission lists, which may be outdated, and request the ones
+	/// again, which were known before.
+	pub(crate) fn refetch_permission_lists(&self, owners: &[PermissionOwner])
+		-> BoxFuture<()> {
+		let known = self.inner.permissions.write().invalidate(owners);
+		let futures = known.into_iter()
+			.map(|o| self.fetch_permission_list(o))
+			.collect::<Vec<_>>();
+		Box::new(future::join_all(futures).map(|_| ()))
+	}
+
+	/// Subscribe to channels, so we get to know the clients in them.
+	///
+	/// The future resolves when the server accepted the subscription.
+	/// Afterwards, the clients of the channels are in the connection data
+	/// structure and the channels are marked as [`subscribed`].
+	///
+	/// [`subscribed`]: data/struct.Channel.html#structfield.subscribed
+	pub fn subscribe_channels(&self, channels: &[ChannelId]) -> BoxFuture<()> {
+		if channels.is_empty() {
+			return Box::new(future::ok(()));
+		}
+		Box::new(self.send_packet(subscriptions::create_command(true,
+			Some(channels))))
+	}
+
+	/// Unsubscribe from channels.
+	///
+	/// The clients of the channels are removed from the connection data
+	/// structure, except for the channel of our own client.
+	pub fn unsubscribe_channels(&self, channels: &[ChannelId])
+		-> BoxFuture<()> {
+		if channels.is_empty() {
+			return Box::new(future::ok(()));
+		}
+		Box::new(self.send_packet(subscriptions::create_command(false,
+			Some(channels))))
+	}
+
+	/// Subscribe to a channel and all its subchannels.
+	pub fn subscribe_subtree(&self, channel: ChannelId) -> BoxFuture<()> {
+		let channels = subscriptions::subtree(&self.inner.connection.read(),
+			channel);
+		self.subscribe_channels(&channels)
+	}
+
+	/// Unsubscribe from a channel and all its subchannels.
+	pub fn unsubscribe_subtree(&self, channel: ChannelId) -> BoxFuture<()> {
+		let channels = subscriptions::subtree(&self.inner.connection.read(),
+			channel);
+		self.unsubscribe_channels(&channels)
+	}
+
+	/// Subscribe to all channels of the server.
+	///
+	/// This can also be done when connecting with
+	/// [`ConnectOptions::auto_subscribe`].
+	///
+	/// [`ConnectOptions::auto_subscribe`]: struct.ConnectOptions.html#method.auto_subscribe
+	pub fn subscribe_all(&self) -> BoxFuture<()> {
+		Box::new(self.send_packet(subscriptions::create_command(true, None)))
+	}
+
+	/// Unsubscribe from all channels of the server.
+	pub fn unsubscribe_all(&self) -> BoxFuture<()> {
+		Box::new(self.send_packet(subscriptions::create_command(false, None)))
+	}
+
+	/// Request the content of a directory in the files of a channel.
+	///
+	/// The future resolves when the list was received. Afterwards, the
+	/// entries can also be found in the [`files`] of the channel.
+	///
+	/// [`files`]: data/struct.Channel.html#structfield.files
+	pub fn list_files(&self, channel: ChannelId, password: &str, path: &str)
+		-> BoxFuture<Vec<data::File>> {
+		let packet = filetransfer::file_command("ftgetfilelist", channel,
+			password, vec![("path", path.to_string())]);
+		let con = self.clone();
+		let path = path.to_string();
+		Box::new(self.send_packet(packet).then(move |r| {
+			match r {
+				Ok(()) => {}
+				Err(Error::Ts(TsError::DatabaseEmptyResult)) => {
+					// The directory is empty
+					con.update_book(|c, events| filetransfer::set_directory(
+						c, channel, &path, Vec::new(), events));
+				}
+				Err(e) => return Err(e),
+			}
+			let c = con.lock();
+			Ok(c.server.channels.get(&channel)
+				.map(|c| filetransfer::get_directory(c, &path).into_iter()
+					.cloned().collect())
+				.unwrap_or_default())
+		}))
+	}
+
+	/// Upload `size` bytes from `reader` to `path` in the files of a channel.
+	///
+	/// An existing file is overwritten. If `resume` is `true`, an incomplete
+	/// upload to the same path is continued instead. The part, which the
+	/// server already has, is skipped in `reader`.
+	///
+	/// The future resolves when the server accepted the upload.
+	pub fn upload_file<R: AsyncRead + Send + 'static>(
+		&self,
+		channel: ChannelId,
+		password: &str,
+		path: &str,
+		size: u64,
+		resume: bool,
+		reader: R,
+	) -> BoxFuture<FileTransfer>
+	{
+		let init = filetransfer::init_transfer(self, "ftinitupload", channel,
+			password, vec![
+				("name", path.to_string()),
+				("size", size.to_string()),
+				("overwrite", (!resume as u8).to_string()),
+				("resume", (resume as u8).to_string()),
+				("proto", "1".to_string()),
+			]);
+		let con = self.clone();
+		let path = path.to_string();
+		Box::new(init.and_then(move |start| {
+			let transfer = FileTransfer::upload(con.clone(), start, size,
+				reader)?;
+			// The file is visible when the upload starts
+			let file = filetransfer::created_file(channel, &path, size, true);
+			con.update_book(|c, events|
+				filetransfer::update_file(c, file, events));
+			Ok(transfer)
+		}))
+	}
+
+	/// Download the file at `path` from the files of a channel into `writer`.
+	///
+	/// To resume a download, set `seek_position` to the number of bytes,
+	/// which were already downloaded, `writer` gets only the rest of the file.
+	///
+	/// The future resolves when the server accepted the download.
+	pub fn download_file<W: AsyncWrite + Send + 'static>(
+		&self,
+		channel: ChannelId,
+		password: &str,
+		path: &str,
+		seek_position: u64,
+		writer: W,
+	) -> BoxFuture<FileTransfer>
+	{
+		let init = filetransfer::init_transfer(self, "ftinitdownload", channel,
+			password, vec![
+				("name", path.to_string()),
+				("seekpos", seek_position.to_string()),
+				("proto", "1".to_string()),
+			]);
+		let con = self.clone();
+		Box::new(init.and_then(move |start|
+			FileTransfer::download(con, start, writer)))
+	}
+
+	/// Create a directory in the files of a channel.
+	pub fn create_directory(&self, channel: ChannelId, password: &str,
+		path: &str) -> BoxFuture<()> {
+		let packet = filetransfer::file_command("ftcreatedir", channel,
+			password, vec![("dirname", path.to_string())]);
+		let con = self.clone();
+		let path = path.to_string();
+		Box::new(self.send_packet(packet).map(move |_| {
+			let file = filetransfer::created_file(channel, &path, 0, false);
+			con.update_book(|c, events|
+				filetransfer::update_file(c, file, events));
+		}))
+	}
+
+	/// Rename or move a file or directory inside the files of a channel.
+	pub fn rename_file(&self, channel: ChannelId, password: &str,
+		old_path: &str, new_path: &str) -> BoxFuture<()> {
+		let packet = filetransfer::file_command("ftrenamefile", channel,
+			password, vec![
+				("oldname", old_path.to_string()),
+				("newname", new_path.to_string()),
+			]);
+		let con = self.clone();
+		let old_path = old_path.to_string();
+		Box::new(self.send_packet(packet).map(move |_| {
+			// The new location is unknown until it is listed again
+			con.update_book(|c, events|
+				filetransfer::remove_file(c, channel, &old_path, events));
+		}))
+	}
+
+	/// Delete files or directories with their content from the files of a
+	/// channel.
+	pub fn delete_files(&self, channel: ChannelId, password: &str,
+		paths: &[&str]) -> BoxFuture<()> {
+		let packet = OutCommand::new(
+			Direction::C2S,
+			PacketType::Command,
+			"ftdeletefile",
+			vec![("cid", channel.0.to_string()),
+				("cpw", password.to_string())].into_iter(),
+			paths.iter().map(|p| std::iter::once(("name", *p))),
+		);
+		let con = self.clone();
+		let paths = paths.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+		Box::new(self.send_packet(packet).map(move |_| {
+			con.update_book(|c, events| for p in &paths {
+				filetransfer::remove_file(c, channel, p, events);
+			});
+		}))
+	}
+}
+
+/// The nickname for a connection attempt, a number is appended for retries.
+fn get_nickname(name: &str, retries: u32) -> String {
+	if retries == 0 {
+		return name.to_string();
+	}
+	let suffix = retries.to_string();
+	let len = MAX_NICKNAME_LEN.saturating_sub(suffix.len());
+	format!("{}{}", name.chars().take(len).collect::<String>(), suffix)
+}
+
+impl ConnectAttempt {
+	/// Create a new client for `addr` and wait until the server answers the
+	/// first init packet.
+	fn start(
+		logger: Logger,
+		options: &ConnectOptions,
+		private_key: crypto::EccKeyPrivP256,
+		addr: SocketAddr,
+	) -> BoxFuture<Self>
+	{
+		let (initserver_send, initserver_recv) = oneshot::channel();
+		let (connection_send, connection_recv) = oneshot::channel();
+		let ph: Option<PHBox> =
+			options.handle_packets.as_ref().map(|h| (*h).clone());
+		let packet_handler = SimplePacketHandler::new(
+			logger.clone(),
+			ph,
+			initserver_send,
+			connection_recv,
+			#[cfg(feature = "audio")]
+			options.audio_packet_handler.clone(),
+		);
+		let return_code_handler = packet_handler.return_codes.clone();
+		let local_address = options.local_address.unwrap_or_else(|| {
+			if addr.is_ipv4() {
+				"0.0.0.0:0".parse().unwrap()
+			} else {
+				"[::]:0".parse().unwrap()
+			}
+		});
+
+		// Reuse the socket of another connection if possible
+		let shared = options.sockets.as_ref()
+			.and_then(|s| s.get(options, local_address));
+		let (client, packet_handler) = if let Some(client) = shared {
+			(client, Some(packet_handler))
+		} else {
+			// Connections outside of a manager do not share their socket, so
+			// finding the connection of a packet stays cheap.
+			let client = match client::new_shared(
+				local_address,
+				private_key.clone(),
+				packet_handler,
+				logger.clone(),
+			) {
+				Ok(client) => client,
+				Err(error) => return Box::new(future::err(error.into())),
+			};
+
+			{
+				let mut c = client.lock();
+				let c = &mut *c;
+				// Logging
+				if options.log_commands { log::add_command_logger(c); }
+				if options.log_packets { log::add_packet_logger(c); }
+				if options.log_udp_packets { log::add_udp_packet_logger(c); }
+			}
+
+			if let Some(prepare_client) = &options.prepare_client {
+				prepare_client(&client);
+			}
+			if let Some(sockets) = &options.sockets {
+				sockets.insert(options, local_address, &client);
+			}
+			(client, None)
+		};
+
+		// Create a connection
+		debug!(logger, "Connecting"; "address" => %addr);
+		let (con, answered) = {
+			let mut c = client.lock();
+			if let Some(packet_handler) = packet_handler {
+				// The packet handler gets the streams of the new connection,
+				// which is added under the same lock.
+				c.packet_handler.inner = packet_handler;
+			}
+			client::start_connect_with_key(
+				Arc::downgrade(&client),
+				&mut *c,
+				addr,
+				private_key,
+			)
+		};
+		let connecting = Box::new(client::wait_for_state(&con, |state| {
+			*state == client::ServerConnectionState::Connecting
+		}).from_err());
+
+		let attempt = Self {
+			addr,
+			logger: logger.clone(),
+			client,
+			con,
+			connecting,
+			initserver_recv,
+			connection_send,
+			return_code_handler,
+		};
+		Box::new(answered.from_err().map(move |_| attempt).map_err(
+			move |e: Error| {
+				debug!(logger, "Server did not answer"; "address" => %addr,
+					"error" => ?e);
+				e
+			},
+		))
+	}
+
+	/// Stop the handshake and remove the connection from the client.
+	fn abort(self) {
+		debug!(self.logger, "Aborting connection attempt";
+			"address" => %self.addr);
+		let mut client = self.client.lock();
+		if let Some(key) = client.get_connection_key(&self.con) {
+			client.remove_connection(&key);
+		}
+	}
+
+	/// Abort the other attempts when they answer, so they do not keep
+	/// sending init packets.
+	fn abort_all(attempts: Vec<BoxFuture<Self>>) {
+		for a in attempts {
+			tokio::spawn(a.map(Self::abort).map_err(|_| ()));
+		}
+	}
+
+	/// Finish the handshake and create the connection.
+	fn finish(self, options: Arc<ConnectOptions>, name: String)
+		-> BoxFuture<Connection> {
+		let ConnectAttempt {
+			logger,
+			client,
+			con,
+			connecting,
+			initserver_recv,
+			connection_send,
+			return_code_handler,
+			..
+		} = self;
+		let client2 = client.clone();
+
+		let initserver_poll = initserver_recv
+			.map_err(|e| {
+				format_err!(
+					"Error while waiting for initserver ({:?})",
+					e
+				).into()
+			}).and_then(move |cmd| {
+				let msg = InMessage::new(cmd).map_err(|(_, e)| e)?;
+				if let InMessages::InitServer(_) = msg.msg() {
+					Ok(msg)
+				} else if let InMessages::CommandError(cmd) = msg.msg() {
+					// The server rejected the clientinit
+					let cmd = cmd.iter().next().ok_or_else(||
+						format_err!("Got an empty error"))?;
+					Err(if cmd.id == TsError::Ok {
+						Error::ConnectionFailed(String::from(
+							"Got no initserver",
+						))
+					} else {
+						cmd.id.into()
+					})
+				} else {
+					Err(Error::ConnectionFailed(String::from(
+						"Got no initserver",
+					)))
+				}
+			});
+
+		Box::new(
+			connecting
+				.and_then(move |_| -> Box<Future<Item=_, Error=_> + Send> {
+					// TODO Add possibility to specify offset and level in ConnectOptions
+					// Compute hash cash
+					let mut time_reporter = slog_perf::TimeReporter::new_with_level(
+						"Compute public key hash cash level", logger.clone(),
+						slog::Level::Info);
+					time_reporter.start("Compute public key hash cash level");
+					// Every connection on a shared socket has its own key
+					let pub_k = match con.upgrade() {
+						Some(con) => con.mutex.lock().0.private_key.to_pub(),
+						None => {
+							let e: Error = format_err!(
+								"Connection does not exist anymore").into();
+							return Box::new(future::err(e));
+						}
+					};
+					Box::new(future::poll_fn(move || {
+						tokio_threadpool::blocking(|| {
+							let res = (
+								con.clone(),
+								algs::hash_cash(&pub_k, 8).unwrap(),
+								pub_k.to_ts().unwrap(),
+								logger.clone(),
+							);
+							res
+						})
+					}).map(|r| { time_reporter.finish(); r })
+					.map_err(|e| format_err!("Failed to start \
+						blocking operation ({:?})", e).into()))
+				})
+				.and_then(move |(con, offset, omega, logger)| {
+					info!(logger, "Computed hash cash level";
+						"level" => algs::get_hash_cash_level(&omega, offset),
+						"offset" => offset);
+
+					// Create clientinit packet
+					let version_string = options.version.get_version_string();
+					let version_platform = options.version.get_platform();
+					let version_sign = base64::encode(options.version.get_signature());
+					let offset = offset.to_string();
+					let input_hardware = (options.input_hardware as u8).to_string();
+					let output_hardware = (options.output_hardware as u8).to_string();
+					let packet = OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+						Direction::C2S,
+						PacketType::Command,
+						"clientinit",
+						vec![
+							("client_nickname", name.as_str()),
+							("client_version", &version_string),
+							("client_platform", &version_platform),
+							("client_input_hardware", &input_hardware),
+							("client_output_hardware", &output_hardware),
+							("client_default_channel", &options.default_channel),
+							("client_default_channel_password",
+								&options.default_channel_password),
+							("client_server_password", &options.server_password),
+							("client_meta_data", &options.metadata),
+							("client_version_sign", &version_sign),
+							("client_nickname_phonetic", &options.phonetic_name),
+							("client_key_offset", &offset),
+							("client_default_token", &options.privilege_key),
+							("hwid", &options.hwid),
+						].into_iter(),
+						std::iter::empty(),
+					);
+
+					let sink = con.as_packet_sink();
+					sink.send(packet).map(move |_| con).from_err()
+				})
+				// Wait until we sent the clientinit packet and afterwards received
+				// the initserver packet.
+				.and_then(move |con| initserver_poll.map(|r| (con, r)))
+				.and_then(move |(con, initserver)| {
+					// Get uid of server
+					let uid = {
+						let mutex = con.upgrade().ok_or_else(||
+							format_err!("Connection does not exist anymore"))?
+							.mutex;
+						let con = mutex.lock();
+						con.1.params.as_ref().ok_or_else(||
+							format_err!("Connection params do not exist"))?
+							.public_key.get_uid()?
+					};
+
+					// Create connection
+					let data = data::Connection::new(Uid(uid), &initserver);
+					let con = InnerConnection {
+						connection: Arc::new(RwLock::new(data)),
+						client_data: client2,
+						client_connection: con,
+						return_code_handler,
+						event_listeners: Arc::new(RwLock::new(HashMap::new())),
+						permissions: Arc::new(RwLock::new(
+							Permissions::default())),
+						file_requests: Arc::new(RwLock::new(
+							FileRequests::default())),
+						plugin_listeners: Arc::new(Mutex::new(Vec::new())),
+						notification_waiters: Arc::new(Mutex::new(
+							NotificationWaiters::default())),
+					};
+
+					// Send connection to packet handler
+					let con = Connection { inner: con };
+					connection_send.send(con.clone()).map_err(|_|
+						format_err!("Failed to send connection to packet \
+							handler"))?;
+
+					Ok(con)
+				}),
+		)
+	}
 }
 
 #[cfg(feature = "audio")]
@@ -720,10 +1564,22 @@ impl tsproto_audio::audio_to_ts::PacketSinkCreator<Error> for ConnectionPacketSi
 	fn get_sink(&self) -> Self::S { Box::new(self.con.get_packet_sink()) }
 }
 
-impl<CM: ConnectionManager> ConnectionListener<CM> for DisconnectListener {
-	fn on_connection_removed(&mut self, _: &CM::Key, _: &mut ConnectionValue<CM::AssociatedData>) -> bool {
-		self.0.take().unwrap()();
-		false
+impl ConnectionListener<client::SharedCM<SimplePacketHandler>>
+	for DisconnectListener {
+	fn on_connection_removed(
+		&mut self,
+		_: &<client::SharedCM<SimplePacketHandler> as ConnectionManager>::Key,
+		con: &mut ConnectionValue<client::ServerConnectionData>,
+	) -> bool
+	{
+		// Other connections can share the socket
+		if self.con.upgrade().map(|c| c != *con).unwrap_or(false) {
+			return false;
+		}
+		if let Some(f) = self.f.take() {
+			f();
+		}
+		true
 	}
 }
 
@@ -751,6 +1607,24 @@ impl<'a> ConnectionLock<'a> {
 			inner: &*self.guard,
 		}
 	}
+
+	/// The permission lists which were received from the server.
+	pub fn permissions(&self) -> RwLockReadGuard<Permissions> {
+		self.connection.inner.permissions.read()
+	}
+
+	/// Check if our own client is allowed to do an action.
+	///
+	/// Returns `None` if not all needed permissions are known. For actions on
+	/// other clients, their permission lists have to be requested with
+	/// [`Connection::fetch_client_permissions`] first, or use
+	/// [`Connection::can`], which does this automatically.
+	///
+	/// [`Connection::fetch_client_permissions`]: struct.Connection.html#method.fetch_client_permissions
+	/// [`Connection::can`]: struct.Connection.html#method.can
+	pub fn can(&self, action: permissions::Action) -> Option<bool> {
+		self.permissions().can(&*self.guard, action)
+	}
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -820,6 +1694,19 @@ pub struct ConnectOptions {
 	private_key: Option<crypto::EccKeyPrivP256>,
 	name: String,
 	version: Version,
+	/// Hashed
+	server_password: String,
+	default_channel: String,
+	/// Hashed
+	default_channel_password: String,
+	hwid: String,
+	metadata: String,
+	phonetic_name: String,
+	input_hardware: bool,
+	output_hardware: bool,
+	privilege_key: String,
+	nickname_retries: u32,
+	auto_subscribe: bool,
 	logger: Option<Logger>,
 	log_commands: bool,
 	log_packets: bool,
@@ -828,8 +1715,10 @@ pub struct ConnectOptions {
 	audio_packet_handler: Option<AudioPacketHandler>,
 	handle_packets: Option<PHBox>,
 	prepare_client: Option<
-		Box<Fn(&client::ClientDataM<SimplePacketHandler>) + Send + Sync>,
+		Box<Fn(&client::SharedClientDataM<SimplePacketHandler>) + Send + Sync>,
 	>,
+	/// Set by the connection manager to share sockets between connections.
+	sockets: Option<Sockets>,
 }
 
 impl ConnectOptions {
@@ -853,6 +1742,18 @@ impl ConnectOptions {
 			private_key: None,
 			name: String::from("TeamSpeakUser"),
 			version: Version::Linux_3_2_1,
+			server_password: String::new(),
+			default_channel: String::new(),
+			default_channel_password: String::new(),
+			hwid: String::from("923f136fb1e22ae6ce95e60255529c00,\
+				d13231b1bc33edfecfb9169cc7a63bcc"),
+			metadata: String::new(),
+			phonetic_name: String::new(),
+			input_hardware: true,
+			output_hardware: true,
+			privilege_key: String::new(),
+			nickname_retries: 0,
+			auto_subscribe: false,
 			logger: None,
 			log_commands: false,
 			log_packets: false,
@@ -861,6 +1762,7 @@ impl ConnectOptions {
 			audio_packet_handler: None,
 			handle_packets: None,
 			prepare_client: None,
+			sockets: None,
 		}
 	}
 
@@ -933,6 +1835,144 @@ impl ConnectOptions {
 		self
 	}
 
+	/// The password of the server.
+	///
+	/// The password is hashed, like the server expects it.
+	///
+	/// # Default
+	/// No password
+	#[inline]
+	pub fn server_password(mut self, password: &str) -> Self {
+		self.server_password = algs::hash_password(password);
+		self
+	}
+
+	/// The password of the server, if it is already hashed.
+	///
+	/// The hash is the base64 encoded SHA1 of the password.
+	///
+	/// # Default
+	/// No password
+	#[inline]
+	pub fn server_password_hash(mut self, hash: String) -> Self {
+		self.server_password = hash;
+		self
+	}
+
+	/// The channel which is joined after connecting.
+	///
+	/// This can be the path of the channel, where the names of the channels
+	/// are separated by a `/`, or `/<id>` with the id of the channel.
+	///
+	/// # Default
+	/// The default channel of the server
+	#[inline]
+	pub fn default_channel(mut self, channel: String) -> Self {
+		self.default_channel = channel;
+		self
+	}
+
+	/// The password of the channel which is joined after connecting.
+	///
+	/// The password is hashed, like the server expects it.
+	///
+	/// # Default
+	/// No password
+	#[inline]
+	pub fn default_channel_password(mut self, password: &str) -> Self {
+		self.default_channel_password = algs::hash_password(password);
+		self
+	}
+
+	/// The hardware id of the client.
+	///
+	/// # Default
+	/// A fixed id, which is the same for all clients of this library.
+	#[inline]
+	pub fn hwid(mut self, hwid: String) -> Self {
+		self.hwid = hwid;
+		self
+	}
+
+	/// Metadata of the client, which is visible to other clients.
+	///
+	/// # Default
+	/// No metadata
+	#[inline]
+	pub fn metadata(mut self, metadata: String) -> Self {
+		self.metadata = metadata;
+		self
+	}
+
+	/// How the name of the user is pronounced.
+	///
+	/// # Default
+	/// No phonetic name
+	#[inline]
+	pub fn phonetic_name(mut self, phonetic_name: String) -> Self {
+		self.phonetic_name = phonetic_name;
+		self
+	}
+
+	/// If the client has a working microphone.
+	///
+	/// # Default
+	/// `true`
+	#[inline]
+	pub fn input_hardware(mut self, input_hardware: bool) -> Self {
+		self.input_hardware = input_hardware;
+		self
+	}
+
+	/// If the client has working speakers.
+	///
+	/// # Default
+	/// `true`
+	#[inline]
+	pub fn output_hardware(mut self, output_hardware: bool) -> Self {
+		self.output_hardware = output_hardware;
+		self
+	}
+
+	/// A privilege key, which is redeemed when connecting.
+	///
+	/// The key adds the client to a server or channel group.
+	///
+	/// # Default
+	/// No key
+	#[inline]
+	pub fn privilege_key(mut self, privilege_key: String) -> Self {
+		self.privilege_key = privilege_key;
+		self
+	}
+
+	/// How often to retry with another nickname if the nickname is in use.
+	///
+	/// For every retry, a number is appended to the name.
+	///
+	/// # Default
+	/// `0`, an error is returned if the nickname is in use.
+	#[inline]
+	pub fn nickname_retries(mut self, nickname_retries: u32) -> Self {
+		self.nickname_retries = nickname_retries;
+		self
+	}
+
+	/// Subscribe to all channels after connecting.
+	///
+	/// The future of [`Connection::new`] resolves when the subscription
+	/// succeeded, so all clients of the server are known.
+	///
+	/// # Default
+	/// `false`
+	///
+	/// [`Connection::new`]: struct.Connection.html#method.new
+	#[inline]
+	pub fn auto_subscribe(mut self, auto_subscribe: bool) -> Self {
+		self.auto_subscribe = auto_subscribe;
+		self
+	}
+
 	/// If the content of all commands should be written to the logger.
 	///
 	/// # Default
@@ -1019,7 +2059,7 @@ impl ConnectOptions {
 	pub fn prepare_client(
 		mut self,
 		prepare_client: Box<
-			Fn(&client::ClientDataM<SimplePacketHandler>) + Send + Sync,
+			Fn(&client::SharedClientDataM<SimplePacketHandler>) + Send + Sync,
 		>,
 	) -> Self
 	{
@@ -1037,6 +2077,18 @@ impl fmt::Debug for ConnectOptions {
 			private_key,
 			name,
 			version,
+			// Passwords and keys are not printed
+			server_password: _,
+			default_channel,
+			default_channel_password: _,
+			hwid,
+			metadata,
+			phonetic_name,
+			input_hardware,
+			output_hardware,
+			privilege_key: _,
+			nickname_retries,
+			auto_subscribe,
 			logger,
 			log_commands,
 			log_packets,
@@ -1045,17 +2097,29 @@ impl fmt::Debug for ConnectOptions {
 			audio_packet_handler,
 			handle_packets: _,
 			prepare_client: _,
+			sockets: _,
 		} = self;
 		write!(
 			f,
 			"ConnectOptions {{ address: {:?}, local_address: {:?}, \
-			 private_key: {:?}, name: {}, version: {}, logger: {:?}, \
-			 log_commands: {}, log_packets: {}, log_udp_packets: {},",
+			 private_key: {:?}, name: {}, version: {}, default_channel: {}, \
+			 hwid: {}, metadata: {}, phonetic_name: {}, input_hardware: {}, \
+			 output_hardware: {}, nickname_retries: {}, auto_subscribe: {}, \
+			 logger: {:?}, log_commands: {}, log_packets: {}, \
+			 log_udp_packets: {},",
 			address,
 			local_address,
 			private_key,
 			name,
 			version,
+			default_channel,
+			hwid,
+			metadata,
+			phonetic_name,
+			input_hardware,
+			output_hardware,
+			nickname_retries,
+			auto_subscribe,
 			logger,
 			log_commands,
 			log_packets,
@@ -1068,6 +2132,7 @@ impl fmt::Debug for ConnectOptions {
 	}
 }
 
+#[derive(Clone, Debug)]
 pub struct DisconnectOptions {
 	reason: Option<Reason>,
 	message: Option<String>,
@@ -0,0 +1,430 @@
+//! Run many connections in one process.
+//!
+//! A [`ConnectionManager`] owns a set of connections, which are identified by
+//! a [`ConnectionId`]. All connections share one logger, so there is only one
+//! logging thread, and they run on the same tokio runtime.
+//!
+//! Connections with the same local address and logging options share one UDP
+//! socket, every connection keeps its own identity. Connections with a
+//! [`prepare_client`] function get their own socket. Only one connection of a
+//! manager does its handshake at a time, because the packets of a handshake
+//! cannot be assigned to a connection if two handshakes to the same server
+//! run on one socket.
+//!
+//! [`prepare_client`]: ../struct.ConnectOptions.html#method.prepare_client
+//! [`ConnectionManager`]: struct.ConnectionManager.html
+//! [`ConnectionId`]: struct.ConnectionId.html
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Future};
+use parking_lot::Mutex;
+use slog::{o, Drain, Logger};
+use tokio::timer::Delay;
+use tsproto::client;
+
+use crate::events::Events;
+use crate::packet_handler::SimplePacketHandler;
+use crate::{BoxFuture, ConnectOptions, Connection, DisconnectOptions, Result};
+
+type WeakClient = Weak<Mutex<client::SharedClientData<SimplePacketHandler>>>;
+
+/// Identifies a connection in a [`ConnectionManager`].
+///
+/// [`ConnectionManager`]: struct.ConnectionManager.html
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ConnectionId(pub usize);
+
+/// The events of a connection in a [`ConnectionManager`].
+///
+/// [`ConnectionManager`]: struct.ConnectionManager.html
+pub type ManagerEvents = (ConnectionId, Vec<Events>);
+
+/// Owns many connections.
+///
+/// # Example
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use futures::Future;
+/// # use tsclientlib::ConnectOptions;
+/// # use tsclientlib::manager::ConnectionManager;
+/// # fn f() {
+/// let manager = ConnectionManager::new(None);
+/// // Connect 100 bots, start a new connection every 100 ms
+/// let options = (0..100)
+/// 	.map(|i| ConnectOptions::new("localhost").name(format!("Bot {}", i)))
+/// 	.collect();
+/// let connect = manager.connect_all(options, Duration::from_millis(100))
+/// 	.map(|results| {
+/// 		let failed = results.iter().filter(|r| r.is_err()).count();
+/// 		println!("{} connections failed", failed);
+/// 	});
+/// tokio::spawn(connect.map_err(|_| ()));
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ConnectionManager {
+	logger: Logger,
+	inner: Arc<Mutex<Inner>>,
+	listeners: Arc<Mutex<Vec<mpsc::UnboundedSender<ManagerEvents>>>>,
+	sockets: Sockets,
+}
+
+struct Inner {
+	next_id: usize,
+	connections: HashMap<ConnectionId, Connection>,
+	/// Resolves when the last started handshake is finished.
+	handshake: Option<oneshot::Receiver<()>>,
+}
+
+/// The options of a connection, which apply to the whole socket.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct SocketKey {
+	local_address: SocketAddr,
+	log_commands: bool,
+	log_packets: bool,
+	log_udp_packets: bool,
+}
+
+/// The clients of a manager.
+///
+/// A client owns a socket, so connections with the same socket options use
+/// the same client.
+#[derive(Clone, Default)]
+pub(crate) struct Sockets(Arc<Mutex<HashMap<SocketKey, WeakClient>>>);
+
+impl SocketKey {
+	fn new(options: &ConnectOptions, local_address: SocketAddr)
+		-> Option<Self> {
+		// The function could prepare the client for a single connection
+		if options.prepare_client.is_some() {
+			return None;
+		}
+		Some(Self {
+			local_address,
+			log_commands: options.log_commands,
+			log_packets: options.log_packets,
+			log_udp_packets: options.log_udp_packets,
+		})
+	}
+}
+
+impl Sockets {
+	pub(crate) fn get(
+		&self,
+		options: &ConnectOptions,
+		local_address: SocketAddr,
+	) -> Option<client::SharedClientDataM<SimplePacketHandler>>
+	{
+		let key = SocketKey::new(options, local_address)?;
+		let mut sockets = self.0.lock();
+		// Remove closed sockets
+		sockets.retain(|_, c| c.upgrade().is_some());
+		sockets.get(&key).and_then(Weak::upgrade)
+	}
+
+	pub(crate) fn insert(
+		&self,
+		options: &ConnectOptions,
+		local_address: SocketAddr,
+		client: &client::SharedClientDataM<SimplePacketHandler>,
+	)
+	{
+		if let Some(key) = SocketKey::new(options, local_address) {
+			self.0.lock().insert(key, Arc::downgrade(client));
+		}
+	}
+}
+
+impl ConnectionManager {
+	/// Create a new manager.
+	///
+	/// If no logger is given, a new one is created, which is used for all
+	/// connections which have no own logger.
+	pub fn new(logger: Option<Logger>) -> Self {
+		let logger = logger.unwrap_or_else(|| {
+			let decorator = slog_term::TermDecorator::new().build();
+			let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+			let drain = slog_async::Async::new(drain).build().fuse();
+
+			slog::Logger::root(drain, o!())
+		});
+		Self {
+			logger,
+			inner: Arc::new(Mutex::new(Inner {
+				next_id: 0,
+				connections: HashMap::new(),
+				handshake: None,
+			})),
+			listeners: Arc::new(Mutex::new(Vec::new())),
+			sockets: Sockets::default(),
+		}
+	}
+
+	/// Open a new connection and add it to this manager.
+	///
+	/// The future resolves when the connection is established.
+	pub fn connect(&self, options: ConnectOptions) -> BoxFuture<ConnectionId> {
+		let (id, con) = self.start_connect(options);
+		Box::new(con.map(move |_| id))
+	}
+
+	/// Open a new connection and add it to this manager.
+	///
+	/// The id of the connection is known before the connection is
+	/// established. The connection is started when the returned future is
+	/// polled.
+	pub fn start_connect(&self, mut options: ConnectOptions)
+		-> (ConnectionId, BoxFuture<Connection>) {
+		let (id, handshake) = {
+			let mut inner = self.inner.lock();
+			let id = ConnectionId(inner.next_id);
+			inner.next_id += 1;
+			(id, queue_handshake(&mut inner))
+		};
+		if options.logger.is_none() {
+			options = options.logger(self.logger.new(o!("connection" => id.0)));
+		}
+		options.sockets = Some(self.sockets.clone());
+
+		let (done, wait) = handshake;
+		let inner = self.inner.clone();
+		let listeners = self.listeners.clone();
+		(id, Box::new(wait
+			.and_then(move |_| Connection::new(options))
+			// Start the next handshake
+			.then(move |r| { drop(done); r })
+			.map(move |con| {
+				con.add_on_event(
+					String::from("tsclientlib-connection-manager"),
+					Box::new(move |_, events| {
+						let mut listeners = listeners.lock();
+						// Remove closed streams
+						listeners.retain(|l| {
+							l.unbounded_send((id, events.to_vec())).is_ok()
+						});
+					}),
+				);
+				// The manager should not keep its connections alive
+				let weak = Arc::downgrade(&inner);
+				con.add_on_disconnect(Box::new(move || {
+					// The connection cannot be dropped while its socket is
+					// locked, so remove it later.
+					tokio::spawn(future::lazy(move || {
+						if let Some(inner) = weak.upgrade() {
+							inner.lock().connections.remove(&id);
+						}
+						Ok(())
+					}));
+				}));
+				inner.lock().connections.insert(id, con.clone());
+				con
+			})))
+	}
+
+	/// Open many connections.
+	///
+	/// The connections are started one after another, with `interval` between
+	/// them, so the server does not see a flood of new clients. The future
+	/// resolves when all connections are established or failed.
+	pub fn connect_all(&self, options: Vec<ConnectOptions>, interval: Duration)
+		-> BoxFuture<Vec<Result<ConnectionId>>> {
+		let now = Instant::now();
+		let manager = self.clone();
+		Box::new(future::join_all(options.into_iter().enumerate()
+			.map(move |(i, options)| {
+				let manager = manager.clone();
+				Delay::new(now + interval * i as u32)
+					.map_err(|e| format_err!("Timer failed ({:?})", e).into())
+					.and_then(move |_| manager.connect(options))
+					.then(Ok::<_, crate::Error>)
+			})))
+	}
+
+	/// Disconnect a connection and remove it from this manager.
+	pub fn disconnect<O: Into<Option<DisconnectOptions>>>(
+		&self,
+		id: ConnectionId,
+		options: O,
+	) -> BoxFuture<()>
+	{
+		let con = self.inner.lock().connections.remove(&id);
+		match con {
+			Some(con) => con.disconnect(options),
+			None => Box::new(future::err(format_err!(
+				"Connection {} does not exist", id.0).into())),
+		}
+	}
+
+	/// Disconnect all connections with `interval` between them.
+	///
+	/// The future resolves when all connections are closed. It fails if one
+	/// of the disconnects failed, but the other connections are closed
+	/// nevertheless.
+	pub fn disconnect_all<O: Into<Option<DisconnectOptions>>>(
+		&self,
+		options: O,
+		interval: Duration,
+	) -> BoxFuture<()>
+	{
+		let options = options.into();
+		let cons = self.inner.lock().connections.drain().collect::<Vec<_>>();
+		let now = Instant::now();
+		Box::new(future::join_all(cons.into_iter().enumerate()
+			.map(move |(i, (_, con))| {
+				let options = options.clone();
+				Delay::new(now + interval * i as u32)
+					.map_err(|e| format_err!("Timer failed ({:?})", e).into())
+					.and_then(move |_| con.disconnect(options))
+					.then(Ok::<_, crate::Error>)
+			}))
+			.and_then(|results| {
+				results.into_iter().collect::<Result<Vec<_>>>()?;
+				Ok(())
+			}))
+	}
+
+	/// Get a connection of this manager.
+	pub fn get(&self, id: ConnectionId) -> Option<Connection> {
+		self.inner.lock().connections.get(&id).cloned()
+	}
+
+	/// The ids of all connections in this manager.
+	pub fn ids(&self) -> Vec<ConnectionId> {
+		self.inner.lock().connections.keys().cloned().collect()
+	}
+
+	pub fn len(&self) -> usize { self.inner.lock().connections.len() }
+
+	pub fn is_empty(&self) -> bool { self.inner.lock().connections.is_empty() }
+
+	/// Get the events of all connections, tagged with the id of the
+	/// connection.
+	///
+	/// Connections, which are added later, are also included.
+	pub fn events(&self) -> mpsc::UnboundedReceiver<ManagerEvents> {
+		let (send, recv) = mpsc::unbounded();
+		self.listeners.lock().push(send);
+		recv
+	}
+}
+
+/// Append a handshake to the queue of handshakes.
+///
+/// Returns a sender, which has to be dropped when the handshake is finished,
+/// and a future, which resolves when the handshake can start.
+fn queue_handshake(inner: &mut Inner) -> (oneshot::Sender<()>, BoxFuture<()>) {
+	let (send, recv) = oneshot::channel();
+	let wait: BoxFuture<()> = match inner.handshake.replace(recv) {
+		// The sender is dropped and not used, so ignore the error
+		Some(prev) => Box::new(prev.then(|_| Ok(()))),
+		None => Box::new(future::ok(())),
+	};
+	(send, wait)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::Async;
+	use tsproto::crypto::EccKeyPrivP256;
+
+	fn logger() -> Logger { Logger::root(slog::Discard, o!()) }
+
+	fn new_client() -> client::SharedClientDataM<SimplePacketHandler> {
+		let (initserver_send, _) = oneshot::channel();
+		let (_, connection_recv) = oneshot::channel();
+		let packet_handler = SimplePacketHandler::new(
+			logger(),
+			None,
+			initserver_send,
+			connection_recv,
+			#[cfg(feature = "audio")]
+			None,
+		);
+		client::new_shared("127.0.0.1:0".parse().unwrap(),
+			EccKeyPrivP256::create().unwrap(), packet_handler, logger())
+			.unwrap()
+	}
+
+	#[test]
+	fn share_sockets() {
+		let mut rt = tokio::runtime::Runtime::new().unwrap();
+		rt.block_on(future::lazy(|| {
+			let sockets = Sockets::default();
+			let addr = "127.0.0.1:0".parse().unwrap();
+			let other_addr = "0.0.0.0:0".parse().unwrap();
+			let options = ConnectOptions::new("localhost");
+
+			let c = new_client();
+			sockets.insert(&options, addr, &c);
+			assert!(Arc::ptr_eq(&sockets.get(&options, addr).unwrap(), &c));
+			// Different identities share the socket
+			let other_key = ConnectOptions::new("localhost")
+				.private_key(EccKeyPrivP256::create().unwrap());
+			assert!(sockets.get(&other_key, addr).is_some());
+			assert!(sockets.get(&options, other_addr).is_none());
+
+			// Different socket options do not
+			let logging = ConnectOptions::new("localhost").log_commands(true);
+			assert!(sockets.get(&logging, addr).is_none());
+			let prepared = ConnectOptions::new("localhost")
+				.prepare_client(Box::new(|_| {}));
+			assert!(sockets.get(&prepared, addr).is_none());
+			sockets.insert(&prepared, addr, &c);
+			assert!(sockets.get(&prepared, addr).is_none());
+
+			// Closed sockets are not shared
+			drop(c);
+			assert!(sockets.get(&options, addr).is_none());
+			Ok::<_, ()>(())
+		})).unwrap();
+	}
+
+	#[test]
+	fn serialize_handshakes() {
+		let mut rt = tokio::runtime::Runtime::new().unwrap();
+		rt.block_on(future::lazy(|| {
+			let manager = ConnectionManager::new(Some(logger()));
+			let mut inner = manager.inner.lock();
+			let (first, mut wait_first) = queue_handshake(&mut inner);
+			let (second, mut wait_second) = queue_handshake(&mut inner);
+			let (_, mut wait_third) = queue_handshake(&mut inner);
+
+			assert_eq!(wait_first.poll().unwrap(), Async::Ready(()));
+			assert_eq!(wait_second.poll().unwrap(), Async::NotReady);
+			drop(first);
+			assert_eq!(wait_second.poll().unwrap(), Async::Ready(()));
+			assert_eq!(wait_third.poll().unwrap(), Async::NotReady);
+			drop(second);
+			assert_eq!(wait_third.poll().unwrap(), Async::Ready(()));
+			Ok::<_, ()>(())
+		})).unwrap();
+	}
+
+	#[test]
+	fn unknown_connection() {
+		let manager = ConnectionManager::new(Some(logger()));
+		assert!(manager.is_empty());
+		assert!(manager.get(ConnectionId(0)).is_none());
+		assert!(manager.disconnect(ConnectionId(0), None).wait().is_err());
+		assert!(manager.disconnect_all(None, Duration::from_millis(0))
+			.wait().is_ok());
+	}
+
+	#[test]
+	fn connection_ids() {
+		let manager = ConnectionManager::new(Some(logger()));
+		let (first, _) =
+			manager.start_connect(ConnectOptions::new("localhost"));
+		let (second, _) =
+			manager.start_connect(ConnectOptions::new("localhost"));
+		assert_ne!(first, second);
+		// Only established connections are added
+		assert!(manager.ids().is_empty());
+	}
+}
@@ -1,23 +1,55 @@
+use std::mem;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use chashmap::CHashMap;
 use futures::sync::oneshot;
 use futures::{task, try_ready, Async, Future, Poll, Stream};
-use slog::{error, warn, Logger};
+use parking_lot::Mutex;
+use slog::{debug, error, warn, Logger};
+use tokio::timer::{Delay, Timeout};
+use tsproto::commands::CanonicalCommand;
 use tsproto::handler_data::ConnectionValue;
 use tsproto::packets::*;
 #[cfg(feature = "audio")]
 use tsproto_audio::ts_to_audio::AudioPacketHandler;
 use tsproto_commands::messages::s2c::{InMessage, InMessages};
 
-use crate::{Connection, PHBox, TsError};
+use crate::events::Events;
+use crate::filetransfer;
+use crate::groups;
+use crate::permissions::{self, ClientContext};
+use crate::plugin;
+use crate::subscriptions;
+use crate::talk::TalkTimes;
+use crate::{Connection, Error, PHBox, Result, TsError};
 
 pub(crate) struct ReturnCodeHandler {
 	return_codes: CHashMap<usize, oneshot::Sender<TsError>>,
 	cur_return_code: AtomicUsize,
 }
 
+/// Requests, which wait for a notification from the server.
+///
+/// Some commands are answered with a notification, e.g. `getconnectioninfo`
+/// with a `notifyconnectioninfo`. The return code only tells if the request
+/// was accepted.
+#[derive(Default)]
+pub(crate) struct NotificationWaiters {
+	waiters: Vec<NotificationWaiter>,
+}
+
+struct NotificationWaiter {
+	/// The name of the notification.
+	name: &'static str,
+	/// The argument, which identifies the requested object.
+	arg: &'static str,
+	value: String,
+	sender: oneshot::Sender<()>,
+}
+
 /// **This is part of the unstable interface.**
 ///
 /// You can use it if you need access to lower level functions, but this
@@ -41,9 +73,25 @@ struct SimplePacketStreamHandler<
 	initserver_sender: Option<oneshot::Sender<InCommand>>,
 	connection_recv: Option<oneshot::Receiver<Connection>>,
 	connection: Option<Connection>,
+	/// Shares the connection with the audio stream, when it is received.
+	audio_connection: Arc<Mutex<Option<Connection>>>,
 	return_codes: Arc<ReturnCodeHandler>,
 }
 
+/// Updates the talk status of clients from the audio packets.
+struct AudioStreamHandler<
+	Inner: Stream<Item = InAudio, Error = tsproto::Error>,
+> {
+	inner: Inner,
+	logger: Logger,
+	/// Set by the command stream, no talk status is tracked before.
+	connection: Arc<Mutex<Option<Connection>>>,
+	/// The last voice packets of talking clients.
+	talk_times: TalkTimes,
+	/// Fires when the next client can time out.
+	timeout: Option<Delay>,
+}
+
 impl SimplePacketHandler {
 	#[cfg(not(feature = "audio"))]
 	pub(crate) fn new(
@@ -103,6 +151,57 @@ impl ReturnCodeHandler {
 	}
 }
 
+impl NotificationWaiters {
+	/// Get a receiver, which gets notified when the notification `name`
+	/// contains `arg=value`.
+	pub(crate) fn add(
+		&mut self,
+		name: &'static str,
+		arg: &'static str,
+		value: String,
+	) -> oneshot::Receiver<()>
+	{
+		// Remove requests which do not wait anymore
+		self.waiters.retain(|w| !w.sender.is_canceled());
+		let (sender, recv) = oneshot::channel();
+		self.waiters.push(NotificationWaiter { name, arg, value, sender });
+		recv
+	}
+
+	/// Notify all requests, which wait for this command.
+	pub(crate) fn notify(&mut self, cmd: &InCommand) {
+		if !self.waiters.iter().any(|w| w.name == cmd.name()) {
+			return;
+		}
+		for w in mem::replace(&mut self.waiters, Vec::new()) {
+			if w.name == cmd.name()
+				&& cmd.iter().any(|r| r.get(w.arg) == Some(w.value.as_str()))
+			{
+				// Ignore if the request does not wait anymore
+				let _ = w.sender.send(());
+			} else {
+				self.waiters.push(w);
+			}
+		}
+	}
+}
+
+/// Wait until a notification is received, fails after `timeout`.
+pub(crate) fn wait_for_notification(
+	recv: oneshot::Receiver<()>,
+	name: &'static str,
+	timeout: Duration,
+) -> impl Future<Item = (), Error = Error>
+{
+	Timeout::new(recv, timeout).map_err(move |e| {
+		if e.is_elapsed() {
+			format_err!("Timed out while waiting for {}", name).into()
+		} else {
+			format_err!("Failed to wait for {} ({:?})", name, e).into()
+		}
+	})
+}
+
 impl<T: 'static> tsproto::handler_data::PacketHandler<T>
 	for SimplePacketHandler
 {
@@ -126,14 +225,23 @@ impl<T: 'static> tsproto::handler_data::PacketHandler<T>
 			}),
 		);
 
+		let audio_connection = Arc::new(Mutex::new(None));
 		let handler = SimplePacketStreamHandler {
 			inner: command_stream,
 			logger: self.logger.clone(),
 			initserver_sender: self.initserver_sender.take(),
 			connection_recv: self.connection_recv.take(),
 			connection: None,
+			audio_connection: audio_connection.clone(),
 			return_codes: self.return_codes.clone(),
 		};
+		let audio_stream = AudioStreamHandler {
+			inner: audio_stream,
+			logger: self.logger.clone(),
+			connection: audio_connection,
+			talk_times: TalkTimes::default(),
+			timeout: None,
+		};
 
 		#[cfg(feature = "audio")]
 		let audio_stream: Box<Stream<Item=_, Error=_> + Send> =
@@ -188,14 +296,20 @@ impl<Inner: Stream<Item = InCommand, Error = tsproto::Error>> Stream
 
 			// 3.
 			let mut con = connection.inner.connection.write();
+			let old_context = ClientContext::new(&con, con.own_client);
 			let name = cmd.name().to_string();
 			let msg = InMessage::new(cmd);
+			let mut events = Vec::new();
 			let cmd;
 			match msg {
 				Err((c, e)) => {
-					warn!(self.logger, "Failed to parse message";
-						"command" => name,
-						"error" => ?e);
+					drop(con);
+					if !handle_command(connection, &c, &mut events,
+						&self.logger) {
+						warn!(self.logger, "Failed to parse message";
+							"command" => name,
+							"error" => ?e);
+					}
 					cmd = c;
 				}
 				Ok(msg) => {
@@ -217,34 +331,61 @@ impl<Inner: Stream<Item = InCommand, Error = tsproto::Error>> Stream
 
 					// 3.2
 					// Apply
-					let events = match con.handle_message(&msg, &self.logger) {
-						Ok(e) => if e.len() > 0 {
-							Some(e)
-						} else {
-							None
-						},
+					match con.handle_message(&msg, &self.logger) {
+						Ok(e) => events = e,
 						Err(e) => {
 							warn!(self.logger, "Failed to handle message";
 								"command" => name,
 								"error" => ?e);
-							None
-						}
-					};
-
-					// Call event handler
-					drop(con);
-					if let Some(events) = events {
-						let con = connection.lock();
-						let listeners = connection.inner.event_listeners.read();
-						for l in listeners.values() {
-							l(&con, &events);
 						}
 					}
+					drop(con);
 
+					handle_command(connection, msg.command(), &mut events,
+						&self.logger);
 					cmd = msg.into_command();
 				}
 			}
 
+			connection.inner.notification_waiters.lock().notify(&cmd);
+
+			let changed = permissions::changed_lists(&cmd);
+			if !changed.is_empty() {
+				let logger = self.logger.clone();
+				tokio::spawn(connection.refetch_permission_lists(&changed)
+					.map_err(move |e| {
+						debug!(logger, "Failed to fetch permissions";
+							"error" => ?e)
+					}));
+			}
+
+			let context = {
+				let con = connection.inner.connection.read();
+				ClientContext::new(&con, con.own_client)
+			};
+			if context.is_some() && context != old_context {
+				// Our own client appeared or changed its channel or groups
+				let fetch = if let (Some(old), Some(new)) =
+					(&old_context, &context)
+				{
+					// The lists of the groups, which we joined or left, may
+					// be outdated.
+					connection.inner.permissions.write()
+						.invalidate(&old.changed_groups(new));
+					connection.fetch_missing_permissions()
+				} else {
+					connection.fetch_permissions()
+				};
+				let logger = self.logger.clone();
+				tokio::spawn(fetch.map_err(move |e| {
+					debug!(logger, "Failed to fetch permissions";
+						"error" => ?e)
+				}));
+			}
+
+			// Call event handler
+			call_event_listeners(connection, &events);
+
 			// 4.
 			return Ok(Async::Ready(Some(cmd)));
 		} else {
@@ -281,8 +422,213 @@ impl<Inner: Stream<Item = InCommand, Error = tsproto::Error>> Stream
 		}
 		// Also 2.
 		self.connection_recv = None;
+		*self.audio_connection.lock() = Some(con.clone());
 		self.connection = Some(con);
 		task::current().notify();
 		return Ok(Async::NotReady);
 	}
 }
+
+impl<Inner: Stream<Item = InAudio, Error = tsproto::Error>>
+	AudioStreamHandler<Inner>
+{
+	/// Stop talking clients, which timed out, and wait for the next timeout.
+	fn poll_timeout(&mut self, connection: &Connection) {
+		loop {
+			let mut events = Vec::new();
+			let next = {
+				let mut con = connection.inner.connection.write();
+				self.talk_times.timeout(&mut con, Instant::now(), &mut events)
+			};
+			call_event_listeners(connection, &events);
+
+			let next = if let Some(next) = next {
+				next
+			} else {
+				self.timeout = None;
+				return;
+			};
+			let delay = self.timeout.get_or_insert_with(|| Delay::new(next));
+			delay.reset(next);
+			match delay.poll() {
+				Ok(Async::Ready(())) => {}
+				Ok(Async::NotReady) => return,
+				Err(e) => {
+					warn!(self.logger, "Talk status timer failed";
+						"error" => ?e);
+					self.timeout = None;
+					return;
+				}
+			}
+		}
+	}
+}
+
+impl<Inner: Stream<Item = InAudio, Error = tsproto::Error>> Stream
+	for AudioStreamHandler<Inner>
+{
+	type Item = InAudio;
+	type Error = tsproto::Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		let connection = self.connection.lock().clone();
+		let res = self.inner.poll()?;
+		if let Some(connection) = &connection {
+			if let Async::Ready(Some(packet)) = &res {
+				let mut events = Vec::new();
+				{
+					let mut con = connection.inner.connection.write();
+					self.talk_times.handle_audio(&mut con, packet.data(),
+						Instant::now(), &mut events);
+				}
+				call_event_listeners(connection, &events);
+			}
+			self.poll_timeout(connection);
+		}
+		Ok(res)
+	}
+}
+
+/// Send events to the event listeners of a connection.
+pub(crate) fn call_event_listeners(connection: &Connection, events: &[Events]) {
+	if !events.is_empty() {
+		let con = connection.lock();
+		let listeners = connection.inner.event_listeners.read();
+		for l in listeners.values() {
+			l(&con, events);
+		}
+	}
+}
+
+/// Apply a command to the data which is not part of the generated book.
+///
+/// Returns `false` if the command is not handled here.
+fn handle_command(
+	connection: &Connection,
+	cmd: &InCommand,
+	events: &mut Vec<Events>,
+	logger: &Logger,
+) -> bool
+{
+	if cmd.name() == "notifyconnectioninforequest" {
+		let logger = logger.clone();
+		tokio::spawn(connection.send_connection_info().map_err(move |e| {
+			warn!(logger, "Failed to send connection info"; "error" => ?e)
+		}));
+		return true;
+	}
+
+	let res = {
+		let mut con = connection.inner.connection.write();
+		groups::handle_command(&mut con, cmd, events)
+	}
+	.and_then(|handled| {
+		if handled {
+			Ok(true)
+		} else {
+			connection.inner.permissions.write().handle_command(cmd, events)
+		}
+	})
+	.and_then(|handled| {
+		if handled {
+			Ok(true)
+		} else {
+			let mut con = connection.inner.connection.write();
+			let mut requests = connection.inner.file_requests.write();
+			filetransfer::handle_command(&mut con, &mut requests, cmd, events)
+		}
+	})
+	.and_then(|handled| {
+		if handled {
+			Ok(true)
+		} else {
+			let mut con = connection.inner.connection.write();
+			subscriptions::handle_command(&mut con, cmd, events)
+		}
+	})
+	.and_then(|handled| {
+		if handled {
+			Ok(true)
+		} else {
+			let mut listeners = connection.inner.plugin_listeners.lock();
+			plugin::handle_command(&mut listeners, cmd)
+		}
+	});
+
+	match res {
+		Ok(handled) => handled,
+		Err(e) => {
+			warn!(logger, "Failed to handle message";
+				"command" => cmd.name(),
+				"error" => ?e);
+			true
+		}
+	}
+}
+
+pub(crate) fn get_parse<T: FromStr>(row: &CanonicalCommand, key: &str) -> Result<T>
+where T::Err: std::fmt::Debug {
+	Ok(row.get_parse(key).map_err(|e| {
+		format_err!("Cannot parse argument {} ({:?})", key, e)
+	})?)
+}
+
+/// Missing flags are `false`.
+pub(crate) fn get_bool(row: &CanonicalCommand, key: &str) -> Result<bool> {
+	if row.has(key) {
+		Ok(get_parse::<u8>(row, key)? != 0)
+	} else {
+		Ok(false)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::future;
+	use crate::tests::parse_cmd;
+
+	#[test]
+	fn notification_waiters() {
+		let mut waiters = NotificationWaiters::default();
+		let mut first =
+			waiters.add("notifyconnectioninfo", "clid", "2".into());
+		let mut second =
+			waiters.add("notifyconnectioninfo", "clid", "3".into());
+		let canceled = waiters.add("notifyclientupdated", "clid", "2".into());
+		drop(canceled);
+
+		// Other notifications and objects do not resolve the requests
+		waiters.notify(&parse_cmd("notifyclientupdated clid=2"));
+		waiters.notify(&parse_cmd("notifyconnectioninfo clid=4"));
+		future::lazy(|| {
+			assert_eq!(first.poll().unwrap(), Async::NotReady);
+			assert_eq!(second.poll().unwrap(), Async::NotReady);
+			Ok::<_, ()>(())
+		}).wait().unwrap();
+
+		waiters.notify(&parse_cmd("notifyconnectioninfo clid=2"));
+		assert_eq!(first.wait(), Ok(()));
+		assert_eq!(waiters.waiters.len(), 1);
+
+		// Canceled requests are removed
+		drop(second);
+		let _third = waiters.add("notifyconnectioninfo", "clid", "5".into());
+		assert_eq!(waiters.waiters.len(), 1);
+	}
+
+	#[test]
+	fn notification_timeout() {
+		let mut rt = tokio::runtime::Runtime::new().unwrap();
+		let mut waiters = NotificationWaiters::default();
+		let recv = waiters.add("notifyconnectioninfo", "clid", "2".into());
+		let res = rt.block_on(wait_for_notification(recv,
+			"notifyconnectioninfo", Duration::from_millis(10)));
+		assert!(res.is_err());
+
+		let recv = waiters.add("notifyconnectioninfo", "clid", "2".into());
+		waiters.notify(&parse_cmd("notifyconnectioninfo clid=2"));
+		rt.block_on(wait_for_notification(recv, "notifyconnectioninfo",
+			Duration::from_secs(10))).unwrap();
+	}
+}
@@ -0,0 +1,547 @@
+//! The permission system of TeamSpeak.
+//!
+//! Permissions are not part of the [`data`] structures because most of them
+//! have to be requested explicitly from the server (see
+//! [`Connection::fetch_permissions`]). Afterwards, the lists are kept up to
+//! date with the notifications of the server. Known group lists are requested
+//! again, when the server sends a new group list or when our own client joins
+//! or leaves a group.
+//!
+//! The effective value of a permission for a client is computed from all lists
+//! that apply to this client, where later lists override earlier ones:
+//!
+//! 1. Server groups: The highest value is used. If the permission is negated in
+//!    any of the groups, the lowest value is used instead.
+//! 2. Client permissions
+//! 3. Channel permissions
+//! 4. Channel group permissions
+//! 5. Channel-client permissions
+//!
+//! If the skip flag is set in a server group or in the client permissions, the
+//! channel lists (3. – 5.) are ignored.
+//!
+//! [`data`]: ../data/index.html
+//! [`Connection::fetch_permissions`]: ../struct.Connection.html#method.fetch_permissions
+use std::collections::HashMap;
+
+use tsproto::commands::CanonicalCommand;
+use tsproto::packets::InCommand;
+use tsproto_commands::*;
+
+use crate::data;
+use crate::events::Events;
+use crate::packet_handler::{get_bool, get_parse};
+use crate::Result;
+
+/// The value of a permission inside a permission list.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PermissionValue {
+	pub value: i32,
+	/// Use the lowest instead of the highest value of all server groups.
+	pub negated: bool,
+	/// Ignore channel, channel group and channel-client permissions.
+	pub skip: bool,
+}
+
+pub type PermissionList = HashMap<Permission, PermissionValue>;
+
+/// Identifies a permission list.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PermissionOwner {
+	ServerGroup(ServerGroupId),
+	ChannelGroup(ChannelGroupId),
+	Client(ClientDbId),
+	Channel(ChannelId),
+	ChannelClient(ChannelId, ClientDbId),
+	/// The effective permissions of our own client, which are computed and
+	/// sent by the server.
+	OwnClient,
+}
+
+/// An action which needs a certain power.
+///
+/// The action is allowed if the granted power of our own client is at least as
+/// high as the needed power of the target.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+	/// Move a client into a channel.
+	MoveClient(ClientId, ChannelId),
+	KickFromChannel(ClientId),
+	KickFromServer(ClientId),
+	JoinChannel(ChannelId),
+	SubscribeChannel(ChannelId),
+	/// Talk in a channel.
+	Talk(ChannelId),
+}
+
+/// The groups and the channel of a client, which are needed to compute its
+/// permissions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientContext {
+	pub database_id: ClientDbId,
+	pub server_groups: Vec<ServerGroupId>,
+	pub channel: ChannelId,
+	pub channel_group: ChannelGroupId,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Permissions {
+	names: HashMap<Permission, String>,
+	ids: HashMap<String, Permission>,
+	lists: HashMap<PermissionOwner, PermissionList>,
+}
+
+impl ClientContext {
+	pub fn new(con: &data::Connection, client: ClientId) -> Option<Self> {
+		let client = con.server.clients.get(&client)?;
+		Some(Self {
+			database_id: client.database_id,
+			server_groups: client.server_groups.clone(),
+			channel: client.channel,
+			channel_group: client.channel_group,
+		})
+	}
+}
+
+impl ClientContext {
+	/// The group lists, which apply to only one of the contexts.
+	///
+	/// These are the groups, which a client joined or left.
+	pub(crate) fn changed_groups(&self, other: &ClientContext)
+		-> Vec<PermissionOwner> {
+		let mut owners = self.server_groups.iter()
+			.chain(other.server_groups.iter())
+			.filter(|g| !self.server_groups.contains(g)
+				|| !other.server_groups.contains(g))
+			.map(|g| PermissionOwner::ServerGroup(*g))
+			.collect::<Vec<_>>();
+		if self.channel_group != other.channel_group {
+			owners.push(PermissionOwner::ChannelGroup(self.channel_group));
+			owners.push(PermissionOwner::ChannelGroup(other.channel_group));
+		}
+		owners
+	}
+}
+
+/// The group lists, which may have changed because of a notification.
+///
+/// The server sends the server and channel group lists again, when a group
+/// was changed, e.g. when its permissions were edited.
+pub(crate) fn changed_lists(cmd: &InCommand) -> Vec<PermissionOwner> {
+	let (arg, owner): (_, fn(u64) -> PermissionOwner) = match cmd.name() {
+		"notifyservergrouplist" => {
+			("sgid", |id| PermissionOwner::ServerGroup(ServerGroupId(id)))
+		}
+		"notifychannelgrouplist" => {
+			("cgid", |id| PermissionOwner::ChannelGroup(ChannelGroupId(id)))
+		}
+		_ => return Vec::new(),
+	};
+	// Invalid groups are reported when they are applied to the book
+	cmd.iter()
+		.filter_map(|r| get_parse(&r, arg).ok())
+		.map(owner)
+		.collect()
+}
+
+impl Permissions {
+	/// The id of a permission like `i_client_move_power`.
+	///
+	/// The ids differ between server versions, they are known after the
+	/// `permissionlist` was received.
+	pub fn get_id(&self, name: &str) -> Option<Permission> {
+		self.ids.get(name).cloned()
+	}
+
+	pub fn get_name(&self, perm: Permission) -> Option<&str> {
+		self.names.get(&perm).map(|s| s.as_str())
+	}
+
+	/// Get a permission list, if it was received from the server.
+	pub fn get_list(&self, owner: PermissionOwner) -> Option<&PermissionList> {
+		self.lists.get(&owner)
+	}
+
+	/// Compute the value of a permission for a client.
+	///
+	/// Returns `None` if not all permission lists of the client are known.
+	pub fn get_value(&self, perm: Permission, ctx: &ClientContext)
+		-> Option<i32> {
+		let mut values = Vec::new();
+		for g in &ctx.server_groups {
+			let list = self.lists.get(&PermissionOwner::ServerGroup(*g))?;
+			values.extend(list.get(&perm));
+		}
+		let mut skip = values.iter().any(|v| v.skip);
+		let mut value = if values.iter().any(|v| v.negated) {
+			values.iter().map(|v| v.value).min()
+		} else {
+			values.iter().map(|v| v.value).max()
+		};
+
+		let list = self
+			.lists
+			.get(&PermissionOwner::Client(ctx.database_id))?;
+		if let Some(v) = list.get(&perm) {
+			value = Some(v.value);
+			skip |= v.skip;
+		}
+
+		if !skip {
+			for owner in &[
+				PermissionOwner::Channel(ctx.channel),
+				PermissionOwner::ChannelGroup(ctx.channel_group),
+				PermissionOwner::ChannelClient(ctx.channel, ctx.database_id),
+			] {
+				if let Some(v) = self.lists.get(owner)?.get(&perm) {
+					value = Some(v.value);
+				}
+			}
+		}
+
+		Some(value.unwrap_or_default())
+	}
+
+	/// Compute the value of a permission for our own client.
+	///
+	/// If not all lists are known, this falls back to the values which were
+	/// sent by the server.
+	pub fn get_own_value(&self, perm: Permission, ctx: &ClientContext)
+		-> Option<i32> {
+		self.get_value(perm, ctx).or_else(|| {
+			self.lists
+				.get(&PermissionOwner::OwnClient)?
+				.get(&perm)
+				.map(|v| v.value)
+		})
+	}
+
+	/// Check if our own client is allowed to do an action.
+	///
+	/// Returns `None` if not all needed permissions are known.
+	pub fn can(&self, con: &data::Connection, action: Action) -> Option<bool> {
+		let own = ClientContext::new(con, con.own_client)?;
+		let granted = |name: &str| self.get_own_value(self.get_id(name)?, &own);
+		let needed = |name: &str, client: ClientId| {
+			let ctx = ClientContext::new(con, client)?;
+			self.get_value(self.get_id(name)?, &ctx)
+		};
+		let needed_channel = |name: &str, channel: ChannelId| {
+			let perm = self.get_id(name)?;
+			let list = self.lists.get(&PermissionOwner::Channel(channel))?;
+			Some(list.get(&perm).map(|v| v.value).unwrap_or_default())
+		};
+
+		let (granted, needed) = match action {
+			Action::MoveClient(client, channel) => {
+				// We need to be allowed to move the client and to join the
+				// channel.
+				if granted("i_channel_join_power")?
+					< needed_channel("i_channel_needed_join_power", channel)? {
+					return Some(false);
+				}
+				(granted("i_client_move_power")?,
+					needed("i_client_needed_move_power", client)?)
+			}
+			Action::KickFromChannel(client) => (
+				granted("i_client_kick_from_channel_power")?,
+				needed("i_client_needed_kick_from_channel_power", client)?,
+			),
+			Action::KickFromServer(client) => (
+				granted("i_client_kick_from_server_power")?,
+				needed("i_client_needed_kick_from_server_power", client)?,
+			),
+			Action::JoinChannel(channel) => (
+				granted("i_channel_join_power")?,
+				needed_channel("i_channel_needed_join_power", channel)?,
+			),
+			Action::SubscribeChannel(channel) => (
+				granted("i_channel_subscribe_power")?,
+				needed_channel("i_channel_needed_subscribe_power", channel)?,
+			),
+			Action::Talk(channel) => {
+				let client = con.server.clients.get(&con.own_client)?;
+				let channel = con.server.channels.get(&channel)?;
+				(client.talk_power, channel.needed_talk_power)
+			}
+		};
+		Some(granted >= needed)
+	}
+
+	/// Apply a permission notification.
+	///
+	/// Returns `false` if the command is not related to permissions.
+	pub(crate) fn handle_command(
+		&mut self,
+		cmd: &InCommand,
+		events: &mut Vec<Events>,
+	) -> Result<bool>
+	{
+		let owner: fn(&CanonicalCommand) -> Result<PermissionOwner> =
+			match cmd.name() {
+				"notifypermissionlist" => {
+					self.handle_permission_list(cmd)?;
+					return Ok(true);
+				}
+				"notifyservergrouppermlist" => |r: &CanonicalCommand| {
+					Ok(PermissionOwner::ServerGroup(ServerGroupId(get_parse(
+						r, "sgid",
+					)?)))
+				},
+				"notifychannelgrouppermlist" => |r: &CanonicalCommand| {
+					Ok(PermissionOwner::ChannelGroup(ChannelGroupId(get_parse(
+						r, "cgid",
+					)?)))
+				},
+				"notifyclientpermlist" => |r: &CanonicalCommand| {
+					Ok(PermissionOwner::Client(ClientDbId(get_parse(
+						r, "cldbid",
+					)?)))
+				},
+				"notifychannelpermlist" => |r: &CanonicalCommand| {
+					Ok(PermissionOwner::Channel(ChannelId(get_parse(r, "cid")?)))
+				},
+				"notifychannelclientpermlist" => |r: &CanonicalCommand| {
+					Ok(PermissionOwner::ChannelClient(
+						ChannelId(get_parse(r, "cid")?),
+						ClientDbId(get_parse(r, "cldbid")?),
+					))
+				},
+				"notifyclientneededpermissions" => {
+					|_: &CanonicalCommand| Ok(PermissionOwner::OwnClient)
+				}
+				_ => return Ok(false),
+			};
+
+		let mut lists: HashMap<PermissionOwner, PermissionList> =
+			HashMap::new();
+		for r in cmd.iter() {
+			let owner = owner(&r)?;
+			let perm = if let Some(id) = r.get("permid") {
+				Permission(id.parse().map_err(|e| {
+					format_err!("Cannot parse permid {} ({:?})", id, e)
+				})?)
+			} else {
+				let name = r.get("permsid").ok_or_else(|| {
+					format_err!("Permission list contains no permission id")
+				})?;
+				self.get_id(name).ok_or_else(|| {
+					format_err!("Unknown permission {}", name)
+				})?
+			};
+			let value = PermissionValue {
+				value: get_parse(&r, "permvalue")?,
+				negated: get_bool(&r, "permnegated")?,
+				skip: get_bool(&r, "permskip")?,
+			};
+			lists.entry(owner).or_default().insert(perm, value);
+		}
+
+		for (owner, list) in lists {
+			if owner == PermissionOwner::OwnClient {
+				// Only changed permissions are sent
+				self.lists.entry(owner).or_default().extend(list);
+			} else {
+				self.lists.insert(owner, list);
+			}
+			events.push(Events::PermissionsChanged(owner));
+		}
+		Ok(true)
+	}
+
+	/// Forget permission lists, which may be outdated.
+	///
+	/// Returns the lists, which were known before, so they can be requested
+	/// again.
+	pub(crate) fn invalidate(&mut self, owners: &[PermissionOwner])
+		-> Vec<PermissionOwner> {
+		owners.iter()
+			.filter(|o| self.lists.remove(o).is_some())
+			.cloned()
+			.collect()
+	}
+
+	/// Mark a list as known, but empty.
+	///
+	/// The server answers with an error instead of an empty list.
+	pub(crate) fn set_empty(&mut self, owner: PermissionOwner) {
+		self.lists.insert(owner, PermissionList::new());
+	}
+
+	/// The ids of the permissions are given implicitly by their order in the
+	/// list, starting at `1`.
+	fn handle_permission_list(&mut self, cmd: &InCommand) -> Result<()> {
+		let mut next_id = 1;
+		for r in cmd.iter() {
+			let name = if let Some(name) = r.get("permname") {
+				name
+			} else {
+				// Group delimiters
+				continue;
+			};
+			let perm = if r.has("permid") {
+				Permission(get_parse(&r, "permid")?)
+			} else {
+				Permission(next_id)
+			};
+			next_id = perm.0 + 1;
+			self.names.insert(perm, name.to_string());
+			self.ids.insert(name.to_string(), perm);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const PERM: Permission = Permission(1);
+
+	fn context() -> ClientContext {
+		ClientContext {
+			database_id: ClientDbId(1),
+			server_groups: vec![ServerGroupId(1), ServerGroupId(2)],
+			channel: ChannelId(1),
+			channel_group: ChannelGroupId(1),
+		}
+	}
+
+	fn set(perms: &mut Permissions, owner: PermissionOwner, value: i32,
+		negated: bool, skip: bool) {
+		perms.lists.entry(owner).or_default().insert(PERM, PermissionValue {
+			value,
+			negated,
+			skip,
+		});
+	}
+
+	/// All lists of the context are known but empty.
+	fn permissions() -> Permissions {
+		let mut perms = Permissions::default();
+		for owner in &[
+			PermissionOwner::ServerGroup(ServerGroupId(1)),
+			PermissionOwner::ServerGroup(ServerGroupId(2)),
+			PermissionOwner::Client(ClientDbId(1)),
+			PermissionOwner::Channel(ChannelId(1)),
+			PermissionOwner::ChannelGroup(ChannelGroupId(1)),
+			PermissionOwner::ChannelClient(ChannelId(1), ClientDbId(1)),
+		] {
+			perms.set_empty(*owner);
+		}
+		perms
+	}
+
+	#[test]
+	fn unknown_list() {
+		let perms = Permissions::default();
+		assert_eq!(perms.get_value(PERM, &context()), None);
+	}
+
+	#[test]
+	fn server_groups_max() {
+		let mut perms = permissions();
+		set(&mut perms, PermissionOwner::ServerGroup(ServerGroupId(1)), 50,
+			false, false);
+		set(&mut perms, PermissionOwner::ServerGroup(ServerGroupId(2)), 75,
+			false, false);
+		assert_eq!(perms.get_value(PERM, &context()), Some(75));
+	}
+
+	#[test]
+	fn server_groups_negated() {
+		let mut perms = permissions();
+		set(&mut perms, PermissionOwner::ServerGroup(ServerGroupId(1)), 50,
+			true, false);
+		set(&mut perms, PermissionOwner::ServerGroup(ServerGroupId(2)), 75,
+			false, false);
+		assert_eq!(perms.get_value(PERM, &context()), Some(50));
+	}
+
+	#[test]
+	fn channel_client_overrides() {
+		let mut perms = permissions();
+		set(&mut perms, PermissionOwner::ServerGroup(ServerGroupId(1)), 50,
+			false, false);
+		set(&mut perms, PermissionOwner::ChannelGroup(ChannelGroupId(1)), 20,
+			false, false);
+		assert_eq!(perms.get_value(PERM, &context()), Some(20));
+		set(&mut perms,
+			PermissionOwner::ChannelClient(ChannelId(1), ClientDbId(1)), 30,
+			false, false);
+		assert_eq!(perms.get_value(PERM, &context()), Some(30));
+	}
+
+	#[test]
+	fn skip_channel() {
+		let mut perms = permissions();
+		set(&mut perms, PermissionOwner::Client(ClientDbId(1)), 50, false,
+			true);
+		set(&mut perms, PermissionOwner::ChannelGroup(ChannelGroupId(1)), 20,
+			false, false);
+		assert_eq!(perms.get_value(PERM, &context()), Some(50));
+	}
+
+	#[test]
+	fn invalidate_lists() {
+		let mut perms = permissions();
+		let group = PermissionOwner::ServerGroup(ServerGroupId(1));
+		let unknown = PermissionOwner::ServerGroup(ServerGroupId(3));
+		assert_eq!(perms.invalidate(&[group, unknown]), vec![group]);
+		assert!(perms.get_list(group).is_none());
+		assert_eq!(perms.get_value(PERM, &context()), None);
+	}
+
+	#[test]
+	fn changed_group_lists() {
+		let cmd = InCommand::new(
+			b"notifyservergrouplist sgid=2 name=Admin|sgid=5 name=Guest"
+				.to_vec(),
+			tsproto::packets::PacketType::Command,
+			false,
+			tsproto::packets::Direction::S2C,
+		)
+		.unwrap();
+		assert_eq!(changed_lists(&cmd), vec![
+			PermissionOwner::ServerGroup(ServerGroupId(2)),
+			PermissionOwner::ServerGroup(ServerGroupId(5)),
+		]);
+
+		let mut other = context();
+		other.server_groups = vec![ServerGroupId(2), ServerGroupId(3)];
+		other.channel_group = ChannelGroupId(2);
+		assert_eq!(context().changed_groups(&other), vec![
+			PermissionOwner::ServerGroup(ServerGroupId(1)),
+			PermissionOwner::ServerGroup(ServerGroupId(3)),
+			PermissionOwner::ChannelGroup(ChannelGroupId(1)),
+			PermissionOwner::ChannelGroup(ChannelGroupId(2)),
+		]);
+		assert!(context().changed_groups(&context()).is_empty());
+	}
+
+	#[test]
+	fn parse_perm_list() {
+		let cmd = InCommand::new(
+			b"notifyservergrouppermlist sgid=2 permid=1 permvalue=75 \
+			permnegated=0 permskip=1|permid=2 permvalue=1 permnegated=1 \
+			permskip=0"
+				.to_vec(),
+			tsproto::packets::PacketType::Command,
+			false,
+			tsproto::packets::Direction::S2C,
+		)
+		.unwrap();
+		let mut perms = Permissions::default();
+		let mut events = Vec::new();
+		assert!(perms.handle_command(&cmd, &mut events).unwrap());
+		let owner = PermissionOwner::ServerGroup(ServerGroupId(2));
+		assert_eq!(events, vec![Events::PermissionsChanged(owner)]);
+		let list = perms.get_list(owner).unwrap();
+		assert_eq!(list[&Permission(1)], PermissionValue {
+			value: 75,
+			negated: false,
+			skip: true,
+		});
+		assert!(list[&Permission(2)].negated);
+	}
+}
@@ -0,0 +1,135 @@
+//! Plugin commands, which are used by client plugins to exchange data.
+//!
+//! The server forwards plugin commands to other clients without looking at
+//! them. They are sent with [`Connection::send_plugin_command`] and received
+//! with [`Connection::plugin_commands`].
+//!
+//! [`Connection::send_plugin_command`]: ../struct.Connection.html#method.send_plugin_command
+//! [`Connection::plugin_commands`]: ../struct.Connection.html#method.plugin_commands
+use futures::sync::mpsc;
+use num_traits::ToPrimitive;
+use tsproto::packets::{Direction, InCommand, OutCommand, OutPacket, PacketType};
+use tsproto_commands::*;
+
+use crate::packet_handler::get_parse;
+use crate::Result;
+
+/// A received plugin command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PluginCommand {
+	/// Identifies the plugin.
+	pub name: String,
+	pub data: String,
+	/// The client which sent the command.
+	pub invoker: ClientId,
+	pub invoker_name: Option<String>,
+	pub invoker_uid: Option<Uid>,
+}
+
+/// The receivers of a plugin command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PluginTarget {
+	/// All clients in the current channel.
+	Channel,
+	/// All clients on the server.
+	Server,
+	Clients(Vec<ClientId>),
+	/// All clients, which are subscribed to the current channel.
+	SubscribedClients,
+}
+
+pub(crate) type PluginListeners = Vec<mpsc::UnboundedSender<PluginCommand>>;
+
+pub(crate) fn create_command(name: &str, data: &str, target: &PluginTarget)
+	-> OutPacket {
+	let mode = match target {
+		PluginTarget::Channel => PluginTargetMode::CurrentChannel,
+		PluginTarget::Server => PluginTargetMode::Server,
+		PluginTarget::Clients(_) => PluginTargetMode::Client,
+		PluginTarget::SubscribedClients =>
+			PluginTargetMode::CurrentChannelSubsribedClients,
+	};
+	let targets = match target {
+		PluginTarget::Clients(clients) => clients.clone(),
+		_ => Vec::new(),
+	};
+	OutCommand::new(
+		Direction::C2S,
+		PacketType::Command,
+		"plugincmd",
+		vec![
+			("name", name.to_string()),
+			("data", data.to_string()),
+			("targetmode", mode.to_u32().unwrap().to_string()),
+		].into_iter(),
+		targets.into_iter()
+			.map(|c| std::iter::once(("target", c.0.to_string()))),
+	)
+}
+
+/// Send received plugin commands to the listeners.
+///
+/// Returns `false` if the command is not a plugin command.
+pub(crate) fn handle_command(listeners: &mut PluginListeners, cmd: &InCommand)
+	-> Result<bool> {
+	if cmd.name() != "notifyplugincmd" {
+		return Ok(false);
+	}
+	for r in cmd.iter() {
+		let command = PluginCommand {
+			name: r.get("name").unwrap_or_default().to_string(),
+			data: r.get("data").unwrap_or_default().to_string(),
+			invoker: ClientId(get_parse(&r, "invokerid")?),
+			invoker_name: r.get("invokername").map(|s| s.to_string()),
+			invoker_uid: r.get("invokeruid").map(|s| Uid(s.to_string())),
+		};
+		// Remove closed streams
+		listeners.retain(|l| l.unbounded_send(command.clone()).is_ok());
+	}
+	Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::{Future, Stream};
+
+	use crate::tests::parse_cmd;
+
+	#[test]
+	fn plugin_command_targets() {
+		let packet = create_command("test", "a b",
+			&PluginTarget::Clients(vec![ClientId(1), ClientId(2)]));
+		assert_eq!(packet.content(), &b"plugincmd name=test data=a\\sb \
+			targetmode=2 target=1|target=2"[..]);
+		let packet = create_command("test", "", &PluginTarget::Server);
+		assert_eq!(packet.content(),
+			&b"plugincmd name=test data targetmode=1"[..]);
+	}
+
+	#[test]
+	fn receive_plugin_command() {
+		let (send, recv) = mpsc::unbounded();
+		let (closed, _) = mpsc::unbounded();
+		let mut listeners = vec![send, closed];
+
+		let cmd = parse_cmd("notifyplugincmd name=test data=a\\sb \
+			invokerid=5 invokername=Bot invokeruid=YWJj");
+		assert!(handle_command(&mut listeners, &cmd).unwrap());
+		// The closed listener is removed
+		assert_eq!(listeners.len(), 1);
+		drop(listeners);
+
+		let commands = recv.collect().wait().unwrap();
+		assert_eq!(commands, vec![PluginCommand {
+			name: "test".into(),
+			data: "a b".into(),
+			invoker: ClientId(5),
+			invoker_name: Some("Bot".into()),
+			invoker_uid: Some(Uid("YWJj".into())),
+		}]);
+
+		let cmd = parse_cmd("notifyclientupdated clid=5");
+		assert!(!handle_command(&mut Vec::new(), &cmd).unwrap());
+	}
+}
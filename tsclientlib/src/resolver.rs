@@ -2,16 +2,20 @@
 // Changes with TeamSpeak client 3.1:
 // https://support.teamspeakusa.com/index.php?/Knowledgebase/Article/View/332
 
+use std::collections::HashMap;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::str::{self, FromStr};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use futures::future::Loop;
 use futures::{future, stream, Async, Future, Poll, Stream};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
 use rand::{thread_rng, Rng};
 use slog::{debug, o, Logger};
 use tokio::io;
 use tokio::net::TcpStream;
-use tokio::util::StreamExt;
+use tokio::util::FutureExt;
 use trust_dns_resolver::{AsyncResolver, Name};
 use {reqwest, tokio, tokio_threadpool};
 
@@ -21,9 +25,19 @@ const DEFAULT_PORT: u16 = 9987;
 const DNS_PREFIX_TCP: &str = "_tsdns._tcp.";
 const DNS_PREFIX_UDP: &str = "_ts3._udp.";
 const NICKNAME_LOOKUP_ADDRESS: &str = "https://named.myteamspeak.com/lookup";
-// TODO Dynamic timeout with exponential backoff starting at 1s
-/// Wait this amount of seconds before giving up.
-const TIMEOUT_SECONDS: u64 = 10;
+/// The timeout for the first try to resolve an address.
+///
+/// The timeout is doubled for every following try.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Give up when a try with this timeout failed.
+const MAX_TIMEOUT: Duration = Duration::from_secs(8);
+/// How long resolved addresses are reused.
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+lazy_static! {
+	static ref CACHE: Mutex<ResolveCache> =
+		Mutex::new(ResolveCache::default());
+}
 
 #[derive(Debug, PartialEq, Eq)]
 enum ParseIpResult<'a> {
@@ -31,6 +45,44 @@ enum ParseIpResult<'a> {
 	Other(&'a str, Option<u16>),
 }
 
+/// Stores resolved addresses together with the time they were resolved.
+#[derive(Default)]
+struct ResolveCache {
+	entries: HashMap<String, (Instant, Vec<SocketAddr>)>,
+}
+
+impl ResolveCache {
+	/// Get the addresses for `address` if they are not older than `CACHE_TTL`.
+	fn get(
+		&mut self,
+		address: &str,
+		now: Instant,
+	) -> Option<Vec<SocketAddr>>
+	{
+		if let Some((time, addrs)) = self.entries.get(address) {
+			if now.duration_since(*time) < CACHE_TTL {
+				return Some(addrs.clone());
+			}
+		}
+		// Remove outdated entries
+		self.entries.remove(address);
+		None
+	}
+
+	fn insert(
+		&mut self,
+		address: String,
+		addrs: Vec<SocketAddr>,
+		now: Instant,
+	)
+	{
+		// Remove outdated entries, so the cache does not grow forever
+		self.entries
+			.retain(|_, (time, _)| now.duration_since(*time) < CACHE_TTL);
+		self.entries.insert(address, (now, addrs));
+	}
+}
+
 /// Pick the first stream which does not return an error or is empty.
 ///
 /// # Panic
@@ -114,6 +166,73 @@ pub fn resolve(
 		Err(res) => return Box::new(stream::once(Err(res))),
 	}
 
+	if let Some(addrs) = CACHE.lock().get(address, Instant::now()) {
+		debug!(logger, "Using cached addresses"; "addresses" => ?addrs);
+		return Box::new(stream::iter_ok(addrs));
+	}
+
+	// Try again with a doubled timeout if resolving timed out
+	let address = address.to_string();
+	Box::new(
+		future::loop_fn(INITIAL_TIMEOUT, move |timeout| {
+			let logger = logger.clone();
+			resolve_uncached(&logger, addr.clone(), port)
+				.collect()
+				.timeout(timeout)
+				.then(move |r| match r {
+					Ok(addrs) => Ok(Loop::Break(addrs)),
+					Err(ref e) if e.is_elapsed() && timeout < MAX_TIMEOUT => {
+						debug!(logger, "Resolve timed out, trying again";
+							"timeout" => ?timeout);
+						Ok(Loop::Continue(timeout * 2))
+					}
+					Err(e) => Err(e.into_inner().unwrap_or_else(|| {
+						format_err!("Resolve timed out").into()
+					})),
+				})
+		})
+		.map(move |addrs| {
+			if !addrs.is_empty() {
+				CACHE.lock().insert(address, addrs.clone(), Instant::now());
+			}
+			stream::iter_ok(addrs)
+		})
+		.flatten_stream(),
+	)
+}
+
+/// Remove all cached addresses, so the next call to [`resolve`] queries them
+/// again.
+///
+/// [`resolve`]: fn.resolve.html
+pub fn clear_cache() { CACHE.lock().entries.clear(); }
+
+/// Sort addresses for connecting, so that IPv6 and IPv4 addresses alternate,
+/// starting with IPv6 (see RFC 8305).
+///
+/// The order of addresses of the same family is kept.
+pub fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+	let (v6, v4): (Vec<_>, Vec<_>) =
+		addrs.into_iter().partition(|a| a.is_ipv6());
+	let mut res = Vec::with_capacity(v6.len() + v4.len());
+	let mut v6 = v6.into_iter();
+	let mut v4 = v4.into_iter();
+	loop {
+		match (v6.next(), v4.next()) {
+			(None, None) => break,
+			(a, b) => res.extend(a.into_iter().chain(b)),
+		}
+	}
+	res
+}
+
+/// Try all methods to resolve `addr` without using the cache and timeouts.
+fn resolve_uncached(
+	logger: &Logger,
+	addr: String,
+	port: Option<u16>,
+) -> Box<Stream<Item = SocketAddr, Error = Error> + Send>
+{
 	let p = port.clone();
 	let address = addr.clone();
 	let logger2 = logger.clone();
@@ -229,14 +348,7 @@ pub fn resolve(
 		Box::new(last_res),
 	];
 
-	Box::new(
-		StreamCombiner::new(streams)
-			.timeout(Duration::from_secs(TIMEOUT_SECONDS))
-			.map_err(|e| {
-				e.into_inner()
-					.unwrap_or_else(|| format_err!("Resolve timed out").into())
-			}),
-	)
+	Box::new(StreamCombiner::new(streams))
 }
 
 fn parse_ip(address: &str) -> Result<ParseIpResult> {
@@ -577,6 +689,56 @@ mod test {
 		assert!(parse_ip("127.0.0.1:").is_err());
 	}
 
+	#[test]
+	fn cache_entry_valid() {
+		let mut cache = ResolveCache::default();
+		let now = Instant::now();
+		let addrs = vec!["127.0.0.1:1".parse().unwrap()];
+		cache.insert("localhost:1".into(), addrs.clone(), now);
+		assert_eq!(cache.get("localhost:1", now + CACHE_TTL / 2), Some(addrs));
+		assert_eq!(cache.get("localhost", now), None);
+	}
+
+	#[test]
+	fn cache_entry_expired() {
+		let mut cache = ResolveCache::default();
+		let now = Instant::now();
+		let addrs = vec!["127.0.0.1:1".parse().unwrap()];
+		cache.insert("localhost:1".into(), addrs, now);
+		assert_eq!(cache.get("localhost:1", now + CACHE_TTL), None);
+		assert!(cache.entries.is_empty());
+	}
+
+	#[test]
+	fn cache_prune_on_insert() {
+		let mut cache = ResolveCache::default();
+		let now = Instant::now();
+		let addrs = vec!["127.0.0.1:1".parse().unwrap()];
+		cache.insert("old:1".into(), addrs.clone(), now);
+		cache.insert("new:1".into(), addrs.clone(), now + CACHE_TTL);
+		assert_eq!(cache.entries.len(), 1);
+		assert_eq!(cache.get("new:1", now + CACHE_TTL), Some(addrs));
+	}
+
+	#[test]
+	fn interleave_ip_families() {
+		let addrs: Vec<SocketAddr> = vec![
+			"127.0.0.1:1".parse().unwrap(),
+			"127.0.0.2:1".parse().unwrap(),
+			"127.0.0.3:1".parse().unwrap(),
+			"[::1]:1".parse().unwrap(),
+			"[::2]:1".parse().unwrap(),
+		];
+		let expected: Vec<SocketAddr> = vec![
+			"[::1]:1".parse().unwrap(),
+			"127.0.0.1:1".parse().unwrap(),
+			"[::2]:1".parse().unwrap(),
+			"127.0.0.2:1".parse().unwrap(),
+			"127.0.0.3:1".parse().unwrap(),
+		];
+		assert_eq!(interleave_families(addrs), expected);
+	}
+
 	#[test]
 	fn resolve_localhost() {
 		let (logger, mut rt) = setup();
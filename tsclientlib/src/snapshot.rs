@@ -0,0 +1,178 @@
+//! Save the state of a connection and compare saved states.
+//!
+//! A snapshot is a copy of the [`data::Connection`], which can be serialized
+//! with serde. [`diff`] computes the [`Events`], which turn one snapshot into
+//! another one. These are the same events, which are sent to an
+//! [`EventListener`] when the state of a connection changes.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use tsclientlib::Connection;
+//! # use tsclientlib::snapshot;
+//! # fn f(con: &Connection) -> Result<(), tsclientlib::Error> {
+//! let old = con.snapshot();
+//! let json = snapshot::to_json(&old)?;
+//! // …
+//! let new = con.snapshot();
+//! let events = snapshot::diff(&snapshot::from_json(&json)?, &new);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`data::Connection`]: ../data/struct.Connection.html
+//! [`diff`]: fn.diff.html
+//! [`Events`]: ../events/enum.Events.html
+//! [`EventListener`]: ../type.EventListener.html
+use crate::data::*;
+use crate::events::{Events, Property, PropertyId};
+use crate::Result;
+
+include!(concat!(env!("OUT_DIR"), "/book_diff.rs"));
+
+/// Serialize the state of a connection as JSON.
+pub fn to_json(con: &Connection) -> Result<String> {
+	Ok(serde_json::to_string(con)?)
+}
+
+/// Deserialize a state, which was created by [`to_json`].
+///
+/// [`to_json`]: fn.to_json.html
+pub fn from_json(s: &str) -> Result<Connection> {
+	Ok(serde_json::from_str(s)?)
+}
+
+/// Compute the events, which change `old` into `new`.
+///
+/// Objects which are only in `new` are reported as `PropertyAdded`, objects
+/// which are only in `old` as `PropertyRemoved`. Changed attributes are
+/// reported with their old value.
+///
+/// Changes of the server groups of clients are not included. The order of
+/// events for items in maps, e.g. clients, is not specified.
+pub fn diff(old: &Connection, new: &Connection) -> Vec<Events> {
+	let mut events = Vec::new();
+	diff_connection(old, new, &mut events);
+	events
+}
+
+/// Serialize a `chrono::Duration` as milliseconds.
+pub mod serde_duration {
+	use chrono::Duration;
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	pub fn serialize<S: Serializer>(d: &Duration, s: S)
+		-> Result<S::Ok, S::Error> {
+		s.serialize_i64(d.num_milliseconds())
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D)
+		-> Result<Duration, D::Error> {
+		Ok(Duration::milliseconds(i64::deserialize(d)?))
+	}
+}
+
+/// Serialize an optional `chrono::Duration` as milliseconds.
+pub mod serde_opt_duration {
+	use chrono::Duration;
+	use serde::{Deserialize, Deserializer, Serializer};
+
+	pub fn serialize<S: Serializer>(d: &Option<Duration>, s: S)
+		-> Result<S::Ok, S::Error> {
+		match d {
+			Some(d) => s.serialize_some(&d.num_milliseconds()),
+			None => s.serialize_none(),
+		}
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D)
+		-> Result<Option<Duration>, D::Error> {
+		Ok(Option::<i64>::deserialize(d)?.map(Duration::milliseconds))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::connection;
+	use crate::{
+		ChannelGroupId, ChannelId, ClientId, GroupNamingMode, GroupType,
+		IconHash,
+	};
+
+	fn group(name: &str) -> ChannelGroup {
+		ChannelGroup {
+			id: ChannelGroupId(5),
+			name: name.into(),
+			group_type: GroupType::Regular,
+			icon_id: IconHash(0),
+			is_permanent: true,
+			sort_id: 0,
+			naming_mode: GroupNamingMode::None,
+			needed_modify_power: 75,
+			needed_member_add_power: 50,
+			needed_member_remove_power: 50,
+		}
+	}
+
+	#[test]
+	fn unchanged() {
+		let con = connection();
+		assert!(diff(&con, &con.clone()).is_empty());
+	}
+
+	#[test]
+	fn added_and_removed_client() {
+		let old = connection();
+		let mut new = old.clone();
+		let mut client = new.server.clients[&ClientId(2)].clone();
+		client.id = ClientId(3);
+		new.server.clients.insert(client.id, client);
+
+		assert_eq!(diff(&old, &new), vec![Events::PropertyAdded(
+			PropertyId::Client(ClientId(3)))]);
+		let removed = new.server.clients[&ClientId(3)].clone();
+		assert_eq!(diff(&new, &old), vec![Events::PropertyRemoved(
+			PropertyId::Client(ClientId(3)), Property::Client(removed))]);
+	}
+
+	#[test]
+	fn changed_channel() {
+		let old = connection();
+		let mut new = old.clone();
+		new.server.channels.get_mut(&ChannelId(1)).unwrap().name =
+			"New".into();
+
+		assert_eq!(diff(&old, &new), vec![Events::PropertyChanged(
+			PropertyId::ChannelName(ChannelId(1)),
+			Property::ChannelName("Name".into()))]);
+	}
+
+	#[test]
+	fn channel_groups() {
+		let old = connection();
+		let mut new = old.clone();
+		new.server.channel_groups.insert(ChannelGroupId(5), group("Admin"));
+		assert_eq!(diff(&old, &new), vec![Events::PropertyAdded(
+			PropertyId::ChannelGroup(ChannelGroupId(5)))]);
+
+		let mut newer = new.clone();
+		newer.server.channel_groups.insert(ChannelGroupId(5), group("Mod"));
+		assert_eq!(diff(&new, &newer), vec![Events::PropertyChanged(
+			PropertyId::ChannelGroupName(ChannelGroupId(5)),
+			Property::ChannelGroupName("Admin".into()))]);
+		assert_eq!(diff(&newer, &old), vec![Events::PropertyRemoved(
+			PropertyId::ChannelGroup(ChannelGroupId(5)),
+			Property::ChannelGroup(group("Mod")))]);
+	}
+
+	#[test]
+	fn json_round_trip() {
+		let mut con = connection();
+		con.server.channel_groups.insert(ChannelGroupId(5), group("Admin"));
+		let json = to_json(&con).unwrap();
+		let parsed = from_json(&json).unwrap();
+		assert_eq!(parsed, con);
+		assert!(diff(&parsed, &con).is_empty());
+	}
+}
@@ -0,0 +1,146 @@
+//! Subscribe to channels and unsubscribe from them.
+//!
+//! The server sends the clients of a channel only if we are subscribed to it,
+//! so clients in unsubscribed channels are missing in the [`data`]
+//! structures. If we are subscribed to a channel is stored in its
+//! [`subscribed`] attribute.
+//!
+//! [`data`]: ../data/index.html
+//! [`subscribed`]: ../data/struct.Channel.html#structfield.subscribed
+use std::mem;
+
+use tsproto::packets::{Direction, InCommand, OutCommand, OutPacket, PacketType};
+use tsproto_commands::*;
+
+use crate::data;
+use crate::events::{Events, Property, PropertyId};
+use crate::packet_handler::get_parse;
+use crate::Result;
+
+/// Apply a subscription notification.
+///
+/// Notifications for unknown channels are ignored.
+///
+/// Returns `false` if the command is not related to subscriptions.
+pub(crate) fn handle_command(
+	con: &mut data::Connection,
+	cmd: &InCommand,
+	events: &mut Vec<Events>,
+) -> Result<bool>
+{
+	let subscribe = match cmd.name() {
+		"notifychannelsubscribed" => true,
+		"notifychannelunsubscribed" => false,
+		_ => return Ok(false),
+	};
+	for r in cmd.iter() {
+		let id = ChannelId(get_parse(&r, "cid")?);
+		if let Some(channel) = con.server.channels.get_mut(&id) {
+			if channel.subscribed != subscribe {
+				let old = mem::replace(&mut channel.subscribed, subscribe);
+				events.push(Events::PropertyChanged(
+					PropertyId::ChannelSubscribed(id),
+					Property::ChannelSubscribed(old),
+				));
+			}
+		}
+	}
+	Ok(true)
+}
+
+/// Get a channel and all its subchannels.
+pub(crate) fn subtree(con: &data::Connection, channel: ChannelId)
+	-> Vec<ChannelId> {
+	let mut res = vec![channel];
+	let mut i = 0;
+	while i < res.len() {
+		let parent = res[i];
+		res.extend(con.server.channels.values()
+			.filter(|c| c.parent == parent)
+			.map(|c| c.id));
+		i += 1;
+	}
+	res
+}
+
+/// Create a `channelsubscribe` or `channelunsubscribe` command.
+///
+/// If `channels` is `None`, the command for all channels is created.
+pub(crate) fn create_command(subscribe: bool, channels: Option<&[ChannelId]>)
+	-> OutPacket {
+	let name = match (subscribe, channels.is_some()) {
+		(true, true) => "channelsubscribe",
+		(true, false) => "channelsubscribeall",
+		(false, true) => "channelunsubscribe",
+		(false, false) => "channelunsubscribeall",
+	};
+	OutCommand::new(
+		Direction::C2S,
+		PacketType::Command,
+		name,
+		std::iter::empty::<(&str, &str)>(),
+		channels.unwrap_or(&[]).iter()
+			.map(|c| std::iter::once(("cid", c.0.to_string()))),
+	)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::{connection, parse_cmd, parse_msg};
+
+	#[test]
+	fn subscribe() {
+		let mut con = connection();
+		let id = ChannelId(1);
+		assert!(!con.server.channels[&id].subscribed);
+
+		let mut events = Vec::new();
+		let cmd = parse_cmd("notifychannelsubscribed cid=1 es=0|cid=5 es=0");
+		assert!(handle_command(&mut con, &cmd, &mut events).unwrap());
+		assert!(con.server.channels[&id].subscribed);
+		assert_eq!(events, vec![Events::PropertyChanged(
+			PropertyId::ChannelSubscribed(id),
+			Property::ChannelSubscribed(false),
+		)]);
+
+		// Subscribing again changes nothing
+		events.clear();
+		assert!(handle_command(&mut con, &cmd, &mut events).unwrap());
+		assert!(events.is_empty());
+
+		let cmd = parse_cmd("notifychannelunsubscribed cid=1");
+		assert!(handle_command(&mut con, &cmd, &mut events).unwrap());
+		assert!(!con.server.channels[&id].subscribed);
+		assert_eq!(events, vec![Events::PropertyChanged(
+			PropertyId::ChannelSubscribed(id),
+			Property::ChannelSubscribed(true),
+		)]);
+	}
+
+	#[test]
+	fn removed_channel() {
+		let mut con = connection();
+		let logger = slog::Logger::root(slog::Discard, slog::o!());
+		let cmd = parse_cmd("notifychannelsubscribed cid=1");
+		handle_command(&mut con, &cmd, &mut Vec::new()).unwrap();
+
+		let msg = parse_msg("notifychanneldeleted invokerid=0 \
+			invokername=Server invokeruid=serveradmin cid=1");
+		let events = con.handle_message(&msg, &logger).unwrap();
+		assert!(con.server.channels.is_empty());
+		match &events[..] {
+			[Events::PropertyRemoved(PropertyId::Channel(_),
+				Property::Channel(c))] => assert!(c.subscribed),
+			e => panic!("Unexpected events {:?}", e),
+		}
+	}
+
+	#[test]
+	fn subscribe_commands() {
+		let packet = create_command(true, Some(&[ChannelId(2), ChannelId(5)]));
+		assert_eq!(packet.content(), &b"channelsubscribe cid=2|cid=5"[..]);
+		let packet = create_command(false, None);
+		assert_eq!(packet.content(), &b"channelunsubscribeall"[..]);
+	}
+}
@@ -0,0 +1,199 @@
+//! Which clients are currently talking.
+//!
+//! The talk status is derived from the incoming voice packets. A client
+//! starts talking with its first voice packet and stops with an empty voice
+//! packet, which marks the end of a transmission. If the end is lost, the
+//! client stops talking when no voice packet was received for
+//! [`TALK_TIMEOUT`].
+//!
+//! The talk status of our own client is not tracked. The status of the other
+//! clients is stored in their [`talk_status`] attribute.
+//!
+//! [`TALK_TIMEOUT`]: constant.TALK_TIMEOUT.html
+//! [`talk_status`]: ../data/struct.Client.html#structfield.talk_status
+use std::collections::HashMap;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use num_derive::{FromPrimitive, ToPrimitive};
+use serde_derive::{Deserialize, Serialize};
+use tsproto::packets::AudioData;
+use tsproto_commands::*;
+
+use crate::data;
+use crate::events::{Events, Property, PropertyId};
+
+/// A client stops talking if no voice packet is received for this time.
+pub const TALK_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(
+	Clone, Copy, Debug, Eq, Hash, PartialEq, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
+)]
+pub enum TalkStatus {
+	NotTalking,
+	/// Talking to the channel.
+	Talking,
+	/// Talking to us with a whisper.
+	Whispering,
+}
+
+impl Default for TalkStatus {
+	fn default() -> Self { TalkStatus::NotTalking }
+}
+
+/// The time of the last voice packet of every talking client.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TalkTimes {
+	clients: HashMap<ClientId, Instant>,
+}
+
+impl TalkTimes {
+	/// Apply a received voice packet.
+	pub(crate) fn handle_audio(
+		&mut self,
+		con: &mut data::Connection,
+		audio: &AudioData,
+		now: Instant,
+		events: &mut Vec<Events>,
+	)
+	{
+		let (from, whisper, data) = match audio {
+			AudioData::S2C { from, data, .. } => (*from, false, data),
+			AudioData::S2CWhisper { from, data, .. } => (*from, true, data),
+			_ => return,
+		};
+		let client = ClientId(from);
+		let status = if data.is_empty() {
+			self.clients.remove(&client);
+			TalkStatus::NotTalking
+		} else {
+			self.clients.insert(client, now);
+			if whisper {
+				TalkStatus::Whispering
+			} else {
+				TalkStatus::Talking
+			}
+		};
+		set_status(con, client, status, events);
+	}
+
+	/// Stop clients, which sent no voice packet for [`TALK_TIMEOUT`].
+	///
+	/// Returns the next time, at which a client can time out.
+	///
+	/// [`TALK_TIMEOUT`]: constant.TALK_TIMEOUT.html
+	pub(crate) fn timeout(
+		&mut self,
+		con: &mut data::Connection,
+		now: Instant,
+		events: &mut Vec<Events>,
+	) -> Option<Instant>
+	{
+		let mut next: Option<Instant> = None;
+		let mut stopped = Vec::new();
+		self.clients.retain(|c, last_packet| {
+			let end = *last_packet + TALK_TIMEOUT;
+			if end <= now {
+				stopped.push(*c);
+				false
+			} else {
+				next = Some(next.map(|n| n.min(end)).unwrap_or(end));
+				true
+			}
+		});
+		for c in stopped {
+			set_status(con, c, TalkStatus::NotTalking, events);
+		}
+		next
+	}
+}
+
+/// Change the talk status of a client in the book.
+///
+/// Unknown clients are ignored.
+fn set_status(
+	con: &mut data::Connection,
+	client: ClientId,
+	status: TalkStatus,
+	events: &mut Vec<Events>,
+)
+{
+	if let Some(c) = con.server.clients.get_mut(&client) {
+		if c.talk_status != status {
+			let old = mem::replace(&mut c.talk_status, status);
+			events.push(Events::PropertyChanged(
+				PropertyId::ClientTalkStatus(client),
+				Property::ClientTalkStatus(old),
+			));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tsproto::packets::CodecType;
+
+	use crate::tests::connection;
+
+	fn changed(old: TalkStatus) -> Vec<Events> {
+		vec![Events::PropertyChanged(
+			PropertyId::ClientTalkStatus(ClientId(2)),
+			Property::ClientTalkStatus(old),
+		)]
+	}
+
+	#[test]
+	fn talk_status() {
+		let mut con = connection();
+		let mut times = TalkTimes::default();
+		let mut events = Vec::new();
+		let start = Instant::now();
+		let audio = |data: &'static [u8]| AudioData::S2C {
+			id: 0,
+			from: 2,
+			codec: CodecType::OpusVoice,
+			data,
+		};
+		let status = |con: &data::Connection|
+			con.server.clients[&ClientId(2)].talk_status;
+		assert_eq!(status(&con), TalkStatus::NotTalking);
+
+		times.handle_audio(&mut con, &audio(&[1]), start, &mut events);
+		times.handle_audio(&mut con, &audio(&[1]), start, &mut events);
+		assert_eq!(status(&con), TalkStatus::Talking);
+		assert_eq!(events, changed(TalkStatus::NotTalking));
+
+		// End of transmission
+		events.clear();
+		times.handle_audio(&mut con, &audio(&[]), start, &mut events);
+		assert_eq!(status(&con), TalkStatus::NotTalking);
+		assert_eq!(events, changed(TalkStatus::Talking));
+
+		// Timeout
+		events.clear();
+		times.handle_audio(&mut con, &audio(&[1]), start, &mut events);
+		assert_eq!(times.timeout(&mut con, start, &mut events),
+			Some(start + TALK_TIMEOUT));
+		assert_eq!(times.timeout(&mut con, start + TALK_TIMEOUT, &mut events),
+			None);
+		assert_eq!(status(&con), TalkStatus::NotTalking);
+		assert_eq!(events.len(), 2);
+	}
+
+	#[test]
+	fn unknown_client() {
+		let mut con = connection();
+		let mut times = TalkTimes::default();
+		let mut events = Vec::new();
+		let audio = AudioData::S2CWhisper {
+			id: 0,
+			from: 5,
+			codec: CodecType::OpusVoice,
+			data: &[1],
+		};
+		times.handle_audio(&mut con, &audio, Instant::now(), &mut events);
+		assert!(events.is_empty());
+	}
+}
@@ -1,11 +1,93 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::TimeZone;
+use futures::sync::oneshot;
+use parking_lot::{Mutex, RwLock};
+use tsproto::client;
+use tsproto::crypto::EccKeyPrivP256;
 use tsproto::packets::{InCommand, Direction, PacketType};
 use tsproto_commands::messages::s2c::{InMessage, InMessages};
+use tsproto_commands::Uid;
+
+use crate::data;
+use crate::events::{Events, Property, PropertyId};
+use crate::packet_handler::{NotificationWaiters, SimplePacketHandler};
+
+const INITSERVER: &str = r#"initserver virtualserver_welcomemessage=Welcome virtualserver_platform=Linux virtualserver_version=3.5.0\s[Build:\s1540447474] virtualserver_maxclients=32 virtualserver_created=1500000000 virtualserver_codec_encryption_mode=0 virtualserver_hostmessage virtualserver_hostmessage_mode=0 virtualserver_default_server_group=8 virtualserver_default_channel_group=8 virtualserver_hostbanner_url virtualserver_hostbanner_gfx_url virtualserver_hostbanner_gfx_interval=0 virtualserver_priority_speaker_dimm_modificator=-18.0000 virtualserver_id=1 virtualserver_hostbutton_tooltip virtualserver_hostbutton_url virtualserver_hostbutton_gfx_url virtualserver_name_phonetic virtualserver_icon_id=0 virtualserver_ip=0.0.0.0,:: virtualserver_ask_for_privilegekey=0 virtualserver_hostbanner_mode=0 virtualserver_channel_temp_delete_delay_default=0 acn=Bot aclid=2 pv=6 lt=0 client_talk_power=-1 client_needed_serverquery_view_power=75 virtualserver_name=Server"#;
+
+const CHANNELLIST: &str = r#"channellist cid=1 cpid=0 channel_name=Name channel_topic channel_codec=4 channel_codec_quality=10 channel_maxclients=-1 channel_maxfamilyclients=-1 channel_order=0 channel_flag_permanent=1 channel_flag_semi_permanent=0 channel_flag_default=1 channel_flag_password=0 channel_codec_latency_factor=1 channel_codec_is_unencrypted=0 channel_delete_delay=0 channel_flag_maxclients_unlimited=1 channel_flag_maxfamilyclients_unlimited=0 channel_flag_maxfamilyclients_inherited=1 channel_needed_talk_power=0 channel_forced_silence=0 channel_name_phonetic channel_flag_private=0 channel_icon_id=0"#;
+
+const CLIENTENTERVIEW: &str = r#"notifycliententerview cfid=0 ctid=1 reasonid=0 clid=2 client_unique_identifier=YWJj client_nickname=Bot client_input_muted=0 client_output_muted=0 client_outputonly_muted=0 client_input_hardware=1 client_output_hardware=1 client_meta_data client_is_recording=0 client_database_id=3 client_channel_group_id=8 client_servergroups=8 client_away=0 client_away_message client_type=0 client_flag_avatar client_talk_power=0 client_talk_request=0 client_talk_request_msg client_description client_is_talker=0 client_is_priority_speaker=0 client_unread_messages=0 client_nickname_phonetic client_needed_serverquery_view_power=75 client_icon_id=0 client_is_channel_commander=0 client_country client_channel_group_inherited_channel_id=1 client_badges"#;
+
+pub(crate) fn parse_cmd(cmd: &str) -> InCommand {
+	InCommand::new(cmd.as_bytes().to_vec(), PacketType::Command, false,
+		Direction::S2C).unwrap()
+}
+
+pub(crate) fn parse_msg(msg: &str) -> InMessage {
+	InMessage::new(parse_cmd(msg)).unwrap()
+}
+
+/// The book of a connection to a server with the channel `1` and our own
+/// client `2` in it.
+pub(crate) fn connection() -> data::Connection {
+	let logger = slog::Logger::root(slog::Discard, slog::o!());
+	let mut con = data::Connection::new(Uid("server".into()),
+		&parse_msg(INITSERVER));
+	con.handle_message(&parse_msg(CHANNELLIST), &logger).unwrap();
+	con.handle_message(&parse_msg(CLIENTENTERVIEW), &logger).unwrap();
+	con
+}
 
-fn parse_msg(msg: &str) -> InMessage {
-	let cmd = InCommand::new(msg.as_bytes().to_vec(), PacketType::Command,
-		false, Direction::S2C).unwrap();
+/// A `Connection` with the book of [`connection`], which is not connected to a
+/// server.
+///
+/// This creates a socket, so it has to be called inside a tokio runtime.
+///
+/// [`connection`]: fn.connection.html
+pub(crate) fn offline_connection() -> crate::Connection {
+	let logger = slog::Logger::root(slog::Discard, slog::o!());
+	let (initserver_send, _) = oneshot::channel();
+	let (_, connection_recv) = oneshot::channel();
+	let packet_handler = SimplePacketHandler::new(
+		logger.clone(),
+		None,
+		initserver_send,
+		connection_recv,
+		#[cfg(feature = "audio")]
+		None,
+	);
+	let return_code_handler = packet_handler.return_codes.clone();
+	let private_key = EccKeyPrivP256::create().unwrap();
+	let client_data = client::new_shared("127.0.0.1:0".parse().unwrap(),
+		private_key.clone(), packet_handler, logger).unwrap();
+	let client_connection = {
+		let mut c = client_data.lock();
+		let state = client::ServerConnectionData {
+			state_change_listener: Vec::new(),
+			state: client::ServerConnectionState::Connected,
+			private_key,
+		};
+		let key = c.add_connection(Arc::downgrade(&client_data), state,
+			"127.0.0.1:9987".parse().unwrap());
+		c.get_connection(&key).unwrap().downgrade()
+	};
 
-	InMessage::new(cmd).unwrap()
+	crate::Connection {
+		inner: crate::InnerConnection {
+			connection: Arc::new(RwLock::new(connection())),
+			client_data,
+			client_connection,
+			return_code_handler,
+			event_listeners: Arc::new(RwLock::new(HashMap::new())),
+			permissions: Default::default(),
+			file_requests: Default::default(),
+			plugin_listeners: Arc::new(Mutex::new(Vec::new())),
+			notification_waiters: Arc::new(Mutex::new(
+				NotificationWaiters::default())),
+		},
+	}
 }
 
 fn test_iconid(input: &str, expected: u32) {
@@ -32,3 +114,104 @@ fn negative_iconid() {
 fn big_iconid() {
 	test_iconid("18446744073225738240", 3811153920);
 }
+
+#[derive(Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+struct DurationTest {
+	#[serde(with = "crate::snapshot::serde_duration")]
+	d: chrono::Duration,
+	#[serde(with = "crate::snapshot::serde_opt_duration")]
+	opt: Option<chrono::Duration>,
+	channels: std::collections::HashMap<crate::ChannelId, crate::ClientId>,
+}
+
+#[test]
+fn snapshot_serde() {
+	let mut channels = std::collections::HashMap::new();
+	channels.insert(crate::ChannelId(2), crate::ClientId(5));
+	let val = DurationTest {
+		d: chrono::Duration::milliseconds(1500),
+		opt: None,
+		channels,
+	};
+	let json = serde_json::to_string(&val).unwrap();
+	assert_eq!(json, r#"{"d":1500,"opt":null,"channels":{"2":5}}"#);
+	assert_eq!(serde_json::from_str::<DurationTest>(&json).unwrap(), val);
+}
+
+#[test]
+fn nickname_retries() {
+	assert_eq!(crate::get_nickname("Bot", 0), "Bot");
+	assert_eq!(crate::get_nickname("Bot", 2), "Bot2");
+	let long = "a".repeat(30);
+	assert_eq!(crate::get_nickname(&long, 12), format!("{}12", "a".repeat(28)));
+}
+
+#[test]
+fn client_details() {
+	let logger = slog::Logger::root(slog::Discard, slog::o!());
+	let mut con = connection();
+	let id = crate::ClientId(2);
+	assert!(con.server.clients[&id].optional_data.is_none());
+
+	// An update without the details does not create them
+	let msg = parse_msg("notifyclientupdated clid=2 client_description=Hi");
+	let events = con.handle_message(&msg, &logger).unwrap();
+	assert!(con.server.clients[&id].optional_data.is_none());
+	assert!(!events.contains(&Events::PropertyAdded(
+		PropertyId::OptionalClientData(id))));
+
+	// The answer to `clientgetvariables`
+	let msg = parse_msg(r#"notifyclientupdated clid=2 client_version=3.2.3\s[Build:\s1544170829] client_platform=Linux client_login_name client_created=1500000000 client_lastconnected=1540000000 client_totalconnections=5 client_month_bytes_uploaded=10 client_month_bytes_downloaded=20 client_total_bytes_uploaded=30 client_total_bytes_downloaded=40 client_description=Hi client_country=DE"#);
+	let events = con.handle_message(&msg, &logger).unwrap();
+	assert!(events.contains(&Events::PropertyAdded(
+		PropertyId::OptionalClientData(id))));
+	let data = con.server.clients[&id].optional_data.clone().unwrap();
+	assert_eq!(data.version, "3.2.3 [Build: 1544170829]");
+	assert_eq!(data.created, chrono::Utc.timestamp(1500000000, 0));
+	assert_eq!(data.connection_total, 5);
+	assert_eq!(data.total_bytes_downloaded, 40);
+	assert_eq!(data.description, "Hi");
+	assert_eq!(data.country, "DE");
+
+	// A partial update
+	let msg = parse_msg("notifyclientupdated clid=2 client_description=Bye");
+	let events = con.handle_message(&msg, &logger).unwrap();
+	assert!(events.contains(&Events::PropertyChanged(
+		PropertyId::OptionalClientDataDescription(id),
+		Property::OptionalClientDataDescription("Hi".into()),
+	)));
+	assert!(!events.iter().any(|e| match e {
+		Events::PropertyAdded(_) => true,
+		Events::PropertyChanged(PropertyId::OptionalClientDataCountry(_), _)
+			=> true,
+		_ => false,
+	}));
+	let data = con.server.clients[&id].optional_data.as_ref().unwrap();
+	assert_eq!(data.description, "Bye");
+	assert_eq!(data.country, "DE");
+}
+
+#[test]
+fn channel_description() {
+	let logger = slog::Logger::root(slog::Discard, slog::o!());
+	let mut con = connection();
+	let id = crate::ChannelId(1);
+
+	let msg = parse_msg("notifychanneledited cid=1 reasonid=10 \
+		channel_description=Old");
+	let events = con.handle_message(&msg, &logger).unwrap();
+	assert!(events.contains(&Events::PropertyAdded(
+		PropertyId::OptionalChannelData(id))));
+	assert_eq!(con.server.channels[&id].optional_data.as_ref().unwrap()
+		.description, "Old");
+
+	let msg = parse_msg("notifychanneledited cid=1 reasonid=10 \
+		channel_description=New");
+	let events = con.handle_message(&msg, &logger).unwrap();
+	assert!(events.contains(&Events::PropertyChanged(
+		PropertyId::OptionalChannelDataDescription(id),
+		Property::OptionalChannelDataDescription("Old".into()),
+	)));
+	assert_eq!(con.server.channels[&id].optional_data.as_ref().unwrap()
+		.description, "New");
+}
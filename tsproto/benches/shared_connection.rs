@@ -0,0 +1,154 @@
+//! Find the connection of an incoming packet, if many connections to the same
+//! server share a socket.
+#[macro_use]
+extern crate criterion;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use criterion::{Bencher, Criterion};
+use futures::sync::mpsc;
+use futures::{future, Stream};
+use slog::{o, Logger};
+use tsproto::connection::{ConnectedParams, Connection, SharedIv};
+use tsproto::connectionmanager::{ConnectionManager, SharedConnectionManager};
+use tsproto::crypto::EccKeyPrivP256;
+use tsproto::handler_data::{ConnectionValue, Data, PacketHandler};
+use tsproto::packet_codec::PacketCodecSender;
+use tsproto::packets::*;
+use tsproto::resend::{DefaultResender, ResendConfig};
+use tsproto::Error;
+
+struct Handler;
+
+impl PacketHandler<()> for Handler {
+	fn new_connection<S1, S2, S3, S4>(
+		&mut self,
+		_: &ConnectionValue<()>,
+		_: S1,
+		_: S2,
+		_: S3,
+		_: S4,
+	) where
+		S1: Stream<Item = InS2CInit, Error = Error> + Send + 'static,
+		S2: Stream<Item = InC2SInit, Error = Error> + Send + 'static,
+		S3: Stream<Item = InCommand, Error = Error> + Send + 'static,
+		S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
+	{
+	}
+}
+
+type CM = SharedConnectionManager<Handler, ()>;
+
+/// The id of the command packet from the server.
+const PACKET_ID: u16 = 5;
+
+fn params(i: usize) -> ConnectedParams {
+	let i = i as u8;
+	ConnectedParams::new(
+		EccKeyPrivP256::create().unwrap().to_pub(),
+		SharedIv::Protocol31([i; 64]),
+		[i; 8],
+	)
+}
+
+/// Encode a command from the server of the connection with the params `i`.
+fn packet(addr: SocketAddr, logger: Logger, i: usize) -> InPacket {
+	let (udp_send, _) = mpsc::channel(1);
+	let (s2c_init_send, _) = mpsc::unbounded();
+	let (c2s_init_send, _) = mpsc::unbounded();
+	let (command_send, _) = mpsc::unbounded();
+	let (audio_send, _) = mpsc::unbounded();
+	let mut server = Connection::new(
+		addr,
+		DefaultResender::new(ResendConfig::default(), logger.clone()),
+		logger,
+		udp_send,
+		false,
+		s2c_init_send,
+		c2s_init_send,
+		command_send,
+		audio_send,
+	);
+	server.params = Some(params(i));
+	server.outgoing_p_ids[PacketType::Command as usize] = (0, PACKET_ID);
+	let packet =
+		OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+			Direction::S2C,
+			PacketType::Command,
+			"notifytest",
+			std::iter::empty(),
+			std::iter::empty(),
+		);
+	let mut packets = PacketCodecSender::new(false)
+		.encode_packet(&mut server, packet)
+		.unwrap();
+	InPacket::try_new(packets.remove(0).1, Direction::S2C).unwrap()
+}
+
+/// Find the owner of a packet among `count` connections to the same server.
+///
+/// If `same_id` is set, all connections expect the packet id next, so the
+/// packet has to be decrypted with many of them.
+fn find(b: &mut Bencher, count: usize, same_id: bool) {
+	tsproto::init().unwrap();
+	let logger = Logger::root(slog::Discard, o!());
+	let addr: SocketAddr = "127.0.0.1:9987".parse().unwrap();
+	let mut rt = tokio::runtime::Runtime::new().unwrap();
+	let logger2 = logger.clone();
+	let data = rt
+		.block_on(future::lazy(move || {
+			let data = Data::new(
+				"127.0.0.1:0".parse().unwrap(),
+				EccKeyPrivP256::create().unwrap(),
+				true,
+				None,
+				Handler,
+				CM::new(),
+				logger2,
+			)?;
+			{
+				let mut d = data.lock();
+				for i in 0..count {
+					let key = d.add_connection(Arc::downgrade(&data), (), addr);
+					let con = d.get_connection(&key).unwrap();
+					let mut con = con.mutex.lock();
+					con.1.params = Some(params(i));
+					// The packet belongs to the last connection
+					let next = if same_id || i + 1 == count {
+						PACKET_ID
+					} else {
+						PACKET_ID + 1
+					};
+					con.1.incoming_p_ids[PacketType::Command as usize] =
+						(0, next);
+				}
+			}
+			Ok::<_, Error>(data)
+		}))
+		.unwrap();
+	let packet = packet(addr, logger, count - 1);
+	let connections = data.lock().connections.clone();
+
+	b.iter(|| CM::find_connection(&connections, addr, &packet).unwrap());
+}
+
+fn bench_find_single(c: &mut Criterion) {
+	c.bench_function("find single connection", |b| find(b, 1, true));
+}
+fn bench_find_100(c: &mut Criterion) {
+	c.bench_function("find connection of 100", |b| find(b, 100, false));
+}
+fn bench_find_100_same_id(c: &mut Criterion) {
+	c.bench_function("find connection of 100 with the same packet id", |b| {
+		find(b, 100, true)
+	});
+}
+
+criterion_group!(
+	benches,
+	bench_find_single,
+	bench_find_100,
+	bench_find_100_same_id,
+);
+criterion_main!(benches);
@@ -321,6 +321,17 @@ pub fn get_hash_cash_level(omega: &str, offset: u64) -> u8 {
 	res
 }
 
+/// Hash a server or channel password, like the server expects it.
+///
+/// The hash is the base64 encoded SHA1 of the password. An empty password is
+/// not hashed.
+pub fn hash_password(password: &str) -> String {
+	if password.is_empty() {
+		return String::new();
+	}
+	base64::encode(digest::digest(&digest::SHA1, password.as_bytes()).as_ref())
+}
+
 pub fn biguint_to_array(i: &BigUint) -> [u8; 64] {
 	let mut v = i.to_bytes_le();
 
@@ -364,6 +375,12 @@ mod tests {
 		assert_eq!(&data, &dec_data);
 	}
 
+	#[test]
+	fn test_hash_password() {
+		assert_eq!(hash_password(""), "");
+		assert_eq!(hash_password("password"), "W6ph5Mm5Pz8GgiULbPgzG37mj9g=");
+	}
+
 	#[test]
 	fn test_fake_encrypt() {
 		let mut packet = OutAck::new(Direction::C2S, PacketType::Command, 0);
@@ -20,22 +20,29 @@ use {base64, tokio, tokio_threadpool};
 use crate::algorithms as algs;
 use crate::connection::*;
 use crate::connectionmanager::{
-	Resender, ResenderEvent, SocketConnectionManager,
+	ConnectionManager, Resender, ResenderEvent, SharedConnectionManager,
+	SocketConnectionManager,
 };
 use crate::crypto::{EccKeyPrivEd25519, EccKeyPrivP256, EccKeyPubP256};
 use crate::handler_data::{
 	ConnectionValue, ConnectionValueWeak, Data, DataM, OutPacketObserver,
 	PacketHandler,
 };
+use crate::impairment::ImpairmentConfig;
 use crate::license::Licenses;
 use crate::packets::*;
 use crate::{Error, Result};
 
 pub type CM<PH> =
 	SocketConnectionManager<DefaultPacketHandler<PH>, ServerConnectionData>;
+/// A client which can have multiple connections to the same server.
+pub type SharedCM<PH> =
+	SharedConnectionManager<DefaultPacketHandler<PH>, ServerConnectionData>;
 /// The data of our client.
 pub type ClientData<PH> = Data<CM<PH>>;
 pub type ClientDataM<PH> = DataM<CM<PH>>;
+pub type SharedClientData<PH> = Data<SharedCM<PH>>;
+pub type SharedClientDataM<PH> = DataM<SharedCM<PH>>;
 /// Connections from a client to a server.
 pub type ClientConnection = Connection;
 pub type ClientConVal = ConnectionValueWeak<ServerConnectionData>;
@@ -49,6 +56,8 @@ pub struct ServerConnectionData {
 	pub state_change_listener:
 		Vec<Box<FnMut(&ServerConnectionState) -> bool + Send>>,
 	pub state: ServerConnectionState,
+	/// The identity which is used for this connection.
+	pub private_key: EccKeyPrivP256,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -125,13 +134,90 @@ pub fn new<
 	logger: L,
 ) -> Result<Arc<Mutex<ClientData<PH>>>>
 {
-	let c = ClientData::new(
+	new_impaired(
+		local_addr,
+		private_key,
+		packet_handler,
+		ImpairmentConfig::default(),
+		logger,
+	)
+}
+
+/// Create a new client, which simulates a bad network connection.
+///
+/// This should only be used for testing.
+pub fn new_impaired<
+	PH: PacketHandler<ServerConnectionData> + 'static,
+	L: Into<Option<slog::Logger>>,
+>(
+	local_addr: SocketAddr,
+	private_key: EccKeyPrivP256,
+	packet_handler: PH,
+	impairment: ImpairmentConfig,
+	logger: L,
+) -> Result<Arc<Mutex<ClientData<PH>>>>
+{
+	new_with_manager(
+		local_addr,
+		private_key,
+		packet_handler,
+		SocketConnectionManager::new(),
+		impairment,
+		logger,
+	)
+}
+
+/// Create a new client, which can have multiple connections to the same
+/// server on its socket.
+///
+/// Finding the connection of an incoming packet is more expensive for such a
+/// client, see [`SharedConnectionManager`].
+///
+/// [`SharedConnectionManager`]: ../connectionmanager/struct.SharedConnectionManager.html
+pub fn new_shared<
+	PH: PacketHandler<ServerConnectionData> + 'static,
+	L: Into<Option<slog::Logger>>,
+>(
+	local_addr: SocketAddr,
+	private_key: EccKeyPrivP256,
+	packet_handler: PH,
+	logger: L,
+) -> Result<Arc<Mutex<SharedClientData<PH>>>>
+{
+	new_with_manager(
+		local_addr,
+		private_key,
+		packet_handler,
+		SharedConnectionManager::new(),
+		ImpairmentConfig::default(),
+		logger,
+	)
+}
+
+fn new_with_manager<
+	PH: PacketHandler<ServerConnectionData> + 'static,
+	CM: ConnectionManager<
+			PacketHandler = DefaultPacketHandler<PH>,
+			AssociatedData = ServerConnectionData,
+		> + 'static,
+	L: Into<Option<slog::Logger>>,
+>(
+	local_addr: SocketAddr,
+	private_key: EccKeyPrivP256,
+	packet_handler: PH,
+	connection_manager: CM,
+	impairment: ImpairmentConfig,
+	logger: L,
+) -> Result<Arc<Mutex<Data<CM>>>>
+{
+	let c = Data::new_impaired(
 		local_addr,
 		private_key,
 		true,
 		None,
 		DefaultPacketHandler::new(packet_handler),
-		SocketConnectionManager::new(),
+		connection_manager,
+		impairment,
 		logger,
 	)?;
 
@@ -160,11 +246,56 @@ pub fn new<
 ///
 /// [`ServerConnectionState::Connecting`]: enum.ServerConnectionState.html
 /// [`wait_until_connected`]: method.wait_until_connected.html
-pub fn connect<PH: PacketHandler<ServerConnectionData>>(
-	datam: Weak<Mutex<ClientData<PH>>>,
-	data: &mut ClientData<PH>,
+pub fn connect<
+	CM: ConnectionManager<AssociatedData = ServerConnectionData> + 'static,
+>(
+	datam: Weak<Mutex<Data<CM>>>,
+	data: &mut Data<CM>,
 	server_addr: SocketAddr,
 ) -> impl Future<Item = ClientConVal, Error = Error>
+{
+	let (con, answered) = start_connect(datam, data, server_addr);
+	let connecting = wait_for_state(&con, |state| {
+		*state == ServerConnectionState::Connecting
+	});
+	answered.and_then(move |_| connecting).map(move |_| con)
+}
+
+/// Start connecting to a server.
+///
+/// The first init packet is sent and the new connection is returned together
+/// with a future, which resolves when the server answered the init packet.
+/// The rest of the handshake continues in the background, so
+/// [`ServerConnectionState::Connecting`] can be awaited afterwards.
+///
+/// This is useful to try multiple addresses at once and continue with the
+/// server that answers first.
+///
+/// [`ServerConnectionState::Connecting`]: enum.ServerConnectionState.html
+pub fn start_connect<
+	CM: ConnectionManager<AssociatedData = ServerConnectionData> + 'static,
+>(
+	datam: Weak<Mutex<Data<CM>>>,
+	data: &mut Data<CM>,
+	server_addr: SocketAddr,
+) -> (ClientConVal, impl Future<Item = (), Error = Error>)
+{
+	let private_key = data.private_key.clone();
+	start_connect_with_key(datam, data, server_addr, private_key)
+}
+
+/// Start connecting to a server with another identity than the one of the
+/// client.
+///
+/// This allows connections with different identities on one socket.
+pub fn start_connect_with_key<
+	CM: ConnectionManager<AssociatedData = ServerConnectionData> + 'static,
+>(
+	datam: Weak<Mutex<Data<CM>>>,
+	data: &mut Data<CM>,
+	server_addr: SocketAddr,
+	private_key: EccKeyPrivP256,
+) -> (ClientConVal, impl Future<Item = (), Error = Error>)
 {
 	// Send the first init packet
 	// Get the current timestamp
@@ -178,21 +309,20 @@ pub fn connect<PH: PacketHandler<ServerConnectionData>>(
 	let state = ServerConnectionData {
 		state_change_listener: Vec::new(),
 		state: ServerConnectionState::Init0 { version: timestamp },
+		private_key,
 	};
 	// Add the connection to the connection list
 	let key = data.add_connection(datam, state, server_addr);
 	let con = data.get_connection(&key).unwrap().downgrade();
 
 	let packet = OutC2SInit0::new(timestamp, timestamp, random0);
-	let con2 = con.clone();
-	con.as_packet_sink()
-		.send(packet)
-		.and_then(move |_| {
-			wait_for_state(&con, |state| {
-				*state == ServerConnectionState::Connecting
-			})
-		})
-		.and_then(move |_| Ok(con2))
+	// Register the listener before sending, so the answer cannot be missed
+	let answered = wait_for_state(&con, |state| match state {
+		ServerConnectionState::Init0 { .. } => false,
+		_ => true,
+	});
+	let fut = con.as_packet_sink().send(packet).and_then(move |_| answered);
+	(con, fut)
 }
 
 struct ClientOutPacketObserver;
@@ -223,7 +353,28 @@ pub struct DefaultPacketHandler<
 	pub inner: IPH,
 	/// The data instance is created after the packet handler so this has to be
 	/// an option.
-	data: Option<Weak<Mutex<ClientData<IPH>>>>,
+	data: Option<Arc<ConnectionRemover>>,
+}
+
+/// Removes connections from the data of a client.
+///
+/// This hides the connection manager of the data from the packet handler.
+trait ConnectionRemover: Send + Sync {
+	fn remove_connection(&self, con: &ClientConVal);
+}
+
+impl<CM: ConnectionManager<AssociatedData = ServerConnectionData> + 'static>
+	ConnectionRemover for Weak<Mutex<Data<CM>>>
+{
+	fn remove_connection(&self, con: &ClientConVal) {
+		// If the data is gone, the connection is already gone
+		if let Some(d) = self.upgrade() {
+			let mut d = d.lock();
+			if let Some(key) = d.get_connection_key(con) {
+				d.remove_connection(&key);
+			}
+		}
+	}
 }
 
 impl<IPH: PacketHandler<ServerConnectionData> + 'static>
@@ -243,24 +394,8 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 		S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
 	{
 		let con_val2 = con_val.downgrade();
-		let data = self.data.as_ref().unwrap().clone();
 		let s2c_init_stream = s2c_init_stream
 			.and_then(move |p| -> Result<Option<InS2CInit>> {
-				// Get private key
-				let key = {
-					let d = if let Some(d) = data.upgrade() {
-						d
-					} else {
-						// Connection doesn't exist anymore
-						return Err(format_err!(
-							"Connection does not exist while handling packet"
-						)
-						.into());
-					};
-					let d = d.lock();
-					d.private_key.clone()
-				};
-
 				let con_val_weak = con_val2.clone();
 				let con_val = con_val2
 					.upgrade()
@@ -268,6 +403,7 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 				let con_val3 = con_val.clone();
 				let mut con = con_val.mutex.lock();
 				let logger = con.1.logger.clone();
+				let key = con.0.private_key.clone();
 				let mut ignore_packet = true;
 				let handle_res = match Self::handle_init(
 					&con_val3,
@@ -368,27 +504,13 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 					return Ok(Some(cmd));
 				}
 
-				// Get private key
-				let key = {
-					let d = if let Some(d) = data.upgrade() {
-						d
-					} else {
-						// Connection doesn't exist anymore
-						return Err(format_err!(
-							"Connection does not exist while handling packet"
-						)
-						.into());
-					};
-					let d = d.lock();
-					d.private_key.clone()
-				};
-
 				let con_val_weak = con_val2.clone();
 				let con_val = con_val2
 					.upgrade()
 					.ok_or_else(|| format_err!("Connection is gone"))?;
 				let mut con = con_val.mutex.lock();
 				let logger = con.1.logger.clone();
+				let key = con.0.private_key.clone();
 				let mut ignore_packet = true;
 				let handle_res = match Self::handle_command(
 					&mut *con,
@@ -406,7 +528,6 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 					}
 				};
 
-				let addr = con.1.address;
 				let is_end;
 				if let Some((s, packet)) = handle_res {
 					is_end = s == ServerConnectionState::Disconnecting;
@@ -471,14 +592,7 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 
 				if is_end {
 					// Close connection
-					let d = if let Some(d) = data.upgrade() {
-						d
-					} else {
-						// Connection doesn't exist anymore, ignore it, the
-						// connection is already gone.
-						return Ok(None);
-					};
-					d.lock().remove_connection(&addr);
+					data.remove_connection(&con_val2);
 				}
 
 				if ignore_packet {
@@ -508,8 +622,14 @@ impl<IPH: PacketHandler<ServerConnectionData> + 'static>
 	pub fn new(inner: IPH) -> Self { Self { inner, data: None } }
 
 	/// Needs to be called to complete the initialization of this packet handler.
-	pub fn complete(&mut self, data: Weak<Mutex<ClientData<IPH>>>) {
-		self.data = Some(data);
+	pub fn complete<
+		CM: ConnectionManager<AssociatedData = ServerConnectionData> + 'static,
+	>(
+		&mut self,
+		data: Weak<Mutex<Data<CM>>>,
+	)
+	{
+		self.data = Some(Arc::new(data));
 	}
 
 	fn handle_init(
@@ -220,6 +220,22 @@ mod tests {
 		assert!(cmd.list_args.is_empty());
 	}
 
+	#[test]
+	fn escape_newline() {
+		let cmd = test_loop("cmd a=abc\\ndef\\r");
+		assert_eq!(cmd.static_args, vec![("a", "abc\ndef\r".into())]);
+
+		// A raw newline would end the command in a ServerQuery connection
+		let mut written = Vec::new();
+		OutCommand::new_into(
+			"cmd",
+			vec![("a", "abc\ndef")].into_iter(),
+			std::iter::empty::<std::iter::Empty<(&str, &str)>>(),
+			&mut written,
+		);
+		assert_eq!(str::from_utf8(&written).unwrap(), "cmd a=abc\\ndef");
+	}
+
 	#[test]
 	fn array() {
 		let cmd = test_loop("cmd a=1 c=3 b=2|b=4|b=5");
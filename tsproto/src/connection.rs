@@ -1,5 +1,7 @@
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use std::u16;
 
 use bytes::Bytes;
@@ -12,8 +14,52 @@ use crate::crypto::EccKeyPubP256;
 use crate::handler_data::{ConnectionValue, ConnectionValueWeak};
 use crate::packets::*;
 use crate::resend::DefaultResender;
+use crate::stats::ConnectionStats;
 use crate::Error;
 
+/// Remembers which of the 32 packet ids before the next expected id were
+/// received.
+///
+/// Used to detect duplicated packets and packets which arrive late.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RecentIds(u32);
+
+impl RecentIds {
+	/// If `p_id` was already received.
+	///
+	/// `next_id` is the next expected packet id. Ids, which are too old to be
+	/// remembered, are counted as received.
+	pub fn contains(&self, p_id: u16, next_id: u16) -> bool {
+		let late = next_id.wrapping_sub(p_id);
+		if late == 0 || late > u16::MAX / 2 {
+			false
+		} else if late <= 32 {
+			self.0 & (1 << (late - 1)) != 0
+		} else {
+			true
+		}
+	}
+
+	/// Mark `p_id` as received.
+	///
+	/// `next_id` is the next expected packet id before this packet arrived.
+	/// Returns `false` if the id was already received.
+	pub fn receive(&mut self, p_id: u16, next_id: u16) -> bool {
+		let late = next_id.wrapping_sub(p_id);
+		if late == 0 || late > u16::MAX / 2 {
+			// A new packet, move the window forward
+			let shift = u32::from(p_id.wrapping_sub(next_id)) + 1;
+			self.0 = self.0.checked_shl(shift).unwrap_or(0) | 1;
+			true
+		} else if self.contains(p_id, next_id) {
+			false
+		} else {
+			self.0 |= 1 << (late - 1);
+			true
+		}
+	}
+}
+
 /// A cache for the key and nonce for a generation id.
 /// This has to be stored for each packet type.
 #[derive(Debug)]
@@ -60,6 +106,20 @@ impl fmt::Debug for SharedIv {
 	}
 }
 
+/// Outgoing voice packets, which could not be sent immediately.
+///
+/// Instead of waiting until the packets can be sent, which would build up
+/// latency, they are stored here. If the queue is full, the oldest packet is
+/// dropped.
+#[derive(Debug)]
+pub struct VoiceQueue {
+	/// The maximum number of packets in the queue.
+	pub max_len: usize,
+	/// Older packets are dropped instead of sent.
+	pub max_delay: Duration,
+	queue: VecDeque<(Instant, PacketType, u16, Bytes)>,
+}
+
 /// Data that has to be stored for a connection when it is connected.
 #[derive(Debug)]
 pub struct ConnectedParams {
@@ -136,6 +196,23 @@ pub struct Connection {
 	///
 	/// Works like the `outgoing_p_ids`.
 	pub incoming_p_ids: [(u32, u16); 8],
+	/// The recently received ids of voice packets.
+	///
+	/// Indexed by the packet type, only `Voice` and `VoiceWhisper`.
+	pub voice_ids: [RecentIds; 2],
+	/// Packet counters and the round trip time.
+	pub stats: ConnectionStats,
+	/// Outgoing voice packets, which wait until they can be sent.
+	pub voice_queue: VoiceQueue,
+	/// Concatenate fragmented incoming voice packets.
+	///
+	/// The first packets of a transmission can be fragmented. They are
+	/// forwarded as they are if this is `false`.
+	pub reassemble_voice: bool,
+	/// Used for incoming fragmented voice packets.
+	///
+	/// Indexed by the packet type and the sending client.
+	pub voice_fragmented_queue: HashMap<(PacketType, u16), InPacket>,
 }
 
 impl Connection {
@@ -168,6 +245,11 @@ impl Connection {
 			receive_queue: Default::default(),
 			fragmented_queue: Default::default(),
 			incoming_p_ids: Default::default(),
+			voice_ids: Default::default(),
+			stats: ConnectionStats::new(),
+			voice_queue: VoiceQueue::default(),
+			reassemble_voice: false,
+			voice_fragmented_queue: HashMap::new(),
 		};
 		if is_client {
 			// The first command is sent as part of the C2SInit::Init4 packet
@@ -181,6 +263,14 @@ impl Connection {
 		res
 	}
 
+	/// Get the packet counters together with the current state of the
+	/// resender.
+	pub fn get_stats(&self) -> ConnectionStats {
+		let mut stats = self.stats.clone();
+		stats.resend = self.resender.get_stats();
+		stats
+	}
+
 	/// Check if a given id is in the receive window.
 	///
 	/// Returns
@@ -203,6 +293,20 @@ impl Connection {
 		let limit = ((u32::from(cur_next) + u32::from(u16::MAX) / 2)
 			% u32::from(u16::MAX)) as u16;
 		let gen = self.incoming_p_ids[type_i].0;
+
+		// Voice packets, which arrive a bit too late, can still be played,
+		// unless they were already received.
+		let late = cur_next.wrapping_sub(p_id);
+		if p_type.is_voice() && late > 0 && late <= crate::MAX_VOICE_LATENESS
+		{
+			return (
+				!self.voice_ids[type_i].contains(p_id, cur_next),
+				if p_id < cur_next { gen } else { gen.wrapping_sub(1) },
+				cur_next,
+				limit,
+			);
+		}
+
 		(
 			(cur_next < limit && p_id >= cur_next && p_id < limit)
 				|| (cur_next > limit && (p_id >= cur_next || p_id < limit)),
@@ -213,6 +317,60 @@ impl Connection {
 	}
 }
 
+impl Default for VoiceQueue {
+	fn default() -> Self {
+		Self {
+			max_len: 5,
+			max_delay: Duration::from_millis(100),
+			queue: VecDeque::new(),
+		}
+	}
+}
+
+impl VoiceQueue {
+	/// Add a packet to the queue.
+	///
+	/// Returns the number of dropped packets.
+	fn push(&mut self, p_type: PacketType, p_id: u16, packet: Bytes) -> u64 {
+		let mut dropped = 0;
+		while !self.queue.is_empty() && self.queue.len() >= self.max_len {
+			self.queue.pop_front();
+			dropped += 1;
+		}
+		self.queue.push_back((Instant::now(), p_type, p_id, packet));
+		dropped
+	}
+
+	/// Send as many packets as possible from the queue into the sink and drop
+	/// stale packets.
+	///
+	/// Returns the number of dropped packets.
+	fn flush(
+		&mut self,
+		address: SocketAddr,
+		sink: &mut mpsc::Sender<(SocketAddr, Bytes)>,
+	) -> Result<u64, Error>
+	{
+		let mut dropped = 0;
+		let now = Instant::now();
+		while let Some((time, p_type, p_id, packet)) = self.queue.pop_front() {
+			if time + self.max_delay < now {
+				dropped += 1;
+				continue;
+			}
+			if let AsyncSink::NotReady((_, packet)) = sink
+				.start_send((address, packet))
+				.map_err(|e| {
+					format_err!("Failed to send udp packet ({:?})", e)
+				})? {
+				self.queue.push_front((time, p_type, p_id, packet));
+				break;
+			}
+		}
+		Ok(dropped)
+	}
+}
+
 pub struct ConnectionUdpPacketSink<T: Send + 'static> {
 	con: ConnectionValueWeak<T>,
 	address: SocketAddr,
@@ -255,6 +413,21 @@ impl<T: Send + 'static> Sink for ConnectionUdpPacketSink<T> {
 					Err(format_err!("Connection is gone").into())
 				}
 			}
+			PacketType::Voice | PacketType::VoiceWhisper => {
+				if let Some(mutex) = self.con.mutex.upgrade() {
+					let mut con = mutex.lock();
+					let con = &mut con.1;
+					let mut dropped =
+						con.voice_queue.push(p_type, p_id, udp_packet);
+					dropped += con
+						.voice_queue
+						.flush(self.address, &mut self.udp_packet_sink)?;
+					con.stats.dropped_voice += dropped;
+					Ok(AsyncSink::Ready)
+				} else {
+					Err(format_err!("Connection is gone").into())
+				}
+			}
 			_ => Ok(
 				match self
 					.udp_packet_sink
@@ -271,10 +444,90 @@ impl<T: Send + 'static> Sink for ConnectionUdpPacketSink<T> {
 		}
 	}
 
+	/// Voice packets, which are still in the queue, are sent with the next
+	/// call to `start_send` or `poll_complete`.
 	fn poll_complete(&mut self) -> futures::Poll<(), Self::SinkError> {
+		if let Some(mutex) = self.con.mutex.upgrade() {
+			let mut con = mutex.lock();
+			let con = &mut con.1;
+			let dropped = con
+				.voice_queue
+				.flush(self.address, &mut self.udp_packet_sink)?;
+			con.stats.dropped_voice += dropped;
+		}
 		self.udp_packet_sink.poll_complete().map_err(|e| {
 			format_err!("Failed to complete sending udp packet ({:?})", e)
 				.into()
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use slog::{o, Logger};
+
+	use super::*;
+	use crate::resend::ResendConfig;
+
+	fn connection() -> Connection {
+		let logger = Logger::root(slog::Discard, o!());
+		let (udp_send, _) = mpsc::channel(1);
+		let (s2c_init_send, _) = mpsc::unbounded();
+		let (c2s_init_send, _) = mpsc::unbounded();
+		let (command_send, _) = mpsc::unbounded();
+		let (audio_send, _) = mpsc::unbounded();
+		Connection::new(
+			"127.0.0.1:9987".parse().unwrap(),
+			DefaultResender::new(ResendConfig::default(), logger.clone()),
+			logger,
+			udp_send,
+			true,
+			s2c_init_send,
+			c2s_init_send,
+			command_send,
+			audio_send,
+		)
+	}
+
+	/// Accept a voice packet like the packet codec does.
+	fn receive_voice(con: &mut Connection, p_id: u16) -> bool {
+		let (in_recv_win, gen_id, cur_next, _) =
+			con.in_receive_window(PacketType::Voice, p_id);
+		if in_recv_win {
+			con.voice_ids[0].receive(p_id, cur_next);
+			if p_id.wrapping_sub(cur_next) < u16::MAX / 2 {
+				con.incoming_p_ids[0] = (gen_id, p_id.wrapping_add(1));
+			}
+		}
+		in_recv_win
+	}
+
+	#[test]
+	fn duplicate_voice() {
+		let mut con = connection();
+		assert!(receive_voice(&mut con, 0));
+		assert!(receive_voice(&mut con, 3));
+		// The same id again
+		assert!(!receive_voice(&mut con, 3));
+		// Late packets are accepted once
+		assert!(receive_voice(&mut con, 1));
+		assert!(!receive_voice(&mut con, 1));
+		assert!(!receive_voice(&mut con, 0));
+		assert!(receive_voice(&mut con, 2));
+	}
+
+	#[test]
+	fn recent_ids() {
+		let mut ids = RecentIds::default();
+		assert!(ids.receive(u16::MAX, u16::MAX));
+		assert!(ids.contains(u16::MAX, 0));
+		// Wrap around and skip 0
+		assert!(ids.receive(1, 0));
+		assert!(!ids.contains(0, 2));
+		assert!(ids.receive(0, 2));
+		assert!(!ids.receive(0, 2));
+		// Too old
+		assert!(ids.contains(100, 200));
+		assert!(!ids.receive(100, 200));
+	}
+}
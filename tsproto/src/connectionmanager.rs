@@ -5,10 +5,11 @@ use std::net::SocketAddr;
 use bytes::Bytes;
 use futures::Sink;
 
+use crate::algorithms as algs;
 use crate::connection::Connection;
-use crate::handler_data::PacketHandler;
-use crate::packets::{InPacket, PacketType};
-use crate::Error;
+use crate::handler_data::{ConnectionValue, PacketHandler};
+use crate::packets::{Flags, InPacket, PacketType};
+use crate::{Error, LockedHashMap};
 
 /// The unique identification of a connection is handled by the implementation.
 pub trait ConnectionManager: Send {
@@ -32,6 +33,25 @@ pub trait ConnectionManager: Send {
 		src_addr: SocketAddr,
 		udp_packet: &InPacket,
 	) -> Self::Key;
+
+	/// Find the connection of an incoming udp packet.
+	///
+	/// Returns `None` if the packet belongs to no known connection. The
+	/// default implementation looks up the key from [`get_connection_key`].
+	///
+	/// [`get_connection_key`]: #tymethod.get_connection_key
+	fn find_connection(
+		connections: &LockedHashMap<
+			Self::Key,
+			ConnectionValue<Self::AssociatedData>,
+		>,
+		src_addr: SocketAddr,
+		udp_packet: &InPacket,
+	) -> Option<ConnectionValue<Self::AssociatedData>>
+	{
+		let key = Self::get_connection_key(src_addr, udp_packet);
+		connections.read().get(&key).cloned()
+	}
 }
 
 /// Events to inform a resender of the current state of a connection.
@@ -126,3 +146,320 @@ impl<PH: PacketHandler<T>, T: Send + 'static> ConnectionManager
 
 	fn get_connection_key(addr: SocketAddr, _: &InPacket) -> Self::Key { addr }
 }
+
+/// A connectionmanager, which allows multiple connections to the same server
+/// over one socket.
+///
+/// A connection is identified by the address of the server and a local id.
+/// The local id is only used to distinguish connections to the same server, it
+/// is not the client id that the server assigns.
+///
+/// Packets from the server contain no client id, so the connection of an
+/// incoming packet is found by its mac. Unencrypted packets contain the shared
+/// mac of their connection, which is cheap to compare. Encrypted packets are
+/// decrypted with the keys of each connection to the server, until one
+/// succeeds. The connections which expect the packet id next are tried first,
+/// so most packets are decrypted once, but the worst case needs one try per
+/// connection. The `shared_connection` benchmark measures this.
+///
+/// Packets during the handshake are not really encrypted, so only one
+/// connection to a server should do the handshake at a time. Packets which
+/// cannot be decrypted by any connection are dropped.
+///
+/// `T` contains associated data that will be saved for each connection.
+pub struct SharedConnectionManager<PH: PacketHandler<T>, T: Send + 'static> {
+	next_id: u16,
+	phantom: PhantomData<T>,
+	phantom2: PhantomData<PH>,
+}
+
+impl<PH: PacketHandler<T>, T: Send + 'static> Default
+	for SharedConnectionManager<PH, T>
+{
+	fn default() -> Self {
+		SharedConnectionManager {
+			next_id: 0,
+			phantom: PhantomData,
+			phantom2: PhantomData,
+		}
+	}
+}
+
+impl<PH: PacketHandler<T>, T: Send + 'static> SharedConnectionManager<PH, T> {
+	/// Create a new connection manager.
+	pub fn new() -> Self { Self::default() }
+}
+
+impl<PH: PacketHandler<T>, T: Send + 'static> ConnectionManager
+	for SharedConnectionManager<PH, T>
+{
+	type Key = (SocketAddr, u16);
+	type AssociatedData = T;
+	type PacketHandler = PH;
+
+	fn new_connection_key(
+		&mut self,
+		_: &mut Self::AssociatedData,
+		con: &mut Connection,
+	) -> Self::Key
+	{
+		let id = self.next_id;
+		self.next_id = self.next_id.wrapping_add(1);
+		(con.address, id)
+	}
+
+	/// The key cannot be computed from a packet alone, this is the key of the
+	/// first connection to the address.
+	fn get_connection_key(addr: SocketAddr, _: &InPacket) -> Self::Key {
+		(addr, 0)
+	}
+
+	fn find_connection(
+		connections: &LockedHashMap<Self::Key, ConnectionValue<T>>,
+		src_addr: SocketAddr,
+		udp_packet: &InPacket,
+	) -> Option<ConnectionValue<T>>
+	{
+		// Do not hold the lock of the list while locking a connection
+		let candidates = connections
+			.read()
+			.iter()
+			.filter(|(k, _)| k.0 == src_addr)
+			.map(|(_, c)| c.clone())
+			.collect::<Vec<_>>();
+		if candidates.len() <= 1 {
+			return candidates.into_iter().next();
+		}
+
+		let header = udp_packet.header();
+		let p_type = header.packet_type();
+		let p_id = header.packet_id();
+		if header.flags().contains(Flags::UNENCRYPTED) {
+			// Init packets belong to the connection in the handshake
+			return candidates.into_iter().find(|c| {
+				let con = c.mutex.lock();
+				match &con.1.params {
+					Some(params) => {
+						p_type != PacketType::Init
+							&& *header.mac() == params.shared_mac
+					}
+					None => p_type == PacketType::Init,
+				}
+			});
+		}
+
+		if algs::decrypt_fake(udp_packet).is_ok() {
+			// Prefer the connection which is still waiting for its keys
+			let no_params = candidates
+				.iter()
+				.find(|c| c.mutex.lock().1.params.is_none());
+			return no_params
+				.or_else(|| {
+					candidates.iter().find(|c| in_handshake(&c.mutex.lock().1))
+				})
+				.cloned();
+		}
+
+		// Sort by the distance to the next expected packet id, late packets
+		// come last.
+		let mut candidates = candidates
+			.into_iter()
+			.filter_map(|c| {
+				let distance = {
+					let con = c.mutex.lock();
+					con.1.params.as_ref()?;
+					let (_, _, next, _) = con.1.in_receive_window(p_type, p_id);
+					p_id.wrapping_sub(next)
+				};
+				Some((distance, c))
+			})
+			.collect::<Vec<_>>();
+		candidates.sort_by_key(|(distance, _)| *distance);
+
+		candidates.into_iter().map(|(_, c)| c).find(|c| {
+			let mut con = c.mutex.lock();
+			let con = &mut con.1;
+			let (in_window, mut gen_id, next, _) =
+				con.in_receive_window(p_type, p_id);
+			if !in_window {
+				// Late packets still need an ack, they belong to the current
+				// or the last generation.
+				let cur_gen = con.incoming_p_ids[p_type as usize].0;
+				gen_id =
+					if p_id < next { cur_gen } else { cur_gen.wrapping_sub(1) };
+			}
+			if let Some(params) = &mut con.params {
+				algs::decrypt(
+					udp_packet,
+					gen_id,
+					&params.shared_iv,
+					&mut params.key_cache,
+				)
+				.is_ok()
+			} else {
+				false
+			}
+		})
+	}
+}
+
+/// If the packets of a connection can still be fake encrypted.
+///
+/// The first acks from the server are fake encrypted, until the `clientinit`,
+/// which is the third command, is acknowledged.
+fn in_handshake(con: &Connection) -> bool {
+	con.params.is_none()
+		|| con.outgoing_p_ids[PacketType::Command as usize] <= (0, 3)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+	use std::sync::Arc;
+
+	use futures::sync::mpsc;
+	use futures::Stream;
+	use parking_lot::RwLock;
+	use slog::{o, Logger};
+
+	use super::*;
+	use crate::connection::{ConnectedParams, SharedIv};
+	use crate::crypto::EccKeyPrivP256;
+	use crate::packet_codec::PacketCodecSender;
+	use crate::packets::*;
+	use crate::resend::{DefaultResender, ResendConfig};
+
+	struct Handler;
+
+	impl PacketHandler<()> for Handler {
+		fn new_connection<S1, S2, S3, S4>(
+			&mut self,
+			_: &ConnectionValue<()>,
+			_: S1,
+			_: S2,
+			_: S3,
+			_: S4,
+		) where
+			S1: Stream<Item = InS2CInit, Error = Error> + Send + 'static,
+			S2: Stream<Item = InC2SInit, Error = Error> + Send + 'static,
+			S3: Stream<Item = InCommand, Error = Error> + Send + 'static,
+			S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
+		{
+		}
+	}
+
+	type CM = SharedConnectionManager<Handler, ()>;
+
+	fn connection(is_client: bool, params: Option<u8>) -> Connection {
+		let logger = Logger::root(slog::Discard, o!());
+		let (udp_send, _) = mpsc::channel(1);
+		let (s2c_init_send, _) = mpsc::unbounded();
+		let (c2s_init_send, _) = mpsc::unbounded();
+		let (command_send, _) = mpsc::unbounded();
+		let (audio_send, _) = mpsc::unbounded();
+		let mut con = Connection::new(
+			"127.0.0.1:9987".parse().unwrap(),
+			DefaultResender::new(ResendConfig::default(), logger.clone()),
+			logger,
+			udp_send,
+			is_client,
+			s2c_init_send,
+			c2s_init_send,
+			command_send,
+			audio_send,
+		);
+		con.params = params.map(|i| {
+			ConnectedParams::new(
+				EccKeyPrivP256::create().unwrap().to_pub(),
+				SharedIv::Protocol31([i; 64]),
+				[i; 8],
+			)
+		});
+		con
+	}
+
+	/// Create a packet from the server of the connection with the params `i`.
+	///
+	/// Without params, the packet is fake encrypted.
+	fn packet(i: Option<u8>, p_type: PacketType) -> InPacket {
+		let mut server = connection(false, i);
+		// The first command is fake encrypted
+		server.outgoing_p_ids[PacketType::Command as usize] = (0, 5);
+		let packet = if p_type == PacketType::Command {
+			OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+				Direction::S2C,
+				p_type,
+				"notifytest",
+				std::iter::empty(),
+				std::iter::empty(),
+			)
+		} else {
+			OutPacket::new_with_dir(Direction::S2C, Flags::empty(), p_type)
+		};
+		let mut packets = PacketCodecSender::new(false)
+			.encode_packet(&mut server, packet)
+			.unwrap();
+		InPacket::try_new(packets.remove(0).1, Direction::S2C).unwrap()
+	}
+
+	fn find(
+		connections: &LockedHashMap<(SocketAddr, u16), ConnectionValue<()>>,
+		packet: &InPacket,
+	) -> Option<(SocketAddr, u16)>
+	{
+		let addr = "127.0.0.1:9987".parse().unwrap();
+		let con = CM::find_connection(connections, addr, packet)?;
+		connections
+			.read()
+			.iter()
+			.find(|(_, c)| **c == con)
+			.map(|(k, _)| *k)
+	}
+
+	#[test]
+	fn shared_connections() {
+		let mut manager = CM::new();
+		let connections = Arc::new(RwLock::new(HashMap::new()));
+		let observer = Arc::new(RwLock::new(HashMap::new()));
+		let mut add = |mut con: Connection| {
+			let key = manager.new_connection_key(&mut (), &mut con);
+			connections
+				.write()
+				.insert(key, ConnectionValue::new((), con, observer.clone()));
+			key
+		};
+
+		let owner = |i, p_type| find(&connections, &packet(i, p_type));
+
+		let a = add(connection(true, Some(1)));
+		// A single connection gets all packets
+		assert_eq!(owner(Some(2), PacketType::Command), Some(a));
+
+		let b = add(connection(true, Some(2)));
+		assert_ne!(a, b);
+		assert_eq!(owner(Some(1), PacketType::Command), Some(a));
+		assert_eq!(owner(Some(2), PacketType::Command), Some(b));
+		// Unencrypted packets are found by the shared mac
+		assert_eq!(owner(Some(1), PacketType::Ping), Some(a));
+		assert_eq!(owner(Some(2), PacketType::Ping), Some(b));
+		// Packets of unknown connections are dropped
+		assert_eq!(owner(Some(3), PacketType::Command), None);
+		assert_eq!(owner(Some(3), PacketType::Ping), None);
+
+		// Duplicated packets are found, so they can be acknowledged again
+		for con in connections.read().values() {
+			con.mutex.lock().1.incoming_p_ids[PacketType::Command as usize] =
+				(0, 6);
+		}
+		assert_eq!(owner(Some(1), PacketType::Command), Some(a));
+		assert_eq!(owner(Some(2), PacketType::Command), Some(b));
+
+		// Fake encrypted packets belong to the connection in the handshake
+		let c = add(connection(true, None));
+		assert_eq!(owner(None, PacketType::Command), Some(c));
+		// Unknown packets do not
+		assert_eq!(owner(Some(3), PacketType::Command), None);
+		assert_eq!(owner(Some(3), PacketType::Ping), None);
+		assert_eq!(owner(Some(2), PacketType::Ping), Some(b));
+	}
+}
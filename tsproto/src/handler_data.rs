@@ -15,6 +15,7 @@ use {slog, slog_async, slog_term, tokio};
 use crate::connection::*;
 use crate::connectionmanager::ConnectionManager;
 use crate::crypto::EccKeyPrivP256;
+use crate::impairment::{ImpairedStream, ImpairmentConfig};
 use crate::packet_codec::{PacketCodecReceiver, PacketCodecSender};
 use crate::packets::*;
 use crate::resend::DefaultResender;
@@ -147,6 +148,9 @@ impl<T: Send + 'static> ConnectionValue<T> {
 			Ok(r) => r,
 			Err(e) => return Box::new(stream::once(Err(e))),
 		};
+		for (p_id, p) in &udp_packets {
+			con.1.stats.packet_sent(p_type, *p_id, p.len());
+		}
 
 		let udp_packets = udp_packets
 			.into_iter()
@@ -328,6 +332,34 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		connection_manager: CM,
 		logger: L,
 	) -> Result<Arc<Mutex<Self>>>
+	{
+		Self::new_impaired(
+			local_addr,
+			private_key,
+			is_client,
+			unknown_udp_packet_sink,
+			packet_handler,
+			connection_manager,
+			ImpairmentConfig::default(),
+			logger,
+		)
+	}
+
+	/// Create a socket, which simulates a bad network connection.
+	///
+	/// The impairment is applied to incoming and outgoing packets. This should
+	/// only be used for testing.
+	#[allow(clippy::too_many_arguments)]
+	pub fn new_impaired<L: Into<Option<slog::Logger>>>(
+		local_addr: SocketAddr,
+		private_key: EccKeyPrivP256,
+		is_client: bool,
+		unknown_udp_packet_sink: Option<mpsc::Sender<(SocketAddr, InPacket)>>,
+		packet_handler: CM::PacketHandler,
+		connection_manager: CM,
+		impairment: ImpairmentConfig,
+		logger: L,
+	) -> Result<Arc<Mutex<Self>>>
 	{
 		let logger = logger.into().unwrap_or_else(|| {
 			let decorator = slog_term::TermDecorator::new().build();
@@ -342,6 +374,11 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		let local_addr = socket.local_addr().unwrap_or(local_addr);
 		debug!(logger, "Listening"; "local_addr" => %local_addr);
 		let (sink, stream) = UdpFramed::new(socket, BytesCodec::new()).split();
+		// Use a different seed for incoming packets
+		let stream = ImpairedStream::new(stream, ImpairmentConfig {
+			seed: impairment.seed.wrapping_add(1),
+			..impairment.clone()
+		});
 
 		let (exit_send, exit_recv) = oneshot::channel();
 		let (udp_packet_sink, udp_packet_sink_sender) =
@@ -378,7 +415,7 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 
 		let out_udp_packet_observer = data.out_udp_packet_observer.clone();
 		tokio::spawn(
-			udp_packet_sink_sender
+			ImpairedStream::new(udp_packet_sink_sender, impairment)
 				.map(move |(addr, p)| {
 					for o in out_udp_packet_observer.read().values() {
 						o.observe(addr, &p);
@@ -510,6 +547,11 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 			error!(logger, "Resend future failed"; "error" => ?e);
 		}));
 
+		// Start pinging
+		let con_val = self.connections.read()[&key].downgrade();
+		let logger = self.logger.new(o!("addr" => addr.to_string()));
+		::tokio::spawn(crate::stats::ping_connection(con_val, logger));
+
 		key
 	}
 
@@ -546,6 +588,22 @@ impl<CM: ConnectionManager + 'static> Data<CM> {
 		self.connections.read().get(key).cloned()
 	}
 
+	/// Get the key of a connection.
+	///
+	/// Returns `None` if the connection does not exist anymore.
+	pub fn get_connection_key(
+		&self,
+		con: &ConnectionValueWeak<CM::AssociatedData>,
+	) -> Option<CM::Key>
+	{
+		let con = con.upgrade()?;
+		self.connections
+			.read()
+			.iter()
+			.find(|(_, c)| **c == con)
+			.map(|(k, _)| k.clone())
+	}
+
 	pub fn wait_for_disconnect(
 		&mut self,
 		key: CM::Key,
@@ -0,0 +1,217 @@
+//! Simulate a bad network connection.
+//!
+//! This is meant for testing. All udp packets of a socket can be dropped,
+//! duplicated, reordered and delayed. The decisions are taken by a seeded
+//! random number generator, so a test run can be reproduced.
+//!
+//! Use [`client::new_impaired`] or [`Data::new_impaired`] to create a socket
+//! with an impaired connection.
+//!
+//! [`client::new_impaired`]: ../client/fn.new_impaired.html
+//! [`Data::new_impaired`]: ../handler_data/struct.Data.html#method.new_impaired
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll, Stream};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::timer::Delay;
+
+/// Configure how packets are impaired.
+///
+/// Probabilities are between `0` and `1`. The default does not change the
+/// packets at all.
+#[derive(Clone, Debug, Default)]
+pub struct ImpairmentConfig {
+	/// The seed for the random number generator.
+	pub seed: u64,
+	/// The probability that a packet gets dropped.
+	pub loss: f64,
+	/// The probability that a packet is sent twice.
+	pub duplicate: f64,
+	/// The probability that a packet is delayed additionally by
+	/// `reorder_delay`, so later packets overtake it.
+	pub reorder: f64,
+	pub reorder_delay: Duration,
+	/// All packets are delayed by this duration.
+	pub delay: Duration,
+	/// A random additional delay between zero and this duration.
+	pub jitter: Duration,
+}
+
+/// Counters of an [`ImpairedStream`].
+///
+/// [`ImpairedStream`]: struct.ImpairedStream.html
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ImpairmentStats {
+	pub passed: u64,
+	pub dropped: u64,
+	pub duplicated: u64,
+	pub reordered: u64,
+}
+
+/// Applies an [`ImpairmentConfig`] to the items of a stream.
+///
+/// [`ImpairmentConfig`]: struct.ImpairmentConfig.html
+pub struct ImpairedStream<S: Stream> {
+	inner: S,
+	config: ImpairmentConfig,
+	rng: StdRng,
+	pub stats: ImpairmentStats,
+	/// Items with their release time, sorted by the release time.
+	///
+	/// Items with the same release time keep their order.
+	queue: Vec<(Instant, S::Item)>,
+	timer: Delay,
+	inner_done: bool,
+}
+
+impl ImpairmentConfig {
+	/// Returns `true` if packets are passed through without changes.
+	pub fn is_noop(&self) -> bool {
+		self.loss <= 0.0
+			&& self.duplicate <= 0.0
+			&& self.reorder <= 0.0
+			&& self.delay == Duration::from_secs(0)
+			&& self.jitter == Duration::from_secs(0)
+	}
+}
+
+impl<S: Stream> ImpairedStream<S>
+where S::Item: Clone
+{
+	pub fn new(inner: S, config: ImpairmentConfig) -> Self {
+		let rng = StdRng::seed_from_u64(config.seed);
+		Self {
+			inner,
+			config,
+			rng,
+			stats: ImpairmentStats::default(),
+			queue: Vec::new(),
+			timer: Delay::new(Instant::now()),
+			inner_done: false,
+		}
+	}
+
+	fn chance(&mut self, p: f64) -> bool { p > 0.0 && self.rng.gen::<f64>() < p }
+
+	fn push(&mut self, item: S::Item, now: Instant) {
+		if self.chance(self.config.loss) {
+			self.stats.dropped += 1;
+			return;
+		}
+		let copies = if self.chance(self.config.duplicate) {
+			self.stats.duplicated += 1;
+			2
+		} else {
+			1
+		};
+		self.stats.passed += 1;
+
+		for _ in 0..copies {
+			let mut release = now + self.config.delay;
+			if self.config.jitter > Duration::from_secs(0) {
+				let jitter = self.config.jitter;
+				let nanos = jitter.as_secs() * 1_000_000_000
+					+ u64::from(jitter.subsec_nanos());
+				let factor = self.rng.gen::<f64>();
+				release += Duration::from_nanos((nanos as f64 * factor) as u64);
+			}
+			if self.chance(self.config.reorder) {
+				self.stats.reordered += 1;
+				release += self.config.reorder_delay;
+			}
+
+			let i = self
+				.queue
+				.iter()
+				.position(|(r, _)| *r > release)
+				.unwrap_or_else(|| self.queue.len());
+			self.queue.insert(i, (release, item.clone()));
+		}
+	}
+}
+
+impl<S: Stream> Stream for ImpairedStream<S>
+where S::Item: Clone
+{
+	type Item = S::Item;
+	type Error = S::Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		if self.config.is_noop() {
+			return self.inner.poll();
+		}
+
+		while !self.inner_done {
+			match self.inner.poll()? {
+				Async::Ready(Some(item)) => self.push(item, Instant::now()),
+				Async::Ready(None) => self.inner_done = true,
+				Async::NotReady => break,
+			}
+		}
+
+		loop {
+			let release = match self.queue.first() {
+				Some((r, _)) => *r,
+				None if self.inner_done => return Ok(Async::Ready(None)),
+				None => return Ok(Async::NotReady),
+			};
+			if release <= Instant::now() {
+				return Ok(Async::Ready(Some(self.queue.remove(0).1)));
+			}
+
+			self.timer.reset(release);
+			match self.timer.poll() {
+				Ok(Async::Ready(())) => {}
+				Ok(Async::NotReady) => return Ok(Async::NotReady),
+				// Without a timer, release the packet immediately
+				Err(_) => return Ok(Async::Ready(Some(self.queue.remove(0).1))),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::stream;
+
+	fn run(config: ImpairmentConfig) -> (Vec<u32>, ImpairmentStats) {
+		let mut s = ImpairedStream::new(
+			stream::iter_ok::<_, ()>(0..1000),
+			config,
+		);
+		let mut res = Vec::new();
+		// No delay is configured, so the timer is not needed
+		while let Async::Ready(Some(i)) = s.poll().unwrap() {
+			res.push(i);
+		}
+		(res, s.stats)
+	}
+
+	#[test]
+	fn seeded_loss() {
+		let config = ImpairmentConfig {
+			seed: 42,
+			loss: 0.1,
+			duplicate: 0.05,
+			..Default::default()
+		};
+		let (res, stats) = run(config.clone());
+		assert_eq!(stats.passed + stats.dropped, 1000);
+		assert_eq!(res.len() as u64, stats.passed + stats.duplicated);
+		assert!(stats.dropped > 50 && stats.dropped < 150);
+		// Packets stay in order
+		assert!(res.windows(2).all(|w| w[0] <= w[1]));
+
+		// The same seed gives the same result
+		assert_eq!(run(config).0, res);
+	}
+
+	#[test]
+	fn noop() {
+		let (res, stats) = run(ImpairmentConfig::default());
+		assert_eq!(res, (0..1000).collect::<Vec<_>>());
+		assert_eq!(stats, ImpairmentStats::default());
+	}
+}
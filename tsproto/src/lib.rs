@@ -18,11 +18,14 @@ pub mod connection;
 pub mod connectionmanager;
 pub mod crypto;
 pub mod handler_data;
+pub mod impairment;
 pub mod license;
 pub mod log;
 pub mod packet_codec;
 pub mod packets;
 pub mod resend;
+pub mod stats;
+pub mod trace;
 pub mod utils;
 
 type Result<T> = std::result::Result<T, Error>;
@@ -35,6 +38,9 @@ const MAX_FRAGMENTS_LENGTH: usize = 40960;
 /// The maximum number of packets which are stored, if they are received
 /// out-of-order.
 const MAX_QUEUE_LEN: usize = 50;
+/// Voice packets, which are at most this many ids behind the receive window,
+/// are still accepted.
+const MAX_VOICE_LATENESS: u16 = 10;
 /// The maximum decompressed size of a packet.
 #[allow(clippy::unreadable_literal)]
 const MAX_DECOMPRESSED_SIZE: u32 = 40960;
@@ -1,8 +1,9 @@
+use std::collections::HashMap;
 use std::io::Cursor;
 use std::net::SocketAddr;
 use std::u16;
 
-use byteorder::{NetworkEndian, WriteBytesExt};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
 use bytes::Bytes;
 use futures::sync::mpsc;
 use futures::{future, Future, IntoFuture, Sink};
@@ -61,17 +62,15 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 	) -> impl Future<Item = (), Error = Error>
 	{
 		// Find the right connection
-		let cons = self.connections.read();
 		if let Some(con) =
-			cons.get(&CM::get_connection_key(addr, &packet)).cloned()
+			CM::find_connection(&self.connections, addr, &packet)
 		{
 			// If we are a client and have only a single connection, we will do the
 			// work inside this future and not spawn a new one.
 			let logger = self.logger.new(o!("addr" => addr));
 			let in_packet_observer = self.in_packet_observer.clone();
 			let in_command_observer = self.in_command_observer.clone();
-			if self.is_client && cons.len() == 1 {
-				drop(cons);
+			if self.is_client && self.connections.read().len() == 1 {
 				Self::connection_handle_udp_packet(
 					&logger,
 					in_packet_observer,
@@ -83,7 +82,6 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 				)
 				.into_future()
 			} else {
-				drop(cons);
 				let is_client = self.is_client;
 				tokio::spawn(future::lazy(move || {
 					if let Err(e) = Self::connection_handle_udp_packet(
@@ -102,7 +100,6 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 				future::ok(())
 			}
 		} else {
-			drop(cons);
 			// Unknown connection
 			if let Some(sink) = &mut self.unknown_udp_packet_sink {
 				// Don't block if the queue is full
@@ -158,6 +155,20 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 			return Err(Error::UnexpectedInitPacket);
 		}
 
+		if in_recv_win {
+			let header_len = if dir == Direction::S2C {
+				crate::S2C_HEADER_LEN
+			} else {
+				crate::C2S_HEADER_LEN
+			};
+			con.1.stats.packet_received(
+				p_type,
+				id,
+				cur_next,
+				header_len + packet.content().len(),
+			);
+		}
+
 		// Ignore range for acks
 		if p_type == PacketType::Ack
 			|| p_type == PacketType::AckLow
@@ -247,10 +258,15 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 					if p_type == PacketType::Ping {
 						ack = true;
 					}
-					// Update packet ids
+					// Update packet ids, late voice packets do not move
+					// the receive window back.
+					if p_type.is_voice() {
+						con.1.voice_ids[type_i].receive(id, cur_next);
+					}
 					let in_ids = &mut con.1.incoming_p_ids;
+					let is_late = id.wrapping_sub(cur_next) > u16::MAX / 2;
 					let (id, next_gen) = id.overflowing_add(1);
-					if p_type != PacketType::Init {
+					if p_type != PacketType::Init && !is_late {
 						in_ids[type_i] =
 							(if next_gen { gen_id + 1 } else { gen_id }, id);
 					}
@@ -263,13 +279,12 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 							PacketType::CommandLow
 						};
 						con.1.resender.ack_packet(p_type, ack_id);
-					} else if p_type.is_voice() {
-						// Seems to work better without assembling the first 3 voice packets
-						// Use handle_voice_packet to assemble fragmented voice packets
-						/*let mut res = Self::handle_voice_packet(&logger, params, &header, p_data);
-						let res = res.drain(..).map(|p|
-							(con_key.clone(), p)).collect();
-						Ok(res)*/
+					} else if p_type == PacketType::Pong {
+						if let Ok(ping_id) =
+							packet.content().read_u16::<NetworkEndian>()
+						{
+							con.1.stats.pong_received(ping_id);
+						}
 					}
 
 					// Call observer after handling acks
@@ -308,10 +323,23 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 
 		if let Some(packet) = packet_res? {
 			if p_type.is_voice() {
-				if let Err(e) =
-					con.1.audio_sink.unbounded_send(packet.into_audio()?)
-				{
-					error!(logger, "Failed to send packet to handler"; "error" => ?e);
+				// Seems to work better without assembling the first 3 voice
+				// packets, so it is disabled by default.
+				let packets = if con.1.reassemble_voice {
+					Self::handle_voice_packet(
+						logger,
+						&mut con.1.voice_fragmented_queue,
+						packet,
+					)
+				} else {
+					vec![packet]
+				};
+				for packet in packets {
+					if let Err(e) =
+						con.1.audio_sink.unbounded_send(packet.into_audio()?)
+					{
+						error!(logger, "Failed to send packet to handler"; "error" => ?e);
+					}
 				}
 			} else if p_type == PacketType::Init {
 				if is_client {
@@ -464,58 +492,51 @@ impl<CM: ConnectionManager + 'static> PacketCodecReceiver<CM> {
 		}
 	}
 
-	/*/// Handle `Voice` and `VoiceLow` packets.
+	/// Handle `Voice` and `VoiceWhisper` packets.
 	///
 	/// The first 3 packets for each audio transmission have the compressed flag
 	/// set, which means they are fragmented and should be concatenated.
 	fn handle_voice_packet(
-		logger: &slog::Logger,
-		params: &mut ConnectedParams,
-		header: &Header,
-		packet: PData,
-	) -> Vec<Packet> {
-		let cmd_i = if header.get_type() == PacketType::Voice {
-			0
-		} else {
-			1
-		};
-		let frag_queue = &mut params.voice_fragmented_queue[cmd_i];
-
-		let (id, from_id, codec_type, voice_data) = match packet {
-			PData::VoiceS2C { id, from_id, codec_type, voice_data } => (id, from_id, codec_type, voice_data),
-			PData::VoiceWhisperS2C { id, from_id, codec_type, voice_data } => (id, from_id, codec_type, voice_data),
-			_ => unreachable!("handle_voice_packet did get an unknown voice packet"),
-		};
-
-		if header.get_compressed() {
-			let queue = frag_queue.entry(from_id).or_insert_with(Vec::new);
-			// Append to fragments
-			if queue.len() < MAX_FRAGMENTS_LENGTH {
-				queue.extend_from_slice(&voice_data);
+		logger: &Logger,
+		frag_queue: &mut HashMap<(PacketType, u16), InPacket>,
+		packet: InPacket,
+	) -> Vec<InPacket>
+	{
+		// Only packets from the server contain the sending client
+		// (id: u16, from: u16, codec: u8, data)
+		if packet.direction() != Direction::S2C || packet.content().len() < 5 {
+			return vec![packet];
+		}
+		let p_type = packet.header().packet_type();
+		let compressed = packet.header().flags().contains(Flags::COMPRESSED);
+		let from = (&packet.content()[2..]).read_u16::<NetworkEndian>().unwrap();
+		let key = (p_type, from);
+
+		if compressed {
+			if let Some(first) = frag_queue.get_mut(&key) {
+				// Append to fragments
+				if first.content().len() < MAX_FRAGMENTS_LENGTH {
+					let mut content = first.take_content();
+					content.extend_from_slice(&packet.content()[5..]);
+					first.set_content(content);
+					return Vec::new();
+				}
+				warn!(logger, "Length of voice fragment queue exceeded";
+					"len" => first.content().len());
+			} else {
+				frag_queue.insert(key, packet);
 				return Vec::new();
 			}
-			warn!(logger, "Length of voice fragment queue exceeded"; "len" => queue.len());
 		}
 
 		let mut res = Vec::new();
-		if let Some(frags) = frag_queue.remove(&from_id) {
+		if let Some(frags) = frag_queue.remove(&key) {
 			// We got two packets
-			let packet_data = if header.get_type() == PacketType::Voice {
-				PData::VoiceS2C { id, from_id, codec_type, voice_data: frags }
-			} else {
-				PData::VoiceWhisperS2C { id, from_id, codec_type, voice_data: frags }
-			};
-			res.push(Packet::new(header.clone(), packet_data));
+			res.push(frags);
 		}
-
-		let packet_data = if header.get_type() == PacketType::Voice {
-			PData::VoiceS2C { id, from_id, codec_type, voice_data }
-		} else {
-			PData::VoiceWhisperS2C { id, from_id, codec_type, voice_data }
-		};
-		res.push(Packet::new(header.clone(), packet_data));
+		res.push(packet);
 		res
-	}*/
+	}
 }
 
 /// Encodes outgoing packets.
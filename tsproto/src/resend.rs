@@ -1,5 +1,5 @@
 use std::cmp::{Ord, Ordering};
-use std::collections::{binary_heap, BinaryHeap};
+use std::collections::{binary_heap, BinaryHeap, VecDeque};
 use std::convert::From;
 use std::hash::{Hash, Hasher};
 use std::mem;
@@ -9,7 +9,7 @@ use std::sync::Weak;
 use std::time::Instant;
 
 use bytes::Bytes;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use futures::sync::mpsc;
 use futures::task::{self, Task};
 use futures::{self, Async, Future, Sink};
@@ -34,6 +34,12 @@ struct SendRecord {
 	pub last: DateTime<Utc>,
 	/// How often the packet was already resent.
 	pub tries: usize,
+	/// The number of acks for later packets, which were received since the
+	/// last time this packet was sent.
+	pub later_acks: usize,
+	/// If the packet should be resent immediately because later packets were
+	/// acknowledged.
+	pub fast_resend: bool,
 	pub id: PacketId,
 	/// The packet of this record.
 	pub packet: Bytes,
@@ -76,8 +82,30 @@ impl Hash for SendRecord {
 	fn hash<H: Hasher>(&self, state: &mut H) { self.id.hash(state); }
 }
 
+/// Counters of the resender.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ResendStats {
+	/// How often a packet was sent again.
+	pub retransmits: u64,
+	/// How often a packet was resent because no ack was received in time.
+	pub timeouts: u64,
+	/// How often a packet was resent because acks for later packets were
+	/// received.
+	pub fast_retransmits: u64,
+	/// The number of packets, which are not yet acknowledged.
+	pub queue_len: usize,
+	/// The number of packets, which were sent but not yet acknowledged.
+	pub in_flight: usize,
+	/// The current size of the congestion window.
+	pub window: usize,
+}
+
 /// An implementation of a [`Resender`] that is provided by this library.
 ///
+/// The number of packets, which are sent but not yet acknowledged, is limited
+/// by a congestion window. The window grows when acks are received and shrinks
+/// when packets get lost.
+///
 /// [`Resender`]: ../connectionmanager/trait.Resender.html
 #[derive(Debug)]
 pub struct DefaultResender {
@@ -86,6 +114,14 @@ pub struct DefaultResender {
 	state: ResendStates,
 	config: ResendConfig,
 
+	/// Packets, which do not fit into the congestion window yet.
+	waiting: VecDeque<SendRecord>,
+	/// The size of the congestion window in packets.
+	window: f32,
+	/// The window grows exponentially until it reaches this threshold.
+	slow_start_threshold: f32,
+	stats: ResendStats,
+
 	/// Smoothed Round Trip Time
 	srtt: Duration,
 	/// Deviation of the srtt.
@@ -147,6 +183,8 @@ impl DefaultResender {
 	pub fn new(config: ResendConfig, logger: Logger) -> Self {
 		let srtt = config.srtt;
 		let srtt_dev = config.srtt_dev;
+		let window = config.initial_window as f32;
+		let slow_start_threshold = config.max_window as f32;
 		Self {
 			logger,
 			state: ResendStates::Connecting {
@@ -154,6 +192,10 @@ impl DefaultResender {
 				start_time: Utc::now(),
 			},
 			config,
+			waiting: VecDeque::new(),
+			window,
+			slow_start_threshold,
+			stats: ResendStats::default(),
 			srtt,
 			srtt_dev,
 
@@ -173,6 +215,97 @@ impl DefaultResender {
 		self.srtt = self.srtt * 7 / 8 + rtt / 8;
 	}
 
+	/// Get the counters of this resender.
+	pub fn get_stats(&self) -> ResendStats {
+		let mut stats = self.stats;
+		stats.queue_len = self.state.len() + self.waiting.len();
+		stats.in_flight = self.state.in_flight();
+		stats.window = self.get_window();
+		stats
+	}
+
+	/// The current size of the congestion window in packets.
+	fn get_window(&self) -> usize { std::cmp::max(self.window as usize, 1) }
+
+	/// A packet was acknowledged, so increase the window.
+	fn grow_window(&mut self) {
+		if self.window < self.slow_start_threshold {
+			self.window += 1.0;
+		} else {
+			self.window += 1.0 / self.window;
+		}
+		let max = self.config.max_window as f32;
+		if self.window > max {
+			self.window = max;
+		}
+	}
+
+	/// A packet got lost, so decrease the window.
+	///
+	/// If `timeout` is `false`, the loss was detected by acks of later
+	/// packets and the connection still works, so the window is only halved.
+	fn shrink_window(&mut self, timeout: bool) {
+		self.slow_start_threshold = (self.window / 2.0).max(2.0);
+		self.window = if timeout { 1.0 } else { self.slow_start_threshold };
+	}
+
+	/// Move waiting packets into the send queue while they fit into the
+	/// congestion window.
+	fn fill_window(&mut self) {
+		let window = self.get_window();
+		let mut moved = false;
+		while self.state.len() < window {
+			if let Some(rec) = self.waiting.pop_front() {
+				self.state.push(rec);
+				moved = true;
+			} else {
+				break;
+			}
+		}
+
+		// Notify the resender future that a new packet is available
+		if moved {
+			if let Some(ref task) = self.resender_future_task {
+				task.notify();
+			}
+		}
+	}
+
+	/// Count an acknowledgement for all sent packets with a lower id.
+	///
+	/// If a packet was overtaken by enough later packets, it is marked for an
+	/// immediate resend.
+	fn count_later_ack(&mut self, p_type: PacketType, p_id: u16) {
+		let threshold = self.config.fast_retransmit_acks;
+		let mut lost = false;
+		if let ResendStates::Normal { to_send } = &mut self.state {
+			let mut v = mem::replace(to_send, BinaryHeap::new()).into_vec();
+			for rec in &mut v {
+				if rec.id.0 != p_type
+					|| rec.tries == 0 || rec.fast_resend
+					|| p_id.wrapping_sub(rec.id.1) >= u16::max_value() / 2
+				{
+					continue;
+				}
+				rec.later_acks += 1;
+				if rec.later_acks >= threshold {
+					// Send as soon as possible
+					rec.fast_resend = true;
+					rec.last = Utc.timestamp(0, 0);
+					lost = true;
+				}
+			}
+			*to_send = v.into();
+		}
+
+		if lost {
+			self.shrink_window(false);
+			if let Some(ref task) = self.resender_future_task {
+				task.notify();
+			}
+		}
+	}
+
 	/// Replaces the current state by a new state and return the old state.
 	fn set_state(&mut self, state: ResendStates) -> ResendStates {
 		info!(self.logger, "Changed state"; "old" => self.state.get_name(),
@@ -248,6 +381,8 @@ impl Resender for DefaultResender {
 					now.naive_utc().signed_duration_since(rec.sent.naive_utc());
 				self.update_srtt(diff);
 			}
+			self.grow_window();
+			self.count_later_ack(p_type, p_id);
 		}
 
 		// Switch to Normal mode if we are currently in stalling or dead mode
@@ -277,6 +412,8 @@ impl Resender for DefaultResender {
 			}
 		}
 
+		self.fill_window();
+
 		// Notify, that a packet was removed from the queue
 		for t in self.resender_task.drain(..) {
 			t.notify()
@@ -294,13 +431,7 @@ impl Resender for DefaultResender {
 	}
 
 	fn is_empty(&self) -> bool {
-		match self.state {
-			ResendStates::Connecting { ref to_send, .. }
-			| ResendStates::Disconnecting { ref to_send, .. }
-			| ResendStates::Normal { ref to_send, .. } => to_send.is_empty(),
-			ResendStates::Stalling { ref to_send, .. }
-			| ResendStates::Dead { ref to_send, .. } => to_send.is_empty(),
-		}
+		self.state.len() == 0 && self.waiting.is_empty()
 	}
 
 	fn handle_event(&mut self, event: ResenderEvent) {
@@ -378,65 +509,36 @@ impl Sink for DefaultResender {
 		(p_type, p_id, packet): Self::SinkItem,
 	) -> futures::StartSend<Self::SinkItem, Self::SinkError>
 	{
-		let rec = SendRecord {
-			sent: Utc::now(),
-			last: Utc::now(),
-			tries: 0,
-			id: PacketId(p_type, p_id),
-			packet,
-		};
-
 		// Put the packet into the queue if there is space left
-		// otherwise, put it into rec_res.
-		let mut rec_res = None;
-		match &mut self.state {
-			ResendStates::Connecting {
-				to_send,
-				start_time,
-			}
-			| ResendStates::Disconnecting {
-				to_send,
-				start_time,
-			} => {
-				if to_send.len() >= self.config.max_send_queue_len {
-					rec_res = Some(rec);
-				} else {
-					to_send.push(rec);
-					// Update start time
-					*start_time = Utc::now();
-				}
-			}
-			ResendStates::Stalling { to_send, .. }
-			| ResendStates::Dead { to_send, .. } => {
-				if to_send.len() >= self.config.max_send_queue_len {
-					rec_res = Some(rec);
-				} else {
-					to_send.push(rec);
-				}
-			}
-			ResendStates::Normal { to_send } => {
-				if to_send.len() >= self.config.max_send_queue_len {
-					rec_res = Some(rec);
-				} else {
-					to_send.push(rec);
-				}
-			}
-		}
-
-		if let Some(rec) = rec_res {
+		if self.state.len() + self.waiting.len()
+			>= self.config.max_send_queue_len
+		{
 			// Set the task, so we get woken up if a place in the queue gets
 			// free.
 			self.resender_task.push(task::current());
-			Ok(futures::AsyncSink::NotReady((
-				rec.id.0, rec.id.1, rec.packet,
-			)))
-		} else {
-			// Notify the resender future that a new packet is available
-			if let Some(ref task) = self.resender_future_task {
-				task.notify();
+			return Ok(futures::AsyncSink::NotReady((p_type, p_id, packet)));
+		}
+
+		match &mut self.state {
+			ResendStates::Connecting { start_time, .. }
+			| ResendStates::Disconnecting { start_time, .. } => {
+				// Update start time
+				*start_time = Utc::now();
 			}
-			Ok(futures::AsyncSink::Ready)
+			_ => {}
 		}
+
+		self.waiting.push_back(SendRecord {
+			sent: Utc::now(),
+			last: Utc::now(),
+			tries: 0,
+			later_acks: 0,
+			fast_resend: false,
+			id: PacketId(p_type, p_id),
+			packet,
+		});
+		self.fill_window();
+		Ok(futures::AsyncSink::Ready)
 	}
 
 	fn poll_complete(&mut self) -> futures::Poll<(), Self::SinkError> {
@@ -480,6 +582,51 @@ impl<'a, T: Ord + 'a> From<binary_heap::PeekMut<'a, T>> for PeekMut<'a, T> {
 }
 
 impl ResendStates {
+	/// The number of packets in the queue.
+	fn len(&self) -> usize {
+		match *self {
+			ResendStates::Connecting { ref to_send, .. }
+			| ResendStates::Disconnecting { ref to_send, .. }
+			| ResendStates::Normal { ref to_send, .. } => to_send.len(),
+			ResendStates::Stalling { ref to_send, .. }
+			| ResendStates::Dead { ref to_send, .. } => to_send.len(),
+		}
+	}
+
+	/// The number of packets which were sent at least once.
+	fn in_flight(&self) -> usize {
+		match *self {
+			ResendStates::Connecting { ref to_send, .. }
+			| ResendStates::Disconnecting { ref to_send, .. }
+			| ResendStates::Normal { ref to_send, .. } => {
+				to_send.iter().filter(|r| r.tries != 0).count()
+			}
+			ResendStates::Stalling { ref to_send, .. }
+			| ResendStates::Dead { ref to_send, .. } => {
+				to_send.iter().filter(|r| r.tries != 0).count()
+			}
+		}
+	}
+
+	/// Add a packet to the queue.
+	fn push(&mut self, rec: SendRecord) {
+		match *self {
+			ResendStates::Connecting {
+				ref mut to_send, ..
+			}
+			| ResendStates::Disconnecting {
+				ref mut to_send, ..
+			}
+			| ResendStates::Normal { ref mut to_send } => to_send.push(rec),
+			ResendStates::Stalling {
+				ref mut to_send, ..
+			}
+			| ResendStates::Dead {
+				ref mut to_send, ..
+			} => to_send.push(rec),
+		}
+	}
+
 	/// Returns the next record which should be sent, if there is one.
 	fn peek_mut_next_record(&mut self) -> Option<PeekMut<SendRecord>> {
 		match *self {
@@ -549,7 +696,17 @@ pub struct ResendConfig {
 	pub srtt_dev: Duration,
 
 	/// The maximum number of not acknowledged packets which are stored.
+	///
+	/// Only packets in the congestion window are sent, the rest waits in the
+	/// queue.
 	pub max_send_queue_len: usize,
+	/// The start size of the congestion window.
+	pub initial_window: usize,
+	/// The maximum size of the congestion window.
+	pub max_window: usize,
+	/// Resend a packet immediately if acks for this many later packets were
+	/// received.
+	pub fast_retransmit_acks: usize,
 }
 
 impl Default for ResendConfig {
@@ -568,6 +725,9 @@ impl Default for ResendConfig {
 			srtt_dev: Duration::milliseconds(0),
 
 			max_send_queue_len: 50,
+			initial_window: 4,
+			max_window: 32,
+			fast_retransmit_acks: 3,
 		}
 	}
 }
@@ -852,6 +1012,7 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 
 				let is_normal_state;
 				let p_id;
+				let mut timed_out = false;
 				{
 					is_normal_state = if let ResendStates::Normal { .. } =
 						con.resender.state
@@ -864,17 +1025,27 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 					let mut rec =
 						con.resender.state.peek_mut_next_record().unwrap();
 					p_id = rec.id.1;
-					// Double srtt on packet loss
-					if rec.tries != 0
-						&& con.resender.srtt
-							< con.resender.config.normal_timeout
-					{
-						con.resender.srtt = con.resender.srtt * 2;
+					if rec.tries != 0 {
+						con.resender.stats.retransmits += 1;
+						if rec.fast_resend {
+							con.resender.stats.fast_retransmits += 1;
+						} else {
+							con.resender.stats.timeouts += 1;
+							timed_out = true;
+							// Double srtt on packet loss
+							if con.resender.srtt
+								< con.resender.config.normal_timeout
+							{
+								con.resender.srtt = con.resender.srtt * 2;
+							}
+						}
 					}
 
 					// Update record
 					rec.last = now;
 					rec.tries += 1;
+					rec.later_acks = 0;
+					rec.fast_resend = false;
 
 					if rec.tries != 1 {
 						let to_s = if self.is_client { "S" } else { "C" };
@@ -891,6 +1062,10 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 					}
 				}
 
+				if timed_out {
+					con.resender.shrink_window(true);
+				}
+
 				let next = now + rto;
 				let dur =
 					next.naive_utc().signed_duration_since(now.naive_utc());
@@ -926,3 +1101,63 @@ impl<CM: ConnectionManager + 'static> Future for ResendFuture<CM> {
 		Ok(futures::Async::NotReady)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use slog::o;
+
+	fn resender() -> DefaultResender {
+		let mut r = DefaultResender::new(
+			ResendConfig::default(),
+			Logger::root(slog::Discard, o!()),
+		);
+		r.handle_event(ResenderEvent::Connected);
+		for i in 0..6 {
+			r.start_send((PacketType::Command, i, Bytes::new())).unwrap();
+		}
+		r
+	}
+
+	/// Mark all packets in the window as sent.
+	fn send_all(r: &mut DefaultResender) {
+		if let ResendStates::Normal { to_send } = &mut r.state {
+			let mut v = mem::replace(to_send, BinaryHeap::new()).into_vec();
+			for rec in &mut v {
+				rec.tries = 1;
+			}
+			*to_send = v.into();
+		}
+	}
+
+	#[test]
+	fn congestion_window() {
+		let mut r = resender();
+		let stats = r.get_stats();
+		assert_eq!(stats.queue_len, 6);
+		assert_eq!(stats.window, 4);
+		assert_eq!(r.state.len(), 4);
+
+		send_all(&mut r);
+		assert_eq!(r.get_stats().in_flight, 4);
+		r.ack_packet(PacketType::Command, 0);
+		// Slow start
+		assert_eq!(r.get_stats().window, 5);
+		assert_eq!(r.state.len(), 5);
+		assert!(r.waiting.is_empty());
+	}
+
+	#[test]
+	fn fast_retransmit() {
+		let mut r = resender();
+		send_all(&mut r);
+		for i in 1..4 {
+			r.ack_packet(PacketType::Command, i);
+		}
+		// The window was halved after growing to 7
+		assert_eq!(r.get_stats().window, 3);
+		let rec = r.state.peek_mut_next_record().unwrap();
+		assert_eq!(rec.id.1, 0);
+		assert!(rec.fast_resend);
+	}
+}
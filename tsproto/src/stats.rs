@@ -0,0 +1,370 @@
+//! Packet counters and round trip time measurements of a connection.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Sink, Stream};
+use num_traits::ToPrimitive;
+use slog::{debug, Logger};
+use tokio::timer::Interval;
+
+use crate::connection::RecentIds;
+use crate::handler_data::ConnectionValueWeak;
+use crate::packets::*;
+use crate::resend::ResendStats;
+
+/// Send a ping this often.
+pub const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Keep the bandwidth history for this long.
+const BANDWIDTH_HISTORY: Duration = Duration::from_secs(60);
+/// The maximum number of pings which wait for a pong.
+///
+/// If more pings are sent, the oldest is counted as lost.
+const MAX_PENDING_PINGS: usize = 16;
+/// The number of packet types.
+pub(crate) const TYPE_COUNT: usize = 9;
+
+/// The packet types are grouped into these categories in the connection info
+/// of TeamSpeak.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PacketCategory {
+	/// Voice and whisper packets.
+	Speech,
+	/// Pings and pongs.
+	Keepalive,
+	/// Commands, acks and init packets.
+	Control,
+}
+
+/// The number of packets and bytes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Counter {
+	pub packets: u64,
+	pub bytes: u64,
+}
+
+/// Bytes sent and received in one second.
+#[derive(Clone, Debug)]
+struct BandwidthSlot {
+	start: Instant,
+	sent: [u64; 3],
+	received: [u64; 3],
+}
+
+/// Statistics of a single connection.
+///
+/// The counters are indexed by [`PacketType`], use [`get_sent`] and
+/// [`get_received`] to get the sum for a [`PacketCategory`].
+///
+/// [`PacketType`]: ../packets/enum.PacketType.html
+/// [`PacketCategory`]: enum.PacketCategory.html
+/// [`get_sent`]: #method.get_sent
+/// [`get_received`]: #method.get_received
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+	pub sent: [Counter; TYPE_COUNT],
+	pub received: [Counter; TYPE_COUNT],
+	/// Incoming packets which never arrived.
+	///
+	/// They are detected by gaps in the packet ids. Pings, which got no answer,
+	/// are counted as lost `Ping` packets.
+	pub lost: [u64; TYPE_COUNT],
+	/// The smoothed round trip time, measured with pings.
+	///
+	/// This is `None` until the first pong is received.
+	pub rtt: Option<Duration>,
+	/// The mean deviation of the round trip time.
+	pub jitter: Duration,
+	/// Outgoing voice packets, which were dropped because they could not be
+	/// sent in time.
+	pub dropped_voice: u64,
+	/// Counters of the resender for command packets.
+	///
+	/// This is only a snapshot, it gets filled by [`Connection::get_stats`].
+	///
+	/// [`Connection::get_stats`]: ../connection/struct.Connection.html#method.get_stats
+	pub resend: ResendStats,
+
+	/// The recently received packet ids, to recognize late packets which
+	/// were counted as lost.
+	recent_ids: [RecentIds; TYPE_COUNT],
+	/// Ids and send times of pings, which got no pong yet.
+	pending_pings: VecDeque<(u16, Instant)>,
+	bandwidth: VecDeque<BandwidthSlot>,
+}
+
+impl PacketCategory {
+	pub fn from_type(p_type: PacketType) -> Self {
+		match p_type {
+			PacketType::Voice | PacketType::VoiceWhisper => {
+				PacketCategory::Speech
+			}
+			PacketType::Ping | PacketType::Pong => PacketCategory::Keepalive,
+			_ => PacketCategory::Control,
+		}
+	}
+
+	fn index(self) -> usize {
+		match self {
+			PacketCategory::Speech => 0,
+			PacketCategory::Keepalive => 1,
+			PacketCategory::Control => 2,
+		}
+	}
+}
+
+impl ConnectionStats {
+	pub fn new() -> Self { Self::default() }
+
+	/// The sum of all sent packets in a category.
+	pub fn get_sent(&self, category: PacketCategory) -> Counter {
+		Self::sum(&self.sent, category)
+	}
+
+	/// The sum of all received packets in a category.
+	pub fn get_received(&self, category: PacketCategory) -> Counter {
+		Self::sum(&self.received, category)
+	}
+
+	/// The number of lost incoming packets in a category.
+	pub fn get_lost(&self, category: PacketCategory) -> u64 {
+		Self::types(category).map(|i| self.lost[i]).sum()
+	}
+
+	/// The ratio of lost incoming packets in a category, between `0` and `1`.
+	pub fn get_loss(&self, category: PacketCategory) -> f32 {
+		let lost = self.get_lost(category);
+		let total = lost + self.get_received(category).packets;
+		if total == 0 {
+			0.0
+		} else {
+			lost as f32 / total as f32
+		}
+	}
+
+	/// The ratio of lost incoming packets over all categories.
+	pub fn get_total_loss(&self) -> f32 {
+		let lost: u64 = self.lost.iter().sum();
+		let total = lost + self.received.iter().map(|c| c.packets).sum::<u64>();
+		if total == 0 {
+			0.0
+		} else {
+			lost as f32 / total as f32
+		}
+	}
+
+	/// The average sent and received bytes per second in a category, over the
+	/// last `period`.
+	///
+	/// The currently running second is not included.
+	pub fn get_bandwidth(&self, category: PacketCategory, period: Duration)
+		-> (u64, u64) {
+		let now = Instant::now();
+		let i = category.index();
+		let secs = std::cmp::max(period.as_secs(), 1);
+		let (sent, received) = self
+			.bandwidth
+			.iter()
+			.filter(|s| {
+				s.start + Duration::from_secs(1) <= now
+					&& s.start + period + Duration::from_secs(1) > now
+			})
+			.fold((0, 0), |(s, r), slot| (s + slot.sent[i], r + slot.received[i]));
+		(sent / secs, received / secs)
+	}
+
+	/// Count a packet which was sent.
+	pub fn packet_sent(&mut self, p_type: PacketType, p_id: u16, len: usize) {
+		let now = Instant::now();
+		let counter = &mut self.sent[p_type.to_usize().unwrap()];
+		counter.packets += 1;
+		counter.bytes += len as u64;
+		self.get_slot(now).sent[PacketCategory::from_type(p_type).index()] +=
+			len as u64;
+
+		if p_type == PacketType::Ping {
+			if self.pending_pings.len() >= MAX_PENDING_PINGS {
+				self.pending_pings.pop_front();
+				self.lost[PacketType::Ping.to_usize().unwrap()] += 1;
+			}
+			self.pending_pings.push_back((p_id, now));
+		}
+	}
+
+	/// Count a packet which was received.
+	///
+	/// `expected_id` is the next expected packet id for this type. If `p_id`
+	/// is higher, the packets in between are counted as lost.
+	pub fn packet_received(
+		&mut self,
+		p_type: PacketType,
+		p_id: u16,
+		expected_id: u16,
+		len: usize,
+	)
+	{
+		let type_i = p_type.to_usize().unwrap();
+		let counter = &mut self.received[type_i];
+		counter.packets += 1;
+		counter.bytes += len as u64;
+		self.get_slot(Instant::now()).received
+			[PacketCategory::from_type(p_type).index()] += len as u64;
+
+		// Commands are resent and init packets have no ids
+		if p_type != PacketType::Command
+			&& p_type != PacketType::CommandLow
+			&& p_type != PacketType::Init
+		{
+			let gap = p_id.wrapping_sub(expected_id);
+			let new = self.recent_ids[type_i].receive(p_id, expected_id);
+			if gap < u16::max_value() / 2 {
+				self.lost[type_i] += u64::from(gap);
+			} else if new {
+				// A late packet, which was already counted as lost
+				self.lost[type_i] = self.lost[type_i].saturating_sub(1);
+			}
+		}
+	}
+
+	/// Handle a pong for the ping with the given id.
+	pub fn pong_received(&mut self, p_id: u16) {
+		let now = Instant::now();
+		while let Some((id, sent)) = self.pending_pings.pop_front() {
+			if id == p_id {
+				self.update_rtt(now - sent);
+				break;
+			}
+			// Pings are answered in order, so this one got lost
+			self.lost[PacketType::Ping.to_usize().unwrap()] += 1;
+		}
+	}
+
+	fn update_rtt(&mut self, rtt: Duration) {
+		if let Some(srtt) = self.rtt {
+			let diff = if rtt > srtt { rtt - srtt } else { srtt - rtt };
+			self.jitter = self.jitter * 3 / 4 + diff / 4;
+			self.rtt = Some(srtt * 7 / 8 + rtt / 8);
+		} else {
+			self.jitter = rtt / 2;
+			self.rtt = Some(rtt);
+		}
+	}
+
+	fn sum(counters: &[Counter; TYPE_COUNT], category: PacketCategory)
+		-> Counter {
+		Self::types(category).fold(Counter::default(), |mut sum, i| {
+			sum.packets += counters[i].packets;
+			sum.bytes += counters[i].bytes;
+			sum
+		})
+	}
+
+	/// The indices of all packet types in a category.
+	fn types(category: PacketCategory) -> impl Iterator<Item = usize> {
+		(0..TYPE_COUNT).filter(move |i| {
+			PacketCategory::from_type(
+				num_traits::FromPrimitive::from_usize(*i).unwrap(),
+			) == category
+		})
+	}
+
+	/// Get the bandwidth slot for the current second and remove old slots.
+	fn get_slot(&mut self, now: Instant) -> &mut BandwidthSlot {
+		while self
+			.bandwidth
+			.front()
+			.map(|s| s.start + BANDWIDTH_HISTORY + Duration::from_secs(1) <= now)
+			.unwrap_or(false)
+		{
+			self.bandwidth.pop_front();
+		}
+
+		let new_slot = self
+			.bandwidth
+			.back()
+			.map(|s| s.start + Duration::from_secs(1) <= now)
+			.unwrap_or(true);
+		if new_slot {
+			self.bandwidth.push_back(BandwidthSlot {
+				start: now,
+				sent: [0; 3],
+				received: [0; 3],
+			});
+		}
+		self.bandwidth.back_mut().unwrap()
+	}
+}
+
+/// Send a ping every [`PING_INTERVAL`] while the connection is established.
+///
+/// The future ends when the connection is gone.
+///
+/// [`PING_INTERVAL`]: constant.PING_INTERVAL.html
+pub(crate) fn ping_connection<T: Send + 'static>(
+	con: ConnectionValueWeak<T>,
+	logger: Logger,
+) -> impl Future<Item = (), Error = ()>
+{
+	Interval::new(Instant::now() + PING_INTERVAL, PING_INTERVAL)
+		.map_err(move |e| debug!(logger, "Ping timer failed"; "error" => ?e))
+		.take_while(move |_| {
+			let con_val = if let Some(c) = con.upgrade() {
+				c
+			} else {
+				return Ok(false);
+			};
+			let dir = {
+				let con = con_val.mutex.lock();
+				if con.1.params.is_none() {
+					// Not yet connected
+					return Ok(true);
+				}
+				if con.1.is_client { Direction::C2S } else { Direction::S2C }
+			};
+			let packet =
+				OutPacket::new_with_dir(dir, Flags::empty(), PacketType::Ping);
+			tokio::spawn(
+				con.as_packet_sink()
+					.send(packet)
+					.map(|_| ())
+					// The connection may be gone already
+					.map_err(|_| ()),
+			);
+			Ok(true)
+		})
+		.for_each(|_| Ok(()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn count_lost_packets() {
+		let mut stats = ConnectionStats::new();
+		stats.packet_received(PacketType::Voice, 0, 0, 100);
+		stats.packet_received(PacketType::Voice, 3, 1, 100);
+		// A late packet, which is not lost anymore
+		stats.packet_received(PacketType::Voice, 1, 4, 100);
+		assert_eq!(stats.get_lost(PacketCategory::Speech), 1);
+		// A duplicate does not change the lost packets
+		stats.packet_received(PacketType::Voice, 1, 4, 100);
+		assert_eq!(stats.get_lost(PacketCategory::Speech), 1);
+		assert_eq!(stats.get_received(PacketCategory::Speech), Counter {
+			packets: 4,
+			bytes: 400,
+		});
+		assert_eq!(stats.get_loss(PacketCategory::Speech), 0.2);
+		assert_eq!(stats.get_loss(PacketCategory::Control), 0.0);
+	}
+
+	#[test]
+	fn ping_rtt() {
+		let mut stats = ConnectionStats::new();
+		stats.packet_sent(PacketType::Ping, 0, 10);
+		stats.packet_sent(PacketType::Ping, 1, 10);
+		stats.pong_received(1);
+		assert!(stats.rtt.is_some());
+		assert_eq!(stats.lost[PacketType::Ping.to_usize().unwrap()], 1);
+		assert_eq!(stats.get_sent(PacketCategory::Keepalive).packets, 2);
+	}
+}
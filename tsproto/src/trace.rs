@@ -0,0 +1,511 @@
+//! Structured protocol tracing.
+//!
+//! In contrast to the [`log`] module, which writes human readable `Debug`
+//! output, this module writes one JSON object per line for every packet and
+//! command. The output can be filtered by packet type and command name, and
+//! sampled, so it can stay enabled in production.
+//!
+//! # Example
+//! ```rust,no_run
+//! # use tsproto::trace::{self, TraceConfig};
+//! # fn f<CM: tsproto::connectionmanager::ConnectionManager>(
+//! #     data: &mut tsproto::handler_data::Data<CM>) {
+//! let file = std::fs::File::create("trace.jsonl").unwrap();
+//! trace::add_tracer(data, TraceConfig::default(), file);
+//! # }
+//! ```
+//!
+//! [`log`]: ../log/index.html
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use chrono::{SecondsFormat, Utc};
+use num_traits::ToPrimitive;
+use parking_lot::Mutex;
+use slog::warn;
+
+use crate::connection::Connection;
+use crate::connectionmanager::ConnectionManager;
+use crate::handler_data::{
+	Data, InCommandObserver, InPacketObserver, OutPacketObserver,
+};
+use crate::packets::*;
+use crate::stats::TYPE_COUNT;
+
+/// Configure which packets are traced.
+#[derive(Clone, Debug)]
+pub struct TraceConfig {
+	/// Trace packets of these types. If this is empty, all packets are traced.
+	pub packet_types: Vec<PacketType>,
+	/// Trace commands with these names. If this is empty, all commands are
+	/// traced.
+	pub commands: Vec<String>,
+	/// Only trace every n-th packet of each type.
+	///
+	/// `1` traces every packet, `0` disables packet tracing.
+	pub packet_sample_rate: u32,
+	/// Only trace every n-th command.
+	///
+	/// `1` traces every command, `0` disables command tracing.
+	pub command_sample_rate: u32,
+	/// Include the arguments of commands.
+	pub command_arguments: bool,
+}
+
+/// A traced packet or command.
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+	pub incoming: bool,
+	/// The address of the other side.
+	pub addr: SocketAddr,
+	pub is_client: bool,
+	pub p_type: PacketType,
+	/// The packet id, this is `None` for commands.
+	pub p_id: Option<u16>,
+	/// The generation of the packet id, this is `None` for commands.
+	pub generation: Option<u32>,
+	pub flags: Flags,
+	/// The size of the content in bytes, without the header.
+	pub size: usize,
+	/// The name of a command.
+	pub command: Option<String>,
+	/// The static arguments of a command.
+	pub args: Vec<(String, String)>,
+	/// The list arguments of a command.
+	pub list_args: Vec<Vec<(String, String)>>,
+}
+
+struct Tracer {
+	config: TraceConfig,
+	output: Mutex<Box<Write + Send>>,
+	/// Counters for sampling, indexed by the packet type.
+	packet_counters: Mutex<[u64; TYPE_COUNT]>,
+	command_counter: Mutex<u64>,
+}
+
+impl Default for TraceConfig {
+	fn default() -> Self {
+		Self {
+			packet_types: Vec::new(),
+			commands: Vec::new(),
+			packet_sample_rate: 1,
+			command_sample_rate: 1,
+			command_arguments: true,
+		}
+	}
+}
+
+impl TraceRecord {
+	/// Serialize this record as a single line of JSON, without the newline.
+	pub fn to_json(&self) -> String {
+		let mut s = String::new();
+		s.push('{');
+		write_key(&mut s, "time");
+		write_str(
+			&mut s,
+			&Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+		);
+		s.push(',');
+		write_key(&mut s, "kind");
+		write_str(&mut s, if self.command.is_some() { "command" } else {
+			"packet"
+		});
+		s.push(',');
+		write_key(&mut s, "dir");
+		write_str(&mut s, if self.incoming { "in" } else { "out" });
+		s.push(',');
+		write_key(&mut s, "to");
+		// Outgoing packets of a client and incoming packets of a server go
+		// to the server.
+		let to_server = self.is_client != self.incoming;
+		write_str(&mut s, if to_server { "S" } else { "C" });
+		s.push(',');
+		write_key(&mut s, "addr");
+		write_str(&mut s, &self.addr.to_string());
+		s.push(',');
+		write_key(&mut s, "type");
+		write_str(&mut s, &format!("{:?}", self.p_type));
+		if let Some(id) = self.p_id {
+			s.push(',');
+			write_key(&mut s, "id");
+			let _ = write!(s, "{}", id);
+		}
+		if let Some(gen) = self.generation {
+			s.push(',');
+			write_key(&mut s, "gen");
+			let _ = write!(s, "{}", gen);
+		}
+		s.push(',');
+		write_key(&mut s, "flags");
+		s.push('[');
+		let flags = [
+			(Flags::UNENCRYPTED, "unencrypted"),
+			(Flags::COMPRESSED, "compressed"),
+			(Flags::NEWPROTOCOL, "newprotocol"),
+			(Flags::FRAGMENTED, "fragmented"),
+		];
+		let mut first = true;
+		for (f, name) in &flags {
+			if self.flags.contains(*f) {
+				if !first {
+					s.push(',');
+				}
+				first = false;
+				write_str(&mut s, name);
+			}
+		}
+		s.push_str("],");
+		write_key(&mut s, "size");
+		let _ = write!(s, "{}", self.size);
+
+		if let Some(name) = &self.command {
+			s.push(',');
+			write_key(&mut s, "name");
+			write_str(&mut s, name);
+			s.push(',');
+			write_key(&mut s, "args");
+			write_args(&mut s, &self.args);
+			s.push(',');
+			write_key(&mut s, "list");
+			s.push('[');
+			for (i, args) in self.list_args.iter().enumerate() {
+				if i != 0 {
+					s.push(',');
+				}
+				write_args(&mut s, args);
+			}
+			s.push(']');
+		}
+		s.push('}');
+		s
+	}
+}
+
+impl Tracer {
+	fn sample_packet(&self, p_type: PacketType) -> bool {
+		let rate = u64::from(self.config.packet_sample_rate);
+		if rate == 0
+			|| (!self.config.packet_types.is_empty()
+				&& !self.config.packet_types.contains(&p_type))
+		{
+			return false;
+		}
+		let mut counters = self.packet_counters.lock();
+		let counter = &mut counters[p_type.to_usize().unwrap()];
+		*counter += 1;
+		(*counter - 1) % rate == 0
+	}
+
+	fn sample_command(&self, name: &str) -> bool {
+		let rate = u64::from(self.config.command_sample_rate);
+		if rate == 0
+			|| (!self.config.commands.is_empty()
+				&& !self.config.commands.iter().any(|c| c == name))
+		{
+			return false;
+		}
+		let mut counter = self.command_counter.lock();
+		*counter += 1;
+		(*counter - 1) % rate == 0
+	}
+
+	fn write(&self, con: &Connection, record: &TraceRecord) {
+		let mut line = record.to_json();
+		line.push('\n');
+		if let Err(e) = self.output.lock().write_all(line.as_bytes()) {
+			warn!(con.logger, "Failed to write trace"; "error" => ?e);
+		}
+	}
+
+	fn packet_record(
+		con: &Connection,
+		incoming: bool,
+		header: InHeader,
+		size: usize,
+	) -> TraceRecord
+	{
+		let p_type = header.packet_type();
+		let type_i = p_type.to_usize().unwrap();
+		// Outgoing packets do not have an id yet, it is assigned when they are
+		// encoded.
+		let (gen, next) = if p_type == PacketType::Init {
+			(0, 0)
+		} else if incoming {
+			con.incoming_p_ids[type_i]
+		} else {
+			con.outgoing_p_ids[type_i]
+		};
+		let p_id = if incoming { header.packet_id() } else { next };
+		TraceRecord {
+			incoming,
+			addr: con.address,
+			is_client: con.is_client,
+			p_type,
+			p_id: Some(p_id),
+			generation: Some(estimate_generation(gen, next, p_id)),
+			flags: header.flags(),
+			size,
+			command: None,
+			args: Vec::new(),
+			list_args: Vec::new(),
+		}
+	}
+
+	fn command_record(
+		&self,
+		con: &Connection,
+		incoming: bool,
+		cmd: &InCommand,
+	) -> TraceRecord
+	{
+		let data = cmd.data();
+		let (args, list_args) = if self.config.command_arguments {
+			let to_owned = |args: &[(&str, std::borrow::Cow<str>)]| {
+				args.iter()
+					.map(|(k, v)| (k.to_string(), v.to_string()))
+					.collect::<Vec<_>>()
+			};
+			(
+				to_owned(&data.static_args),
+				data.list_args.iter().map(|a| to_owned(a)).collect(),
+			)
+		} else {
+			(Vec::new(), Vec::new())
+		};
+		TraceRecord {
+			incoming,
+			addr: con.address,
+			is_client: con.is_client,
+			p_type: cmd.packet_type(),
+			p_id: None,
+			generation: None,
+			flags: if cmd.newprotocol() {
+				Flags::NEWPROTOCOL
+			} else {
+				Flags::empty()
+			},
+			size: cmd.content().len(),
+			command: Some(cmd.name().to_string()),
+			args,
+			list_args,
+		}
+	}
+}
+
+impl<T: Send> InPacketObserver<T> for Tracer {
+	fn observe(&self, con: &mut (T, Connection), packet: &InPacket) {
+		let header = packet.header();
+		if self.sample_packet(header.packet_type()) {
+			let record = Self::packet_record(
+				&con.1,
+				true,
+				header,
+				packet.content().len(),
+			);
+			self.write(&con.1, &record);
+		}
+	}
+}
+
+impl<T: Send> OutPacketObserver<T> for Tracer {
+	fn observe(&self, con: &mut (T, Connection), packet: &mut OutPacket) {
+		let header = packet.header();
+		let p_type = header.packet_type();
+		if self.sample_packet(p_type) {
+			let record = Self::packet_record(
+				&con.1,
+				false,
+				header,
+				packet.content().len(),
+			);
+			self.write(&con.1, &record);
+		}
+
+		// Decode outgoing commands, only parse them if they are sampled
+		if p_type == PacketType::Command || p_type == PacketType::CommandLow {
+			let name = packet.content().split(|b| *b == b' ').next()
+				.and_then(|n| std::str::from_utf8(n).ok())
+				.unwrap_or("");
+			if !self.sample_command(name) {
+				return;
+			}
+			let newprotocol =
+				packet.header().flags().contains(Flags::NEWPROTOCOL);
+			if let Ok(cmd) = InCommand::new(
+				packet.content().to_vec(),
+				p_type,
+				newprotocol,
+				packet.direction(),
+			) {
+				let record = self.command_record(&con.1, false, &cmd);
+				self.write(&con.1, &record);
+			}
+		}
+	}
+}
+
+impl<T: Send> InCommandObserver<T> for Tracer {
+	fn observe(&self, con: &mut (T, Connection), cmd: &InCommand) {
+		if self.sample_command(cmd.name()) {
+			let record = self.command_record(&con.1, true, cmd);
+			self.write(&con.1, &record);
+		}
+	}
+}
+
+/// Wraps a `Tracer` so it can be added as multiple observers.
+struct SharedTracer(Arc<Tracer>);
+
+impl<T: Send> InPacketObserver<T> for SharedTracer {
+	fn observe(&self, con: &mut (T, Connection), packet: &InPacket) {
+		InPacketObserver::observe(&*self.0, con, packet)
+	}
+}
+
+impl<T: Send> OutPacketObserver<T> for SharedTracer {
+	fn observe(&self, con: &mut (T, Connection), packet: &mut OutPacket) {
+		OutPacketObserver::observe(&*self.0, con, packet)
+	}
+}
+
+impl<T: Send> InCommandObserver<T> for SharedTracer {
+	fn observe(&self, con: &mut (T, Connection), cmd: &InCommand) {
+		InCommandObserver::observe(&*self.0, con, cmd)
+	}
+}
+
+/// Write a trace of all packets and commands as JSON lines into `output`.
+///
+/// Use [`remove_tracer`] to stop tracing.
+///
+/// [`remove_tracer`]: fn.remove_tracer.html
+pub fn add_tracer<CM: ConnectionManager + 'static, W: Write + Send + 'static>(
+	data: &mut Data<CM>,
+	config: TraceConfig,
+	output: W,
+) {
+	let tracer = Arc::new(Tracer {
+		config,
+		output: Mutex::new(Box::new(output)),
+		packet_counters: Mutex::new([0; TYPE_COUNT]),
+		command_counter: Mutex::new(0),
+	});
+	data.add_in_packet_observer(
+		"trace".into(),
+		Box::new(SharedTracer(tracer.clone())),
+	);
+	data.add_out_packet_observer(
+		"trace".into(),
+		Box::new(SharedTracer(tracer.clone())),
+	);
+	data.add_in_command_observer("trace".into(), Box::new(SharedTracer(tracer)));
+}
+
+pub fn remove_tracer<CM: ConnectionManager + 'static>(data: &mut Data<CM>) {
+	data.remove_in_packet_observer("trace");
+	data.remove_out_packet_observer("trace");
+	data.remove_in_command_observer("trace");
+}
+
+/// Get the generation of `p_id`, which is the closest to the next expected id.
+fn estimate_generation(gen: u32, next: u16, p_id: u16) -> u32 {
+	if p_id >= next {
+		if p_id - next < u16::max_value() / 2 {
+			gen
+		} else {
+			// Older packet from the last generation
+			gen.wrapping_sub(1)
+		}
+	} else if next - p_id < u16::max_value() / 2 {
+		gen
+	} else {
+		// Newer packet from the next generation
+		gen.wrapping_add(1)
+	}
+}
+
+fn write_key(s: &mut String, key: &str) {
+	write_str(s, key);
+	s.push(':');
+}
+
+fn write_args(s: &mut String, args: &[(String, String)]) {
+	s.push('{');
+	for (i, (k, v)) in args.iter().enumerate() {
+		if i != 0 {
+			s.push(',');
+		}
+		write_key(s, k);
+		write_str(s, v);
+	}
+	s.push('}');
+}
+
+/// Write a quoted and escaped JSON string.
+fn write_str(s: &mut String, val: &str) {
+	s.push('"');
+	for c in val.chars() {
+		match c {
+			'"' => s.push_str("\\\""),
+			'\\' => s.push_str("\\\\"),
+			'\n' => s.push_str("\\n"),
+			'\r' => s.push_str("\\r"),
+			'\t' => s.push_str("\\t"),
+			c if (c as u32) < 0x20 => {
+				let _ = write!(s, "\\u{:04x}", c as u32);
+			}
+			c => s.push(c),
+		}
+	}
+	s.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn generation() {
+		assert_eq!(estimate_generation(1, 10, 12), 1);
+		assert_eq!(estimate_generation(1, 10, 5), 1);
+		assert_eq!(estimate_generation(1, 10, 65530), 0);
+		assert_eq!(estimate_generation(1, 65530, 3), 2);
+	}
+
+	#[test]
+	fn json_record() {
+		let mut record = TraceRecord {
+			incoming: true,
+			addr: "127.0.0.1:9987".parse().unwrap(),
+			is_client: true,
+			p_type: PacketType::Command,
+			p_id: None,
+			generation: None,
+			flags: Flags::NEWPROTOCOL,
+			size: 42,
+			command: Some("notifytextmessage".into()),
+			args: vec![("msg".into(), "a \"b\"\n".into())],
+			list_args: Vec::new(),
+		};
+		let json = record.to_json();
+		assert!(json.starts_with("{\"time\":\""));
+		assert!(json.ends_with(
+			"\"kind\":\"command\",\"dir\":\"in\",\"to\":\"C\",\
+			\"addr\":\"127.0.0.1:9987\",\"type\":\"Command\",\
+			\"flags\":[\"newprotocol\"],\"size\":42,\
+			\"name\":\"notifytextmessage\",\
+			\"args\":{\"msg\":\"a \\\"b\\\"\\n\"},\"list\":[]}"
+		));
+
+		// An outgoing packet of a client goes to the server
+		record.incoming = false;
+		let json = record.to_json();
+		assert!(json.contains("\"dir\":\"out\",\"to\":\"S\","));
+		// An outgoing packet of a server goes to the client
+		record.is_client = false;
+		let json = record.to_json();
+		assert!(json.contains("\"dir\":\"out\",\"to\":\"C\","));
+	}
+}
@@ -0,0 +1,600 @@
+//! Connect a client to a server and send commands over a simulated bad
+//! connection.
+//!
+//! Both sides run in the same process on localhost. The impairment is applied
+//! to the socket of the client, so packets in both directions are affected.
+//! The client runs the real handshake of [`client::connect`], the test server
+//! answers it with the original protocol (`initivexpand`).
+//!
+//! [`client::connect`]: ../tsproto/client/fn.connect.html
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use futures::{future, stream, Future, Sink, Stream};
+use parking_lot::Mutex;
+use rand::distributions::Alphanumeric;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use slog::{o, Logger};
+use tokio::timer::{Interval, Timeout};
+use tsproto::algorithms as algs;
+use tsproto::client::{self, ClientDataM, ServerConnectionData};
+use tsproto::connection::{ConnectedParams, Connection, SharedIv};
+use tsproto::connectionmanager::{
+	Resender, ResenderEvent, SocketConnectionManager,
+};
+use tsproto::crypto::{EccKeyPrivP256, EccKeyPubP256};
+use tsproto::handler_data::{
+	ConnectionValue, ConnectionValueWeak, Data, DataM, InPacketObserver,
+	PacketHandler,
+};
+use tsproto::impairment::ImpairmentConfig;
+use tsproto::packets::*;
+use tsproto::stats::{ConnectionStats, PacketCategory};
+use tsproto::Error;
+
+type ServerCM = SocketConnectionManager<TestServer, ()>;
+
+/// The maximum number of command packets, which can be queued on the
+/// receiving side if they arrive out of order.
+const MAX_QUEUE_LEN: u16 = 50;
+/// Give up if not all commands arrive in this time.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+type Received = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// Stores the content of all received test commands.
+struct CommandCollector(Received);
+
+impl<T: 'static> PacketHandler<T> for CommandCollector {
+	fn new_connection<S1, S2, S3, S4>(
+		&mut self,
+		_: &ConnectionValue<T>,
+		s2c_init_stream: S1,
+		c2s_init_stream: S2,
+		command_stream: S3,
+		audio_stream: S4,
+	) where
+		S1: Stream<Item = InS2CInit, Error = Error> + Send + 'static,
+		S2: Stream<Item = InC2SInit, Error = Error> + Send + 'static,
+		S3: Stream<Item = InCommand, Error = Error> + Send + 'static,
+		S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
+	{
+		tokio::spawn(s2c_init_stream.for_each(|_| Ok(())).map_err(|_| ()));
+		tokio::spawn(c2s_init_stream.for_each(|_| Ok(())).map_err(|_| ()));
+		tokio::spawn(audio_stream.for_each(|_| Ok(())).map_err(|_| ()));
+
+		let received = self.0.clone();
+		tokio::spawn(
+			command_stream
+				.for_each(move |c| {
+					// Skip the commands of the handshake
+					if c.name() == "notifytest" {
+						received.lock().push(c.content().to_vec());
+					}
+					Ok(())
+				})
+				.map_err(|e| panic!("Command stream failed ({:?})", e)),
+		);
+	}
+}
+
+/// Answers the handshake of a client and collects its test commands.
+struct TestServer {
+	private_key: EccKeyPrivP256,
+	received: Received,
+}
+
+impl PacketHandler<()> for TestServer {
+	fn new_connection<S1, S2, S3, S4>(
+		&mut self,
+		con_val: &ConnectionValue<()>,
+		s2c_init_stream: S1,
+		c2s_init_stream: S2,
+		command_stream: S3,
+		audio_stream: S4,
+	) where
+		S1: Stream<Item = InS2CInit, Error = Error> + Send + 'static,
+		S2: Stream<Item = InC2SInit, Error = Error> + Send + 'static,
+		S3: Stream<Item = InCommand, Error = Error> + Send + 'static,
+		S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
+	{
+		tokio::spawn(s2c_init_stream.for_each(|_| Ok(())).map_err(|_| ()));
+		tokio::spawn(audio_stream.for_each(|_| Ok(())).map_err(|_| ()));
+
+		let con = con_val.downgrade();
+		let private_key = self.private_key.clone();
+		let mut step = 0;
+		tokio::spawn(
+			c2s_init_stream
+				.for_each(move |p| {
+					let answer = p.with_data(|init| {
+						handle_init(&con, &private_key, &mut step, init)
+					});
+					match answer {
+						Some(packet) => future::Either::A(
+							con.as_packet_sink().send(packet).map(|_| ()),
+						),
+						None => future::Either::B(future::ok(())),
+					}
+				})
+				.map_err(|e| panic!("Init stream failed ({:?})", e)),
+		);
+
+		let con = con_val.downgrade();
+		let received = self.received.clone();
+		tokio::spawn(
+			command_stream
+				.for_each(move |c| {
+					if c.name() != "clientinit" {
+						received.lock().push(c.content().to_vec());
+						return future::Either::B(future::ok(()));
+					}
+
+					// The client is connected after the initserver
+					if let Some(con) = con.upgrade() {
+						let mut con = con.mutex.lock();
+						con.1.resender.handle_event(ResenderEvent::Connected);
+					}
+					let packet = OutCommand::new::<
+						_,
+						_,
+						String,
+						String,
+						_,
+						_,
+						std::iter::Empty<_>,
+					>(
+						Direction::S2C,
+						PacketType::Command,
+						"initserver",
+						vec![("aclid", "1".into())].into_iter(),
+						std::iter::empty(),
+					);
+					future::Either::A(
+						con.as_packet_sink().send(packet).map(|_| ()),
+					)
+				})
+				.map_err(|e| panic!("Command stream failed ({:?})", e)),
+		);
+	}
+}
+
+/// Answer an init packet of the client.
+///
+/// Every step is answered only once, retransmits are left to the resender of
+/// the server. The answers to later steps acknowledge the init packets of the
+/// server.
+fn handle_init(
+	con: &ConnectionValueWeak<()>,
+	private_key: &EccKeyPrivP256,
+	step: &mut u8,
+	init: &C2SInitData,
+) -> Option<OutPacket>
+{
+	let con = con.upgrade()?;
+	let mut con = con.mutex.lock();
+	let con = &mut con.1;
+	match init {
+		C2SInitData::Init0 { random0, .. } if *step == 0 => {
+			*step = 1;
+			let mut random0_r = **random0;
+			random0_r.reverse();
+			Some(OutS2CInit1::new(&[1; 16], random0_r))
+		}
+		C2SInitData::Init2 { .. } if *step == 1 => {
+			*step = 2;
+			con.resender.ack_packet(PacketType::Init, 1);
+			// An easy puzzle: y = x ^ (2 ^ level) % n
+			let mut x = [0; 64];
+			x[63] = 2;
+			Some(OutS2CInit3::new(&x, &[0xff; 64], 1, &[2; 100]))
+		}
+		C2SInitData::Init4 { command, .. } if *step == 2 => {
+			*step = 3;
+			con.resender.ack_packet(PacketType::Init, 3);
+			let cmd = command.iter().next().unwrap();
+			let alpha_s = cmd.get("alpha").unwrap().to_string();
+			let mut alpha = [0; 10];
+			alpha.copy_from_slice(&base64::decode(&alpha_s).unwrap());
+			let beta = [3; 10];
+			let client_key =
+				EccKeyPubP256::from_ts(cmd.get("omega").unwrap()).unwrap();
+
+			let (iv, mac) = algs::compute_iv_mac(
+				&alpha,
+				&beta,
+				private_key.clone(),
+				client_key.clone(),
+			)
+			.unwrap();
+			// The initivexpand is the first command of the server, so it is
+			// fake encrypted even though the parameters are set.
+			con.params = Some(ConnectedParams::new(
+				client_key,
+				SharedIv::ProtocolOrig(iv),
+				mac,
+			));
+
+			let omega = private_key.to_pub().to_ts().unwrap();
+			Some(OutCommand::new::<
+				_,
+				_,
+				String,
+				String,
+				_,
+				_,
+				std::iter::Empty<_>,
+			>(
+				Direction::S2C,
+				PacketType::Command,
+				"initivexpand",
+				vec![
+					("alpha", alpha_s),
+					("beta", base64::encode(&beta)),
+					("omega", omega),
+				]
+				.into_iter(),
+				std::iter::empty(),
+			))
+		}
+		// A retransmit of an already answered step
+		_ => None,
+	}
+}
+
+/// Remembers how far incoming command packets are ahead of the next expected
+/// packet id.
+struct WindowObserver(Arc<Mutex<u16>>);
+
+impl<T> InPacketObserver<T> for WindowObserver {
+	fn observe(&self, con: &mut (T, Connection), packet: &InPacket) {
+		let p_type = packet.header().packet_type();
+		if p_type != PacketType::Command {
+			return;
+		}
+		let next = con.1.incoming_p_ids[p_type as usize].1;
+		let ahead = packet.header().packet_id().wrapping_sub(next);
+		let mut max = self.0.lock();
+		if ahead > *max {
+			*max = ahead;
+		}
+	}
+}
+
+/// The results of a session.
+struct Session {
+	client: ConnectionStats,
+	server: ConnectionStats,
+	/// The maximum distance of an incoming command packet from the next
+	/// expected packet id on the client.
+	max_ahead: u16,
+}
+
+fn create_server(logger: &Logger) -> (DataM<ServerCM>, Received) {
+	let received = Received::default();
+	let private_key = EccKeyPrivP256::create().unwrap();
+	let data = Data::new(
+		"127.0.0.1:0".parse().unwrap(),
+		private_key.clone(),
+		false,
+		None,
+		TestServer { private_key, received: received.clone() },
+		ServerCM::new(),
+		logger.new(o!("client" => false)),
+	)
+	.unwrap();
+	(data, received)
+}
+
+fn create_client(
+	logger: &Logger,
+	impairment: ImpairmentConfig,
+) -> (ClientDataM<CommandCollector>, Received)
+{
+	let received = Received::default();
+	let data = client::new_impaired(
+		"127.0.0.1:0".parse().unwrap(),
+		EccKeyPrivP256::create().unwrap(),
+		CommandCollector(received.clone()),
+		impairment,
+		logger.new(o!("client" => true)),
+	)
+	.unwrap();
+	(data, received)
+}
+
+/// Run the handshake of the client and wait until it is connected.
+fn connect(
+	client: &ClientDataM<CommandCollector>,
+	server_addr: SocketAddr,
+) -> impl Future<Item = ConnectionValue<ServerConnectionData>, Error = String>
+{
+	let weak: Weak<_> = Arc::downgrade(client);
+	client::connect(weak, &mut *client.lock(), server_addr)
+		.and_then(|con| {
+			let packet = OutCommand::new::<
+				_,
+				_,
+				String,
+				String,
+				_,
+				_,
+				std::iter::Empty<_>,
+			>(
+				Direction::C2S,
+				PacketType::Command,
+				"clientinit",
+				vec![("client_nickname", "impaired".into())].into_iter(),
+				std::iter::empty(),
+			);
+			// Listen before sending, so the initserver cannot be missed
+			let connected = client::wait_until_connected(&con);
+			con.as_packet_sink()
+				.send(packet)
+				.and_then(move |_| connected)
+				.map(move |_| con)
+		})
+		.map(|con| con.upgrade().unwrap())
+		.map_err(|e| format!("Failed to connect ({:?})", e))
+}
+
+/// Add the connection of the client on the server.
+///
+/// The server does not accept unknown connections, so this has to happen
+/// before the client starts its handshake.
+fn accept(server: &DataM<ServerCM>, addr: SocketAddr) -> ConnectionValue<()> {
+	let mut d = server.lock();
+	let key = d.add_connection(Arc::downgrade(server), (), addr);
+	d.get_connection(&key).unwrap()
+}
+
+/// Create a command with a random payload of `len` bytes.
+///
+/// The payload does not compress well, so long commands are fragmented.
+fn command(dir: Direction, i: usize, len: usize) -> OutPacket {
+	let payload = StdRng::seed_from_u64(i as u64)
+		.sample_iter(&Alphanumeric)
+		.take(len)
+		.collect::<String>();
+	OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+		dir,
+		PacketType::Command,
+		"notifytest",
+		vec![("i", i.to_string()), ("payload", payload)].into_iter(),
+		std::iter::empty(),
+	)
+}
+
+fn send<T: Send + 'static>(
+	con: &ConnectionValue<T>,
+	packets: Vec<OutPacket>,
+) -> impl Future<Item = (), Error = String>
+{
+	con.downgrade()
+		.as_packet_sink()
+		.send_all(stream::iter_ok::<_, Error>(packets))
+		.map(|_| ())
+		.map_err(|e| format!("Failed to send commands ({:?})", e))
+}
+
+/// Wait until `count` commands are received on both sides and all sent
+/// commands are acknowledged.
+fn wait_for_commands(
+	client: ConnectionValue<ServerConnectionData>,
+	server: ConnectionValue<()>,
+	received: Vec<Received>,
+	count: usize,
+) -> impl Future<Item = (), Error = String>
+{
+	Interval::new_interval(Duration::from_millis(20))
+		.map_err(|e| format!("Timer failed ({:?})", e))
+		.skip_while(move |_| {
+			let done = received.iter().all(|r| r.lock().len() >= count)
+				&& client.mutex.lock().1.resender.is_empty()
+				&& server.mutex.lock().1.resender.is_empty();
+			Ok(!done)
+		})
+		.into_future()
+		.map(|_| ())
+		.map_err(|(e, _)| e)
+}
+
+/// Check that nothing is left in the queues of a connection and return its
+/// statistics.
+fn finish<T: Send + 'static>(con: &ConnectionValue<T>) -> ConnectionStats {
+	let con = con.mutex.lock();
+	let queue = &con.1.receive_queue;
+	assert!(queue.iter().all(Vec::is_empty));
+	let queue = &con.1.fragmented_queue;
+	assert!(queue.iter().all(Option::is_none));
+	con.1.get_stats()
+}
+
+/// Connect and send `count` commands with a payload of `len` bytes in both
+/// directions.
+///
+/// Checks that all commands arrive exactly once and in the right order.
+fn session(impairment: ImpairmentConfig, count: usize, len: usize) -> Session {
+	tsproto::init().unwrap();
+	let logger = Logger::root(slog::Discard, o!());
+	let mut rt = tokio::runtime::Runtime::new().unwrap();
+
+	let to_server = (0..count)
+		.map(|i| command(Direction::C2S, i, len))
+		.collect::<Vec<_>>();
+	let to_client = (0..count)
+		.map(|i| command(Direction::S2C, count + i, len))
+		.collect::<Vec<_>>();
+	let expected_server =
+		to_server.iter().map(|p| p.content().to_vec()).collect::<Vec<_>>();
+	let expected_client =
+		to_client.iter().map(|p| p.content().to_vec()).collect::<Vec<_>>();
+
+	let max_ahead = Arc::new(Mutex::new(0));
+	let max_ahead2 = max_ahead.clone();
+	let (received_server, received_client, client, server) = rt
+		.block_on(future::lazy(move || {
+			let (server, received_server) = create_server(&logger);
+			let (client, received_client) = create_client(&logger, impairment);
+			client.lock().add_in_packet_observer(
+				"window".into(),
+				Box::new(WindowObserver(max_ahead2)),
+			);
+
+			let server_addr = server.lock().local_addr;
+			let client_addr = client.lock().local_addr;
+			let server_con = accept(&server, client_addr);
+
+			let received =
+				vec![received_server.clone(), received_client.clone()];
+			let server_con2 = server_con.clone();
+			let done = connect(&client, server_addr).and_then(move |con| {
+				send(&con, to_server)
+					.join(send(&server_con, to_client))
+					.and_then(move |_| {
+						wait_for_commands(
+							con.clone(),
+							server_con,
+							received,
+							count,
+						)
+						.map(move |_| con)
+					})
+			});
+			Timeout::new(done, TIMEOUT)
+				.map_err(|e| format!("Session failed ({:?})", e))
+				.map(move |client_con| {
+					let stats = (finish(&client_con), finish(&server_con2));
+					// Close the sockets
+					drop((client_con, client, server));
+					let received_server = received_server.lock().clone();
+					let received_client = received_client.lock().clone();
+					(received_server, received_client, stats.0, stats.1)
+				})
+		}))
+		.unwrap();
+	rt.shutdown_now().wait().unwrap();
+
+	assert_eq!(received_server, expected_server);
+	assert_eq!(received_client, expected_client);
+	for stats in &[&client, &server] {
+		assert_eq!(stats.resend.queue_len, 0);
+		assert_eq!(stats.resend.in_flight, 0);
+	}
+
+	let max_ahead = *max_ahead.lock();
+	Session { client, server, max_ahead }
+}
+
+#[test]
+fn handshake() {
+	let s = session(
+		ImpairmentConfig {
+			seed: 6,
+			loss: 0.3,
+			reorder: 0.3,
+			reorder_delay: Duration::from_millis(30),
+			..Default::default()
+		},
+		5,
+		10,
+	);
+	// Lost init packets are sent again
+	assert!(s.client.resend.retransmits > 0);
+}
+
+#[test]
+fn loss() {
+	let s = session(
+		ImpairmentConfig {
+			seed: 1,
+			loss: 0.2,
+			..Default::default()
+		},
+		50,
+		10,
+	);
+	// Lost acks are detected by gaps in the packet ids
+	assert!(s.client.get_lost(PacketCategory::Control) > 0);
+	// Lost commands and lost acks lead to retransmits on both sides
+	assert!(s.client.resend.retransmits > 0);
+	assert!(s.server.resend.retransmits > 0);
+}
+
+#[test]
+fn reordering() {
+	let s = session(
+		ImpairmentConfig {
+			seed: 2,
+			reorder: 0.3,
+			reorder_delay: Duration::from_millis(30),
+			delay: Duration::from_millis(5),
+			jitter: Duration::from_millis(10),
+			..Default::default()
+		},
+		50,
+		10,
+	);
+	// Some commands arrived before their predecessors
+	assert!(s.max_ahead > 0);
+	assert_eq!(s.client.get_lost(PacketCategory::Control), 0);
+}
+
+#[test]
+fn duplication() {
+	let s = session(
+		ImpairmentConfig {
+			seed: 3,
+			duplicate: 0.3,
+			..Default::default()
+		},
+		50,
+		10,
+	);
+	// Duplicated acks are only counted once
+	assert_eq!(s.client.get_lost(PacketCategory::Control), 0);
+	let ack = PacketType::Ack as usize;
+	let acks = s.client.received[ack].packets;
+	assert!(acks >= 50 && acks <= s.server.sent[ack].packets);
+}
+
+#[test]
+fn receive_window() {
+	let s = session(
+		ImpairmentConfig {
+			seed: 4,
+			reorder: 0.5,
+			reorder_delay: Duration::from_millis(100),
+			..Default::default()
+		},
+		200,
+		10,
+	);
+	// The congestion window of the server keeps the packets inside the
+	// receive queue of the client.
+	assert!(s.max_ahead > 0);
+	assert!(s.max_ahead < MAX_QUEUE_LEN);
+	assert!(s.server.resend.window <= 32);
+}
+
+#[test]
+fn fragments() {
+	let count = 10;
+	let s = session(
+		ImpairmentConfig {
+			seed: 5,
+			loss: 0.1,
+			reorder: 0.1,
+			reorder_delay: Duration::from_millis(20),
+			..Default::default()
+		},
+		count,
+		5000,
+	);
+	// Every command is split into multiple packets
+	let command = PacketType::Command as usize;
+	assert!(s.client.sent[command].packets > 5 * count as u64);
+	assert!(s.server.sent[command].packets > 5 * count as u64);
+}
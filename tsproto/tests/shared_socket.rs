@@ -0,0 +1,201 @@
+//! Two connections to the same server share one socket.
+//!
+//! The handshake is skipped, the connections get their keys directly.
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{future, stream, Future, Sink, Stream};
+use parking_lot::Mutex;
+use slog::{o, Logger};
+use tokio::timer::{Interval, Timeout};
+use tsproto::connection::{ConnectedParams, SharedIv};
+use tsproto::connectionmanager::{
+	Resender, ResenderEvent, SharedConnectionManager,
+};
+use tsproto::crypto::EccKeyPrivP256;
+use tsproto::handler_data::{ConnectionValue, Data, DataM, PacketHandler};
+use tsproto::packets::*;
+use tsproto::Error;
+
+type CM = SharedConnectionManager<CommandCollector, ()>;
+
+/// Give up if not all commands arrive in this time.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The content of the received commands for each connection, in the order in
+/// which the connections were added.
+type Received = Arc<Mutex<Vec<Vec<Vec<u8>>>>>;
+
+struct CommandCollector(Received);
+
+impl PacketHandler<()> for CommandCollector {
+	fn new_connection<S1, S2, S3, S4>(
+		&mut self,
+		_: &ConnectionValue<()>,
+		s2c_init_stream: S1,
+		c2s_init_stream: S2,
+		command_stream: S3,
+		audio_stream: S4,
+	) where
+		S1: Stream<Item = InS2CInit, Error = Error> + Send + 'static,
+		S2: Stream<Item = InC2SInit, Error = Error> + Send + 'static,
+		S3: Stream<Item = InCommand, Error = Error> + Send + 'static,
+		S4: Stream<Item = InAudio, Error = Error> + Send + 'static,
+	{
+		tokio::spawn(s2c_init_stream.for_each(|_| Ok(())).map_err(|_| ()));
+		tokio::spawn(c2s_init_stream.for_each(|_| Ok(())).map_err(|_| ()));
+		tokio::spawn(audio_stream.for_each(|_| Ok(())).map_err(|_| ()));
+
+		let received = self.0.clone();
+		let i = {
+			let mut received = received.lock();
+			received.push(Vec::new());
+			received.len() - 1
+		};
+		tokio::spawn(
+			command_stream
+				.for_each(move |c| {
+					received.lock()[i].push(c.content().to_vec());
+					Ok(())
+				})
+				.map_err(|e| panic!("Command stream failed ({:?})", e)),
+		);
+	}
+}
+
+fn create(logger: &Logger, is_client: bool) -> (DataM<CM>, Received) {
+	let received = Received::default();
+	let data = Data::new(
+		"127.0.0.1:0".parse().unwrap(),
+		EccKeyPrivP256::create().unwrap(),
+		is_client,
+		None,
+		CommandCollector(received.clone()),
+		CM::new(),
+		logger.new(o!("client" => is_client)),
+	)
+	.unwrap();
+	(data, received)
+}
+
+/// Add a connection with the keys `i` without a handshake.
+fn connect(data: &DataM<CM>, addr: SocketAddr, i: u8) -> ConnectionValue<()> {
+	let mut d = data.lock();
+	let key = d.add_connection(Arc::downgrade(data), (), addr);
+	let con = d.get_connection(&key).unwrap();
+	{
+		let mut con = con.mutex.lock();
+		let con = &mut con.1;
+		con.params = Some(ConnectedParams::new(
+			EccKeyPrivP256::create().unwrap().to_pub(),
+			SharedIv::Protocol31([i; 64]),
+			[i; 8],
+		));
+		// The first command and ack are fake encrypted in a handshake
+		for p_type in &[PacketType::Command, PacketType::Ack] {
+			con.outgoing_p_ids[*p_type as usize] = (0, 1);
+			con.incoming_p_ids[*p_type as usize] = (0, 1);
+		}
+		con.resender.handle_event(ResenderEvent::Connected);
+	}
+	con
+}
+
+fn command(dir: Direction, name: &str, i: usize) -> OutPacket {
+	OutCommand::new::<_, _, String, String, _, _, std::iter::Empty<_>>(
+		dir,
+		PacketType::Command,
+		name,
+		vec![("i", i.to_string())].into_iter(),
+		std::iter::empty(),
+	)
+}
+
+fn send(
+	con: &ConnectionValue<()>,
+	packets: Vec<OutPacket>,
+) -> impl Future<Item = (), Error = String>
+{
+	con.downgrade()
+		.as_packet_sink()
+		.send_all(stream::iter_ok::<_, Error>(packets))
+		.map(|_| ())
+		.map_err(|e| format!("Failed to send commands ({:?})", e))
+}
+
+#[test]
+fn separate_commands() {
+	tsproto::init().unwrap();
+	let logger = Logger::root(slog::Discard, o!());
+	let mut rt = tokio::runtime::Runtime::new().unwrap();
+	let count = 20;
+	let commands = |dir, name| {
+		(0..count).map(|i| command(dir, name, i)).collect::<Vec<_>>()
+	};
+	let content = |packets: &[OutPacket]| {
+		packets.iter().map(|p| p.content().to_vec()).collect::<Vec<_>>()
+	};
+	let to_a = commands(Direction::S2C, "notifya");
+	let to_b = commands(Direction::S2C, "notifyb");
+	let from_a = commands(Direction::C2S, "clienta");
+	let from_b = commands(Direction::C2S, "clientb");
+	let expected_client = vec![content(&to_a), content(&to_b)];
+	let expected_server = vec![content(&from_a), content(&from_b)];
+
+	let (received_client, received_server) = rt
+		.block_on(future::lazy(move || {
+			let (server, received_server) = create(&logger, false);
+			let (client, received_client) = create(&logger, true);
+			let server_addr = server.lock().local_addr;
+			let client_addr = client.lock().local_addr;
+			// Both connections use the same socket on both sides
+			let server_a = connect(&server, client_addr, 1);
+			let server_b = connect(&server, client_addr, 2);
+			let client_a = connect(&client, server_addr, 1);
+			let client_b = connect(&client, server_addr, 2);
+
+			let cons = vec![
+				client_a.clone(),
+				client_b.clone(),
+				server_a.clone(),
+				server_b.clone(),
+			];
+			let received =
+				vec![received_client.clone(), received_server.clone()];
+			let done = Interval::new_interval(Duration::from_millis(20))
+				.map_err(|e| format!("Timer failed ({:?})", e))
+				.skip_while(move |_| {
+					let done = received.iter().all(|r| {
+						r.lock().iter().all(|r| r.len() >= count)
+					}) && cons
+						.iter()
+						.all(|c| c.mutex.lock().1.resender.is_empty());
+					Ok(!done)
+				})
+				.into_future()
+				.map(|_| ())
+				.map_err(|(e, _)| e);
+			let sent = send(&server_a, to_a)
+				.join4(
+					send(&server_b, to_b),
+					send(&client_a, from_a),
+					send(&client_b, from_b),
+				)
+				.and_then(move |_| done);
+			Timeout::new(sent, TIMEOUT)
+				.map_err(|e| format!("Session failed ({:?})", e))
+				.map(move |_| {
+					// Close the sockets
+					drop((client, server));
+					let received_client = received_client.lock().clone();
+					let received_server = received_server.lock().clone();
+					(received_client, received_server)
+				})
+		}))
+		.unwrap();
+	rt.shutdown_now().wait().unwrap();
+
+	assert_eq!(received_client, expected_client);
+	assert_eq!(received_server, expected_server);
+}
@@ -11,8 +11,13 @@ use rustyline::error::ReadlineError;
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
 use termion::{color, style};
-use tsproto::commands::Command;
-use tsproto_structs::messages;
+use tsproto::commands;
+use tsproto::packets::{Direction, Flags, OutPacket, PacketType};
+use tsproto_structs::messages::{self, Message};
+
+mod schema;
+
+pub use crate::schema::{convert_value, enum_values, find_message, validate, ValidationError};
 
 const PROMPT: &str = "> ";
 
@@ -139,7 +144,8 @@ impl CommandHelper {
         res
     }
 
-    fn highlight_command<'s>(&self, s: &'s str) -> String {
+    /// If `check` is set, unknown arguments and invalid values are marked.
+    fn highlight_command<'s>(&self, s: &'s str, check: bool) -> String {
         let mut parts: Vec<_> = s.split(SPLIT_CHARS).collect();
         let mut pos = 0;
 
@@ -204,11 +210,15 @@ impl CommandHelper {
         }
 
         // Print
+        let msg = if check { self.get_message(s) } else { None };
         for group in &list_args {
             for (name, val, del) in group {
                 let is_static = static_args.contains(&name);
+                let field = msg.and_then(|m| schema::find_field(m, name));
                 // Name
-                if is_static {
+                if msg.is_some() && field.is_none() && !name.is_empty() {
+                    res.push_str(&error(&name));
+                } else if is_static {
                     res.push_str(&static_arg(&name));
                 } else {
                     res.push_str(&list_arg(&name));
@@ -218,7 +228,13 @@ impl CommandHelper {
                 if !val.is_empty() {
                     if val.starts_with('=') {
                         res.push_str(&eq("="));
-                        res.push_str(&value(&val[1..]));
+                        let v = &val[1..];
+                        if field.map(|f| !v.is_empty() && convert_value(f, v).is_none())
+                            .unwrap_or(false) {
+                            res.push_str(&error(v));
+                        } else {
+                            res.push_str(&value(v));
+                        }
                     } else {
                         res.push_str(&value(val));
                     }
@@ -237,6 +253,42 @@ impl CommandHelper {
         s.split(SPLIT_CHARS).next().and_then(|s|
             if s.contains('=') { None } else { Some(s) })
     }
+
+    /// Find the message of the command in the line.
+    fn get_message(&self, s: &str) -> Option<&'static Message> {
+        self.get_command_name(s).and_then(find_message)
+    }
+
+    /// Possible values for an argument, only enums and bools are completed.
+    fn get_values(&self, msg: &Message, name: &str, prefix: &str) -> Vec<String> {
+        let field = match schema::find_field(msg, name) {
+            Some(f) => f,
+            None => return Vec::new(),
+        };
+        let prefix = prefix.to_lowercase();
+        if let Some(vals) = enum_values(field.type_s.trim_end_matches('?')) {
+            vals.into_iter()
+                .map(|(name, _)| name)
+                .filter(|name| name.to_lowercase().starts_with(&prefix))
+                .collect()
+        } else if field.type_s.starts_with("bool") {
+            ["0", "1"].iter().filter(|v| v.starts_with(&prefix)).map(|v| v.to_string())
+                .collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Required arguments of the message, which are not in the line yet.
+    fn get_missing(&self, msg: &Message, s: &str) -> Vec<&'static str> {
+        let given: Vec<_> = s.split(SPLIT_CHARS).skip(1)
+            .map(|p| p.split('=').next().unwrap())
+            .collect();
+        schema::get_fields(msg)
+            .filter(|(f, optional)| !optional && !given.contains(&f.ts.as_str()))
+            .map(|(f, _)| f.ts.as_str())
+            .collect()
+    }
 }
 
 impl rustyline::Helper for CommandHelper {}
@@ -259,25 +311,23 @@ impl Completer for CommandHelper {
 
         if let Some(pos) = s.rfind(SPLIT_CHARS) {
             // Complete arguments
-            let command_name = self.get_command_name(s);
-            if let Some((_command_name, msg)) = command_name
-                .and_then(|c| messages::DATA.msg_group.iter()
-                    .flat_map(|g| g.msg.iter())
-                    .find(|msg| msg.notify.as_ref().map(|s| s == c).unwrap_or(false))
-                    .map(|r| (c, r))) {
+            if let Some(msg) = self.get_message(s) {
                 // Check where we end in an argument
                 let part = &s[pos + 1..];
-                if !part.contains('=') {
+                if let Some(eq_pos) = part.find('=') {
+                    // Value
+                    let val = &part[eq_pos + 1..];
+                    let vals = self.get_values(msg, &part[..eq_pos], val);
+                    return Ok((s.len() - val.len(), vals));
+                } else {
                     // Name
-                    let vals = msg.attributes.iter()
-                        .map(|a| messages::DATA.get_field(a))
-                        .filter_map(|f| if f.ts.starts_with(part) { Some(f.ts.clone()) }
-                            else { None });
-                    // TODO Wrong position
+                    let vals = schema::get_fields(msg)
+                        .filter_map(|(f, _)| if f.ts.starts_with(part) {
+                            Some(format!("{}=", f.ts))
+                        } else {
+                            None
+                        });
                     return Ok((s.len() - part.len(), vals.collect()));
-                } else {
-                    // Value
-                    // TODO Complete enums
                 }
             }
             return Ok((pos, Vec::new()));
@@ -299,28 +349,30 @@ impl Hinter for CommandHelper {
         }
         if let Some(pos) = s.rfind(SPLIT_CHARS) {
             // Complete arguments
-            let command_name = self.get_command_name(s);
-            if let Some((_command_name, msg)) = command_name
-                .and_then(|c| messages::DATA.msg_group.iter()
-                    .flat_map(|g| g.msg.iter())
-                    .find(|msg| msg.notify.as_ref().map(|s| s == c).unwrap_or(false))
-                    .map(|r| (c, r))) {
+            if let Some(msg) = self.get_message(s) {
                 // Check where we end in an argument
                 let part = &s[pos + 1..];
-                if !part.contains('=') {
+                if part.is_empty() {
+                    // Show the next missing argument
+                    return self.get_missing(msg, s).first().map(|a| format!("{}=", a));
+                } else if let Some(eq_pos) = part.find('=') {
+                    // Value
+                    let val = &part[eq_pos + 1..];
+                    let vals = self.get_values(msg, &part[..eq_pos], val);
+                    if vals.len() == 1 && !val.is_empty() {
+                        return Some(vals[0][val.len()..].to_string());
+                    }
+                } else {
                     // Name
-                    let mut vals = msg.attributes.iter()
-                        .map(|a| messages::DATA.get_field(a))
-                        .filter_map(|f| if f.ts.starts_with(part) { Some(&f.ts) }
+                    let mut vals = schema::get_fields(msg)
+                        .filter_map(|(f, _)| if f.ts.starts_with(part) { Some(&f.ts) }
                             else { None });
                     if let Some(arg) = vals.next() {
                         if vals.next().is_none() {
                             // Only one argument left
-                            return Some(arg[part.len()..].to_string());
+                            return Some(format!("{}=", &arg[part.len()..]));
                         }
                     }
-                } else {
-                    // Value
                 }
             }
             return None;
@@ -351,13 +403,10 @@ impl Highlighter for CommandHelper {
 
         // Try to find the command
         if let Some((command_name, msg)) = command_name
-            .and_then(|c| messages::DATA.msg_group.iter()
-                .flat_map(|g| g.msg.iter())
-                .find(|msg| msg.notify.as_ref().map(|s| s == c).unwrap_or(false))
-                .map(|r| (c, r))) {
+            .and_then(|c| find_message(c).map(|r| (c, r))) {
             if *self.last_command_name.borrow() != command_name {
                 // Print message
-                let mut cmd = Command::new(command_name);
+                let mut cmd = command_name.to_string();
                 for attr in &msg.attributes {
                     let field = messages::DATA.get_field(attr);
                     let attr_name = if attr.ends_with('?') {
@@ -374,20 +423,18 @@ impl Highlighter for CommandHelper {
                             field.type_s.to_string()
                         }
                     };
-                    cmd.push(attr_name, format!("<{}>", type_s));
+                    cmd.push_str(&format!(" {}=<{}>", attr_name, type_s));
                 }
 
                 // Print
-                let mut buf = Vec::new();
-                cmd.write(&mut buf).unwrap();
-                let res = self.highlight_command(std::str::from_utf8(&buf).unwrap());
+                let res = self.highlight_command(&cmd, false);
                 // TODO Don't use println
                 println!("\r{}", res);
                 self.last_command_name.replace(command_name.into());
             }
         }
 
-        let res = self.highlight_command(s);
+        let res = self.highlight_command(s, true);
         Cow::Owned(self.highlight_str(&res))
     }
 
@@ -404,7 +451,24 @@ impl Highlighter for CommandHelper {
     }
 }
 
-pub fn read_command() -> Option<Command> {
+/// Parse a line into a command packet.
+///
+/// The command is checked against the message declarations. Lines starting
+/// with `!` are sent verbatim without any checks.
+pub fn parse_command(line: &str) -> Result<OutPacket, String> {
+    if line.starts_with('!') {
+        let mut packet = OutPacket::new_with_dir(Direction::C2S,
+            Flags::empty(), PacketType::Command);
+        packet.data_mut().extend_from_slice(line[1..].as_bytes());
+        return Ok(packet);
+    }
+
+    let cmd = commands::parse_command(line).map_err(|e| format!("{}", e))?;
+    validate(&cmd).map_err(|errors| errors.iter().map(|e| e.to_string())
+        .collect::<Vec<_>>().join(", "))
+}
+
+pub fn read_command() -> Option<OutPacket> {
     let mut rl = EDITOR.lock().unwrap();
     loop {
         let readline = rl.0.readline("> ");
@@ -413,16 +477,10 @@ pub fn read_command() -> Option<Command> {
                 if line.is_empty() {
                     continue;
                 }
-                if line.starts_with('!') {
-                    // Send as verbatim command
-                    return Some(Command::new(line));
-                }
-
-                let cmd = Command::read((), &mut line.as_bytes());
-                match cmd {
+                match parse_command(&line) {
                     Ok(c) => return Some(c),
                     Err(e) => {
-                        println!("Failed to parse command: {:?}", e);
+                        println!("Failed to parse command: {}", e);
                     }
                 }
             },
@@ -0,0 +1,274 @@
+//! Check commands against the message declarations.
+use std::borrow::Cow;
+use std::fmt;
+
+use num_traits::FromPrimitive;
+use tsproto::commands::CommandData;
+use tsproto::packets::{Direction, OutCommand, OutPacket, PacketType};
+use tsproto_commands::{ChannelType, ClientType, Codec, CodecEncryptionMode, GroupNamingMode,
+    GroupType, HostBannerMode, HostMessageMode, LicenseType, LogLevel, PermissionType,
+    PluginTargetMode, Reason, TextMessageTargetMode, TokenType};
+use tsproto_structs::messages::{self, Field, Message, MessageGroup};
+
+/// A problem in a typed command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ValidationError {
+    /// There is no declaration for a command with this name.
+    UnknownCommand(String),
+    /// A required argument is not given.
+    MissingArgument(String),
+    /// The command has no argument with this name.
+    UnknownArgument(String),
+    /// The value cannot be converted to the type of the argument.
+    InvalidValue { name: String, value: String, type_s: String },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::UnknownCommand(name) => write!(f, "Unknown command {}", name),
+            ValidationError::MissingArgument(name) => write!(f, "Missing argument {}", name),
+            ValidationError::UnknownArgument(name) => write!(f, "Unknown argument {}", name),
+            ValidationError::InvalidValue { name, value, type_s } =>
+                write!(f, "Invalid value {:?} for {}, expected {}", value, name, type_s),
+        }
+    }
+}
+
+/// Find the declaration of a command by the name which is used by TeamSpeak.
+///
+/// Commands which are sent by the client are preferred.
+pub fn find_message(name: &str) -> Option<&'static Message> {
+    find_message_group(name).map(|(_, msg)| msg)
+}
+
+fn find_message_group(name: &str) -> Option<(&'static MessageGroup, &'static Message)> {
+    let mut res = None;
+    for g in &messages::DATA.msg_group {
+        for msg in &g.msg {
+            if msg.notify.as_ref().map(|n| n == name).unwrap_or(false) {
+                if g.default.c2s {
+                    return Some((g, msg));
+                }
+                res = res.or(Some((g, msg)));
+            }
+        }
+    }
+    res
+}
+
+/// All fields of a message with the information if they are optional.
+pub fn get_fields(msg: &Message) -> impl Iterator<Item = (&'static Field, bool)> + '_ {
+    msg.attributes.iter().map(|a| (messages::DATA.get_field(a), a.ends_with('?')))
+}
+
+/// Find the field of a message by its TeamSpeak name.
+pub fn find_field(msg: &Message, name: &str) -> Option<&'static Field> {
+    get_fields(msg).map(|(f, _)| f).find(|f| f.ts == name)
+}
+
+fn variants<T: FromPrimitive + fmt::Debug>() -> Vec<(String, u8)> {
+    (0..=u8::max_value())
+        .filter_map(|i| T::from_u8(i).map(|v| (format!("{:?}", v), i)))
+        .collect()
+}
+
+/// The names and numbers of all values of an enum type.
+///
+/// Returns `None` if the type is not an enum.
+pub fn enum_values(type_s: &str) -> Option<Vec<(String, u8)>> {
+    macro_rules! enums {
+        ($($e:ident),*) => {
+            match type_s {
+                $(stringify!($e) => Some(variants::<$e>()),)*
+                _ => None,
+            }
+        };
+    }
+    enums!(PermissionType, TextMessageTargetMode, HostMessageMode, HostBannerMode, Codec,
+        CodecEncryptionMode, Reason, ClientType, GroupNamingMode, GroupType, LicenseType,
+        ChannelType, TokenType, PluginTargetMode, LogLevel)
+}
+
+/// The type without optional and array markers.
+fn base_type(mut t: &str) -> &str {
+    if t.ends_with("[]") {
+        t = &t[..t.len() - 2];
+    }
+    if t.ends_with('?') {
+        t = &t[..t.len() - 1];
+    }
+    if t.ends_with('T') {
+        t = &t[..t.len() - 1];
+    }
+    t
+}
+
+/// Check if `value` fits a single value of the type.
+///
+/// Enum values can be given by name, they are converted to their number.
+fn convert_single<'a>(t: &str, value: &'a str) -> Option<Cow<'a, str>> {
+    let ok = match t {
+        "bool" => match value {
+            "0" | "1" => true,
+            "true" => return Some("1".into()),
+            "false" => return Some("0".into()),
+            _ => false,
+        },
+        "byte" | "u8" => value.parse::<u8>().is_ok(),
+        "ushort" | "u16" | "ClientId" => value.parse::<u16>().is_ok(),
+        "short" | "i16" => value.parse::<i16>().is_ok(),
+        "int" | "i32" => value.parse::<i32>().is_ok(),
+        "uint" | "u32" | "PermissionId" => value.parse::<u32>().is_ok(),
+        "long" | "i64" | "DateTime" => value.parse::<i64>().is_ok(),
+        "ulong" | "u64" => value.parse::<u64>().is_ok(),
+        "float" | "f32" => value.parse::<f32>().is_ok(),
+        "double" | "f64" => value.parse::<f64>().is_ok(),
+        // The hash is sometimes sent as signed integer
+        "IconHash" => value.parse::<u32>().is_ok() || value.parse::<i32>().is_ok(),
+        _ if t.starts_with("Duration") => value.parse::<f64>().is_ok(),
+        _ if t.ends_with("Id") => value.parse::<u64>().is_ok(),
+        _ => {
+            if let Some(vals) = enum_values(t) {
+                if let Ok(num) = value.parse::<u8>() {
+                    vals.iter().any(|(_, n)| *n == num)
+                } else {
+                    return vals
+                        .iter()
+                        .find(|(name, _)| name.eq_ignore_ascii_case(value))
+                        .map(|(_, n)| n.to_string().into());
+                }
+            } else {
+                // Strings and unknown types
+                true
+            }
+        }
+    };
+    if ok {
+        Some(value.into())
+    } else {
+        None
+    }
+}
+
+/// Check if `value` fits the type of `field`.
+///
+/// Returns the value, which should be sent, or `None` if it is invalid.
+pub fn convert_value<'a>(field: &Field, value: &'a str) -> Option<Cow<'a, str>> {
+    let t = base_type(&field.type_s);
+    let is_array = field.type_s.ends_with("[]")
+        || field.modifier.as_ref().map(|m| m == "array").unwrap_or(false);
+    if is_array && value.contains(',') {
+        let parts = value
+            .split(',')
+            .map(|v| convert_single(t, v))
+            .collect::<Option<Vec<_>>>()?;
+        Some(parts.join(",").into())
+    } else {
+        convert_single(t, value)
+    }
+}
+
+/// Convert the values of arguments and collect all problems.
+fn convert_args(msg: &Message, args: &[(&str, Cow<str>)], errors: &mut Vec<ValidationError>)
+    -> Vec<(String, String)> {
+    let mut res = Vec::new();
+    for (name, value) in args {
+        if let Some(field) = find_field(msg, name) {
+            if let Some(v) = convert_value(field, value) {
+                res.push((name.to_string(), v.into_owned()));
+            } else {
+                errors.push(ValidationError::InvalidValue {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                    type_s: field.type_s.clone(),
+                });
+            }
+        } else {
+            errors.push(ValidationError::UnknownArgument(name.to_string()));
+        }
+    }
+    res
+}
+
+/// Check all arguments of a parsed command.
+///
+/// On success, the command is returned with enum names converted to numbers.
+pub fn validate(cmd: &CommandData) -> Result<OutPacket, Vec<ValidationError>> {
+    let (group, msg) = find_message_group(cmd.name)
+        .ok_or_else(|| vec![ValidationError::UnknownCommand(cmd.name.to_string())])?;
+    let mut errors = Vec::new();
+
+    let static_args = convert_args(msg, &cmd.static_args, &mut errors);
+    let list_args: Vec<_> = cmd.list_args.iter()
+        .map(|l| convert_args(msg, l, &mut errors))
+        .collect();
+
+    // Every list entry needs all required arguments
+    for args in cmd.iter() {
+        for (field, optional) in get_fields(msg) {
+            if !optional && !args.0.contains_key(field.ts.as_str()) {
+                let e = ValidationError::MissingArgument(field.ts.clone());
+                if !errors.contains(&e) {
+                    errors.push(e);
+                }
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let p_type = if group.default.low { PacketType::CommandLow } else { PacketType::Command };
+    Ok(OutCommand::new(
+        Direction::C2S,
+        p_type,
+        cmd.name,
+        static_args.iter().map(|(k, v)| (k, v)),
+        list_args.iter().map(|l| l.iter().map(|(k, v)| (k, v))),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(type_s: &str, modifier: Option<&str>) -> Field {
+        Field {
+            map: "test".into(),
+            ts: "test".into(),
+            pretty: "Test".into(),
+            type_s: type_s.into(),
+            modifier: modifier.map(String::from),
+        }
+    }
+
+    #[test]
+    fn convert_values() {
+        assert_eq!(convert_value(&field("ushort", None), "42").as_ref().map(|s| s.as_ref()),
+            Some("42"));
+        assert!(convert_value(&field("ushort", None), "70000").is_none());
+        assert!(convert_value(&field("ChannelId", None), "a").is_none());
+        assert_eq!(convert_value(&field("bool", None), "true").as_ref().map(|s| s.as_ref()),
+            Some("1"));
+        assert_eq!(convert_value(&field("str", None), "a b").as_ref().map(|s| s.as_ref()),
+            Some("a b"));
+        assert_eq!(convert_value(&field("u64", Some("array")), "1,2,3")
+            .as_ref().map(|s| s.as_ref()), Some("1,2,3"));
+        assert!(convert_value(&field("u64", Some("array")), "1,x").is_none());
+    }
+
+    #[test]
+    fn convert_enums() {
+        let f = field("TextMessageTargetMode", None);
+        assert_eq!(convert_value(&f, "channel").as_ref().map(|s| s.as_ref()), Some("2"));
+        assert_eq!(convert_value(&f, "3").as_ref().map(|s| s.as_ref()), Some("3"));
+        assert!(convert_value(&f, "4").is_none());
+        assert!(convert_value(&f, "nothing").is_none());
+
+        let levels = enum_values("LogLevel").unwrap();
+        assert_eq!(levels[0], ("Error".to_string(), 1));
+        assert!(enum_values("str").is_none());
+    }
+}
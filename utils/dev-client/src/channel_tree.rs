@@ -0,0 +1,63 @@
+//! Render the channel tree of a server as text lines.
+use tsclientlib::data::{self, Channel, Client};
+use tsclientlib::{ChannelId, ClientId};
+
+/// Sort the children of `parent` in the order of the server.
+///
+/// The first channel has an `order` of `0`, every following channel has the id
+/// of its predecessor as `order`. Channels which do not fit into this chain
+/// are appended at the end.
+fn children<'a>(channels: &[&'a Channel], parent: ChannelId)
+	-> Vec<&'a Channel> {
+	let mut rest: Vec<_> =
+		channels.iter().filter(|c| c.parent == parent).cloned().collect();
+	rest.sort_by_key(|c| c.id);
+	let mut res = Vec::with_capacity(rest.len());
+	let mut prev = ChannelId(0);
+	while let Some(i) = rest.iter().position(|c| c.order == prev) {
+		let c = rest.remove(i);
+		prev = c.id;
+		res.push(c);
+	}
+	res.append(&mut rest);
+	res
+}
+
+fn render_channels(
+	con: &data::Connection,
+	clients: &[(ClientId, &Client)],
+	channels: &[&Channel],
+	parent: ChannelId,
+	depth: usize,
+	res: &mut Vec<String>,
+)
+{
+	let indention = "  ".repeat(depth);
+	for channel in children(channels, parent) {
+		res.push(format!("{}- {}", indention, channel.name));
+		for (id, client) in clients {
+			if client.channel == channel.id {
+				let own = if *id == con.own_client { "*" } else { " " };
+				res.push(format!("{} {} {}", indention, own, client.name));
+			}
+		}
+
+		render_channels(con, clients, channels, channel.id, depth + 1, res);
+	}
+}
+
+/// Returns one line per channel and client.
+///
+/// The own client is marked with a `*`.
+pub fn render(con: &data::Connection) -> Vec<String> {
+	let channels: Vec<_> = con.server.channels.values().collect();
+	let mut clients: Vec<_> =
+		con.server.clients.iter().map(|(id, c)| (*id, c)).collect();
+	// Higher talk power first, like in the TeamSpeak client
+	clients.sort_by(|(_, a), (_, b)| b.talk_power.cmp(&a.talk_power)
+		.then_with(|| a.name.cmp(&b.name)));
+
+	let mut res = vec![con.server.name.clone()];
+	render_channels(con, &clients, &channels, ChannelId(0), 0, &mut res);
+	res
+}
@@ -12,25 +12,30 @@ use std::thread;
 use chrono::{DateTime, Utc};
 use crossterm::{style, StyledObject};
 use failure::format_err;
-use futures::{Future, Sink, Stream};
+use futures::{Future, Stream};
 use futures::sync::mpsc;
 use lazy_static::lazy_static;
 use serde_derive::{Deserialize, Serialize};
-use slog::{error, Drain, KV, Level, Logger, o};
+use slog::{error, info, Drain, KV, Level, Logger, o};
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
-use tsclientlib::{ConnectOptions, Connection, DisconnectOptions, Reason};
-use tsclientlib::messages::Message;
-use tsproto::commands::Command;
-use tsproto::handler_data::{PacketObserver, UdpPacketObserver};
-use tsproto::packets::{self, Header, Packet, PacketType};
+use tsclientlib::{ConnectOptions, Connection, ConnectionLock,
+	DisconnectOptions, Reason};
+use tsproto::crypto::EccKeyPrivP256;
+use tsproto::handler_data::{InCommandObserver, InPacketObserver,
+	InUdpPacketObserver, OutPacketObserver, OutUdpPacketObserver};
+use tsproto::packets::{Direction, InCommand, InPacket, InUdpPacket,
+	OutCommand, OutPacket, PacketType};
 
 const SETTINGS_FILENAME: &str = "settings.toml";
 const KEY_FILENAME: &str = "private.key";
 const HISTORY_FILENAME: &str = "history.txt";
+/// The maximum number of saved commands in the history.
+const MAX_HISTORY_LEN: usize = 1000;
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
+mod channel_tree;
 mod colors;
 mod table_widget;
 mod text_widget;
@@ -49,10 +54,22 @@ struct Args {
 	#[structopt(
 		short = "a",
 		long = "address",
-		default_value = "localhost",
-		help = "The address of the server to connect to"
+		help = "The address of the server to connect to. If a bookmark is \
+		        given, the address is saved in the bookmark."
 	)]
-	address: String,
+	address: Option<String>,
+	#[structopt(
+		short = "b",
+		long = "bookmark",
+		help = "The name of the bookmark to connect to"
+	)]
+	bookmark: Option<String>,
+	#[structopt(
+		short = "n",
+		long = "name",
+		help = "The nickname to use"
+	)]
+	name: Option<String>,
 	#[structopt(
 		long = "settings",
 		help = "The settings file"
@@ -63,6 +80,22 @@ struct Args {
 #[derive(Debug, Default, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 struct Settings {
+	/// The default nickname.
+	#[serde(default)]
+	name: Option<String>,
+	/// Saved servers. The first one is used if no address is given.
+	#[serde(default)]
+	bookmarks: Vec<Bookmark>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Bookmark {
+	name: String,
+	address: String,
+	/// Overwrites the default nickname for this server.
+	#[serde(default)]
+	nickname: Option<String>,
 }
 
 #[allow(dead_code)] // TODO Remove
@@ -74,14 +107,17 @@ struct State {
 
 	ui_sender: std::sync::mpsc::Sender<UiEvent>,
 	ui_recv: Option<std::sync::mpsc::Receiver<UiEvent>>,
-	command_sender: mpsc::UnboundedSender<Option<Command>>,
-	command_recv: Option<mpsc::UnboundedReceiver<Option<Command>>>,
+	command_sender: mpsc::UnboundedSender<Option<OutPacket>>,
+	command_recv: Option<mpsc::UnboundedReceiver<Option<OutPacket>>>,
 
 	args: Args,
 	settings: Settings,
-	private_key: Option<Vec<u8>>,
+	/// Entered commands, the newest command is at the end.
+	history: Vec<String>,
 
 	con: Option<Connection>,
+	/// The rendered channel tree of the server.
+	channel_tree: Vec<String>,
 
 	// Recorded
 	log_records: Vec<LogRecord>,
@@ -109,20 +145,23 @@ struct LogRecord {
 	time: DateTime<Utc>,
 	data: Vec<StyledObject<String>>,
 }
+/// A command as text.
 #[derive(Clone, Debug)]
 struct MessageRecord {
 	base: RecordBase,
-	data: Message,
+	data: String,
 }
+/// The decrypted packet in `Debug` form.
 #[derive(Clone, Debug)]
 struct PacketRecord {
 	base: RecordBase,
-	data: Packet,
+	data: String,
 }
+/// The udp packet in `Debug` form.
 #[derive(Clone, Debug)]
 struct UdpPacketRecord {
 	base: RecordBase,
-	data: Vec<u8>,
+	data: String,
 }
 
 lazy_static! {
@@ -149,10 +188,12 @@ lazy_static! {
 		};
 		let config_file = args.settings.as_ref().map(PathBuf::from)
 			.unwrap_or_else(|| proj_dirs.config_dir().join(SETTINGS_FILENAME));
+		let mut write_settings = false;
 		let settings = match fs::read_to_string(&config_file) {
 			Ok(r) => toml::from_str(&r).unwrap(),
 			Err(e) => {
 				error!(logger, "Failed to read settings"; "error" => ?e);
+				write_settings = args.settings.is_none();
 				Settings::default()
 			}
 		};
@@ -162,84 +203,113 @@ lazy_static! {
 			error!(logger, "Failed to create data dictionary"; "error" => ?e);
 		}
 		let history_file = proj_dirs.data_dir().join(HISTORY_FILENAME);
-		//if let Err(e) = ts_cli_completer::load_history(&history_file) {
-			//error!(logger, "Failed to load the history"; "error" => ?e);
-		//}
+		let history = match fs::read_to_string(&history_file) {
+			Ok(r) => r.lines().map(String::from).collect(),
+			Err(e) => {
+				info!(logger, "Failed to load the history"; "error" => ?e);
+				Vec::new()
+			}
+		};
 
 		let (ui_sender, ui_recv) = std::sync::mpsc::channel();
 		let ui_recv = Some(ui_recv);
 		let (command_sender, command_recv) = mpsc::unbounded();
 		let command_recv = Some(command_recv);
 
-		Mutex::new(State {
+		let state = State {
 			logger, proj_dirs, config_file, history_file,
-			args, settings,
-			private_key: Default::default(),
+			args, settings, history,
 
 			ui_sender, ui_recv,
 			command_sender, command_recv,
 
 			con: Default::default(),
+			channel_tree: Default::default(),
 
 			log_records: Default::default(),
 			messages: Default::default(),
 			packets: Default::default(),
 			udp_packets: Default::default(),
 			entries: Default::default(),
-		})
+		};
+		if write_settings {
+			state.save_settings();
+		}
+		Mutex::new(state)
 	};
 }
 
-struct PacketRecorder {
-	incoming: bool,
-}
-impl<T: Send> PacketObserver<T> for PacketRecorder {
-	fn observe(&self, _: &mut (T, tsproto::connection::Connection), packet: &mut Packet) {
-		let p_type = packet.header.get_type();
+/// Records incoming and outgoing commands.
+struct CommandRecorder;
+impl<T: Send> InCommandObserver<T> for CommandRecorder {
+	fn observe(&self, _: &mut (T, tsproto::connection::Connection),
+		cmd: &InCommand) {
+		let text = String::from_utf8_lossy(cmd.content()).into_owned();
 		let mut state = STATE.lock().unwrap();
-		let base = RecordBase::new(self.incoming, p_type);
-		state.packets.push(PacketRecord {
-			base: base.clone(),
-			data: packet.clone(),
-		});
-		//state.add_entry(Entry::new_packet)
-		match &packet.data {
-			packets::Data::Command(cmd) | packets::Data::CommandLow(cmd) => {
-				for c in cmd.get_commands() {
-					match Message::parse(c) {
-						Ok(msg) => {
-							state.add_entry(Entry::new_message(MessageRecord {
-								base: base.clone(),
-								data: msg,
-							}));
-						}
-						Err(e) => {
-							error!(state.logger, "Failed to parse message";
-								"error" => ?e);
-						}
-					}
-				}
-				let _ = state.ui_sender.send(UiEvent::Message);
-			}
-			_ => {
-				let _ = state.ui_sender.send(UiEvent::Packet);
-			}
+		state.add_message(true, cmd.packet_type(), text);
+	}
+}
+
+impl<T: Send> OutPacketObserver<T> for CommandRecorder {
+	fn observe(&self, _: &mut (T, tsproto::connection::Connection),
+		packet: &mut OutPacket) {
+		let p_type = packet.header().packet_type();
+		if p_type == PacketType::Command || p_type == PacketType::CommandLow {
+			let text = String::from_utf8_lossy(packet.content()).into_owned();
+			let mut state = STATE.lock().unwrap();
+			state.add_message(false, p_type, text);
 		}
 	}
 }
 
-struct UdpPacketRecorder {
-	incoming: bool,
+struct PacketRecorder;
+impl<T: Send> InPacketObserver<T> for PacketRecorder {
+	fn observe(&self, _: &mut (T, tsproto::connection::Connection),
+		packet: &InPacket) {
+		let p_type = packet.header().packet_type();
+		let mut state = STATE.lock().unwrap();
+		let rec = PacketRecord {
+			base: RecordBase::new(true, p_type),
+			data: format!("{:?}", packet),
+		};
+		state.packets.push(rec.clone());
+		state.add_entry(Entry::new_packet(rec));
+		let _ = state.ui_sender.send(UiEvent::Packet);
+	}
 }
-impl UdpPacketObserver for UdpPacketRecorder {
-	fn observe(&self, _: SocketAddr, udp_packet: &[u8]) {
+
+impl<T: Send> OutPacketObserver<T> for PacketRecorder {
+	fn observe(&self, _: &mut (T, tsproto::connection::Connection),
+		packet: &mut OutPacket) {
+		let p_type = packet.header().packet_type();
 		let mut state = STATE.lock().unwrap();
-		// TODO Get packet type
-		state.udp_packets.push(UdpPacketRecord {
-			base: RecordBase::new(self.incoming, None),
-			data: udp_packet.to_vec(),
-		});
-		let _ = state.ui_sender.send(UiEvent::UdpPacket);
+		let rec = PacketRecord {
+			base: RecordBase::new(false, p_type),
+			data: format!("{:?}", packet),
+		};
+		state.packets.push(rec.clone());
+		state.add_entry(Entry::new_packet(rec));
+		let _ = state.ui_sender.send(UiEvent::Packet);
+	}
+}
+
+struct UdpPacketRecorder;
+impl InUdpPacketObserver for UdpPacketRecorder {
+	fn observe(&self, _: SocketAddr, udp_packet: &InPacket) {
+		let p_type = udp_packet.header().packet_type();
+		let data = format!("{:?}", InUdpPacket::new(udp_packet));
+		STATE.lock().unwrap().add_udp_packet(true, Some(p_type), data);
+	}
+}
+
+impl OutUdpPacketObserver for UdpPacketRecorder {
+	fn observe(&self, _: SocketAddr, udp_packet: &[u8]) {
+		let (p_type, data) =
+			match InPacket::try_new(udp_packet.into(), Direction::C2S) {
+				Ok(p) => (Some(p.header().packet_type()), format!("{:?}", p)),
+				Err(e) => (None, format!("Invalid packet ({:?})", e)),
+			};
+		STATE.lock().unwrap().add_udp_packet(false, p_type, data);
 	}
 }
 
@@ -338,72 +408,192 @@ impl State {
 		}
 		self.entries.push(EntryWidget(entry));
 	}
+
+	fn add_message(&mut self, incoming: bool, p_type: PacketType,
+		data: String) {
+		let rec = MessageRecord {
+			base: RecordBase::new(incoming, p_type),
+			data,
+		};
+		self.messages.push(rec.clone());
+		self.add_entry(Entry::new_message(rec));
+		let _ = self.ui_sender.send(UiEvent::Message);
+	}
+
+	fn add_udp_packet(&mut self, incoming: bool, p_type: Option<PacketType>,
+		data: String) {
+		let rec = UdpPacketRecord {
+			base: RecordBase::new(incoming, p_type),
+			data,
+		};
+		self.udp_packets.push(rec.clone());
+		self.add_entry(Entry::new_udp_packet(rec));
+		let _ = self.ui_sender.send(UiEvent::UdpPacket);
+	}
+
+	fn save_settings(&self) {
+		if let Some(dir) = self.config_file.parent() {
+			if let Err(e) = fs::create_dir_all(dir) {
+				error!(self.logger, "Failed to create config dictionary";
+					"error" => ?e);
+				return;
+			}
+		}
+		let res = toml::to_string(&self.settings)
+			.map_err(failure::Error::from)
+			.and_then(|s| Ok(fs::write(&self.config_file, s.as_bytes())?));
+		if let Err(e) = res {
+			error!(self.logger, "Failed to write settings"; "error" => ?e);
+		}
+	}
+
+	fn save_history(&self) {
+		let start = self.history.len().saturating_sub(MAX_HISTORY_LEN);
+		let mut content = self.history[start..].join("\n");
+		content.push('\n');
+		if let Err(e) = fs::write(&self.history_file, content.as_bytes()) {
+			error!(self.logger, "Failed to save the history"; "error" => ?e);
+		}
+	}
+
+	/// Load the private key or create a new identity.
+	fn load_identity(&self) -> Result<EccKeyPrivP256> {
+		let key_file = self.proj_dirs.data_dir().join(KEY_FILENAME);
+		if let Ok(key) = fs::read_to_string(&key_file) {
+			return Ok(EccKeyPrivP256::import_str(key.trim())?);
+		}
+
+		info!(self.logger, "Creating new identity");
+		let key = EccKeyPrivP256::create()?;
+		fs::write(&key_file, key.to_ts()?.as_bytes())?;
+		Ok(key)
+	}
+
+	/// Get the bookmark we should connect to.
+	///
+	/// If a bookmark name and an address are given, the bookmark is saved.
+	fn get_bookmark(&mut self) -> Bookmark {
+		if let Some(name) = &self.args.bookmark {
+			let pos = self.settings.bookmarks.iter()
+				.position(|b| b.name == *name);
+			if let Some(address) = &self.args.address {
+				let bookmark = Bookmark {
+					name: name.clone(),
+					address: address.clone(),
+					nickname: self.args.name.clone(),
+				};
+				if let Some(pos) = pos {
+					self.settings.bookmarks[pos] = bookmark.clone();
+				} else {
+					self.settings.bookmarks.push(bookmark.clone());
+				}
+				self.save_settings();
+				return bookmark;
+			}
+			if let Some(pos) = pos {
+				return self.settings.bookmarks[pos].clone();
+			}
+			error!(self.logger, "Bookmark not found"; "name" => name);
+		} else if self.args.address.is_none() {
+			if let Some(b) = self.settings.bookmarks.first() {
+				return b.clone();
+			}
+		}
+
+		Bookmark {
+			name: String::new(),
+			address: self.args.address.clone()
+				.unwrap_or_else(|| "localhost".into()),
+			nickname: None,
+		}
+	}
+
+	fn connect_options(&mut self) -> Result<ConnectOptions> {
+		let bookmark = self.get_bookmark();
+		info!(self.logger, "Connecting"; "address" => &bookmark.address);
+		let name = self.args.name.clone()
+			.or(bookmark.nickname)
+			.or_else(|| self.settings.name.clone());
+		let mut options = ConnectOptions::new(bookmark.address)
+			.logger(self.logger.clone())
+			.private_key(self.load_identity()?)
+			.prepare_client(Box::new(|client| {
+				let mut client = client.lock();
+				client.add_in_command_observer("dev-client".into(),
+					Box::new(CommandRecorder));
+				client.add_out_packet_observer("dev-client-cmd".into(),
+					Box::new(CommandRecorder));
+				client.add_in_packet_observer("dev-client".into(),
+					Box::new(PacketRecorder));
+				client.add_out_packet_observer("dev-client".into(),
+					Box::new(PacketRecorder));
+				client.add_in_udp_packet_observer("dev-client".into(),
+					Box::new(UdpPacketRecorder));
+				client.add_out_udp_packet_observer("dev-client".into(),
+					Box::new(UdpPacketRecorder));
+			}));
+		if let Some(name) = name {
+			options = options.name(name);
+		}
+		Ok(options)
+	}
+}
+
+fn update_channel_tree(con: &ConnectionLock) {
+	let tree = channel_tree::render(con);
+	let mut state = STATE.lock().unwrap();
+	state.channel_tree = tree;
+	let _ = state.ui_sender.send(UiEvent::ChannelTree);
 }
 
 fn run() {
-	let (logger, recv) = {
+	let (logger, recv, options) = {
 		let mut state = STATE.lock().unwrap();
-		(state.logger.clone(), state.command_recv.take().unwrap())
-	};
-	tokio::run(
-		futures::lazy(move || {
-			let state = STATE.lock().unwrap();
-			let args = &state.args;
-			let con_config = ConnectOptions::new(args.address.clone())
-				.logger(logger.clone())
-				.prepare_client(Box::new(|client| {
-					let mut client = client.lock().unwrap();
-					client.add_udp_packet_observer(true, "dev-client".into(),
-						Box::new(UdpPacketRecorder { incoming: true }));
-					client.add_udp_packet_observer(false, "dev-client".into(),
-						Box::new(UdpPacketRecorder { incoming: false }));
-					client.add_packet_observer(true, "dev-client".into(),
-						Box::new(PacketRecorder { incoming: true }));
-					client.add_packet_observer(false, "dev-client".into(),
-						Box::new(PacketRecorder { incoming: false }));
-				}));
-
-			let con_config = con_config.private_key_ts(
-				"MG0DAgeAAgEgAiAIXJBlj1hQbaH0Eq0DuLlCmH8bl+veTAO2+\
-				k9EQjEYSgIgNnImcmKo7ls5mExb6skfK2Tw+u54aeDr0OP1ITs\
-				C/50CIA8M5nmDBnmDM/gZ//4AAAAAAAAAAAAAAAAAAAAZRzOI").unwrap();
-
-			if args.settings.is_none() {
-				// Write settings
-				//if let Err(e) = fs::create_dir_all(proj_dirs.config_dir()) {
-					//error!(logger, "Failed to create config dictionary"; "error" => ?e);
-				//} else if let Err(e) = fs::write(&config_file,
-					//toml::to_string(&settings).unwrap().as_bytes()) {
-					//error!(logger, "Failed to write settings"; "error" => ?e);
-				//}
+		let options = match state.connect_options() {
+			Ok(r) => r,
+			Err(e) => {
+				error!(state.logger, "Failed to create connection";
+					"error" => ?e);
+				return;
 			}
+		};
+		(state.logger.clone(), state.command_recv.take().unwrap(), options)
+	};
+	let logger2 = logger.clone();
 
-			// Connect
-			Connection::new(con_config)
-		}).and_then(|con| {
+	tokio::run(
+		Connection::new(options).and_then(|con| {
+			update_channel_tree(&con.lock());
+			con.add_on_event("dev-client".into(), Box::new(|con, _| {
+				update_channel_tree(con);
+			}));
 			STATE.lock().unwrap().con = Some(con.clone());
 
-			let cmd = Command::new("channelsubscribeall");
-			let header = Header::new(PacketType::Command);
-			let data = packets::Data::Command(cmd);
-			let packet = packets::Packet::new(header, data);
+			let packet = OutCommand::new::<String, String, String, String, _,
+				_, std::iter::Empty<_>>(
+				Direction::C2S,
+				PacketType::Command,
+				"channelsubscribeall",
+				std::iter::empty(),
+				std::iter::empty(),
+			);
 
 			// Send a message and wait until we get an answer for the return code
-			con.get_packet_sink().send(packet).map(|_| con)
-		}).and_then(|con| {
-
+			let con2 = con.clone();
+			con.send_packet(packet).map(move |_| con2)
+		}).and_then(move |con| {
 			// Wait for commands
 			let con2 = con.clone();
 			recv.take_while(|c| Ok(c.is_some()))
-				.map(|c| c.unwrap())
+				.filter_map(|c| c)
 				.map_err(|e| format_err!("Error reading command ({:?})", e).into())
-				.for_each(move |cmd| {
-					// Send command
-					let header = Header::new(PacketType::Command);
-					let data = packets::Data::Command(cmd);
-					let packet = packets::Packet::new(header, data);
-
-					con2.get_packet_sink().send(packet).map(|_| ())
+				.for_each(move |packet| {
+					// Send command, errors are only logged
+					let logger = logger.clone();
+					tokio::spawn(con2.send_packet(packet).map_err(move |e| {
+						error!(logger, "Command failed"; "error" => %e);
+					}));
+					Ok(())
 				})
 				.map(move |_| con)
 		}).and_then(|con| {
@@ -413,13 +603,10 @@ fn run() {
 					.reason(Reason::Clientdisconnect)
 					.message("Is this the real world?"),
 			)
-		}).map_err(|e| panic!("An error occurred {:?}", e)),
+		}).map_err(move |e| {
+			error!(logger2, "An error occurred"; "error" => %e);
+		}),
 	);
-
-	// Save history
-	//if let Err(e) = ts_cli_completer::save_history(&STATE.lock().unwrap().history_file) {
-		//error!(logger2, "Failed to save the history"; "error" => ?e);
-	//}
 }
 
 fn set_panic_hook() {
@@ -439,5 +626,7 @@ fn main() -> Result<()> {
 	thread::spawn(run);
 
 	// The ui has to run in the main thread to get events
-	ui()
+	let res = ui();
+	STATE.lock().unwrap().save_history();
+	res
 }
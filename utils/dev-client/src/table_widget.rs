@@ -3,9 +3,9 @@ use std::io::Write;
 use chrono::{Date, Utc};
 use crossterm::{Screen, TerminalColor, TerminalCursor};
 use crossterm::style::style;
-use tsproto::commands::Command;
 
-use crate::{LogRecord, MessageRecord, Result, State, STATE};
+use crate::{LogRecord, MessageRecord, PacketRecord, RecordBase, Result,
+	State, STATE, UdpPacketRecord};
 use crate::colors::*;
 use crate::text_widget::{Align, TextWidget};
 use crate::ui::{Rect, Widget};
@@ -42,6 +42,8 @@ pub(crate) enum Entry {
 	NewDate(Date<Utc>),
 	Log(LogRecord, TextWidget),
 	Message(MessageRecord, TextWidget),
+	Packet(PacketRecord, TextWidget),
+	UdpPacket(UdpPacketRecord, TextWidget),
 }
 
 /// One entry in the table.
@@ -58,6 +60,8 @@ impl EntryPosition {
 			if match e.0 {
 				Entry::Log(_, _) => vis.log,
 				Entry::Message(_, _) => vis.messages,
+				Entry::Packet(_, _) => vis.packets,
+				Entry::UdpPacket(_, _) => vis.udp_packets,
 				_ => true,
 			} {
 				return Some(e.clone());
@@ -106,11 +110,25 @@ impl Entry {
 	}
 
 	pub(crate) fn new_message(rec: MessageRecord) -> Self {
-		let cmd: Command = (&rec.data).into();
+		let t = Self::text(rec.data.clone());
+		Entry::Message(rec, t)
+	}
+
+	pub(crate) fn new_packet(rec: PacketRecord) -> Self {
+		let t = Self::text(rec.data.clone());
+		Entry::Packet(rec, t)
+	}
+
+	pub(crate) fn new_udp_packet(rec: UdpPacketRecord) -> Self {
+		let t = Self::text(rec.data.clone());
+		Entry::UdpPacket(rec, t)
+	}
+
+	fn text(content: String) -> TextWidget {
 		let mut t = TextWidget::new();
-		t.content.push(style(cmd.command).on(BACKGROUND_COLOR).no_reset());
+		t.content.push(style(content).on(BACKGROUND_COLOR).no_reset());
 		t.update_length();
-		Entry::Message(rec, t)
+		t
 	}
 
 	pub(crate) fn get_date(&self) -> Date<Utc> {
@@ -118,6 +136,8 @@ impl Entry {
 			Entry::NewDate(d) => *d,
 			Entry::Log(e, _) => e.time.date(),
 			Entry::Message(e, _) => e.time.date(),
+			Entry::Packet(e, _) => e.time.date(),
+			Entry::UdpPacket(e, _) => e.time.date(),
 		}
 	}
 }
@@ -127,7 +147,9 @@ impl EntryWidget {
 		match &mut self.0 {
 			Entry::NewDate(_) => {}
 			Entry::Log(_, t) |
-			Entry::Message(_, t) => t.vertical_align = align,
+			Entry::Message(_, t) |
+			Entry::Packet(_, t) |
+			Entry::UdpPacket(_, t) => t.vertical_align = align,
 		}
 	}
 }
@@ -140,7 +162,9 @@ impl Widget for EntryWidget {
 		match &mut self.0 {
 			Entry::NewDate(_) => {}
 			Entry::Log(_, t) => t.set_size(width - LOG_HEADER_LEN, height),
-			Entry::Message(_, t) => t.set_size(width - ENTRY_HEADER_LEN, height),
+			Entry::Message(_, t) |
+			Entry::Packet(_, t) |
+			Entry::UdpPacket(_, t) => t.set_size(width - ENTRY_HEADER_LEN, height),
 		}
 	}
 
@@ -148,7 +172,9 @@ impl Widget for EntryWidget {
 		match &self.0 {
 			Entry::NewDate(_) => 10,
 			Entry::Log(_, t) => LOG_HEADER_LEN + t.width(),
-			Entry::Message(_, t) => ENTRY_HEADER_LEN + t.width(),
+			Entry::Message(_, t) |
+			Entry::Packet(_, t) |
+			Entry::UdpPacket(_, t) => ENTRY_HEADER_LEN + t.width(),
 		}
 	}
 
@@ -156,7 +182,9 @@ impl Widget for EntryWidget {
 		match &self.0 {
 			Entry::NewDate(_) => 1,
 			Entry::Log(_, t) |
-			Entry::Message(_, t) => t.height(),
+			Entry::Message(_, t) |
+			Entry::Packet(_, t) |
+			Entry::UdpPacket(_, t) => t.height(),
 		}
 	}
 
@@ -179,22 +207,14 @@ impl Widget for EntryWidget {
 				area.x += LOG_HEADER_LEN;
 				t.paint(area, screen, cursor, color)?;
 			}
-			Entry::Message(p, t) => {
-				write!(screen, "{} ", p.base.time.format("%H:%M:%S%.3f"))?;
-				if p.base.incoming {
-					let text = style("IN").with(IN_COLOR);
-					text.paint(screen);
-				} else {
-					let text = style("OUT").with(OUT_COLOR);
-					text.paint(screen);
-				}
-				color.set_bg(BACKGROUND_COLOR, screen);
-				color.set_fg(FONT_COLOR, screen);
-
-				// Content
-				area.width -= ENTRY_HEADER_LEN;
-				area.x += ENTRY_HEADER_LEN;
-				t.paint(area, screen, cursor, color)?;
+			Entry::Message(r, t) => {
+				Self::paint_record(&r.base, t, area, screen, cursor, color)?;
+			}
+			Entry::Packet(r, t) => {
+				Self::paint_record(&r.base, t, area, screen, cursor, color)?;
+			}
+			Entry::UdpPacket(r, t) => {
+				Self::paint_record(&r.base, t, area, screen, cursor, color)?;
 			}
 		}
 
@@ -202,6 +222,29 @@ impl Widget for EntryWidget {
 	}
 }
 
+impl EntryWidget {
+	/// Paint the time, the direction and the content of a record.
+	fn paint_record(base: &RecordBase, t: &TextWidget, mut area: Rect,
+		screen: &mut Screen, cursor: &TerminalCursor,
+		color: &mut TerminalColor) -> Result<()> {
+		write!(screen, "{} ", base.time.format("%H:%M:%S%.3f"))?;
+		if base.incoming {
+			let text = style("IN").with(IN_COLOR);
+			text.paint(screen);
+		} else {
+			let text = style("OUT").with(OUT_COLOR);
+			text.paint(screen);
+		}
+		color.set_bg(BACKGROUND_COLOR, screen);
+		color.set_fg(FONT_COLOR, screen);
+
+		// Content
+		area.width -= ENTRY_HEADER_LEN;
+		area.x += ENTRY_HEADER_LEN;
+		t.paint(area, screen, cursor, color)
+	}
+}
+
 impl TableWidget {
 	pub fn new() -> Self {
 		Self {
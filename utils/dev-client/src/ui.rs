@@ -2,11 +2,9 @@ use std::io::Write;
 
 use chrono::{DateTime, Utc};
 use crossterm::{Crossterm, Screen, TerminalColor, TerminalCursor};
-use crossterm::style::style;
 use crossterm::terminal::ClearType;
 use mortal::{Event, Key, MouseEvent, MouseInput, Signal};
-use tsproto::commands::Command;
-use tsproto::packets;
+use slog::warn;
 use unicode_width::UnicodeWidthStr;
 
 use crate::{STATE, Result};
@@ -15,6 +13,10 @@ use crate::table_widget::TableWidget;
 
 /// How many lines to scroll on one scroll event.
 const SCROLL_LINES: isize = 10;
+/// The width of the channel tree on the right side.
+const TREE_WIDTH: u16 = 30;
+/// Show the channel tree only if the terminal is at least this wide.
+const TREE_MIN_WIDTH: u16 = 80;
 
 #[derive(Eq, Clone, Debug, Hash, PartialEq)]
 pub struct Rect {
@@ -42,6 +44,7 @@ pub enum UiEvent {
 	Message,
 	Packet,
 	UdpPacket,
+	ChannelTree,
 	Redraw,
 	Resize,
 	KeyEvent(Key),
@@ -57,7 +60,7 @@ pub struct Ui {
 	// If this part of the screen needs to be redrawn.
 	table_header_invalid: bool,
 	table_invalid: bool,
-	details_invalid: bool,
+	tree_invalid: bool,
 	input_invalid: bool,
 
 	start_time: DateTime<Utc>,
@@ -70,6 +73,8 @@ pub struct Ui {
 
 	input: String,
 	cursor_pos: usize,
+	/// The currently shown entry of the history, `None` for a new command.
+	history_pos: Option<usize>,
 }
 
 impl Rect {
@@ -95,7 +100,7 @@ impl Ui {
 			height,
 			table_header_invalid: true,
 			table_invalid: true,
-			details_invalid: true,
+			tree_invalid: true,
 			input_invalid: true,
 
 			start_time: Utc::now(),
@@ -105,9 +110,19 @@ impl Ui {
 
 			input: Default::default(),
 			cursor_pos: 0,
+			history_pos: None,
 		})
 	}
 
+	/// The width of the table, the rest is used for the channel tree.
+	fn table_width(width: u16) -> u16 {
+		if width >= TREE_MIN_WIDTH {
+			width - TREE_WIDTH
+		} else {
+			width
+		}
+	}
+
 	fn draw_table_header(width: u16, screen: &mut Screen,
 		cursor: &TerminalCursor, color: &mut TerminalColor) -> Result<()> {
 		cursor.goto(0, 0, screen);
@@ -128,42 +143,34 @@ impl Ui {
 		Ok(())
 	}
 
-	fn draw_details(width: u16, screen: &mut Screen,
-		cursor: &TerminalCursor, color: &mut TerminalColor) -> Result<()> {
-		cursor.goto(0, 1, screen);
-
-		let ps = &STATE.lock().unwrap().packets;
-		for p in ps {
-			match &p.data.data {
-				packets::Data::Command(cmd) | packets::Data::CommandLow(cmd) => {
-					color.set_fg(FONT_COLOR, screen);
-					write!(screen, "{} ", p.base.time.format("%H:%M:%S%.3f"))?;
-					let mut len = 13;
-					if p.base.incoming {
-						let text = style("IN  ").with(IN_COLOR);
-						#[cfg(unix)]
-						let text = text.bold();
-						text.paint(screen);
-					} else {
-						let text = style("OUT ").with(OUT_COLOR);
-						#[cfg(unix)]
-						let text = text.bold();
-						text.paint(screen);
-					}
-					color.set_bg(BACKGROUND_COLOR, screen);
-					len += 4;
-
-					// Content
-					write!(screen, "{}", cmd.command)?;
-					len += cmd.command.width();
+	fn draw_tree(area: Rect, screen: &mut Screen, cursor: &TerminalCursor,
+		color: &mut TerminalColor) -> Result<()> {
+		let state = STATE.lock().unwrap();
+		color.set_fg(HEADER_COLOR, screen);
+		cursor.goto(area.x, area.y, screen);
+		write!(screen, "{:<1$}", "Channels", area.width as usize)?;
 
-					// Fill the rest with background color
-					let len = len % width as usize;
-					if width as usize > len {
-						write!(screen, "{}\r\n", " ".repeat(width as usize - len))?;
+		color.set_fg(FONT_COLOR, screen);
+		let mut lines = state.channel_tree.iter();
+		for y in (area.y + 1)..(area.y + area.height) {
+			cursor.goto(area.x, y, screen);
+			// Cut lines which are too long
+			let mut len = 1;
+			write!(screen, " ")?;
+			if let Some(line) = lines.next() {
+				for c in line.chars() {
+					let w = c.to_string().width();
+					if len + w > area.width as usize {
+						break;
 					}
+					write!(screen, "{}", c)?;
+					len += w;
 				}
-				_ => {}
+			}
+
+			// Fill the rest with background color
+			if area.width as usize > len {
+				write!(screen, "{}", " ".repeat(area.width as usize - len))?;
 			}
 		}
 
@@ -203,7 +210,7 @@ impl Ui {
 
 		cursor.save_position(screen);
 		if self.table_header_invalid && self.table_invalid
-			&& self.details_invalid && self.input_invalid {
+			&& self.tree_invalid && self.input_invalid {
 			// TODO Fill everything with background color
 			term.clear(ClearType::All, screen);
 		}
@@ -222,19 +229,23 @@ impl Ui {
 			return Ok(());
 		}
 
+		let table_width = Self::table_width(self.width);
 		if self.table_header_invalid {
 			self.table_header_invalid = false;
-			Self::draw_table_header(self.width, screen, &cursor, &mut color)?;
+			Self::draw_table_header(table_width, screen, &cursor, &mut color)?;
 		}
 		color.set_fg(FONT_COLOR, screen);
 		if self.table_invalid {
 			self.table_invalid = false;
-			self.table.paint(Rect::new(0, 1, self.width, self.height - 3),
+			self.table.paint(Rect::new(0, 1, table_width, self.height - 3),
 				screen, &cursor, &mut color)?;
 		}
-		if self.details_invalid {
-			self.details_invalid = false;
-			//Self::draw_details(self.width, screen, &cursor, &mut color)?;
+		if self.tree_invalid {
+			self.tree_invalid = false;
+			if table_width < self.width {
+				Self::draw_tree(Rect::new(table_width, 0, TREE_WIDTH,
+					self.height - 2), screen, &cursor, &mut color)?;
+			}
 		}
 
 		let cursor_pos = if self.input_invalid {
@@ -279,17 +290,46 @@ impl Ui {
 				self.cursor_pos -= 1;
 			}
 			Key::Enter => {
-				match Command::read((), &mut std::io::Cursor::new(&self.input)) {
-					Ok(cmd) => {
+				let mut state = STATE.lock().unwrap();
+				match ts_cli_completer::parse_command(&self.input) {
+					Ok(packet) => {
+						let _ = state.command_sender.unbounded_send(Some(packet));
+						let input = std::mem::replace(&mut self.input,
+							String::new());
+						if state.history.last() != Some(&input) {
+							state.history.push(input);
+						}
 						self.cursor_pos = 0;
-						self.input.clear();
-						STATE.lock().unwrap().command_sender.unbounded_send(Some(cmd)).unwrap();
+						self.history_pos = None;
 					}
-					Err(_) => {
-						// TODO Show error
+					Err(e) => {
+						warn!(state.logger, "Invalid command"; "error" => e);
 					}
 				}
 			}
+			Key::Up => {
+				let state = STATE.lock().unwrap();
+				let pos = match self.history_pos {
+					Some(0) => 0,
+					Some(p) => p - 1,
+					None if state.history.is_empty() => return,
+					None => state.history.len() - 1,
+				};
+				self.history_pos = Some(pos);
+				self.input = state.history[pos].clone();
+				self.cursor_pos = self.input.len();
+			}
+			Key::Down => if let Some(pos) = self.history_pos {
+				let state = STATE.lock().unwrap();
+				if pos + 1 < state.history.len() {
+					self.history_pos = Some(pos + 1);
+					self.input = state.history[pos + 1].clone();
+				} else {
+					self.history_pos = None;
+					self.input.clear();
+				}
+				self.cursor_pos = self.input.len();
+			}
 			Key::Delete => if self.input.len() > self.cursor_pos {
 				self.input.remove(self.cursor_pos);
 			}
@@ -321,7 +361,7 @@ impl Ui {
 	pub fn event_loop(&mut self) -> Result<()> {
 		let recv = STATE.lock().unwrap().ui_recv.take().unwrap();
 		let (width, height) = self.cross.terminal().terminal_size();
-		self.table.set_size(width, height - 3);
+		self.table.set_size(Self::table_width(width), height - 3);
 		self.draw()?;
 		// TODO Accumulate events
 		while let Ok(e) = recv.recv() {
@@ -334,10 +374,10 @@ impl Ui {
 						self.height = height;
 						self.table_header_invalid = true;
 						self.table_invalid = true;
-						self.details_invalid = true;
+						self.tree_invalid = true;
 						self.input_invalid = true;
 
-						self.table.set_size(width, height - 3);
+						self.table.set_size(Self::table_width(width), height - 3);
 						self.table.update_entries();
 
 						self.draw()?;
@@ -352,7 +392,7 @@ impl Ui {
 				UiEvent::MouseEvent(m) => {
 					self.mouse_input(m);
 					if self.table_header_invalid || self.table_invalid
-						|| self.details_invalid || self.input_invalid {
+						|| self.tree_invalid || self.input_invalid {
 						self.draw()?;
 					}
 				}
@@ -384,6 +424,10 @@ impl Ui {
 						self.draw()?;
 					}
 				}
+				UiEvent::ChannelTree => {
+					self.tree_invalid = true;
+					self.draw()?;
+				}
 				UiEvent::Quit => break,
 			}
 		}
@@ -0,0 +1,384 @@
+//! A TSDNS server, which answers lookups from a zone file.
+//!
+//! The zone file uses the format of the `tsdns_settings.ini` of the official
+//! TSDNS server:
+//!
+//! ```text
+//! # Comments start with a '#' or ';'
+//! ts.example.com=192.0.2.1:9987
+//! *.example.com=192.0.2.2
+//! private.example.com=NORES
+//! ```
+//!
+//! Every line maps a hostname to an ip address with an optional port. A `*` in
+//! the hostname matches any number of characters. The first matching line
+//! wins. `NORES` means that the server should answer that it does not know
+//! the address.
+//!
+//! A client connects with tcp to the server, sends the hostname it wants to
+//! look up and gets back the address or `404`.
+
+#[macro_use]
+extern crate failure;
+
+use std::fs;
+use std::io::{BufReader, Read};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use parking_lot::RwLock;
+use slog::{debug, o, warn, Logger};
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::util::FutureExt;
+
+type Result<T> = std::result::Result<T, failure::Error>;
+
+/// The default port of a TSDNS server.
+pub const DEFAULT_PORT: u16 = 41144;
+/// The answer if an address is not known.
+const NOT_FOUND: &str = "404";
+/// The maximum length of a hostname that is accepted.
+const MAX_REQUEST_LEN: usize = 256;
+/// Close connections which do not send a request in this time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The answer for a hostname.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Answer {
+	/// The address of the TeamSpeak server, with an optional port.
+	Address(String),
+	/// Explicitly answer that the address is not known (`NORES`).
+	NoResult,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+	/// The lowercase hostname, which may contain `*` wildcards.
+	pattern: String,
+	answer: Answer,
+}
+
+/// The parsed content of a zone file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Zone {
+	entries: Vec<Entry>,
+}
+
+/// A TSDNS server which is bound to a tcp socket.
+pub struct Server {
+	listener: TcpListener,
+	zone: Arc<RwLock<Zone>>,
+	logger: Logger,
+}
+
+impl Zone {
+	pub fn new() -> Self { Self::default() }
+
+	/// Read a zone file in the `tsdns_settings.ini` format.
+	pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+		fs::read_to_string(path)?.parse()
+	}
+
+	/// Append an entry, it has a lower priority than all existing entries.
+	pub fn add_entry(&mut self, pattern: &str, answer: Answer) {
+		self.entries.push(Entry {
+			pattern: pattern.to_lowercase(),
+			answer,
+		});
+	}
+
+	/// Look up a hostname.
+	///
+	/// Returns `None` if no entry matches.
+	pub fn lookup(&self, hostname: &str) -> Option<&Answer> {
+		let hostname = hostname.to_lowercase();
+		self.entries
+			.iter()
+			.find(|e| matches_pattern(&e.pattern, &hostname))
+			.map(|e| &e.answer)
+	}
+}
+
+impl FromStr for Zone {
+	type Err = failure::Error;
+	fn from_str(s: &str) -> Result<Self> {
+		let mut res = Self::new();
+		for (i, line) in s.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') || line.starts_with(';')
+			{
+				continue;
+			}
+			// Section headers are allowed but ignored
+			if line.starts_with('[') && line.ends_with(']') {
+				continue;
+			}
+
+			let pos = line.find('=').ok_or_else(|| {
+				format_err!("Missing '=' in line {}", i + 1)
+			})?;
+			let pattern = line[..pos].trim();
+			let value = line[pos + 1..].trim();
+			if pattern.is_empty() || value.is_empty() {
+				bail!("Empty hostname or address in line {}", i + 1);
+			}
+			if pattern.contains(char::is_whitespace)
+				|| value.contains(char::is_whitespace)
+			{
+				bail!("Unexpected whitespace in line {}", i + 1);
+			}
+
+			let answer = if value.eq_ignore_ascii_case("NORES") {
+				Answer::NoResult
+			} else {
+				Answer::Address(value.to_string())
+			};
+			res.add_entry(pattern, answer);
+		}
+		Ok(res)
+	}
+}
+
+/// Check if `s` matches `pattern`, where a `*` matches any number of
+/// characters.
+fn matches_pattern(pattern: &str, s: &str) -> bool {
+	let mut parts = pattern.split('*');
+	// The first part has to be a prefix
+	let first = parts.next().unwrap_or("");
+	if !s.starts_with(first) {
+		return false;
+	}
+	let mut rest = &s[first.len()..];
+	let parts: Vec<_> = parts.collect();
+	let last = match parts.last() {
+		Some(l) => *l,
+		// No wildcard in the pattern
+		None => return rest.is_empty(),
+	};
+
+	// Match all middle parts as early as possible
+	for part in &parts[..parts.len() - 1] {
+		match rest.find(part) {
+			Some(pos) => rest = &rest[pos + part.len()..],
+			None => return false,
+		}
+	}
+	// The last part has to be a suffix
+	rest.ends_with(last)
+}
+
+impl Server {
+	/// Listen on `addr` and answer requests using `zone`.
+	///
+	/// The zone can be replaced while the server is running.
+	pub fn bind(
+		addr: &SocketAddr,
+		zone: Arc<RwLock<Zone>>,
+		logger: Logger,
+	) -> Result<Self>
+	{
+		let listener = TcpListener::bind(addr)?;
+		let local_addr = listener.local_addr()?.to_string();
+		let logger = logger.new(o!("local_addr" => local_addr));
+		Ok(Self {
+			listener,
+			zone,
+			logger,
+		})
+	}
+
+	pub fn local_addr(&self) -> Result<SocketAddr> {
+		Ok(self.listener.local_addr()?)
+	}
+
+	/// Accept connections and answer them.
+	///
+	/// Errors of single connections and failed accepts are logged and do not
+	/// stop the server.
+	pub fn run(self) -> impl Future<Item = (), Error = failure::Error> {
+		let Server {
+			listener,
+			zone,
+			logger,
+		} = self;
+		// An error ends the stream, so turn errors into items
+		let incoming = listener.incoming().then(Ok::<_, failure::Error>);
+		incoming.for_each(move |tcp| {
+			let tcp = match tcp {
+				Ok(tcp) => tcp,
+				Err(e) => {
+					warn!(logger, "Failed to accept connection";
+						"error" => ?e);
+					return Ok(());
+				}
+			};
+			let logger = match tcp.peer_addr() {
+				Ok(addr) => logger.new(o!("peer" => addr.to_string())),
+				Err(_) => logger.clone(),
+			};
+			let logger2 = logger.clone();
+			tokio::spawn(handle_request(tcp, zone.clone(), logger).map_err(
+				move |e| warn!(logger2, "Failed to answer request";
+					"error" => ?e),
+			));
+			Ok(())
+		})
+	}
+}
+
+/// Read one hostname from the connection and write back the answer.
+///
+/// The hostname ends with a newline or when the client closes its side of
+/// the connection. At most `MAX_REQUEST_LEN` bytes are read.
+fn handle_request(
+	tcp: TcpStream,
+	zone: Arc<RwLock<Zone>>,
+	logger: Logger,
+) -> impl Future<Item = (), Error = failure::Error>
+{
+	let reader = BufReader::new(tcp.take(MAX_REQUEST_LEN as u64));
+	io::read_until(reader, b'\n', Vec::new())
+		.timeout(REQUEST_TIMEOUT)
+		.map_err(|e| {
+			e.into_inner().map(failure::Error::from).unwrap_or_else(|| {
+				format_err!("Timed out while waiting for a request")
+			})
+		})
+		.and_then(move |(reader, buf)| {
+			let tcp = reader.into_inner().into_inner();
+			let hostname = std::str::from_utf8(&buf)?.trim();
+			let answer = match zone.read().lookup(hostname) {
+				Some(Answer::Address(a)) => a.clone(),
+				Some(Answer::NoResult) | None => NOT_FOUND.to_string(),
+			};
+			debug!(logger, "Answering request"; "hostname" => hostname,
+				"answer" => &answer);
+			Ok((tcp, answer))
+		})
+		.and_then(|(tcp, answer)| io::write_all(tcp, answer).from_err())
+		.and_then(|(tcp, _)| io::shutdown(tcp).from_err())
+		.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+	use slog::Drain;
+	use tokio::runtime::Runtime;
+
+	use super::*;
+
+	const ZONE: &str = "\
+# Test zone
+[tsdns]
+ts.example.com=192.0.2.1:9987
+private.example.com = NORES
+*.example.com=192.0.2.2
+ts*.test.*=192.0.2.3:1
+";
+
+	#[test]
+	fn parse_zone() {
+		let zone: Zone = ZONE.parse().unwrap();
+		assert_eq!(zone.entries.len(), 4);
+		assert_eq!(zone.entries[1].answer, Answer::NoResult);
+		assert!("example.com".parse::<Zone>().is_err());
+		assert!("=192.0.2.1".parse::<Zone>().is_err());
+	}
+
+	#[test]
+	fn lookup() {
+		let zone: Zone = ZONE.parse().unwrap();
+		assert_eq!(
+			zone.lookup("TS.example.com"),
+			Some(&Answer::Address("192.0.2.1:9987".into()))
+		);
+		assert_eq!(zone.lookup("private.example.com"), Some(&Answer::NoResult));
+		assert_eq!(
+			zone.lookup("a.b.example.com"),
+			Some(&Answer::Address("192.0.2.2".into()))
+		);
+		assert_eq!(
+			zone.lookup("ts3.test.org"),
+			Some(&Answer::Address("192.0.2.3:1".into()))
+		);
+		assert_eq!(zone.lookup("example.com"), None);
+		assert_eq!(zone.lookup("ts.example.org"), None);
+	}
+
+	#[test]
+	fn wildcards() {
+		assert!(matches_pattern("*", ""));
+		assert!(matches_pattern("a*a", "aa"));
+		assert!(!matches_pattern("a*a", "a"));
+		assert!(matches_pattern("*.b.*", "a.b.c"));
+		assert!(!matches_pattern("*.b.*", "a.c.b"));
+		assert!(matches_pattern("a**b", "ab"));
+	}
+
+	#[test]
+	fn serve_resolver() {
+		let logger = {
+			let decorator =
+				slog_term::PlainSyncDecorator::new(std::io::stdout());
+			let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+			let drain = slog_async::Async::new(drain).build().fuse();
+
+			slog::Logger::root(drain, o!())
+		};
+		let mut rt = Runtime::new().unwrap();
+		let zone = Arc::new(RwLock::new(ZONE.parse().unwrap()));
+		let server =
+			Server::bind(&"127.0.0.1:0".parse().unwrap(), zone, logger)
+				.unwrap();
+		let addr = server.local_addr().unwrap();
+		rt.spawn(server.run().map_err(|e| panic!("Server failed: {:?}", e)));
+
+		let res = rt.block_on(
+			tsclientlib::resolver::resolve_tsdns(addr, "ts.example.com".into())
+				.collect(),
+		);
+		assert_eq!(res.unwrap(), vec!["192.0.2.1:9987".parse().unwrap()]);
+
+		let res = rt.block_on(
+			tsclientlib::resolver::resolve_tsdns(
+				addr,
+				"private.example.com".into(),
+			)
+			.collect(),
+		);
+		assert!(res.is_err());
+	}
+
+	#[test]
+	fn split_request() {
+		let logger = slog::Logger::root(slog::Discard, o!());
+		let mut rt = Runtime::new().unwrap();
+		let zone = Arc::new(RwLock::new(ZONE.parse().unwrap()));
+		let server =
+			Server::bind(&"127.0.0.1:0".parse().unwrap(), zone, logger)
+				.unwrap();
+		let addr = server.local_addr().unwrap();
+		rt.spawn(server.run().map_err(|e| panic!("Server failed: {:?}", e)));
+
+		// The hostname arrives in two parts and ends with a newline
+		let answer = TcpStream::connect(&addr)
+			.and_then(|tcp| io::write_all(tcp, "ts.exa"))
+			.and_then(|(tcp, _)| {
+				tokio::timer::Delay::new(
+					std::time::Instant::now() + Duration::from_millis(50),
+				)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+				.map(move |_| tcp)
+			})
+			.and_then(|tcp| io::write_all(tcp, "mple.com\n"))
+			.and_then(|(tcp, _)| io::read_to_end(tcp, Vec::new()))
+			.map(|(_, answer)| answer);
+		let answer = rt.block_on(answer).unwrap();
+		assert_eq!(answer, b"192.0.2.1:9987");
+	}
+}
@@ -0,0 +1,69 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::Future;
+use parking_lot::RwLock;
+use slog::{error, info, o, Drain};
+use structopt::clap::AppSettings;
+use structopt::StructOpt;
+use tsdns::{Server, Zone};
+
+#[derive(StructOpt, Debug)]
+#[structopt(raw(global_settings = "&[AppSettings::ColoredHelp, \
+                                   AppSettings::VersionlessSubcommands]"))]
+struct Args {
+	#[structopt(
+		short = "a",
+		long = "address",
+		default_value = "0.0.0.0:41144",
+		help = "The address to listen on"
+	)]
+	address: SocketAddr,
+	#[structopt(
+		short = "z",
+		long = "zone",
+		default_value = "tsdns_settings.ini",
+		help = "The zone file with the addresses",
+		parse(from_os_str)
+	)]
+	zone: PathBuf,
+}
+
+fn main() {
+	// Parse command line options
+	let args = Args::from_args();
+
+	let logger = {
+		let decorator = slog_term::TermDecorator::new().build();
+		let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+		let drain = slog_async::Async::new(drain).build().fuse();
+
+		slog::Logger::root(drain, o!())
+	};
+
+	let zone = match Zone::from_file(&args.zone) {
+		Ok(z) => z,
+		Err(e) => {
+			error!(logger, "Failed to read zone file"; "file" => ?args.zone,
+				"error" => %e);
+			return;
+		}
+	};
+	let server = match Server::bind(
+		&args.address,
+		Arc::new(RwLock::new(zone)),
+		logger.clone(),
+	) {
+		Ok(s) => s,
+		Err(e) => {
+			error!(logger, "Failed to listen"; "error" => %e);
+			return;
+		}
+	};
+
+	info!(logger, "Started TSDNS server"; "address" => %args.address);
+	tokio::run(server.run().map_err(move |e| {
+		error!(logger, "Server failed"; "error" => %e);
+	}));
+}
@@ -10,16 +10,17 @@ pub mod versions;
 use std::fmt;
 
 use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
 
 /// A `ClientId` identifies a client which is connected to a server.
 ///
 /// Every client that we see on a server has a `ClientId`, even our own
 /// connection.
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct ClientId(pub u16);
 /// Describes a client or server uid which is a base64
 /// encoded hash or a special reserved name.
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Uid(pub String);
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -34,25 +35,25 @@ impl<'a> Into<Uid> for UidRef<'a> {
 ///
 /// This is the id which is saved for a client in the database of one specific
 /// server.
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct ClientDbId(pub u64);
 
 /// Identifies a channel on a server.
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct ChannelId(pub u64);
 
 /// Identifies a server group on a server.
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct ServerGroupId(pub u64);
 
 /// Identifies a channel group on a server.
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct ChannelGroupId(pub u64);
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct IconHash(pub u32);
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub struct Permission(pub u32);
 impl Permission {
 	/// Never fails
@@ -63,6 +64,7 @@ impl Permission {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum PermissionType {
 	/// Server group permission. (id1: ServerGroupId, id2: 0)
@@ -79,6 +81,7 @@ pub enum PermissionType {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum TextMessageTargetMode {
 	/// Maybe to all servers?
@@ -93,6 +96,7 @@ pub enum TextMessageTargetMode {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum HostMessageMode {
 	/// Dont display anything
@@ -107,6 +111,7 @@ pub enum HostMessageMode {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum HostBannerMode {
 	/// Do not adjust
@@ -119,6 +124,7 @@ pub enum HostBannerMode {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum Codec {
 	/// Mono,   16bit,  8kHz, bitrate dependent on the quality setting
@@ -137,6 +143,7 @@ pub enum Codec {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum CodecEncryptionMode {
 	/// Voice encryption is configured per channel
@@ -149,6 +156,7 @@ pub enum CodecEncryptionMode {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum Reason {
 	/// No reason data
@@ -175,6 +183,7 @@ pub enum Reason {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum ClientType {
 	Normal,
@@ -184,6 +193,7 @@ pub enum ClientType {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum GroupNamingMode {
 	/// No group name is displayed.
@@ -196,6 +206,7 @@ pub enum GroupNamingMode {
 
 #[derive(
 	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
 )]
 pub enum GroupType {
 	/// Template group (used for new virtual servers).
@@ -206,7 +217,10 @@ pub enum GroupType {
 	Query,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive)]
+#[derive(
+	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
+)]
 pub enum LicenseType {
 	/// No licence
 	NoLicense,
@@ -220,14 +234,20 @@ pub enum LicenseType {
 	Unknown,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive)]
+#[derive(
+	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
+)]
 pub enum ChannelType {
 	Permanent,
 	SemiPermanent,
 	Temporary,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive)]
+#[derive(
+	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
+)]
 pub enum TokenType {
 	/// Server group token (`id1={groupId}, id2=0`)
 	ServerGroup,
@@ -235,7 +255,10 @@ pub enum TokenType {
 	ChannelGroup,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive)]
+#[derive(
+	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
+)]
 pub enum PluginTargetMode {
 	/// Send to all clients in the current channel.
 	CurrentChannel,
@@ -248,7 +271,10 @@ pub enum PluginTargetMode {
 	CurrentChannelSubsribedClients,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive)]
+#[derive(
+	Debug, PartialEq, Eq, Clone, Copy, Hash, FromPrimitive, ToPrimitive,
+	Serialize, Deserialize,
+)]
 pub enum LogLevel {
 	/// Everything that is really bad.
 	Error = 1,
@@ -260,14 +286,14 @@ pub enum LogLevel {
 	Info,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Serialize, Deserialize)]
 pub enum MaxClients {
 	Unlimited,
 	Inherited,
 	Limited(u16),
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct TalkPowerRequest {
 	pub time: DateTime<Utc>,
 	pub message: String,
@@ -5,8 +5,47 @@ use crate::*;
 pub const DATA_STR: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"),
 	"/../declarations/BookDeclarations.toml"));
 
+/// Structs and properties which are not part of the shared declarations but
+/// are only managed by tsclientlib.
+pub const EXTENSIONS_STR: &str = include_str!(concat!(
+	env!("CARGO_MANIFEST_DIR"),
+	"/declarations/BookExtensions.toml"
+));
+
 lazy_static!{
-	pub static ref DATA: BookDeclarations = toml::from_str(DATA_STR).unwrap();
+	pub static ref DATA: BookDeclarations = {
+		let mut decls: BookDeclarations = toml::from_str(DATA_STR).unwrap();
+		let exts: BookExtensions = toml::from_str(EXTENSIONS_STR).unwrap();
+		for ext in exts.structs {
+			let properties = ext.properties.into_iter().map(|mut p| {
+				p.manual = true;
+				p
+			});
+			if let Some(s) =
+				decls.structs.iter_mut().find(|s| s.name == ext.name)
+			{
+				if ext.id.is_some() {
+					panic!("Struct {} is already declared", ext.name);
+				}
+				s.properties.extend(properties);
+				continue;
+			}
+
+			// A new struct
+			let (id, doc, accessor) = match (ext.id, ext.doc, ext.accessor) {
+				(Some(id), Some(doc), Some(accessor)) => (id, doc, accessor),
+				_ => panic!("Cannot extend struct {}", ext.name),
+			};
+			decls.structs.push(Struct {
+				name: ext.name,
+				id,
+				doc,
+				accessor,
+				properties: properties.collect(),
+			});
+		}
+		decls
+	};
 }
 
 #[derive(Deserialize, Debug)]
@@ -16,6 +55,25 @@ pub struct BookDeclarations {
 	pub structs: Vec<Struct>,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct BookExtensions {
+	#[serde(rename = "struct")]
+	structs: Vec<StructExtension>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct StructExtension {
+	name: String,
+	/// The id, documentation and accessors have to be set if the struct does
+	/// not exist in the declarations.
+	id: Option<Vec<Id>>,
+	doc: Option<String>,
+	accessor: Option<Accessors>,
+	properties: Vec<Property>,
+}
+
 impl BookDeclarations {
 	pub fn get_struct(&self, name: &str) -> &Struct {
 		if let Some(s) = self.structs.iter().find(|s| s.name == name) {
@@ -85,6 +143,10 @@ pub struct Property {
 	#[serde(rename = "mod")]
 	pub modifier: Option<String>,
 	pub key: Option<String>,
+	/// If this property comes from the extensions of tsclientlib and is not
+	/// set by a normal messages to book rule.
+	#[serde(skip)]
+	pub manual: bool,
 }
 
 impl Property {
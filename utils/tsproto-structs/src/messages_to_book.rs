@@ -9,10 +9,17 @@ use crate::messages::{MessageDeclarations, Field, Message};
 
 pub const DATA_STR: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"),
 	"/../declarations/MessagesToBook.toml"));
+/// Rules for properties, which are not part of the shared declarations.
+pub const EXTENSIONS_STR: &str = include_str!(concat!(
+	env!("CARGO_MANIFEST_DIR"),
+	"/declarations/MessagesToBookExtensions.toml"
+));
 
 lazy_static!{
 	pub static ref DATA: MessagesToBookDeclarations<'static> = {
-		let rules: TomlStruct = toml::from_str(DATA_STR).unwrap();
+		let mut rules: TomlStruct = toml::from_str(DATA_STR).unwrap();
+		let extensions: TomlStruct = toml::from_str(EXTENSIONS_STR).unwrap();
+		rules.rule.extend(extensions.rule);
 		let book = &book::DATA;
 		let messages = &messages::DATA;
 
@@ -0,0 +1,67 @@
+use bytes::{BufMut, BytesMut};
+use tokio::codec::{Decoder, Encoder};
+
+use crate::Error;
+
+/// Splits the ServerQuery stream into lines.
+///
+/// The server terminates lines with `\n\r`, the client with `\n`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct QueryCodec;
+
+impl Decoder for QueryCodec {
+	type Item = String;
+	type Error = Error;
+
+	fn decode(
+		&mut self,
+		buf: &mut BytesMut,
+	) -> Result<Option<String>, Error>
+	{
+		let pos = match buf.iter().position(|b| *b == b'\n') {
+			Some(pos) => pos,
+			None => return Ok(None),
+		};
+		let line = buf.split_to(pos + 1);
+		let line = String::from_utf8(line.to_vec())?;
+		// The '\r' of the last line is at the start of this one
+		Ok(Some(line.trim_matches(|c| c == '\r' || c == '\n').to_string()))
+	}
+}
+
+impl Encoder for QueryCodec {
+	type Item = Vec<u8>;
+	type Error = Error;
+
+	fn encode(
+		&mut self,
+		item: Vec<u8>,
+		buf: &mut BytesMut,
+	) -> Result<(), Error>
+	{
+		buf.reserve(item.len() + 1);
+		buf.put_slice(&item);
+		buf.put_u8(b'\n');
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn decode_lines() {
+		let mut codec = QueryCodec;
+		let mut buf = BytesMut::from(&b"TS3\n\rWelcome\n\rerror id=0"[..]);
+		assert_eq!(codec.decode(&mut buf).unwrap(), Some("TS3".into()));
+		assert_eq!(codec.decode(&mut buf).unwrap(), Some("Welcome".into()));
+		assert_eq!(codec.decode(&mut buf).unwrap(), None);
+		buf.extend_from_slice(b" msg=ok\n\r");
+		assert_eq!(
+			codec.decode(&mut buf).unwrap(),
+			Some("error id=0 msg=ok".into())
+		);
+		assert_eq!(&buf[..], b"\r");
+	}
+}
@@ -0,0 +1,792 @@
+//! A client for the ServerQuery interface of TeamSpeak servers.
+//!
+//! ServerQuery is a text protocol on tcp port 10011, which can be used to
+//! administrate a server. It uses the same command syntax as the voice
+//! protocol, so commands are written and parsed with the functions of
+//! [`tsproto`].
+//!
+//! Commands are sent one after another, the future of a command resolves when
+//! the server answered it. Notifications, which were registered with
+//! [`QueryClient::register_notify`], are delivered to the [`Notifications`]
+//! stream as generated [`InMessage`]s. The connection is kept alive
+//! automatically.
+//!
+//! Answers to commands are returned as [`Row`]s, typed answers are only
+//! available for the helper functions like [`QueryClient::whoami`]. The
+//! generated messages are selected by the name of a notification, but
+//! ServerQuery answers contain only the arguments without a name.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # use futures::Future;
+//! # use slog::{o, Discard, Logger};
+//! use tsquery::QueryClient;
+//!
+//! let logger = Logger::root(Discard, o!());
+//! tokio::run(
+//!     QueryClient::connect(&"127.0.0.1:10011".parse().unwrap(), logger)
+//!         .and_then(|(client, _notifications)| {
+//!             client
+//!                 .login("serveradmin", "password")
+//!                 .and_then(move |_| client.use_port(9987).map(|_| client))
+//!         })
+//!         .and_then(|client| client.whoami())
+//!         .map(|whoami| println!("Logged in as {}", whoami.nickname))
+//!         .map_err(|e| println!("Error: {}", e)),
+//! );
+//! ```
+//!
+//! [`tsproto`]: ../tsproto/index.html
+//! [`QueryClient::register_notify`]: struct.QueryClient.html#method.register_notify
+//! [`Notifications`]: struct.Notifications.html
+//! [`InMessage`]: ../tsproto_commands/messages/s2c/struct.InMessage.html
+//! [`Row`]: type.Row.html
+//! [`QueryClient::whoami`]: struct.QueryClient.html#method.whoami
+
+#[macro_use]
+extern crate failure;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use failure::ResultExt;
+use futures::sync::{mpsc, oneshot};
+use futures::{future, Async, AsyncSink, Future, Poll, Sink, Stream};
+use num_traits::FromPrimitive;
+use slog::{debug, error, o, warn, Logger};
+use tokio::codec::Framed;
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+use tsproto::commands::parse_command;
+use tsproto::packets::{Direction, InCommand, OutCommand, PacketType};
+use tsproto_commands::messages::s2c::InMessage;
+use tsproto_commands::{
+	ChannelId, ClientDbId, ClientId, TextMessageTargetMode,
+};
+
+mod codec;
+
+use crate::codec::QueryCodec;
+
+pub use tsproto_commands::errors::Error as TsError;
+
+type BoxFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+type Result<T> = std::result::Result<T, Error>;
+
+/// The default port of the ServerQuery interface.
+pub const DEFAULT_PORT: u16 = 10011;
+/// Send a command after this time of inactivity, so the server does not close
+/// the connection.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(3 * 60);
+/// The command which is sent to keep the connection alive.
+const KEEPALIVE_COMMAND: &[u8] = b"version";
+/// The server sends `TS3` and a welcome message after connecting.
+const GREETING_LINES: u8 = 2;
+
+#[derive(Fail, Debug)]
+pub enum Error {
+	#[fail(display = "{}", _0)]
+	Canceled(#[cause] futures::Canceled),
+	#[fail(display = "{}", _0)]
+	Io(#[cause] std::io::Error),
+	#[fail(display = "{}", _0)]
+	Tsproto(#[cause] tsproto::Error),
+	#[fail(display = "{}", _0)]
+	Utf8(#[cause] std::string::FromUtf8Error),
+	#[fail(display = "{}", _0)]
+	Other(#[cause] failure::Compat<failure::Error>),
+
+	/// The server answered a command with an error.
+	#[fail(display = "{} ({})", error, message)]
+	Ts {
+		#[cause]
+		error: TsError,
+		message: String,
+	},
+
+	#[doc(hidden)]
+	#[fail(display = "Nonexhaustive enum – not an error")]
+	__Nonexhaustive,
+}
+
+impl From<futures::Canceled> for Error {
+	fn from(e: futures::Canceled) -> Self { Error::Canceled(e) }
+}
+
+impl From<std::io::Error> for Error {
+	fn from(e: std::io::Error) -> Self { Error::Io(e) }
+}
+
+impl From<tsproto::Error> for Error {
+	fn from(e: tsproto::Error) -> Self { Error::Tsproto(e) }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+	fn from(e: std::string::FromUtf8Error) -> Self { Error::Utf8(e) }
+}
+
+impl From<failure::Error> for Error {
+	fn from(e: failure::Error) -> Self {
+		let r: std::result::Result<(), _> = Err(e);
+		Error::Other(r.compat().unwrap_err())
+	}
+}
+
+/// One entry of an answer, which maps argument names to their values.
+///
+/// Answers have no command name, so they cannot be parsed as an
+/// `InMessage` like notifications.
+pub type Row = HashMap<String, String>;
+
+/// A connection to the ServerQuery interface of a server.
+///
+/// This is a handle which can be cloned. The connection is closed when all
+/// handles are dropped.
+#[derive(Clone)]
+pub struct QueryClient {
+	requests: mpsc::UnboundedSender<Request>,
+}
+
+/// Notifications which are sent by the server.
+///
+/// Notifications have to be registered with
+/// [`QueryClient::register_notify`] first. Notifications, which cannot be
+/// parsed, are logged and skipped.
+///
+/// [`QueryClient::register_notify`]: struct.QueryClient.html#method.register_notify
+pub struct Notifications(mpsc::UnboundedReceiver<InMessage>);
+
+/// The events, for which notifications can be registered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotifyEvent {
+	/// Clients joining and leaving the server and server edits.
+	Server,
+	/// Events in a channel and its subchannels. `ChannelId(0)` registers for
+	/// all channels.
+	Channel(ChannelId),
+	TextServer,
+	TextChannel,
+	TextPrivate,
+	TokenUsed,
+}
+
+/// Information about our own query client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WhoAmI {
+	/// `None` if no virtual server is selected.
+	pub server_id: Option<u64>,
+	pub client_id: ClientId,
+	pub channel_id: ChannelId,
+	pub nickname: String,
+	pub database_id: ClientDbId,
+	pub login_name: String,
+}
+
+/// An entry of the `serverlist`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualServer {
+	pub id: u64,
+	pub port: u16,
+	/// E.g. `online` or `offline`.
+	pub status: String,
+	pub name: String,
+	pub clients_online: u16,
+	pub max_clients: u16,
+}
+
+struct Request {
+	command: Vec<u8>,
+	/// `None` for internal commands like the keepalive.
+	answer: Option<oneshot::Sender<Result<Vec<Row>>>>,
+}
+
+/// The command which was sent last and waits for an answer.
+struct Pending {
+	/// The command if it was not yet written to the socket.
+	command: Option<Vec<u8>>,
+	answer: Option<oneshot::Sender<Result<Vec<Row>>>>,
+	rows: Vec<Row>,
+}
+
+/// Handles the socket in the background.
+struct Driver {
+	framed: Framed<TcpStream, QueryCodec>,
+	requests: mpsc::UnboundedReceiver<Request>,
+	notifications: mpsc::UnboundedSender<InMessage>,
+	current: Option<Pending>,
+	greeting_lines: u8,
+	keepalive: Delay,
+	logger: Logger,
+}
+
+impl QueryClient {
+	/// Connect to the ServerQuery interface of a server.
+	///
+	/// The connection is handled by a background task, so this has to be run
+	/// inside a tokio runtime.
+	pub fn connect(
+		addr: &SocketAddr,
+		logger: Logger,
+	) -> impl Future<Item = (Self, Notifications), Error = Error>
+	{
+		let logger = logger.new(o!("addr" => addr.to_string()));
+		TcpStream::connect(addr).from_err().map(move |tcp| {
+			let (requests_send, requests) = mpsc::unbounded();
+			let (notifications, notifications_recv) = mpsc::unbounded();
+			let driver = Driver {
+				framed: Framed::new(tcp, QueryCodec),
+				requests,
+				notifications,
+				current: None,
+				greeting_lines: GREETING_LINES,
+				keepalive: Delay::new(Instant::now() + KEEPALIVE_INTERVAL),
+				logger: logger.clone(),
+			};
+			tokio::spawn(driver.map_err(move |e| {
+				error!(logger, "Query connection failed"; "error" => ?e);
+			}));
+
+			(
+				Self {
+					requests: requests_send,
+				},
+				Notifications(notifications_recv),
+			)
+		})
+	}
+
+	/// Send a command and return the answer of the server.
+	///
+	/// The arguments are escaped like in [`OutCommand::new`].
+	///
+	/// [`OutCommand::new`]: ../tsproto/packets/struct.OutCommand.html#method.new
+	pub fn send_command<K1, V1, K2, V2, I1, I2, I3>(
+		&self,
+		name: &str,
+		static_args: I1,
+		list_args: I2,
+	) -> BoxFuture<Vec<Row>>
+	where
+		K1: AsRef<str>,
+		V1: AsRef<str>,
+		K2: AsRef<str>,
+		V2: AsRef<str>,
+		I1: Iterator<Item = (K1, V1)>,
+		I2: Iterator<Item = I3>,
+		I3: Iterator<Item = (K2, V2)>,
+	{
+		let mut command = Vec::new();
+		OutCommand::new_into(name, static_args, list_args, &mut command);
+
+		let (send, recv) = oneshot::channel();
+		let request = Request {
+			command,
+			answer: Some(send),
+		};
+		if self.requests.unbounded_send(request).is_err() {
+			return Box::new(future::err(
+				format_err!("Query connection is closed").into(),
+			));
+		}
+		Box::new(recv.from_err().and_then(|r| r))
+	}
+
+	/// Send a command without list arguments.
+	fn send_simple(
+		&self,
+		name: &str,
+		args: Vec<(&str, String)>,
+	) -> BoxFuture<Vec<Row>>
+	{
+		self.send_command::<_, _, String, String, _, _, std::iter::Empty<_>>(
+			name,
+			args.into_iter(),
+			std::iter::empty(),
+		)
+	}
+
+	pub fn login(&self, name: &str, password: &str) -> BoxFuture<()> {
+		Box::new(
+			self.send_simple(
+				"login",
+				vec![
+					("client_login_name", name.into()),
+					("client_login_password", password.into()),
+				],
+			)
+			.map(|_| ()),
+		)
+	}
+
+	pub fn logout(&self) -> BoxFuture<()> {
+		Box::new(self.send_simple("logout", Vec::new()).map(|_| ()))
+	}
+
+	/// Select a virtual server by its id.
+	pub fn use_server(&self, id: u64) -> BoxFuture<()> {
+		Box::new(
+			self.send_simple("use", vec![("sid", id.to_string())])
+				.map(|_| ()),
+		)
+	}
+
+	/// Select a virtual server by its voice port.
+	pub fn use_port(&self, port: u16) -> BoxFuture<()> {
+		Box::new(
+			self.send_simple("use", vec![("port", port.to_string())])
+				.map(|_| ()),
+		)
+	}
+
+	pub fn whoami(&self) -> BoxFuture<WhoAmI> {
+		Box::new(self.send_simple("whoami", Vec::new()).and_then(|rows| {
+			let row = rows
+				.first()
+				.ok_or_else(|| format_err!("Got no answer for whoami"))?;
+			let server_id = get_parse(row, "virtualserver_id")?;
+			Ok(WhoAmI {
+				server_id: if server_id == 0 { None } else { Some(server_id) },
+				client_id: ClientId(get_parse(row, "client_id")?),
+				channel_id: ChannelId(get_parse(row, "client_channel_id")?),
+				nickname: get_parse(row, "client_nickname")?,
+				database_id: ClientDbId(get_parse(row, "client_database_id")?),
+				login_name: get_parse(row, "client_login_name")?,
+			})
+		}))
+	}
+
+	pub fn server_list(&self) -> BoxFuture<Vec<VirtualServer>> {
+		Box::new(self.send_simple("serverlist", Vec::new()).and_then(|rows| {
+			rows.iter()
+				.map(|row| {
+					Ok(VirtualServer {
+						id: get_parse(row, "virtualserver_id")?,
+						port: get_parse(row, "virtualserver_port")?,
+						status: get_parse(row, "virtualserver_status")?,
+						name: get_parse(row, "virtualserver_name")?,
+						clients_online: get_parse(
+							row,
+							"virtualserver_clientsonline",
+						)
+						.unwrap_or(0),
+						max_clients: get_parse(row, "virtualserver_maxclients")
+							.unwrap_or(0),
+					})
+				})
+				.collect()
+		}))
+	}
+
+	/// Receive notifications for an event in the [`Notifications`] stream.
+	///
+	/// [`Notifications`]: struct.Notifications.html
+	pub fn register_notify(&self, event: NotifyEvent) -> BoxFuture<()> {
+		Box::new(
+			self.send_simple("servernotifyregister", event.args())
+				.map(|_| ()),
+		)
+	}
+
+	pub fn unregister_notify(&self) -> BoxFuture<()> {
+		Box::new(
+			self.send_simple("servernotifyunregister", Vec::new())
+				.map(|_| ()),
+		)
+	}
+
+	/// Send a text message to a client, the current channel or the server.
+	///
+	/// The `target` is the client id and ignored for channel and server
+	/// messages.
+	pub fn send_text_message(
+		&self,
+		mode: TextMessageTargetMode,
+		target: u64,
+		message: &str,
+	) -> BoxFuture<()>
+	{
+		Box::new(
+			self.send_simple(
+				"sendtextmessage",
+				vec![
+					("targetmode", (mode as u8).to_string()),
+					("target", target.to_string()),
+					("msg", message.into()),
+				],
+			)
+			.map(|_| ()),
+		)
+	}
+
+	/// Close the connection.
+	pub fn quit(&self) -> BoxFuture<()> {
+		Box::new(self.send_simple("quit", Vec::new()).map(|_| ()))
+	}
+}
+
+impl Stream for Notifications {
+	type Item = InMessage;
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+		self.0
+			.poll()
+			.map_err(|_| format_err!("Failed to receive notification").into())
+	}
+}
+
+impl NotifyEvent {
+	fn args(self) -> Vec<(&'static str, String)> {
+		let (event, id) = match self {
+			NotifyEvent::Server => ("server", None),
+			NotifyEvent::Channel(id) => ("channel", Some(id.0)),
+			NotifyEvent::TextServer => ("textserver", None),
+			NotifyEvent::TextChannel => ("textchannel", None),
+			NotifyEvent::TextPrivate => ("textprivate", None),
+			NotifyEvent::TokenUsed => ("tokenused", None),
+		};
+		let mut res = vec![("event", event.to_string())];
+		if let Some(id) = id {
+			res.push(("id", id.to_string()));
+		}
+		res
+	}
+}
+
+impl Driver {
+	fn handle_line(&mut self, line: String) -> Result<()> {
+		if self.greeting_lines > 0 {
+			if self.greeting_lines == GREETING_LINES && line != "TS3" {
+				return Err(
+					format_err!("Server is not a ServerQuery interface").into()
+				);
+			}
+			self.greeting_lines -= 1;
+			return Ok(());
+		}
+		if line.is_empty() {
+			return Ok(());
+		}
+
+		if line.starts_with("notify") {
+			match parse_notification(line) {
+				// Ignore if nobody listens to notifications
+				Ok(msg) => drop(self.notifications.unbounded_send(msg)),
+				Err(e) => {
+					warn!(self.logger, "Failed to parse notification";
+						"error" => ?e);
+				}
+			}
+		} else if line.starts_with("error ") {
+			let res = parse_error(&line);
+			match self.current.take() {
+				Some(Pending {
+					answer: Some(answer),
+					rows,
+					..
+				}) => {
+					// Ignore if the future was dropped
+					let _ = answer.send(res.map(|_| rows));
+				}
+				Some(_) => {
+					if let Err(e) = res {
+						warn!(self.logger, "Keepalive failed"; "error" => ?e);
+					}
+				}
+				None => warn!(self.logger, "Got unexpected answer";
+					"line" => &line),
+			}
+		} else {
+			let rows = parse_rows(&line)?;
+			match &mut self.current {
+				Some(cur) => cur.rows.extend(rows),
+				None => warn!(self.logger, "Got unexpected data";
+					"line" => &line),
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Future for Driver {
+	type Item = ();
+	type Error = Error;
+
+	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+		// Handle incoming lines
+		loop {
+			match self.framed.poll()? {
+				Async::Ready(Some(line)) => self.handle_line(line)?,
+				Async::Ready(None) => {
+					debug!(self.logger, "Query connection closed");
+					return Ok(Async::Ready(()));
+				}
+				Async::NotReady => break,
+			}
+		}
+
+		// Send the next command when the last one is answered
+		if self.current.is_none() && self.greeting_lines == 0 {
+			match self.requests.poll() {
+				Ok(Async::Ready(Some(request))) => {
+					self.current = Some(Pending {
+						command: Some(request.command),
+						answer: request.answer,
+						rows: Vec::new(),
+					});
+				}
+				// All clients were dropped
+				Ok(Async::Ready(None)) | Err(_) => {
+					debug!(self.logger, "Closing query connection");
+					return Ok(Async::Ready(()));
+				}
+				Ok(Async::NotReady) => {}
+			}
+		}
+
+		// Keep the connection alive
+		while let Async::Ready(()) = self.keepalive.poll().map_err(|e| {
+			Error::from(format_err!("Keepalive timer failed ({:?})", e))
+		})? {
+			if self.current.is_none() && self.greeting_lines == 0 {
+				self.current = Some(Pending {
+					command: Some(KEEPALIVE_COMMAND.to_vec()),
+					answer: None,
+					rows: Vec::new(),
+				});
+			}
+			self.keepalive.reset(Instant::now() + KEEPALIVE_INTERVAL);
+		}
+
+		if let Some(cur) = &mut self.current {
+			if let Some(command) = cur.command.take() {
+				if let AsyncSink::NotReady(command) =
+					self.framed.start_send(command)?
+				{
+					cur.command = Some(command);
+				} else {
+					self.keepalive.reset(Instant::now() + KEEPALIVE_INTERVAL);
+					// Register the timer again
+					let _ = self.keepalive.poll();
+				}
+			}
+		}
+		self.framed.poll_complete()?;
+
+		Ok(Async::NotReady)
+	}
+}
+
+/// Parse the `error` line, which finishes every answer.
+fn parse_error(line: &str) -> Result<()> {
+	let cmd = parse_command(line)?;
+	let id: u32 = cmd
+		.static_arg("id")
+		.ok_or_else(|| format_err!("Error id is missing"))?
+		.parse()
+		.map_err(|e| format_err!("Cannot parse error id ({:?})", e))?;
+	if id == 0 {
+		return Ok(());
+	}
+
+	let mut message = cmd.static_arg("msg").unwrap_or("").to_string();
+	if let Some(extra) = cmd.static_arg("extra_msg") {
+		message.push_str(": ");
+		message.push_str(extra);
+	}
+	match TsError::from_u32(id) {
+		Some(error) => Err(Error::Ts { error, message }),
+		None => Err(format_err!("Unknown error {} ({})", id, message).into()),
+	}
+}
+
+fn parse_notification(line: String) -> Result<InMessage> {
+	let cmd = InCommand::new(
+		line.into_bytes(),
+		PacketType::Command,
+		false,
+		Direction::S2C,
+	)
+	.map_err(|(_, e)| e)?;
+	InMessage::new(cmd).map_err(|(cmd, e)| {
+		format_err!("Cannot parse {} ({})", cmd.name(), e).into()
+	})
+}
+
+/// Parse a line of data, which can contain multiple rows separated by `|`.
+fn parse_rows(line: &str) -> Result<Vec<Row>> {
+	let cmd = parse_command(line)?;
+	Ok(cmd
+		.iter()
+		.map(|c| {
+			let mut row: Row = c
+				.0
+				.iter()
+				.map(|(k, v)| (k.to_string(), v.to_string()))
+				.collect();
+			// The first argument is parsed as name if it has no value
+			if !cmd.name.is_empty() {
+				row.insert(cmd.name.to_string(), String::new());
+			}
+			row
+		})
+		.collect())
+}
+
+fn get_parse<T: FromStr>(row: &Row, key: &str) -> Result<T>
+where T::Err: std::fmt::Debug {
+	let value =
+		row.get(key).ok_or_else(|| format_err!("Argument {} is missing", key))?;
+	Ok(value.parse().map_err(|e| {
+		format_err!("Cannot parse {} for {} ({:?})", value, key, e)
+	})?)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::{BufRead, BufReader, Write};
+	use std::net::TcpListener;
+	use std::thread;
+
+	use slog::Drain;
+	use tokio::runtime::Runtime;
+
+	use super::*;
+
+	fn setup() -> (Logger, Runtime) {
+		let rt = Runtime::new().unwrap();
+		let logger = {
+			let decorator =
+				slog_term::PlainSyncDecorator::new(std::io::stdout());
+			let drain = slog_term::CompactFormat::new(decorator).build().fuse();
+			let drain = slog_async::Async::new(drain).build().fuse();
+
+			slog::Logger::root(drain, o!())
+		};
+		(logger, rt)
+	}
+
+	#[test]
+	fn parse_error_line() {
+		assert!(parse_error("error id=0 msg=ok").is_ok());
+		match parse_error("error id=520 msg=invalid\\slogin") {
+			Err(Error::Ts { error, message }) => {
+				assert_eq!(Some(error), TsError::from_u32(520));
+				assert_eq!(message, "invalid login");
+			}
+			r => panic!("Unexpected result {:?}", r),
+		}
+	}
+
+	#[test]
+	fn parse_data_rows() {
+		let rows = parse_rows(
+			"virtualserver_id=1 virtualserver_name=A|virtualserver_id=2 \
+			 virtualserver_name=B\\sC",
+		)
+		.unwrap();
+		assert_eq!(rows.len(), 2);
+		assert_eq!(rows[1]["virtualserver_id"], "2");
+		assert_eq!(rows[1]["virtualserver_name"], "B C");
+	}
+
+	#[test]
+	fn notify_args() {
+		assert_eq!(
+			NotifyEvent::TextPrivate.args(),
+			vec![("event", "textprivate".to_string())]
+		);
+		assert_eq!(
+			NotifyEvent::Channel(ChannelId(0)).args(),
+			vec![("event", "channel".to_string()), ("id", "0".to_string())]
+		);
+	}
+
+	#[test]
+	fn fake_server() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let server = thread::spawn(move || {
+			let (mut tcp, _) = listener.accept().unwrap();
+			let mut reader = BufReader::new(tcp.try_clone().unwrap());
+			write!(tcp, "TS3\n\rWelcome to the TeamSpeak 3 ServerQuery \
+				interface\n\r").unwrap();
+			let mut line = String::new();
+
+			reader.read_line(&mut line).unwrap();
+			assert_eq!(
+				line,
+				"login client_login_name=serveradmin \
+				 client_login_password=a\\sb\n"
+			);
+			write!(tcp, "error id=0 msg=ok\n\r").unwrap();
+
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+			assert_eq!(line, "servernotifyregister event=textprivate\n");
+			write!(tcp, "notifytextmessage targetmode=1 msg=Hi invokerid=2 \
+				invokername=a invokeruid=b\n\r").unwrap();
+			write!(tcp, "error id=0 msg=ok\n\r").unwrap();
+
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+			assert_eq!(line, "whoami\n");
+			write!(tcp, "virtualserver_status=unknown virtualserver_id=0 \
+				virtualserver_unique_identifier virtualserver_port=0 \
+				client_id=0 client_channel_id=0 client_nickname \
+				client_database_id=1 client_login_name=serveradmin \
+				client_unique_identifier=serveradmin \
+				client_origin_server_id=0\n\r").unwrap();
+			write!(tcp, "error id=0 msg=ok\n\r").unwrap();
+
+			line.clear();
+			reader.read_line(&mut line).unwrap();
+			assert_eq!(line, "use port=9987\n");
+			write!(tcp, "error id=1033 msg=server\\sis\\snot\\srunning\n\r")
+				.unwrap();
+		});
+
+		let (logger, mut rt) = setup();
+		let (whoami, notification, use_res) = rt
+			.block_on(future::lazy(move || {
+				QueryClient::connect(&addr, logger).and_then(
+					|(client, notifications)| {
+						let client2 = client.clone();
+						let client3 = client.clone();
+						client
+							.login("serveradmin", "a b")
+							.and_then(move |_| {
+								client2.register_notify(NotifyEvent::TextPrivate)
+							})
+							.and_then(move |_| client3.whoami())
+							.and_then(move |whoami| {
+								notifications.into_future().map_err(|(e, _)| e)
+									.map(move |(n, _)| (client, whoami, n))
+							})
+							.and_then(|(client, whoami, n)| {
+								client.use_port(9987).then(move |r| {
+									Ok((whoami, n, r))
+								})
+							})
+					},
+				)
+			}))
+			.unwrap();
+		server.join().unwrap();
+
+		assert_eq!(whoami.server_id, None);
+		assert_eq!(whoami.database_id, ClientDbId(1));
+		assert_eq!(whoami.login_name, "serveradmin");
+		assert_eq!(notification.unwrap().command().name(),
+			"notifytextmessage");
+		match use_res {
+			Err(Error::Ts { error, .. }) => {
+				assert_eq!(Some(error), TsError::from_u32(1033))
+			}
+			r => panic!("Unexpected result {:?}", r),
+		}
+	}
+}